@@ -0,0 +1,75 @@
+//! Compares the stack VM and the register machine on a deep arithmetic expression.
+//!
+//! Run with `cargo bench --bench backends`.
+
+use parallelized_programming_language::compiler::regalloc::{RegCompiler, RegVM};
+use parallelized_programming_language::{try_parse_program, BytecodeCompiler, Compiler, Stmt, VM};
+use std::time::{Duration, Instant};
+
+const DEPTH: usize = 200;
+const RUNS: u32 = 2_000;
+
+/// `x = 1.5; (((x + 1) * x - 2) * x + 3 ...)`, nested `DEPTH` operations deep.
+fn source() -> String {
+    let mut expr = "x".to_string();
+    for i in 0..DEPTH {
+        let op = ["+", "*", "-", "/"][i % 4];
+        expr = format!("({} {} (x + {}))", expr, op, i % 7 + 1);
+    }
+    format!("x = 1.5; {}", expr)
+}
+
+fn compile<C: Compiler>(mut compiler: C, source: &str) -> C::Output
+where
+    C::Error: std::fmt::Debug,
+{
+    for stmt in try_parse_program(source).unwrap() {
+        if let Stmt::Expr(expr) = stmt {
+            compiler.compile_expr(&expr).unwrap();
+        }
+    }
+    compiler.finish().unwrap()
+}
+
+fn time(name: &str, mut run: impl FnMut() -> f64) -> Duration {
+    let result = run();
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        std::hint::black_box(run());
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:<10} {:>10.2?} per run  (result {})",
+        name,
+        elapsed / RUNS,
+        result
+    );
+    elapsed
+}
+
+fn main() {
+    let source = source();
+    let mut stack = compile(BytecodeCompiler::new(), &source);
+    // Running off the end stops the VM too, without `Halt` printing each time
+    stack.pop();
+    let registers = compile(RegCompiler::new(), &source);
+    println!(
+        "{} stack instructions, {} register instructions over {} registers",
+        stack.len(),
+        registers.code.len(),
+        registers.registers
+    );
+    let stack_time = time("stack", || {
+        let mut vm = VM::new(stack.clone());
+        vm.try_execute().unwrap();
+        vm.stack
+            .pop()
+            .and_then(|value| value.as_num())
+            .unwrap_or(0.0)
+    });
+    let register_time = time("registers", || RegVM::run(registers.clone()).unwrap());
+    println!(
+        "registers take {:.2}x the time of the stack VM",
+        register_time.as_secs_f64() / stack_time.as_secs_f64()
+    );
+}
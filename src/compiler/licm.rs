@@ -0,0 +1,552 @@
+//! Loop-invariant code motion over the syntax tree.
+//!
+//! An expression inside a `while` or `for` loop that reads nothing the loop
+//! writes computes the same value on every iteration, so it is computed once
+//! into a temporary before the loop and the loop reads the temporary instead:
+//!
+//! ```text
+//! while i < n { s = s + x * coeff; i = i + 1 }
+//! ```
+//!
+//! becomes `{ licm#0 = x * coeff; while i < n { s = s + licm#0; i = i + 1 } }`.
+//!
+//! The hoisted code runs even when the loop body never does, so only
+//! expressions that cannot fail or have an effect are moved: arithmetic and
+//! comparisons of numbers and of variables assigned a number on every path to
+//! the loop, with division and remainder only by a nonzero literal and `**` only to a
+//! literal power of at least zero, and `!`, `==`, `!=`, `&&` and `||` of any of
+//! these or of bools. A loop containing a call, which may write any global, or
+//! a spawn is left alone, and no variable is known to hold a number after a
+//! call.
+
+use crate::parser::{const_eval, Expr, ExprKind, Stmt};
+use crate::scanner::Token;
+use std::collections::HashSet;
+
+/// Hoists the invariant expressions of every loop in `program`, in place.
+/// Temporaries are named `licm#<n>`, which no identifier can clash with.
+pub fn hoist_loop_invariants(program: &mut [Stmt]) {
+    let mut motion = LoopInvariantMotion { next: 0 };
+    motion.statements(program, &mut HashSet::new());
+}
+
+struct LoopInvariantMotion {
+    /// Number of the next temporary
+    next: usize,
+}
+
+impl LoopInvariantMotion {
+    /// `assigned` holds the variables assigned a number on every path so far,
+    /// and changes with the assignments in `stmts`.
+    fn statements(&mut self, stmts: &mut [Stmt], assigned: &mut HashSet<String>) {
+        for stmt in stmts {
+            match stmt {
+                Stmt::Expr(expr) => self.expr(expr, assigned, false),
+                Stmt::Let { name, value, .. } => {
+                    self.expr(value, assigned, false);
+                    assign(assigned, name, value, false);
+                }
+                Stmt::Func { body, .. } => {
+                    // Globals may still be unset when the function runs, and
+                    // an argument may be a string
+                    self.statements(body, &mut HashSet::new());
+                }
+                Stmt::Return { value, .. } => {
+                    if let Some(value) = value {
+                        self.expr(value, assigned, false);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Walks `expr` in evaluation order. With `conditional`, it may not run,
+    /// so its assignments can only take variables out of `assigned`.
+    fn expr(&mut self, expr: &mut Expr, assigned: &mut HashSet<String>, conditional: bool) {
+        match &mut expr.kind {
+            ExprKind::Number(_)
+            | ExprKind::Ident(_)
+            | ExprKind::Bool(_)
+            | ExprKind::Str(_)
+            | ExprKind::Sync
+            | ExprKind::Barrier
+            | ExprKind::Error => {}
+            ExprKind::UnaryOp { rhs, .. } | ExprKind::Group(rhs) => {
+                self.expr(rhs, assigned, conditional)
+            }
+            ExprKind::BinaryOp { lhs, op, rhs } => {
+                self.expr(lhs, assigned, conditional);
+                let short_circuit = matches!(op, Token::AndAnd | Token::OrOr);
+                self.expr(rhs, assigned, conditional || short_circuit);
+            }
+            ExprKind::Call { callee, args } => {
+                self.expr(callee, assigned, conditional);
+                for arg in args {
+                    self.expr(arg, assigned, conditional);
+                }
+                // The callee may assign anything to any global
+                assigned.clear();
+            }
+            ExprKind::Assign { name, value } => {
+                self.expr(value, assigned, conditional);
+                assign(assigned, name, value, conditional);
+            }
+            ExprKind::Block(body) | ExprKind::Array(body) => {
+                for item in body {
+                    self.expr(item, assigned, conditional);
+                }
+            }
+            ExprKind::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                self.expr(cond, assigned, conditional);
+                self.expr(then_branch, assigned, true);
+                if let Some(else_branch) = else_branch {
+                    self.expr(else_branch, assigned, true);
+                }
+            }
+            ExprKind::Index { target, index } => {
+                self.expr(target, assigned, conditional);
+                self.expr(index, assigned, conditional);
+            }
+            ExprKind::IndexAssign {
+                target,
+                index,
+                value,
+            } => {
+                self.expr(target, assigned, conditional);
+                self.expr(index, assigned, conditional);
+                self.expr(value, assigned, conditional);
+            }
+            ExprKind::Spawn(task) => self.expr(task, assigned, true),
+            ExprKind::While { cond, body } => {
+                let before = assigned.clone();
+                // The condition runs at least once, the body maybe never
+                self.expr(cond, assigned, conditional);
+                for item in body.iter_mut() {
+                    self.expr(item, assigned, true);
+                }
+                let mut written = HashSet::new();
+                if writes(cond, &mut written) && body.iter().all(|item| writes(item, &mut written))
+                {
+                    let mut hoisted = Vec::new();
+                    self.hoist(cond, &written, &before, &mut hoisted);
+                    for item in body.iter_mut() {
+                        self.hoist(item, &written, &before, &mut hoisted);
+                    }
+                    wrap(expr, hoisted);
+                }
+            }
+            ExprKind::For {
+                var,
+                start,
+                end,
+                body,
+            } => {
+                self.expr(start, assigned, conditional);
+                self.expr(end, assigned, conditional);
+                assign(assigned, var, start, conditional);
+                let before = assigned.clone();
+                for item in body.iter_mut() {
+                    self.expr(item, assigned, true);
+                }
+                let mut written = HashSet::from([var.clone()]);
+                if body.iter().all(|item| writes(item, &mut written)) {
+                    let mut hoisted = Vec::new();
+                    for item in body.iter_mut() {
+                        self.hoist(item, &written, &before, &mut hoisted);
+                    }
+                    wrap(expr, hoisted);
+                }
+            }
+        }
+    }
+
+    /// Replaces each largest invariant subexpression of `expr` with a read of
+    /// a temporary, adding the assignment of the temporary to `hoisted`.
+    /// Repeats of one expression share a temporary.
+    fn hoist(
+        &mut self,
+        expr: &mut Expr,
+        written: &HashSet<String>,
+        assigned: &HashSet<String>,
+        hoisted: &mut Vec<Expr>,
+    ) {
+        if is_worth_hoisting(expr) && is_invariant(expr, written, assigned) {
+            let text = expr.to_string();
+            let name = hoisted
+                .iter()
+                .find_map(|assign| match &assign.kind {
+                    ExprKind::Assign { name, value } if value.to_string() == text => {
+                        Some(name.clone())
+                    }
+                    _ => None,
+                })
+                .unwrap_or_else(|| {
+                    let name = format!("licm#{}", self.next);
+                    self.next += 1;
+                    let value = std::mem::replace(expr, Expr::new(ExprKind::Error, expr.span));
+                    hoisted.push(Expr::new(
+                        ExprKind::Assign {
+                            name: name.clone(),
+                            value: Box::new(value),
+                        },
+                        expr.span,
+                    ));
+                    name
+                });
+            expr.kind = ExprKind::Ident(name);
+            return;
+        }
+        let mut hoist = |expr: &mut Expr| self.hoist(expr, written, assigned, hoisted);
+        match &mut expr.kind {
+            ExprKind::UnaryOp { rhs, .. } | ExprKind::Group(rhs) => hoist(rhs),
+            // Hoisting out of a right operand that may not run is still safe,
+            // since only expressions that cannot fail are moved
+            ExprKind::BinaryOp { lhs, rhs, .. } => {
+                hoist(lhs);
+                hoist(rhs);
+            }
+            ExprKind::Call { args: items, .. }
+            | ExprKind::Block(items)
+            | ExprKind::Array(items) => items.iter_mut().for_each(hoist),
+            ExprKind::Assign { value, .. } => hoist(value),
+            ExprKind::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                hoist(cond);
+                hoist(then_branch);
+                if let Some(else_branch) = else_branch {
+                    hoist(else_branch);
+                }
+            }
+            ExprKind::Index { target, index } => {
+                hoist(target);
+                hoist(index);
+            }
+            ExprKind::IndexAssign {
+                target,
+                index,
+                value,
+            } => {
+                hoist(target);
+                hoist(index);
+                hoist(value);
+            }
+            // A nested loop's invariants were hoisted in front of it already,
+            // so what remains of its condition and body varies with it
+            _ => {}
+        }
+    }
+}
+
+/// Records in `assigned` whether `name` holds a number after it is assigned
+/// `value`, which may not happen when the assignment is `conditional`.
+fn assign(assigned: &mut HashSet<String>, name: &str, value: &Expr, conditional: bool) {
+    if !conditional && is_number(value, assigned) {
+        assigned.insert(name.to_string());
+    } else if !is_number(value, assigned) {
+        assigned.remove(name);
+    }
+}
+
+/// Whether `expr` gives a number whenever it succeeds, rather than a bool,
+/// a string or an array, when the variables in `assigned` hold numbers.
+fn is_number(expr: &Expr, assigned: &HashSet<String>) -> bool {
+    match &expr.kind {
+        ExprKind::Number(_) => true,
+        ExprKind::Ident(name) => assigned.contains(name),
+        ExprKind::Group(inner) => is_number(inner, assigned),
+        ExprKind::UnaryOp {
+            op: Token::Minus, ..
+        } => true,
+        ExprKind::UnaryOp {
+            op: Token::Plus,
+            rhs,
+        } => is_number(rhs, assigned),
+        // `+` concatenates strings
+        ExprKind::BinaryOp {
+            lhs,
+            op: Token::Plus,
+            rhs,
+        } => is_number(lhs, assigned) || is_number(rhs, assigned),
+        // Comparisons and logic give bools, and an operator of its own is a call
+        ExprKind::BinaryOp { op, .. } => matches!(
+            op,
+            Token::Minus | Token::Star | Token::Slash | Token::Percent | Token::StarStar
+        ),
+        _ => false,
+    }
+}
+
+/// Adds the variables `expr` assigns to `written`, returning false when it
+/// contains a call or spawn, which may write anything.
+fn writes(expr: &Expr, written: &mut HashSet<String>) -> bool {
+    let all = |items: &[Expr], written: &mut HashSet<String>| {
+        items.iter().all(|item| writes(item, written))
+    };
+    match &expr.kind {
+        ExprKind::Call { .. } | ExprKind::Spawn(_) | ExprKind::Sync | ExprKind::Barrier => false,
+        ExprKind::Number(_)
+        | ExprKind::Ident(_)
+        | ExprKind::Bool(_)
+        | ExprKind::Str(_)
+        | ExprKind::Error => true,
+        ExprKind::UnaryOp { rhs, .. } | ExprKind::Group(rhs) => writes(rhs, written),
+        ExprKind::BinaryOp { lhs, rhs, .. } => writes(lhs, written) && writes(rhs, written),
+        ExprKind::Assign { name, value } => {
+            written.insert(name.clone());
+            writes(value, written)
+        }
+        ExprKind::Block(items) | ExprKind::Array(items) => all(items, written),
+        ExprKind::If {
+            cond,
+            then_branch,
+            else_branch,
+        } => {
+            writes(cond, written)
+                && writes(then_branch, written)
+                && else_branch
+                    .as_ref()
+                    .is_none_or(|else_branch| writes(else_branch, written))
+        }
+        ExprKind::Index { target, index } => writes(target, written) && writes(index, written),
+        ExprKind::IndexAssign {
+            target,
+            index,
+            value,
+        } => writes(target, written) && writes(index, written) && writes(value, written),
+        ExprKind::While { cond, body } => writes(cond, written) && all(body, written),
+        ExprKind::For {
+            var,
+            start,
+            end,
+            body,
+        } => {
+            written.insert(var.clone());
+            writes(start, written) && writes(end, written) && all(body, written)
+        }
+    }
+}
+
+/// Whether `expr` computes something, rather than being a bare variable or a
+/// constant the folder has already reduced.
+fn is_worth_hoisting(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::Group(inner) => is_worth_hoisting(inner),
+        ExprKind::UnaryOp { .. } | ExprKind::BinaryOp { .. } => const_eval(expr).is_none(),
+        _ => false,
+    }
+}
+
+/// Whether `expr` gives the same value on every iteration of a loop writing
+/// `written`, and evaluating it before the loop can neither fail nor have an
+/// effect.
+fn is_invariant(expr: &Expr, written: &HashSet<String>, assigned: &HashSet<String>) -> bool {
+    let invariant = |expr: &Expr| is_invariant(expr, written, assigned);
+    // Arithmetic and ordering fail on bools
+    let number = |expr: &Expr| invariant(expr) && is_number(expr, assigned);
+    match &expr.kind {
+        ExprKind::Number(_) | ExprKind::Bool(_) => true,
+        ExprKind::Ident(name) => assigned.contains(name) && !written.contains(name),
+        ExprKind::Group(inner) => invariant(inner),
+        ExprKind::UnaryOp {
+            op: Token::Minus | Token::Plus,
+            rhs,
+        } => number(rhs),
+        ExprKind::UnaryOp {
+            op: Token::Bang,
+            rhs,
+        } => invariant(rhs),
+        ExprKind::BinaryOp {
+            lhs,
+            op: Token::Slash | Token::Percent,
+            rhs,
+        } => matches!(rhs.kind, ExprKind::Number(divisor) if divisor != 0.0) && number(lhs),
+        // Zero to a negative power divides by zero
+        ExprKind::BinaryOp {
+            lhs,
+            op: Token::StarStar,
+            rhs,
+        } => matches!(rhs.kind, ExprKind::Number(power) if power >= 0.0) && number(lhs),
+        ExprKind::BinaryOp {
+            lhs,
+            op:
+                Token::Plus | Token::Minus | Token::Star | Token::Lt | Token::Le | Token::Gt | Token::Ge,
+            rhs,
+        } => number(lhs) && number(rhs),
+        ExprKind::BinaryOp {
+            lhs,
+            op: Token::EqEq | Token::NotEq | Token::AndAnd | Token::OrOr,
+            rhs,
+        } => invariant(lhs) && invariant(rhs),
+        _ => false,
+    }
+}
+
+/// Puts the `hoisted` assignments in front of the loop `expr`, in a block
+/// that keeps the loop's value.
+fn wrap(expr: &mut Expr, mut hoisted: Vec<Expr>) {
+    if hoisted.is_empty() {
+        return;
+    }
+    let span = expr.span;
+    let lp = std::mem::replace(expr, Expr::new(ExprKind::Error, span));
+    hoisted.push(lp);
+    *expr = Expr::new(ExprKind::Block(hoisted), span);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{BytecodeCompiler, PassManager};
+    use crate::vm::VM;
+
+    /// The program after the pass, one statement per line.
+    fn hoisted(source: &str) -> String {
+        let mut program = crate::try_parse_program(source).unwrap();
+        hoist_loop_invariants(&mut program);
+        let lines: Vec<String> = program.iter().map(|stmt| stmt.to_string()).collect();
+        lines.join("\n")
+    }
+
+    fn run(source: &str) -> f64 {
+        let program = BytecodeCompiler::compile_program(&crate::try_parse_program(source).unwrap());
+        VM::try_run_program(&program.unwrap())
+            .unwrap()
+            .as_num()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_hoists_invariant_product() {
+        let source = "x = 3; coeff = 2; s = 0; i = 0; \
+                      while i < 4 { s = s + x * coeff; i = i + 1 }; s";
+        let after = hoisted(source);
+        assert!(after.contains("licm#0 = x * coeff"), "{}", after);
+        assert!(after.contains("s = s + licm#0"), "{}", after);
+        assert_eq!(run(source), 24.0);
+    }
+
+    #[test]
+    fn test_compile_with_hoists_whatever_the_passes() {
+        // The command line compiles through `compile_with`, even with no passes
+        let source = "x = 3; coeff = 2; s = 0; i = 0; \
+                      while i < 4 { s = s + x * coeff; i = i + 1 }; s";
+        let program = crate::try_parse_program(source).unwrap();
+        for passes in [PassManager::new(), PassManager::default_pipeline()] {
+            let compiled = BytecodeCompiler::compile_with(&program, &passes).unwrap();
+            assert!(compiled.symbols.contains_key("licm#0"));
+            assert_eq!(VM::try_run_program(&compiled), Ok(crate::Value::Num(24.0)));
+        }
+    }
+
+    #[test]
+    fn test_repeats_share_a_temporary() {
+        let source = "a = 2; s = 0; for i = 1 to 3 { s = s + (a + 1) * i - (a + 1) }; s";
+        let after = hoisted(source);
+        assert!(after.contains("licm#0"), "{}", after);
+        assert!(!after.contains("licm#1"), "{}", after);
+        assert_eq!(run(source), 9.0);
+    }
+
+    #[test]
+    fn test_leaves_variant_expressions() {
+        // `i` is written by the loop, and `for` writes its own variable
+        let source = "x = 1; i = 0; while i < 3 { i = i + x * i }; for j = 1 to 2 { x * j }";
+        assert_eq!(hoisted(source), source.replace("; ", "\n"));
+    }
+
+    #[test]
+    fn test_calls_block_hoisting() {
+        let source = "fn bump() { k = k + 1; 0 } k = 1; s = 0; \
+                      for i = 1 to 3 { s = s + k * 10 + bump() }; s";
+        assert!(!hoisted(source).contains("licm#"));
+        assert_eq!(run(source), 10.0 + 20.0 + 30.0);
+    }
+
+    #[test]
+    fn test_never_hoists_a_division_that_might_not_run() {
+        // The body is skipped on the first test of the condition, so the
+        // original never divides by zero and the hoisted code must not either
+        let source = "n = 0; d = 0; s = 0; while n > 0 { s = s + 10 / d; n = n - 1 }; s";
+        assert!(!hoisted(source).contains("licm#"));
+        assert_eq!(run(source), 0.0);
+        let source = "d = 0; s = 0; for i = 1 to 3 { if i > 5 { s = s + 1 % d } }; s";
+        assert!(!hoisted(source).contains("licm#"));
+        assert_eq!(run(source), 0.0);
+        let source = "z = 0; s = 0; for i = 1 to 0 { s = s + z ** -1 }; s";
+        assert!(!hoisted(source).contains("licm#"));
+        assert_eq!(run(source), 0.0);
+        // A nonzero literal divisor cannot fail
+        let source = "x = 6; s = 0; for i = 1 to 2 { s = s + x / 2 }; s";
+        assert!(hoisted(source).contains("licm#0 = x / 2"));
+        assert_eq!(run(source), 6.0);
+    }
+
+    #[test]
+    fn test_needs_variables_assigned_on_every_path() {
+        let source = "c = 0; if c { y = 1 }; s = 0; for i = 1 to 0 { s = y * 2 }; s";
+        assert!(!hoisted(source).contains("licm#"));
+        assert_eq!(run(source), 0.0);
+    }
+
+    #[test]
+    fn test_functions_hoist_from_their_own_assignments() {
+        let source = "g = 5; fn f(n, m) { k = m * 1; s = 0; \
+                      for i = 1 to n { s = s + k * k + g * 2 + m * m }; s } f(3, 2)";
+        let after = hoisted(source);
+        assert!(after.contains("licm#0 = k * k"), "{}", after);
+        assert!(!after.contains("licm#1"), "{}", after);
+        assert_eq!(run(source), 54.0);
+    }
+
+    #[test]
+    fn test_needs_variables_holding_numbers() {
+        let source = "s = \"a\"; t = 0; for i = 1 to 0 { t = s * 2 }; t";
+        assert!(!hoisted(source).contains("licm#"));
+        assert_eq!(run(source), 0.0);
+        let source = "s = 1; s = \"a\"; t = 0; for i = 1 to 0 { t = -s }; t";
+        assert!(!hoisted(source).contains("licm#"));
+        let source = "s = \"a\"; n = s + \"b\"; t = 0; for i = 1 to 0 { t = n < 1 }; t";
+        assert!(!hoisted(source).contains("licm#"));
+        // Nor once a call may have assigned it something else
+        let source = "x = 1; fn f() { x = \"a\"; 0 } f(); t = 0; for i = 1 to 0 { t = -x }; t";
+        assert!(!hoisted(source).contains("licm#"));
+        assert_eq!(run(source), 0.0);
+        let source = "x = 1; fn f() { x = \"a\"; 0 } y = f() + 1; t = 0; \
+                      for i = 1 to 0 { t = -x }; t";
+        assert!(!hoisted(source).contains("licm#"));
+        assert_eq!(run(source), 0.0);
+        let source = "s = \"a\"; n = s * 1; t = 0; for i = 1 to 2 { t = n - 1 }; t";
+        assert!(hoisted(source).contains("licm#0 = n - 1"));
+    }
+
+    #[test]
+    fn test_bools_only_hoist_through_logic() {
+        let source = "a = 1; t = 0; for i = 1 to 0 { t = (a < 2) + 1 }; t";
+        assert!(
+            hoisted(source).contains("licm#0 + 1"),
+            "{}",
+            hoisted(source)
+        );
+        assert_eq!(run(source), 0.0);
+        let source = "a = 1; b = true; t = 0; for i = 1 to 0 { t = -b }; t";
+        assert!(!hoisted(source).contains("licm#"));
+        let source = "a = 1; t = 0; for i = 1 to 2 { t = !(a < 2) || a == 3 }; t";
+        assert!(hoisted(source).contains("licm#0 = !(a < 2) || a == 3"));
+    }
+
+    #[test]
+    fn test_nested_loops_hoist_to_the_outermost() {
+        let source = "a = 3; s = 0; for i = 1 to 2 { for j = 1 to 2 { s = s + a * a + i } }; s";
+        let after = hoisted(source);
+        let outer = after.find("licm#1 = a * a").expect(&after);
+        assert!(outer < after.find("for i").unwrap(), "{}", after);
+        assert_eq!(run(source), 4.0 * 9.0 + 6.0);
+    }
+}
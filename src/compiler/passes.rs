@@ -0,0 +1,705 @@
+//! Optimization passes over a compiled `Program`, and the `PassManager` that
+//! runs them in order until none of them finds anything left to change.
+
+use super::{
+    eliminate_dead_code, jump_targets, peephole_with_entries, remove_instructions, CallTarget,
+    Program,
+};
+use crate::vm::{default_natives, integer_division, Bytecode};
+use std::collections::HashMap;
+
+/// A rewrite of a whole program that keeps its behaviour.
+pub trait Pass {
+    /// A short name for listings and command-line flags, e.g. `"dce"`.
+    fn name(&self) -> &'static str;
+
+    /// Rewrite `program` in place, returning whether anything changed.
+    fn run(&self, program: &mut Program) -> bool;
+}
+
+/// Folds `LoadConst(a); LoadConst(b); <op>` into the `LoadConst` of the
+/// result for every arithmetic operation, and into the `LoadBool` of the
+/// result for every comparison. Division and
+/// remainder by zero, and zero to a negative power, are left to fail at
+/// runtime, and a triple is left alone when a jump lands inside it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FoldConstants;
+
+/// The rewrites of `compiler::peephole`, moving function entries along.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Peephole;
+
+/// Replaces operations whose result a cheaper sequence gives exactly:
+///
+/// - `x * 2` becomes `x + x`, with `x` evaluated once and duplicated, when `x`
+///   is sure to be a number rather than a string `+` would concatenate
+/// - `x / 1` is removed, like `x * 1` by the peephole, and `--x` becomes `x`,
+///   when `x` is likewise sure to be a number, since other values make them fail
+///
+/// `x * 0` and `x - x` are left alone: a number may be NaN or an infinity,
+/// for which they are NaN rather than 0, and any other value makes them fail.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StrengthReduction;
+
+/// Replaces each call of a small user function with a copy of its body.
+///
+/// Only leaf functions are inlined: ones whose body, at most `max_size`
+/// instructions between the parameter prologue and the `Return`, calls no user
+/// function, so recursion is never unrolled. The copy runs in the caller's
+/// frame, so the function's locals, its parameters included, are moved to
+/// slots nothing else in the program uses. `default_pipeline` leaves this
+/// pass out; name it to run it.
+#[derive(Debug, Clone, Copy)]
+pub struct Inline {
+    pub max_size: usize,
+}
+
+/// `compiler::eliminate_dead_code`, dropping functions the program never calls.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DeadCodeElimination;
+
+fn fold_binary(op: &Bytecode, a: f64, b: f64) -> Option<Bytecode> {
+    let number = match op {
+        Bytecode::Add => a + b,
+        Bytecode::Sub => a - b,
+        Bytecode::Mul => a * b,
+        Bytecode::Div | Bytecode::Mod if b == 0.0 => return None,
+        Bytecode::Div => a / b,
+        Bytecode::Mod => a % b,
+        Bytecode::IDiv if b == 0.0 => return None,
+        Bytecode::IDiv => integer_division(a, b),
+        Bytecode::Pow if a == 0.0 && b < 0.0 => return None,
+        Bytecode::Pow => a.powf(b),
+        Bytecode::Eq => return Some(Bytecode::LoadBool(a == b)),
+        Bytecode::Ne => return Some(Bytecode::LoadBool(a != b)),
+        Bytecode::Lt => return Some(Bytecode::LoadBool(a < b)),
+        Bytecode::Le => return Some(Bytecode::LoadBool(a <= b)),
+        Bytecode::Gt => return Some(Bytecode::LoadBool(a > b)),
+        Bytecode::Ge => return Some(Bytecode::LoadBool(a >= b)),
+        _ => return None,
+    };
+    Some(Bytecode::LoadConst(number))
+}
+
+impl Pass for FoldConstants {
+    fn name(&self) -> &'static str {
+        "fold"
+    }
+
+    fn run(&self, program: &mut Program) -> bool {
+        let mut any_changed = false;
+        loop {
+            let code = &mut program.code;
+            let targets = jump_targets(code, &program.functions);
+            let mut keep = vec![true; code.len()];
+            let mut changed = false;
+            let mut i = 0;
+            while i + 2 < code.len() {
+                let folded = match (&code[i], &code[i + 1]) {
+                    (&Bytecode::LoadConst(a), &Bytecode::LoadConst(b))
+                        if !targets[i + 1] && !targets[i + 2] =>
+                    {
+                        fold_binary(&code[i + 2], a, b)
+                    }
+                    _ => None,
+                };
+                match folded {
+                    // The result keeps the operation's span, which covers both operands
+                    Some(result) => {
+                        code[i + 2] = result;
+                        (keep[i], keep[i + 1]) = (false, false);
+                        changed = true;
+                        i += 3;
+                    }
+                    None => i += 1,
+                }
+            }
+            if !changed {
+                return any_changed;
+            }
+            any_changed = true;
+            remove_instructions(
+                &mut program.code,
+                &mut program.spans,
+                &mut program.functions,
+                &keep,
+            );
+        }
+    }
+}
+
+/// Whether `instruction` leaves a number on the stack whenever it succeeds.
+pub(super) fn yields_number(instruction: &Bytecode) -> bool {
+    matches!(
+        instruction,
+        Bytecode::LoadConst(_)
+            | Bytecode::LoadConstIdx(_)
+            | Bytecode::Neg
+            | Bytecode::Sub
+            | Bytecode::Mul
+            | Bytecode::Div
+            | Bytecode::Mod
+            | Bytecode::Pow
+            | Bytecode::CheckPow
+            | Bytecode::IDiv
+            | Bytecode::Floor
+            | Bytecode::Ceil
+            | Bytecode::Trunc
+            | Bytecode::BitAnd
+            | Bytecode::BitOr
+            | Bytecode::BitXor
+            | Bytecode::Shl
+            | Bytecode::Shr
+    )
+}
+
+impl Pass for StrengthReduction {
+    fn name(&self) -> &'static str {
+        "strength"
+    }
+
+    fn run(&self, program: &mut Program) -> bool {
+        let mut any_changed = false;
+        loop {
+            let code = &mut program.code;
+            let targets = jump_targets(code, &program.functions);
+            let mut keep = vec![true; code.len()];
+            let mut changed = false;
+            // Rewrites only look at instructions from here on, which earlier
+            // ones in this sweep have left alone
+            let mut frontier = 0;
+            for i in 1..code.len() {
+                if i - 1 < frontier || targets[i] {
+                    continue;
+                }
+                // Where branches join, the instruction before is only one of
+                // the ways the operand can come
+                let operand_is_number = i >= 2 && !targets[i - 1] && yields_number(&code[i - 2]);
+                // The instructions from `start` through `i` are dropped
+                let start = match (&code[i - 1], &code[i]) {
+                    (Bytecode::LoadConst(2.0), Bytecode::Mul) if operand_is_number => {
+                        (code[i - 1], code[i]) = (Bytecode::Dup, Bytecode::Add);
+                        changed = true;
+                        frontier = i + 1;
+                        continue;
+                    }
+                    // Dividing or negating anything but a number fails
+                    (Bytecode::LoadConst(1.0), Bytecode::Div) | (Bytecode::Neg, Bytecode::Neg)
+                        if operand_is_number =>
+                    {
+                        i - 1
+                    }
+                    _ => continue,
+                };
+                // A jump into the middle would find a different stack
+                if start < frontier || targets[start + 1..i].iter().any(|&target| target) {
+                    continue;
+                }
+                keep[start..=i].fill(false);
+                changed = true;
+                frontier = i + 1;
+            }
+            if !changed {
+                return any_changed;
+            }
+            any_changed = true;
+            remove_instructions(
+                &mut program.code,
+                &mut program.spans,
+                &mut program.functions,
+                &keep,
+            );
+        }
+    }
+}
+
+impl Inline {
+    /// The body size below which functions are inlined unless told otherwise.
+    pub const DEFAULT_MAX_SIZE: usize = 8;
+
+    pub fn new(max_size: usize) -> Self {
+        Inline { max_size }
+    }
+
+    /// The body of the function at `entry` taking `argc` arguments, from its
+    /// prologue up to but not including its only `Return`, if it can be inlined.
+    fn inlinable_body<'a>(
+        &self,
+        program: &'a Program,
+        entry: usize,
+        argc: usize,
+    ) -> Option<&'a [Bytecode]> {
+        let code = &program.code;
+        let end = entry
+            + code
+                .get(entry..)?
+                .iter()
+                .position(|op| *op == Bytecode::Return)?;
+        let body = &code[entry..end];
+        let prologue_ok = body.len() >= argc
+            && body[..argc]
+                .iter()
+                .all(|op| matches!(op, Bytecode::StoreVar(_)));
+        let leaf = body.iter().all(|op| match op {
+            Bytecode::Call(name, _) => !program.functions.contains_key(name),
+            &Bytecode::CallIdx(index, _) => matches!(
+                program.call_targets.get(index as usize),
+                Some(CallTarget::Native(_))
+            ),
+            Bytecode::TailCall(..)
+            | Bytecode::SpawnBlock(..)
+            | Bytecode::Halt
+            | Bytecode::HaltWith(_) => false,
+            // Jumps may only land inside the body or on its `Return`
+            _ => op
+                .jump_target()
+                .is_none_or(|target| (entry..=end).contains(&target)),
+        });
+        (prologue_ok && leaf && body.len() - argc <= self.max_size).then_some(body)
+    }
+}
+
+impl Default for Inline {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_MAX_SIZE)
+    }
+}
+
+impl Pass for Inline {
+    fn name(&self) -> &'static str {
+        "inline"
+    }
+
+    fn run(&self, program: &mut Program) -> bool {
+        let natives = default_natives();
+        let mut next_slot = program
+            .code
+            .iter()
+            .filter_map(|op| match op {
+                Bytecode::LoadVar(slot)
+                | Bytecode::StoreVar(slot)
+                | Bytecode::LoadGlobal(slot)
+                | Bytecode::StoreGlobal(slot) => Some(slot + 1),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        let has_spans = program.spans.len() == program.code.len();
+        let mut code = Vec::with_capacity(program.code.len());
+        let mut spans = Vec::new();
+        // Where each old instruction, and the end of the code, ended up
+        let mut new_address = Vec::with_capacity(program.code.len() + 1);
+        // Copied instructions whose jump target is still an old address
+        let mut fixups = Vec::new();
+        let mut changed = false;
+        for (pc, op) in program.code.iter().enumerate() {
+            new_address.push(code.len());
+            let body = match op {
+                // A native of the same name would take precedence at runtime
+                Bytecode::Call(name, argc) if !natives.contains_key(name) => program
+                    .functions
+                    .get(name)
+                    .and_then(|&entry| Some((entry, self.inlinable_body(program, entry, *argc)?))),
+                _ => None,
+            };
+            let Some((entry, body)) = body else {
+                if op.jump_target().is_some() {
+                    fixups.push(code.len());
+                }
+                code.push(op.clone());
+                if has_spans {
+                    spans.push(program.spans[pc]);
+                }
+                continue;
+            };
+            changed = true;
+            let base = code.len();
+            let mut renamed = HashMap::new();
+            for (offset, op) in body.iter().enumerate() {
+                let mut op = op.clone();
+                match &mut op {
+                    Bytecode::LoadVar(slot) | Bytecode::StoreVar(slot) => {
+                        *slot = *renamed.entry(*slot).or_insert_with(|| {
+                            next_slot += 1;
+                            next_slot - 1
+                        });
+                    }
+                    _ => {}
+                }
+                if let Some(target) = op.jump_target() {
+                    // The `Return` becomes whatever follows the call
+                    op.retarget(base + target - entry);
+                }
+                code.push(op);
+                if has_spans {
+                    spans.push(program.spans[entry + offset]);
+                }
+            }
+        }
+        new_address.push(code.len());
+        if !changed {
+            return false;
+        }
+        for index in fixups {
+            let target = code[index].jump_target().unwrap();
+            let relocated = new_address
+                .get(target)
+                .copied()
+                .unwrap_or_else(|| target - program.code.len() + code.len());
+            code[index].retarget(relocated);
+        }
+        for entry in program.functions.values_mut() {
+            *entry = new_address[*entry];
+        }
+        program.code = code;
+        if has_spans {
+            program.spans = spans;
+        }
+        true
+    }
+}
+
+impl Pass for Peephole {
+    fn name(&self) -> &'static str {
+        "peephole"
+    }
+
+    fn run(&self, program: &mut Program) -> bool {
+        peephole_with_entries(
+            &mut program.code,
+            &mut program.spans,
+            &mut program.functions,
+        )
+    }
+}
+
+impl Pass for DeadCodeElimination {
+    fn name(&self) -> &'static str {
+        "dce"
+    }
+
+    fn run(&self, program: &mut Program) -> bool {
+        // Only ever removes instructions, so the length tells
+        let before = program.code.len();
+        eliminate_dead_code(program, false);
+        program.code.len() != before
+    }
+}
+
+/// An ordered list of passes. `run` repeats the whole list until a round in
+/// which no pass changed anything, or until the iteration cap is reached.
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass>>,
+    max_iterations: usize,
+}
+
+impl PassManager {
+    /// How many rounds `run` makes at most, unless told otherwise.
+    pub const DEFAULT_MAX_ITERATIONS: usize = 16;
+
+    /// A manager without passes, which leaves programs as they are.
+    pub fn new() -> Self {
+        PassManager {
+            passes: Vec::new(),
+            max_iterations: Self::DEFAULT_MAX_ITERATIONS,
+        }
+    }
+
+    /// `fold → strength → peephole → dce`.
+    pub fn default_pipeline() -> Self {
+        Self::new()
+            .with_pass(FoldConstants)
+            .with_pass(StrengthReduction)
+            .with_pass(Peephole)
+            .with_pass(DeadCodeElimination)
+    }
+
+    /// Append `pass`, to run after the ones already added.
+    pub fn with_pass(mut self, pass: impl Pass + 'static) -> Self {
+        self.passes.push(Box::new(pass));
+        self
+    }
+
+    /// Make at most `max_iterations` rounds over the passes.
+    pub fn with_max_iterations(mut self, max_iterations: usize) -> Self {
+        self.max_iterations = max_iterations;
+        self
+    }
+
+    /// The names of the passes, in the order they run.
+    pub fn pass_names(&self) -> Vec<&'static str> {
+        self.passes.iter().map(|pass| pass.name()).collect()
+    }
+
+    /// Run the passes over `program` to a fixpoint, returning the number of
+    /// rounds in which some pass changed it.
+    pub fn run(&self, program: &mut Program) -> usize {
+        let mut rounds = 0;
+        while rounds < self.max_iterations {
+            let mut changed = false;
+            for pass in &self.passes {
+                changed |= pass.run(program);
+            }
+            if !changed {
+                break;
+            }
+            rounds += 1;
+        }
+        rounds
+    }
+}
+
+impl Default for PassManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::BytecodeCompiler;
+
+    fn program(code: Vec<Bytecode>) -> Program {
+        Program {
+            code,
+            ..Program::default()
+        }
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        let mut folded = program(vec![
+            Bytecode::LoadConst(2.),
+            Bytecode::LoadConst(3.),
+            Bytecode::Mul,
+            Bytecode::LoadConst(1.),
+            Bytecode::Lt,
+            Bytecode::LoadConst(0.),
+            Bytecode::Div,
+            Bytecode::Halt,
+        ]);
+        assert!(FoldConstants.run(&mut folded));
+        assert_eq!(
+            folded.code,
+            vec![
+                Bytecode::LoadBool(false),
+                Bytecode::LoadConst(0.),
+                Bytecode::Div,
+                Bytecode::Halt,
+            ]
+        );
+        assert!(!FoldConstants.run(&mut folded));
+    }
+
+    #[test]
+    fn test_pass_order_matters() {
+        // -(2 + 3): folding first leaves `LoadConst(5); Neg` for the peephole
+        let code = vec![
+            Bytecode::LoadConst(2.),
+            Bytecode::LoadConst(3.),
+            Bytecode::Add,
+            Bytecode::Neg,
+            Bytecode::Halt,
+        ];
+        let mut fold_first = program(code.clone());
+        PassManager::new()
+            .with_pass(FoldConstants)
+            .with_pass(Peephole)
+            .with_max_iterations(1)
+            .run(&mut fold_first);
+        assert_eq!(
+            fold_first.code,
+            vec![Bytecode::LoadConst(-5.), Bytecode::Halt]
+        );
+        let mut peephole_first = program(code);
+        PassManager::new()
+            .with_pass(Peephole)
+            .with_pass(FoldConstants)
+            .with_max_iterations(1)
+            .run(&mut peephole_first);
+        assert_eq!(
+            peephole_first.code,
+            vec![Bytecode::LoadConst(5.), Bytecode::Neg, Bytecode::Halt]
+        );
+        // Iterating to a fixpoint makes up for the order
+        PassManager::new()
+            .with_pass(Peephole)
+            .with_pass(FoldConstants)
+            .run(&mut peephole_first);
+        assert_eq!(peephole_first.code, fold_first.code);
+    }
+
+    /// The code of `source` after strength reduction alone.
+    fn reduced(source: &str) -> Vec<Bytecode> {
+        let program = crate::try_parse_program(source).unwrap();
+        let mut program = BytecodeCompiler::compile_program_unfolded(&program).unwrap();
+        StrengthReduction.run(&mut program);
+        program.code
+    }
+
+    #[test]
+    fn test_strength_reduce_double() {
+        assert_eq!(
+            reduced("x = 3; -x * 2"),
+            vec![
+                Bytecode::LoadConst(3.),
+                Bytecode::StoreVar(0),
+                Bytecode::LoadVar(0),
+                Bytecode::Neg,
+                Bytecode::Dup,
+                Bytecode::Add,
+                Bytecode::Halt,
+            ]
+        );
+        // A variable may hold a string, which doubling would concatenate
+        assert!(reduced("x = 3; x * 2").contains(&Bytecode::Mul));
+        let program = crate::try_parse_program("x = \"ab\"; x * 2").unwrap();
+        let mut program = BytecodeCompiler::compile_program_unfolded(&program).unwrap();
+        PassManager::default_pipeline().run(&mut program);
+        assert!(crate::VM::try_run_program(&program).is_err());
+    }
+
+    #[test]
+    fn test_strength_reduce_identities() {
+        assert_eq!(reduced("x = 3; -x / 1"), reduced("x = 3; -x"));
+        assert_eq!(reduced("x = 3; ---x"), reduced("x = 3; -x"));
+        // Two rounds inside one run: the outer negations meet once the inner go
+        assert_eq!(reduced("x = 3; -----x"), reduced("x = 3; -x"));
+        // A variable may hold a bool, which dividing or negating rejects
+        assert!(reduced("x = 3; x / 1").contains(&Bytecode::Div));
+        assert!(reduced("x = 3; --x").contains(&Bytecode::Neg));
+    }
+
+    #[test]
+    fn test_strength_reduction_keeps_zero_products() {
+        // x may be NaN or infinite, and a bool makes the arithmetic fail
+        assert_ne!(reduced("x = 3; x * 0"), reduced("x = 3; 0"));
+        assert_ne!(reduced("x = 3; x - x"), reduced("x = 3; 0"));
+        let source = "x = 3; (x < 4) * 0";
+        assert!(reduced(source).contains(&Bytecode::Mul));
+        let program = crate::try_parse_program(source).unwrap();
+        let mut program = BytecodeCompiler::compile_program_unfolded(&program).unwrap();
+        StrengthReduction.run(&mut program);
+        assert!(crate::VM::try_run_program(&program).is_err());
+    }
+
+    /// The value `program` leaves on top of the stack.
+    fn result(program: Program) -> f64 {
+        let mut vm = crate::VM::from_program(program);
+        vm.execute();
+        vm.stack.last().and_then(crate::Value::as_num).unwrap()
+    }
+
+    /// `source` compiled without and with `inline`, then stripped of dead code.
+    fn compile_inlined(source: &str, inline: Inline) -> (Program, Program) {
+        let program = crate::try_parse_program(source).unwrap();
+        let plain = BytecodeCompiler::compile_program_unfolded(&program).unwrap();
+        let passes = PassManager::new()
+            .with_pass(inline)
+            .with_pass(DeadCodeElimination);
+        (
+            plain,
+            BytecodeCompiler::compile_with(&program, &passes).unwrap(),
+        )
+    }
+
+    fn calls(program: &Program, name: &str) -> usize {
+        program
+            .code
+            .iter()
+            .filter(|op| matches!(op, Bytecode::Call(callee, _) | Bytecode::TailCall(callee, _) if callee == name))
+            .count()
+    }
+
+    #[test]
+    fn test_inline_small_functions() {
+        let sources = [
+            "fn sq(x) { x * x } total = 0; i = 0; while i < 10 { total = total + sq(i); i = i + 1 }; total",
+            "fn abs(x) { if x < 0 { -x } else { x } } abs(0 - 4) * 10 + abs(5)",
+            "fn sq(x) { x * x } fn quad(y) { sq(sq(y)) } quad(3) + sq(2)",
+            "fn half(a, b) { print(a); (a + b) / 2 } half(3, 8)",
+            "g = 4; fn f(x) { t = x + g; g = t; t * 2 } x = 1; t = 2; f(5) + f(1) + x + t + g",
+        ];
+        for source in sources {
+            let (plain, inlined) = compile_inlined(source, Inline::new(16));
+            assert!(inlined.functions.is_empty(), "{}: {:?}", source, inlined);
+            assert_eq!(result(inlined), result(plain), "{}", source);
+        }
+        let (_, inlined) = compile_inlined(sources[3], Inline::new(16));
+        assert_eq!(calls(&inlined, "print"), 1);
+    }
+
+    #[test]
+    fn test_inline_skips_large_and_recursive_functions() {
+        let (plain, inlined) = compile_inlined("fn sq(x) { x * x } sq(3)", Inline::new(2));
+        assert_eq!(calls(&inlined, "sq"), 1);
+        assert_eq!(result(inlined), result(plain));
+        let source = "fn fact(n) { if n <= 1 { 1 } else { n * fact(n - 1) } } fact(5)";
+        let (plain, inlined) = compile_inlined(source, Inline::default());
+        assert_eq!(inlined, plain);
+    }
+
+    #[test]
+    fn test_inline_renames_parameters() {
+        // Each copy gets slots of its own, so nested calls do not clobber each other
+        let (_, inlined) = compile_inlined(
+            "fn add(a, b) { a + b } add(add(1, 2), add(3, 4))",
+            Inline::default(),
+        );
+        let stores: Vec<usize> = inlined
+            .code
+            .iter()
+            .filter_map(|op| match op {
+                Bytecode::StoreVar(slot) => Some(*slot),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(stores, vec![2, 3, 4, 5, 6, 7]);
+        assert_eq!(result(inlined), 10.);
+    }
+
+    struct AlwaysChanges;
+
+    impl Pass for AlwaysChanges {
+        fn name(&self) -> &'static str {
+            "always"
+        }
+
+        fn run(&self, _: &mut Program) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_fixpoint_loop_terminates() {
+        let mut program = program(vec![Bytecode::Halt]);
+        assert_eq!(PassManager::default_pipeline().run(&mut program), 0);
+        let manager = PassManager::default_pipeline()
+            .with_pass(AlwaysChanges)
+            .with_max_iterations(5);
+        assert_eq!(manager.run(&mut program), 5);
+        assert_eq!(
+            PassManager::default_pipeline().pass_names(),
+            vec!["fold", "strength", "peephole", "dce"]
+        );
+    }
+
+    #[test]
+    fn test_compile_with_default_pipeline() {
+        let source = "fn unused() { 1 } x = 2 * 3; -(x + 0) * 1";
+        let program = crate::try_parse_program(source).unwrap();
+        let optimized =
+            BytecodeCompiler::compile_with(&program, &PassManager::default_pipeline()).unwrap();
+        assert!(optimized.functions.is_empty());
+        assert_eq!(optimized.code[0], Bytecode::LoadConst(6.));
+        let mut vm = crate::VM::from_program(optimized);
+        vm.execute();
+        assert_eq!(vm.stack, vec![-6.]);
+        let unoptimized = BytecodeCompiler::compile_with(&program, &PassManager::new()).unwrap();
+        assert_eq!(
+            unoptimized,
+            BytecodeCompiler::compile_program_unfolded(&program).unwrap()
+        );
+    }
+}
@@ -0,0 +1,607 @@
+//! A register-machine encoding of expressions, as an alternative to the stack
+//! `Bytecode`. Every instruction names the registers it reads and writes, so
+//! `x = x + y` on variables is a single `RAdd` with no loads or stores.
+//!
+//! Each variable has a register of its own, numbered like its memory slot in
+//! the stack backend; the temporaries intermediate results need come after them.
+
+use super::{CompileError, Compiler, SymbolTable};
+use crate::parser::{Expr, ExprKind};
+use crate::scanner::Token;
+use crate::visitor::collect_identifiers;
+use crate::vm::{RuntimeError, RuntimeErrorKind};
+
+/// The index of a register.
+pub type Reg = usize;
+
+/// A register-machine instruction. Arithmetic and comparisons write their
+/// result to the first register and read the others; jumps name an index into
+/// the code.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegInstr {
+    RLoadConst(Reg, f64),
+    RMove(Reg, Reg),
+    RNeg(Reg, Reg),
+    RAdd(Reg, Reg, Reg),
+    RSub(Reg, Reg, Reg),
+    RMul(Reg, Reg, Reg),
+    RDiv(Reg, Reg, Reg),
+    RMod(Reg, Reg, Reg),
+    RPow(Reg, Reg, Reg),
+    // Comparisons write 1.0 or 0.0
+    REq(Reg, Reg, Reg),
+    RNe(Reg, Reg, Reg),
+    RLt(Reg, Reg, Reg),
+    RLe(Reg, Reg, Reg),
+    RGt(Reg, Reg, Reg),
+    RGe(Reg, Reg, Reg),
+    RJump(usize),
+    RJumpIfZero(Reg, usize),
+    RJumpIfNotZero(Reg, usize),
+    /// Stop, with the value of the register as the result.
+    RHalt(Reg),
+}
+
+/// Register code together with the number of registers it uses.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RegProgram {
+    pub code: Vec<RegInstr>,
+    pub registers: usize,
+}
+
+// Temporaries are numbered from here until `finish` knows how many variables there are
+const TEMP: Reg = 1 << (usize::BITS - 1);
+
+/// A compiler targeting `RegInstr`. It accepts numbers, booleans, variables and
+/// assignments, arithmetic and comparison operators, blocks, `if` and `while`;
+/// anything else is an `UnsupportedExpression`.
+///
+/// Like `BytecodeCompiler`, expressions compiled one after another share their
+/// variables and the last one's value is the result.
+#[derive(Debug, Default)]
+pub struct RegCompiler {
+    code: Vec<RegInstr>,
+    symbols: SymbolTable,
+    variables: usize,
+    // Temporaries in use, and the most ever in use at once
+    temps: usize,
+    max_temps: usize,
+    // The register holding the last expression's value
+    result: Option<Reg>,
+}
+
+impl RegCompiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Compile a single expression.
+    pub fn try_compile(expr: &Expr) -> Result<RegProgram, CompileError> {
+        let mut compiler = Self::new();
+        compiler.compile_expr(expr)?;
+        compiler.finish()
+    }
+
+    fn temp(&mut self) -> Reg {
+        let reg = TEMP | self.temps;
+        self.temps += 1;
+        self.max_temps = self.max_temps.max(self.temps);
+        reg
+    }
+
+    fn define(&mut self, name: &str) -> Reg {
+        let reg = self.symbols.define(name);
+        self.variables = self.variables.max(reg + 1);
+        reg
+    }
+
+    fn lookup(&self, name: &str, expr: &Expr) -> Result<Reg, CompileError> {
+        self.symbols
+            .lookup(name)
+            .ok_or_else(|| CompileError::UndefinedVariable {
+                name: name.to_string(),
+                span: expr.span,
+            })
+    }
+
+    fn emit_jump(&mut self, jump: RegInstr) -> usize {
+        self.code.push(jump);
+        self.code.len() - 1
+    }
+
+    /// Points the jump at `at` to the next instruction to be emitted.
+    fn patch_jump(&mut self, at: usize) {
+        let next = self.code.len();
+        match &mut self.code[at] {
+            RegInstr::RJump(target)
+            | RegInstr::RJumpIfZero(_, target)
+            | RegInstr::RJumpIfNotZero(_, target) => *target = next,
+            instruction => panic!("Cannot patch non-jump instruction {:?}", instruction),
+        }
+    }
+
+    /// The register holding the value of `expr`: a variable's own register, or
+    /// a new temporary it is computed into.
+    fn operand(&mut self, expr: &Expr) -> Result<Reg, CompileError> {
+        match &expr.kind {
+            ExprKind::Ident(name) => self.lookup(name, expr),
+            ExprKind::Group(inner) => self.operand(inner),
+            _ => {
+                let reg = self.temp();
+                self.compile_into(expr, reg)?;
+                Ok(reg)
+            }
+        }
+    }
+
+    /// Compiles `expr` for its side effects only.
+    fn compile_discarded(&mut self, expr: &Expr) -> Result<(), CompileError> {
+        let mark = self.temps;
+        match &expr.kind {
+            ExprKind::Assign { name, value } => {
+                self.compile_assign(name, value)?;
+            }
+            _ => {
+                let scratch = self.temp();
+                self.compile_into(expr, scratch)?;
+            }
+        }
+        self.temps = mark;
+        Ok(())
+    }
+
+    /// Stores `value` in the register of variable `name`, which it returns.
+    fn compile_assign(&mut self, name: &str, value: &Expr) -> Result<Reg, CompileError> {
+        let reads_name = collect_identifiers(value).iter().any(|ident| ident == name);
+        if reads_name && !writes_result_last(value) {
+            // The old value may still be read after the register would be overwritten
+            let value_reg = self.temp();
+            self.compile_into(value, value_reg)?;
+            let reg = self.define(name);
+            self.code.push(RegInstr::RMove(reg, value_reg));
+            Ok(reg)
+        } else {
+            let reg = self.define(name);
+            self.compile_into(value, reg)?;
+            Ok(reg)
+        }
+    }
+
+    /// Compiles `expr` so that its value ends up in `dst`.
+    fn compile_into(&mut self, expr: &Expr, dst: Reg) -> Result<(), CompileError> {
+        let mark = self.temps;
+        let unsupported = |construct| CompileError::UnsupportedExpression {
+            construct,
+            span: expr.span,
+        };
+        match &expr.kind {
+            ExprKind::Number(value) => self.code.push(RegInstr::RLoadConst(dst, *value)),
+            ExprKind::Bool(value) => self
+                .code
+                .push(RegInstr::RLoadConst(dst, if *value { 1.0 } else { 0.0 })),
+            ExprKind::Group(inner) => self.compile_into(inner, dst)?,
+            ExprKind::Ident(name) => {
+                let reg = self.lookup(name, expr)?;
+                self.code.push(RegInstr::RMove(dst, reg));
+            }
+            ExprKind::UnaryOp { op, rhs } => match op {
+                Token::Minus => {
+                    let reg = self.operand(rhs)?;
+                    self.code.push(RegInstr::RNeg(dst, reg));
+                }
+                // Unary plus leaves its operand unchanged
+                Token::Plus => self.compile_into(rhs, dst)?,
+                _ => {
+                    return Err(CompileError::UnsupportedOperator {
+                        op: op.clone(),
+                        span: expr.span,
+                    })
+                }
+            },
+            ExprKind::BinaryOp {
+                lhs,
+                op: op @ (Token::AndAnd | Token::OrOr),
+                rhs,
+            } => {
+                // The right operand only runs when the left does not decide the result
+                self.compile_into(lhs, dst)?;
+                let jump_to_decided = match op {
+                    Token::AndAnd => self.emit_jump(RegInstr::RJumpIfZero(dst, 0)),
+                    _ => self.emit_jump(RegInstr::RJumpIfNotZero(dst, 0)),
+                };
+                self.compile_into(rhs, dst)?;
+                let zero = self.temp();
+                self.code.push(RegInstr::RLoadConst(zero, 0.0));
+                self.code.push(RegInstr::RNe(dst, dst, zero));
+                let jump_to_end = self.emit_jump(RegInstr::RJump(0));
+                self.patch_jump(jump_to_decided);
+                let decided = if *op == Token::AndAnd { 0.0 } else { 1.0 };
+                self.code.push(RegInstr::RLoadConst(dst, decided));
+                self.patch_jump(jump_to_end);
+            }
+            ExprKind::BinaryOp { lhs, op, rhs } => {
+                let instruction: fn(Reg, Reg, Reg) -> RegInstr = match op {
+                    Token::Plus => RegInstr::RAdd,
+                    Token::Minus => RegInstr::RSub,
+                    Token::Star => RegInstr::RMul,
+                    Token::Slash => RegInstr::RDiv,
+                    Token::Percent => RegInstr::RMod,
+                    Token::StarStar => RegInstr::RPow,
+                    Token::EqEq => RegInstr::REq,
+                    Token::NotEq => RegInstr::RNe,
+                    Token::Lt => RegInstr::RLt,
+                    Token::Le => RegInstr::RLe,
+                    Token::Gt => RegInstr::RGt,
+                    Token::Ge => RegInstr::RGe,
+                    _ => {
+                        return Err(CompileError::UnsupportedOperator {
+                            op: op.clone(),
+                            span: expr.span,
+                        })
+                    }
+                };
+                // A variable read on the left must not see an assignment on the right
+                let a = if has_assignment(rhs) {
+                    let reg = self.temp();
+                    self.compile_into(lhs, reg)?;
+                    reg
+                } else if dst & TEMP != 0 && !matches!(lhs.kind, ExprKind::Ident(_)) {
+                    // Nothing on the right reads a temporary, so it can hold the left value
+                    self.compile_into(lhs, dst)?;
+                    dst
+                } else {
+                    self.operand(lhs)?
+                };
+                let b = self.operand(rhs)?;
+                self.code.push(instruction(dst, a, b));
+            }
+            ExprKind::Assign { name, value } => {
+                let reg = self.compile_assign(name, value)?;
+                if reg != dst {
+                    self.code.push(RegInstr::RMove(dst, reg));
+                }
+            }
+            ExprKind::Block(body) => match body.split_last() {
+                Some((last, rest)) => {
+                    for item in rest {
+                        self.compile_discarded(item)?;
+                    }
+                    self.compile_into(last, dst)?;
+                }
+                None => self.code.push(RegInstr::RLoadConst(dst, 0.0)),
+            },
+            ExprKind::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                let cond = self.operand(cond)?;
+                let jump_to_else = self.emit_jump(RegInstr::RJumpIfZero(cond, 0));
+                self.temps = mark;
+                self.compile_into(then_branch, dst)?;
+                let jump_to_end = self.emit_jump(RegInstr::RJump(0));
+                self.patch_jump(jump_to_else);
+                match else_branch {
+                    Some(else_branch) => self.compile_into(else_branch, dst)?,
+                    None => self.code.push(RegInstr::RLoadConst(dst, 0.0)),
+                }
+                self.patch_jump(jump_to_end);
+            }
+            ExprKind::While { cond, body } => {
+                let head = self.code.len();
+                let cond = self.operand(cond)?;
+                let jump_to_exit = self.emit_jump(RegInstr::RJumpIfZero(cond, 0));
+                self.temps = mark;
+                for item in body {
+                    self.compile_discarded(item)?;
+                }
+                self.code.push(RegInstr::RJump(head));
+                self.patch_jump(jump_to_exit);
+                // The loop itself evaluates to 0.0
+                self.code.push(RegInstr::RLoadConst(dst, 0.0));
+            }
+            ExprKind::Str(_) => return Err(unsupported("A string literal")),
+            ExprKind::Call { .. } => return Err(unsupported("A call")),
+            ExprKind::Array(_) | ExprKind::Index { .. } | ExprKind::IndexAssign { .. } => {
+                return Err(unsupported("An array"))
+            }
+            ExprKind::Spawn(_) => return Err(unsupported("spawn")),
+            ExprKind::Sync => return Err(unsupported("sync")),
+            ExprKind::Barrier => return Err(unsupported("barrier")),
+            ExprKind::For { .. } => return Err(unsupported("A for loop")),
+            ExprKind::Error => return Err(unsupported("A syntax error")),
+        }
+        self.temps = mark;
+        Ok(())
+    }
+}
+
+/// Whether compiling `expr` into a register writes it only with the final
+/// instruction, after every operand has been read.
+fn writes_result_last(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::Group(inner) => writes_result_last(inner),
+        ExprKind::UnaryOp { op, .. } => *op == Token::Minus,
+        ExprKind::BinaryOp { op, .. } => !matches!(op, Token::AndAnd | Token::OrOr),
+        _ => false,
+    }
+}
+
+/// Whether evaluating `expr` may assign to any variable.
+fn has_assignment(expr: &Expr) -> bool {
+    use crate::visitor::Visitor;
+    struct Finder(bool);
+    impl Visitor for Finder {
+        fn visit_assign(&mut self, _name: &str, _value: &Expr) {
+            self.0 = true;
+        }
+    }
+    let mut finder = Finder(false);
+    finder.visit_expr(expr);
+    finder.0
+}
+
+impl Compiler for RegCompiler {
+    type Output = RegProgram;
+    type Error = CompileError;
+
+    fn compile_expr(&mut self, expr: &Expr) -> Result<(), CompileError> {
+        // Only the last expression's register matters, so temporaries start over
+        self.temps = 0;
+        let result = match &expr.kind {
+            ExprKind::Assign { name, value } => self.compile_assign(name, value)?,
+            _ => self.operand(expr)?,
+        };
+        self.result = Some(result);
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<RegProgram, CompileError> {
+        let result = match self.result {
+            Some(result) => result,
+            None => {
+                self.temps = 0;
+                let result = self.temp();
+                self.code.push(RegInstr::RLoadConst(result, 0.0));
+                result
+            }
+        };
+        self.code.push(RegInstr::RHalt(result));
+        let variables = self.variables;
+        let place = |reg: &mut Reg| {
+            if *reg & TEMP != 0 {
+                *reg = variables + (*reg & !TEMP);
+            }
+        };
+        for instruction in &mut self.code {
+            match instruction {
+                RegInstr::RLoadConst(dst, _) => place(dst),
+                RegInstr::RMove(dst, a) | RegInstr::RNeg(dst, a) => {
+                    place(dst);
+                    place(a);
+                }
+                RegInstr::RAdd(dst, a, b)
+                | RegInstr::RSub(dst, a, b)
+                | RegInstr::RMul(dst, a, b)
+                | RegInstr::RDiv(dst, a, b)
+                | RegInstr::RMod(dst, a, b)
+                | RegInstr::RPow(dst, a, b)
+                | RegInstr::REq(dst, a, b)
+                | RegInstr::RNe(dst, a, b)
+                | RegInstr::RLt(dst, a, b)
+                | RegInstr::RLe(dst, a, b)
+                | RegInstr::RGt(dst, a, b)
+                | RegInstr::RGe(dst, a, b) => {
+                    place(dst);
+                    place(a);
+                    place(b);
+                }
+                RegInstr::RJumpIfZero(reg, _)
+                | RegInstr::RJumpIfNotZero(reg, _)
+                | RegInstr::RHalt(reg) => place(reg),
+                RegInstr::RJump(_) => {}
+            }
+        }
+        Ok(RegProgram {
+            code: self.code,
+            registers: variables + self.max_temps,
+        })
+    }
+}
+
+/// An interpreter for `RegProgram`s. Reading a register nothing has written
+/// yet is an `UndefinedVariable` error, as reading an unassigned variable is
+/// on the stack VM.
+pub struct RegVM {
+    pub registers: Vec<f64>,
+    pub pc: usize,
+    code: Vec<RegInstr>,
+    // Which registers have been written
+    written: Vec<bool>,
+}
+
+impl RegVM {
+    pub fn new(program: RegProgram) -> Self {
+        RegVM {
+            registers: vec![0.0; program.registers],
+            pc: 0,
+            code: program.code,
+            written: vec![false; program.registers],
+        }
+    }
+
+    /// Run `program` to its `RHalt`, returning the halting register's value.
+    pub fn run(program: RegProgram) -> Result<f64, RuntimeError> {
+        RegVM::new(program).execute()
+    }
+
+    fn error(&self, kind: RuntimeErrorKind) -> RuntimeError {
+        RuntimeError {
+            kind,
+            pc: self.pc,
+            span: None,
+        }
+    }
+
+    fn read(&self, reg: Reg) -> Result<f64, RuntimeError> {
+        if self.written[reg] {
+            Ok(self.registers[reg])
+        } else {
+            Err(self.error(RuntimeErrorKind::UndefinedVariable(reg)))
+        }
+    }
+
+    fn write(&mut self, reg: Reg, value: f64) {
+        self.registers[reg] = value;
+        self.written[reg] = true;
+    }
+
+    /// Execute from the current pc. Running off the end of the code yields 0.0.
+    pub fn execute(&mut self) -> Result<f64, RuntimeError> {
+        macro_rules! binop {
+            ($dst:expr, $a:expr, $b:expr, $op:tt) => {{
+                let value = self.read(*$a)? $op self.read(*$b)?;
+                self.write(*$dst, value);
+            }};
+        }
+        macro_rules! cmpop {
+            ($dst:expr, $a:expr, $b:expr, $op:tt) => {{
+                let holds = self.read(*$a)? $op self.read(*$b)?;
+                self.write(*$dst, if holds { 1.0 } else { 0.0 });
+            }};
+        }
+        while let Some(instruction) = self.code.get(self.pc) {
+            match instruction {
+                RegInstr::RLoadConst(dst, value) => self.write(*dst, *value),
+                RegInstr::RMove(dst, src) => {
+                    let value = self.read(*src)?;
+                    self.write(*dst, value);
+                }
+                RegInstr::RNeg(dst, src) => {
+                    let value = self.read(*src)?;
+                    self.write(*dst, -value);
+                }
+                RegInstr::RAdd(dst, a, b) => binop!(dst, a, b, +),
+                RegInstr::RSub(dst, a, b) => binop!(dst, a, b, -),
+                RegInstr::RMul(dst, a, b) => binop!(dst, a, b, *),
+                RegInstr::RDiv(dst, a, b) | RegInstr::RMod(dst, a, b) => {
+                    let (a, b) = (self.read(*a)?, self.read(*b)?);
+                    if b == 0.0 {
+                        return Err(self.error(RuntimeErrorKind::DivisionByZero));
+                    }
+                    let is_div = matches!(instruction, RegInstr::RDiv(..));
+                    self.write(*dst, if is_div { a / b } else { a % b });
+                }
+                RegInstr::RPow(dst, a, b) => {
+                    let (a, b) = (self.read(*a)?, self.read(*b)?);
+                    if a == 0.0 && b < 0.0 {
+                        return Err(self.error(RuntimeErrorKind::DivisionByZero));
+                    }
+                    self.write(*dst, a.powf(b));
+                }
+                RegInstr::REq(dst, a, b) => cmpop!(dst, a, b, ==),
+                RegInstr::RNe(dst, a, b) => cmpop!(dst, a, b, !=),
+                RegInstr::RLt(dst, a, b) => cmpop!(dst, a, b, <),
+                RegInstr::RLe(dst, a, b) => cmpop!(dst, a, b, <=),
+                RegInstr::RGt(dst, a, b) => cmpop!(dst, a, b, >),
+                RegInstr::RGe(dst, a, b) => cmpop!(dst, a, b, >=),
+                RegInstr::RJump(target) => {
+                    self.pc = *target;
+                    continue;
+                }
+                RegInstr::RJumpIfZero(reg, target) => {
+                    if self.read(*reg)? == 0.0 {
+                        self.pc = *target;
+                        continue;
+                    }
+                }
+                RegInstr::RJumpIfNotZero(reg, target) => {
+                    if self.read(*reg)? != 0.0 {
+                        self.pc = *target;
+                        continue;
+                    }
+                }
+                RegInstr::RHalt(reg) => return self.read(*reg),
+            }
+            self.pc += 1;
+        }
+        Ok(0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(source: &str) -> RegProgram {
+        let mut compiler = RegCompiler::new();
+        for stmt in crate::try_parse_program(source).unwrap() {
+            let crate::Stmt::Expr(expr) = stmt else {
+                panic!("not an expression statement")
+            };
+            compiler.compile_expr(&expr).unwrap();
+        }
+        compiler.finish().unwrap()
+    }
+
+    #[test]
+    fn test_variables_are_operands_without_moves() {
+        let program = compile("x = 2; y = 3; x * y + x");
+        assert_eq!(
+            program.code,
+            vec![
+                RegInstr::RLoadConst(0, 2.),
+                RegInstr::RLoadConst(1, 3.),
+                RegInstr::RMul(2, 0, 1),
+                RegInstr::RAdd(2, 2, 0),
+                RegInstr::RHalt(2),
+            ]
+        );
+        assert_eq!(program.registers, 3);
+        assert_eq!(RegVM::run(program), Ok(8.));
+    }
+
+    #[test]
+    fn test_loop_updates_variables_in_place() {
+        let program = compile("i = 5; total = 0; while i { total = total + i; i = i - 1 }; total");
+        assert!(program
+            .code
+            .iter()
+            .all(|instruction| !matches!(instruction, RegInstr::RMove(0, _))));
+        assert_eq!(RegVM::run(program), Ok(15.));
+    }
+
+    #[test]
+    fn test_assignment_on_right_does_not_change_left_operand() {
+        assert_eq!(RegVM::run(compile("x = 1; x + (x = 10)")), Ok(11.));
+        assert_eq!(RegVM::run(compile("x = 1; x = x + (x = 10); x")), Ok(11.));
+    }
+
+    #[test]
+    fn test_division_by_zero_is_a_runtime_error() {
+        let err = RegVM::run(compile("x = 0; 1 / x")).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::DivisionByZero);
+        let err = RegVM::run(compile("x = 0; x ** -2")).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::DivisionByZero);
+    }
+
+    #[test]
+    fn test_reading_an_unassigned_variable_is_a_runtime_error() {
+        let err = RegVM::run(compile("if 0 { x = 1 }; x")).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::UndefinedVariable(0));
+        assert_eq!(RegVM::run(compile("if 1 { x = 1 }; x")), Ok(1.));
+    }
+
+    #[test]
+    fn test_unsupported_expressions() {
+        let err = RegCompiler::try_compile(&crate::parse_expr("1 + f(2)")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "A call cannot be compiled by this backend at position 4"
+        );
+        assert!(matches!(
+            RegCompiler::try_compile(&crate::parse_expr("y + 1")),
+            Err(CompileError::UndefinedVariable { .. })
+        ));
+    }
+}
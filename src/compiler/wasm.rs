@@ -0,0 +1,821 @@
+//! A backend emitting a WebAssembly text (WAT) module, for running programs
+//! under a wasm runtime.
+//!
+//! Top-level code becomes the exported function `main`, which returns the
+//! value of the last expression, and its variables become mutable globals.
+//! Each user function becomes a wasm function whose parameters and other
+//! variables are `f64` locals. Wasm has no instructions for `**` and `%`, so
+//! the module imports `pow` and `fmod` from the host module `env`, along with
+//! `print` at every arity it is called with. Division and remainder by zero,
+//! and zero to a negative power, trap where the VM raises `DivisionByZero`.
+
+use super::{CompileError, Compiler, Slot, SymbolTable};
+use crate::parser::{Expr, ExprKind, Stmt};
+use crate::scanner::Token;
+use std::collections::BTreeSet;
+
+/// The instructions of one wasm function, one per line.
+#[derive(Debug, Default)]
+struct Body {
+    lines: Vec<String>,
+    indent: usize,
+    // Outside any function, the symbol table's frame slots are the globals
+    top_level: bool,
+    // One past the highest local slot used, and whether division and `**`
+    // need their scratch locals
+    locals: usize,
+    divides: bool,
+    powers: bool,
+}
+
+impl Body {
+    fn emit(&mut self, instruction: impl Into<String>) {
+        let instruction = instruction.into();
+        self.lines
+            .push(format!("{}{}", "  ".repeat(self.indent), instruction));
+    }
+
+    /// Emit the opening instruction of a block, indenting what follows.
+    fn open(&mut self, instruction: &str) {
+        self.emit(instruction);
+        self.indent += 1;
+    }
+
+    /// Emit the `else` of the innermost `if`.
+    fn otherwise(&mut self) {
+        self.indent -= 1;
+        self.emit("else");
+        self.indent += 1;
+    }
+
+    fn close(&mut self) {
+        self.indent -= 1;
+        self.emit("end");
+    }
+
+    fn place(&self, slot: Slot) -> Slot {
+        match slot {
+            Slot::Frame(slot) if self.top_level => Slot::Global(slot),
+            slot => slot,
+        }
+    }
+
+    fn get(&mut self, slot: Slot) {
+        match self.place(slot) {
+            Slot::Frame(slot) => {
+                self.locals = self.locals.max(slot + 1);
+                self.emit(format!("local.get $l{}", slot));
+            }
+            Slot::Global(slot) => self.emit(format!("global.get $g{}", slot)),
+        }
+    }
+
+    /// Store the top of the stack in `slot`, keeping it on the stack when `keep`.
+    fn set(&mut self, slot: Slot, keep: bool) {
+        match self.place(slot) {
+            Slot::Frame(slot) => {
+                self.locals = self.locals.max(slot + 1);
+                let op = if keep { "tee" } else { "set" };
+                self.emit(format!("local.{} $l{}", op, slot));
+            }
+            Slot::Global(slot) => {
+                self.emit(format!("global.set $g{}", slot));
+                if keep {
+                    self.emit(format!("global.get $g{}", slot));
+                }
+            }
+        }
+    }
+
+    /// Turn the `f64` on top of the stack into an `i32` condition, true unless it is 0.0.
+    fn truthy(&mut self) {
+        self.emit("f64.const 0");
+        self.emit("f64.ne");
+    }
+}
+
+/// A compiler from AST expressions to a WAT module, as a `String`.
+///
+/// Like `BytecodeCompiler`, expressions compiled one after another share their
+/// variables and `main` returns the value of the last one. `compile_program`
+/// also accepts function definitions.
+#[derive(Debug)]
+pub struct WatCompiler {
+    symbols: SymbolTable,
+    main: Body,
+    functions: Vec<String>,
+    imports: BTreeSet<String>,
+    has_value: bool,
+}
+
+impl Default for WatCompiler {
+    fn default() -> Self {
+        let mut symbols = SymbolTable::new();
+        // The host provides `print`; any other call must name a user function
+        symbols.restrict_natives(["print"]);
+        WatCompiler {
+            symbols,
+            main: Body {
+                indent: 2,
+                top_level: true,
+                ..Body::default()
+            },
+            functions: Vec::new(),
+            imports: BTreeSet::new(),
+            has_value: false,
+        }
+    }
+}
+
+impl WatCompiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Compile a whole program, its function definitions included, to a module.
+    pub fn compile_program(program: &[Stmt]) -> Result<String, CompileError> {
+        let mut compiler = Self::new();
+        for stmt in program {
+            if let Stmt::Func { name, params, .. } = stmt {
+                compiler.symbols.declare_function(name, params.len());
+            }
+        }
+        let mut main = std::mem::take(&mut compiler.main);
+        compiler.compile_statements(program, &mut main)?;
+        compiler.main = main;
+        for stmt in program {
+            if let Stmt::Func {
+                name, params, body, ..
+            } = stmt
+            {
+                compiler.compile_function(name, params, body)?;
+            }
+        }
+        Ok(compiler.module(true))
+    }
+
+    fn compile_function(
+        &mut self,
+        name: &str,
+        params: &[String],
+        body: &[Stmt],
+    ) -> Result<(), CompileError> {
+        self.symbols.enter_function();
+        for param in params {
+            self.symbols.define(param);
+        }
+        let mut code = Body {
+            indent: 2,
+            locals: params.len(),
+            ..Body::default()
+        };
+        let compiled = self.compile_statements(body, &mut code);
+        self.symbols.exit_function();
+        compiled?;
+        let mut header = format!("  (func $fn_{}", name);
+        for slot in 0..params.len() {
+            header.push_str(&format!(" (param $l{} f64)", slot));
+        }
+        header.push_str(" (result f64)");
+        let mut text = vec![header];
+        for slot in params.len()..code.locals {
+            text.push(format!("    (local $l{} f64)", slot));
+        }
+        if code.divides {
+            text.push("    (local $div f64)".to_string());
+        }
+        if code.powers {
+            text.push("    (local $base f64)".to_string());
+        }
+        text.extend(code.lines);
+        text.push("  )".to_string());
+        self.functions.push(text.join("\n"));
+        Ok(())
+    }
+
+    /// Compile a statement list into `code`, leaving the value of the last
+    /// expression, or 0.0, as the function's result.
+    fn compile_statements(&mut self, stmts: &[Stmt], code: &mut Body) -> Result<(), CompileError> {
+        for (i, stmt) in stmts.iter().enumerate() {
+            let last = i + 1 == stmts.len();
+            match stmt {
+                Stmt::Expr(expr) if last => self.compile(expr, code)?,
+                Stmt::Expr(expr) => self.compile_discarded(expr, code)?,
+                Stmt::Let { name, value, .. } => {
+                    self.compile(value, code)?;
+                    let slot = Slot::Frame(self.symbols.define(name));
+                    code.set(slot, false);
+                }
+                // Defined separately, by `compile_program`
+                Stmt::Func { .. } => {}
+                Stmt::Return { value, .. } => {
+                    match value {
+                        Some(value) => self.compile(value, code)?,
+                        None => code.emit("f64.const 0"),
+                    }
+                    code.emit("return");
+                }
+            }
+        }
+        if !matches!(stmts.last(), Some(Stmt::Expr(_) | Stmt::Return { .. })) {
+            code.emit("f64.const 0");
+        }
+        Ok(())
+    }
+
+    /// Compile `expr` for its side effects only.
+    fn compile_discarded(&mut self, expr: &Expr, code: &mut Body) -> Result<(), CompileError> {
+        match &expr.kind {
+            ExprKind::Assign { name, value } => {
+                self.compile(value, code)?;
+                let slot = self.symbols.assign(name);
+                code.set(slot, false);
+            }
+            _ => {
+                self.compile(expr, code)?;
+                code.emit("drop");
+            }
+        }
+        Ok(())
+    }
+
+    /// Compile `expr` so that it leaves its value on the stack.
+    fn compile(&mut self, expr: &Expr, code: &mut Body) -> Result<(), CompileError> {
+        let unsupported = |construct| CompileError::UnsupportedExpression {
+            construct,
+            span: expr.span,
+        };
+        match &expr.kind {
+            ExprKind::Number(value) => code.emit(format!("f64.const {}", number(*value))),
+            ExprKind::Bool(value) => code.emit(format!("f64.const {}", u8::from(*value))),
+            ExprKind::Group(inner) => self.compile(inner, code)?,
+            ExprKind::Ident(name) => match self.symbols.resolve(name) {
+                Some(slot) => code.get(slot),
+                None => {
+                    return Err(CompileError::UndefinedVariable {
+                        name: name.clone(),
+                        span: expr.span,
+                    })
+                }
+            },
+            ExprKind::UnaryOp { op, rhs } => {
+                self.compile(rhs, code)?;
+                match op {
+                    Token::Minus => code.emit("f64.neg"),
+                    // Unary plus leaves its operand unchanged
+                    Token::Plus => {}
+                    _ => {
+                        return Err(CompileError::UnsupportedOperator {
+                            op: op.clone(),
+                            span: expr.span,
+                        })
+                    }
+                }
+            }
+            ExprKind::BinaryOp {
+                lhs,
+                op: op @ (Token::AndAnd | Token::OrOr),
+                rhs,
+            } => {
+                // The right operand only runs when the left does not decide the result
+                self.compile(lhs, code)?;
+                code.truthy();
+                code.open("if (result f64)");
+                if *op == Token::AndAnd {
+                    self.compile(rhs, code)?;
+                    code.truthy();
+                    code.emit("f64.convert_i32_u");
+                    code.otherwise();
+                    code.emit("f64.const 0");
+                } else {
+                    code.emit("f64.const 1");
+                    code.otherwise();
+                    self.compile(rhs, code)?;
+                    code.truthy();
+                    code.emit("f64.convert_i32_u");
+                }
+                code.close();
+            }
+            ExprKind::BinaryOp { lhs, op, rhs } => {
+                self.compile(lhs, code)?;
+                self.compile(rhs, code)?;
+                match op {
+                    Token::Plus => code.emit("f64.add"),
+                    Token::Minus => code.emit("f64.sub"),
+                    Token::Star => code.emit("f64.mul"),
+                    Token::Slash | Token::Percent => {
+                        code.divides = true;
+                        code.emit("local.tee $div");
+                        code.emit("f64.const 0");
+                        code.emit("f64.eq");
+                        code.open("if");
+                        code.emit("unreachable");
+                        code.close();
+                        code.emit("local.get $div");
+                        if *op == Token::Slash {
+                            code.emit("f64.div");
+                        } else {
+                            code.emit(self.import("fmod", 2, true));
+                        }
+                    }
+                    // A literal exponent of at least zero cannot divide by zero
+                    Token::StarStar if matches!(rhs.kind, ExprKind::Number(n) if n >= 0.0) => {
+                        code.emit(self.import("pow", 2, true))
+                    }
+                    Token::StarStar => {
+                        code.divides = true;
+                        code.powers = true;
+                        code.emit("local.set $div");
+                        code.emit("local.tee $base");
+                        code.emit("f64.const 0");
+                        code.emit("f64.eq");
+                        code.emit("local.get $div");
+                        code.emit("f64.const 0");
+                        code.emit("f64.lt");
+                        code.emit("i32.and");
+                        code.open("if");
+                        code.emit("unreachable");
+                        code.close();
+                        code.emit("local.get $base");
+                        code.emit("local.get $div");
+                        code.emit(self.import("pow", 2, true));
+                    }
+                    Token::EqEq | Token::NotEq | Token::Lt | Token::Le | Token::Gt | Token::Ge => {
+                        let comparison = match op {
+                            Token::EqEq => "f64.eq",
+                            Token::NotEq => "f64.ne",
+                            Token::Lt => "f64.lt",
+                            Token::Le => "f64.le",
+                            Token::Gt => "f64.gt",
+                            _ => "f64.ge",
+                        };
+                        code.emit(comparison);
+                        code.emit("f64.convert_i32_u");
+                    }
+                    _ => {
+                        return Err(CompileError::UnsupportedOperator {
+                            op: op.clone(),
+                            span: expr.span,
+                        })
+                    }
+                }
+            }
+            ExprKind::Call { callee, args } => {
+                let ExprKind::Ident(name) = &callee.kind else {
+                    return Err(unsupported("A call of anything but a named function"));
+                };
+                match self.symbols.arity(name) {
+                    Some(expected) if expected != args.len() => {
+                        return Err(CompileError::ArityMismatch {
+                            name: name.clone(),
+                            expected,
+                            found: args.len(),
+                            span: expr.span,
+                        })
+                    }
+                    None if !self.symbols.is_callable(name) => {
+                        return Err(CompileError::UndefinedFunction {
+                            name: name.clone(),
+                            span: expr.span,
+                        })
+                    }
+                    _ => {}
+                }
+                for arg in args {
+                    self.compile(arg, code)?;
+                }
+                if self.symbols.arity(name).is_some() {
+                    code.emit(format!("call $fn_{}", name));
+                } else {
+                    // Like the VM's native, `print` evaluates to 0.0
+                    code.emit(self.import("print", args.len(), false));
+                    code.emit("f64.const 0");
+                }
+            }
+            ExprKind::Assign { name, value } => {
+                self.compile(value, code)?;
+                let slot = self.symbols.assign(name);
+                code.set(slot, true);
+            }
+            ExprKind::Block(body) => match body.split_last() {
+                Some((last, rest)) => {
+                    for item in rest {
+                        self.compile_discarded(item, code)?;
+                    }
+                    self.compile(last, code)?;
+                }
+                None => code.emit("f64.const 0"),
+            },
+            ExprKind::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                self.compile(cond, code)?;
+                code.truthy();
+                code.open("if (result f64)");
+                self.compile(then_branch, code)?;
+                code.otherwise();
+                match else_branch {
+                    Some(else_branch) => self.compile(else_branch, code)?,
+                    None => code.emit("f64.const 0"),
+                }
+                code.close();
+            }
+            ExprKind::While { cond, body } => {
+                code.open("block");
+                code.open("loop");
+                self.compile(cond, code)?;
+                code.emit("f64.const 0");
+                code.emit("f64.eq");
+                code.emit("br_if 1");
+                for item in body {
+                    self.compile_discarded(item, code)?;
+                }
+                code.emit("br 0");
+                code.close();
+                code.close();
+                // The loop itself evaluates to 0.0
+                code.emit("f64.const 0");
+            }
+            ExprKind::For {
+                var,
+                start,
+                end,
+                body,
+            } => {
+                self.compile(start, code)?;
+                let counter = self.symbols.assign(var);
+                code.set(counter, false);
+                // The bound is evaluated once, into a variable no identifier can name
+                self.compile(end, code)?;
+                let bound = Slot::Frame(self.symbols.define(&format!("for#end{}", code.indent)));
+                code.set(bound, false);
+                code.open("block");
+                code.open("loop");
+                code.get(counter);
+                code.get(bound);
+                code.emit("f64.le");
+                code.emit("i32.eqz");
+                code.emit("br_if 1");
+                for item in body {
+                    self.compile_discarded(item, code)?;
+                }
+                code.get(counter);
+                code.emit("f64.const 1");
+                code.emit("f64.add");
+                code.set(counter, false);
+                code.emit("br 0");
+                code.close();
+                code.close();
+                code.emit("f64.const 0");
+            }
+            ExprKind::Str(_) => return Err(unsupported("A string literal")),
+            ExprKind::Array(_) | ExprKind::Index { .. } | ExprKind::IndexAssign { .. } => {
+                return Err(unsupported("An array"))
+            }
+            ExprKind::Spawn(_) => return Err(unsupported("spawn")),
+            ExprKind::Sync => return Err(unsupported("sync")),
+            ExprKind::Barrier => return Err(unsupported("barrier")),
+            ExprKind::Error => return Err(unsupported("A syntax error")),
+        }
+        Ok(())
+    }
+
+    /// Record that the module imports `name` from the host with `params`
+    /// arguments, and return the instruction calling it.
+    fn import(&mut self, name: &str, params: usize, returns: bool) -> String {
+        let id = match name {
+            "print" => format!("$env.print_{}", params),
+            _ => format!("$env.{}", name),
+        };
+        let mut signature = " (param f64)".repeat(params);
+        if returns {
+            signature.push_str(" (result f64)");
+        }
+        self.imports.insert(format!(
+            "  (import \"env\" \"{}\" (func {}{}))",
+            name, id, signature
+        ));
+        format!("call {}", id)
+    }
+
+    /// The text of the whole module; `main` already ends in its result when `complete`.
+    fn module(&self, complete: bool) -> String {
+        let mut text = vec!["(module".to_string()];
+        text.extend(self.imports.iter().cloned());
+        let globals = self.symbols.globals().values().map(|&slot| slot + 1);
+        for slot in 0..globals.max().unwrap_or(0) {
+            text.push(format!("  (global $g{} (mut f64) (f64.const 0))", slot));
+        }
+        text.push("  (func $main (export \"main\") (result f64)".to_string());
+        if self.main.divides {
+            text.push("    (local $div f64)".to_string());
+        }
+        if self.main.powers {
+            text.push("    (local $base f64)".to_string());
+        }
+        text.extend(self.main.lines.iter().cloned());
+        if !complete {
+            text.push("    f64.const 0".to_string());
+        }
+        text.push("  )".to_string());
+        text.extend(self.functions.iter().cloned());
+        text.push(")".to_string());
+        text.join("\n") + "\n"
+    }
+}
+
+/// `value` as a WAT floating-point literal.
+fn number(value: f64) -> String {
+    if value.is_nan() {
+        "nan".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "inf" } else { "-inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+impl Compiler for WatCompiler {
+    type Output = String;
+    type Error = CompileError;
+
+    fn compile_expr(&mut self, expr: &Expr) -> Result<(), CompileError> {
+        let mut main = std::mem::take(&mut self.main);
+        // Only the last expression's value is returned
+        if self.has_value {
+            main.emit("drop");
+        }
+        let compiled = self.compile(expr, &mut main);
+        self.main = main;
+        compiled?;
+        self.has_value = true;
+        Ok(())
+    }
+
+    fn finish(self) -> Result<String, CompileError> {
+        Ok(self.module(self.has_value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(source: &str) -> String {
+        WatCompiler::compile_program(&crate::try_parse_program(source).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_globals_and_arithmetic() {
+        assert_eq!(
+            compile("x = 3; y = -x * 2 + 1; y"),
+            "\
+(module
+  (global $g0 (mut f64) (f64.const 0))
+  (global $g1 (mut f64) (f64.const 0))
+  (func $main (export \"main\") (result f64)
+    f64.const 3
+    global.set $g0
+    global.get $g0
+    f64.neg
+    f64.const 2
+    f64.mul
+    f64.const 1
+    f64.add
+    global.set $g1
+    global.get $g1
+  )
+)
+"
+        );
+    }
+
+    #[test]
+    fn test_functions_loops_and_print() {
+        assert_eq!(
+            compile(
+                "fn sq(x) { y = x * x; y } s = 0; for i = 1 to 3 { s = s + sq(i) }; print(s); s"
+            ),
+            "\
+(module
+  (import \"env\" \"print\" (func $env.print_1 (param f64)))
+  (global $g0 (mut f64) (f64.const 0))
+  (global $g1 (mut f64) (f64.const 0))
+  (global $g2 (mut f64) (f64.const 0))
+  (func $main (export \"main\") (result f64)
+    f64.const 0
+    global.set $g0
+    f64.const 1
+    global.set $g1
+    f64.const 3
+    global.set $g2
+    block
+      loop
+        global.get $g1
+        global.get $g2
+        f64.le
+        i32.eqz
+        br_if 1
+        global.get $g0
+        global.get $g1
+        call $fn_sq
+        f64.add
+        global.set $g0
+        global.get $g1
+        f64.const 1
+        f64.add
+        global.set $g1
+        br 0
+      end
+    end
+    f64.const 0
+    drop
+    global.get $g0
+    call $env.print_1
+    f64.const 0
+    drop
+    global.get $g0
+  )
+  (func $fn_sq (param $l0 f64) (result f64)
+    (local $l1 f64)
+    local.get $l0
+    local.get $l0
+    f64.mul
+    local.set $l1
+    local.get $l1
+  )
+)
+"
+        );
+    }
+
+    #[test]
+    fn test_branches_division_and_imports() {
+        assert_eq!(
+            compile("fn half(n) { n > 0 && n / 2 } a = 7 % 4; a ** 2 || half(a)"),
+            "\
+(module
+  (import \"env\" \"fmod\" (func $env.fmod (param f64) (param f64) (result f64)))
+  (import \"env\" \"pow\" (func $env.pow (param f64) (param f64) (result f64)))
+  (global $g0 (mut f64) (f64.const 0))
+  (func $main (export \"main\") (result f64)
+    (local $div f64)
+    f64.const 7
+    f64.const 4
+    local.tee $div
+    f64.const 0
+    f64.eq
+    if
+      unreachable
+    end
+    local.get $div
+    call $env.fmod
+    global.set $g0
+    global.get $g0
+    f64.const 2
+    call $env.pow
+    f64.const 0
+    f64.ne
+    if (result f64)
+      f64.const 1
+    else
+      global.get $g0
+      call $fn_half
+      f64.const 0
+      f64.ne
+      f64.convert_i32_u
+    end
+  )
+  (func $fn_half (param $l0 f64) (result f64)
+    (local $div f64)
+    local.get $l0
+    f64.const 0
+    f64.gt
+    f64.convert_i32_u
+    f64.const 0
+    f64.ne
+    if (result f64)
+      local.get $l0
+      f64.const 2
+      local.tee $div
+      f64.const 0
+      f64.eq
+      if
+        unreachable
+      end
+      local.get $div
+      f64.div
+      f64.const 0
+      f64.ne
+      f64.convert_i32_u
+    else
+      f64.const 0
+    end
+  )
+)
+"
+        );
+    }
+
+    #[test]
+    fn test_power_of_zero_traps() {
+        let module = compile("fn f(x, y) { x ** y } f(0, 0 - 1)");
+        assert!(module.contains("    (local $div f64)\n    (local $base f64)\n"));
+        assert!(module.contains(
+            "\
+    local.set $div
+    local.tee $base
+    f64.const 0
+    f64.eq
+    local.get $div
+    f64.const 0
+    f64.lt
+    i32.and
+    if
+      unreachable
+    end
+    local.get $base
+    local.get $div
+    call $env.pow
+"
+        ));
+        // A literal exponent of at least zero needs no check
+        assert!(!compile("x = 0; x ** 0.5").contains("$base"));
+    }
+
+    #[test]
+    fn test_compiler_trait_returns_last_expression() {
+        let mut compiler = WatCompiler::new();
+        compiler.compile_expr(&crate::parse_expr("x = 1")).unwrap();
+        compiler.compile_expr(&crate::parse_expr("x + 1")).unwrap();
+        let module = compiler.finish().unwrap();
+        assert!(module.contains("    global.get $g0\n    drop\n    global.get $g0\n"));
+        // Without any expression `main` returns 0
+        assert!(WatCompiler::new()
+            .finish()
+            .unwrap()
+            .contains("(result f64)\n    f64.const 0\n  )"));
+    }
+
+    #[test]
+    fn test_unsupported_expressions() {
+        let err = |source: &str| {
+            WatCompiler::compile_program(&crate::try_parse_program(source).unwrap()).unwrap_err()
+        };
+        assert_eq!(
+            err("[1, 2][0]").to_string(),
+            "An array cannot be compiled by this backend at position 0"
+        );
+        assert!(matches!(
+            err("sqrt(2)"),
+            CompileError::UndefinedFunction { ref name, .. } if name == "sqrt"
+        ));
+        assert!(matches!(
+            err("fn f(a) { a } f(1, 2)"),
+            CompileError::ArityMismatch {
+                expected: 1,
+                found: 2,
+                ..
+            }
+        ));
+    }
+
+    /// Runs each program's `main` with the `wasmtime` command-line tool and
+    /// compares the result with the VM's.
+    #[test]
+    #[ignore = "needs the wasmtime command-line tool"]
+    fn test_wasmtime_agrees_with_vm() {
+        let programs = [
+            "x = 3; y = -x * 2 + 1; y",
+            "fn sq(x) { x * x } s = 0; for i = 1 to 10 { s = s + sq(i) }; s",
+            "fn fib(n) { if n < 2 { n } else { fib(n - 1) + fib(n - 2) } } fib(15) / 2",
+            "i = 0; t = 0; while i < 5 { i = i + 1; if i > 2 && i <= 4 { t = t + 1 } }; t",
+        ];
+        let dir = std::env::temp_dir();
+        for (i, source) in programs.iter().enumerate() {
+            let path = dir.join(format!("wat_backend_{}_{}.wat", std::process::id(), i));
+            std::fs::write(&path, compile(source)).unwrap();
+            let output = std::process::Command::new("wasmtime")
+                .args(["run", "--invoke", "main"])
+                .arg(&path)
+                .output()
+                .expect("wasmtime is not installed");
+            std::fs::remove_file(&path).unwrap();
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            let printed = String::from_utf8(output.stdout).unwrap();
+            let expected = crate::compile_and_run(source).unwrap().as_num().unwrap();
+            assert_eq!(
+                printed.trim().parse::<f64>().unwrap(),
+                expected,
+                "{}",
+                source
+            );
+        }
+    }
+}
@@ -0,0 +1,319 @@
+//! A tree-walking evaluator: runs an expression straight from its AST, without
+//! compiling it to bytecode first. It is meant for the REPL and for embedding,
+//! where a VM per line is more machinery than needed.
+
+use crate::parser::{Expr, ExprKind};
+use crate::scanner::{Span, Token};
+use crate::vm::{default_natives, NativeCtx, NativeFn, Slots, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+/// The variables an evaluation reads and assigns, and the native functions it
+/// can call. Variables have names rather than slots, so natives see no
+/// globals, and their output goes to standard output.
+pub struct Env {
+    pub variables: HashMap<String, f64>,
+    pub native_functions: HashMap<String, Rc<NativeFn>>,
+}
+
+impl Env {
+    /// An environment without variables, with the natives a new `VM` has.
+    pub fn new() -> Self {
+        Env {
+            variables: HashMap::new(),
+            native_functions: default_natives(),
+        }
+    }
+}
+
+impl Default for Env {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An error that stops evaluation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalError {
+    /// A variable was read before anything was assigned to it.
+    UndefinedVariable { name: String, span: Span },
+    /// A call names a function that is not in the environment.
+    UndefinedFunction { name: String, span: Span },
+    /// Division or remainder by zero.
+    DivisionByZero { span: Span },
+    /// The operator has no meaning for numbers.
+    UnsupportedOperator { op: Token, span: Span },
+    /// The evaluator has no rule for this kind of expression.
+    UnsupportedExpression { construct: &'static str, span: Span },
+    /// Native function `name` returned an error.
+    NativeFailed {
+        name: String,
+        message: String,
+        span: Span,
+    },
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvalError::UndefinedVariable { name, span } => write!(
+                f,
+                "Undefined variable '{}' at position {}",
+                name, span.start
+            ),
+            EvalError::UndefinedFunction { name, span } => write!(
+                f,
+                "Undefined function '{}' at position {}",
+                name, span.start
+            ),
+            EvalError::DivisionByZero { span } => {
+                write!(f, "Division by zero at position {}", span.start)
+            }
+            EvalError::UnsupportedOperator { op, span } => write!(
+                f,
+                "Operator '{}' cannot be evaluated at position {}",
+                op, span.start
+            ),
+            EvalError::UnsupportedExpression { construct, span } => write!(
+                f,
+                "{} cannot be evaluated at position {}",
+                construct, span.start
+            ),
+            EvalError::NativeFailed {
+                name,
+                message,
+                span,
+            } => write!(
+                f,
+                "Native function '{}' failed: {} at position {}",
+                name, message, span.start
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+fn truth(value: bool) -> f64 {
+    if value {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+/// Evaluate `expr` in `env`, with the same results the bytecode VM gives,
+/// except that its bools are numbers: comparisons and `&&`/`||` yield 1.0 or
+/// 0.0, so arithmetic on them succeeds. A loop or an `if` without
+/// `else` whose condition fails yields 0.0, and an assignment yields the value
+/// assigned.
+pub fn eval(expr: &Expr, env: &mut Env) -> Result<f64, EvalError> {
+    let unsupported = |construct| EvalError::UnsupportedExpression {
+        construct,
+        span: expr.span,
+    };
+    match &expr.kind {
+        ExprKind::Number(value) => Ok(*value),
+        ExprKind::Bool(value) => Ok(truth(*value)),
+        ExprKind::Group(inner) => eval(inner, env),
+        ExprKind::Ident(name) => {
+            env.variables
+                .get(name)
+                .copied()
+                .ok_or_else(|| EvalError::UndefinedVariable {
+                    name: name.clone(),
+                    span: expr.span,
+                })
+        }
+        ExprKind::UnaryOp { op, rhs } => match op {
+            Token::Minus => Ok(-eval(rhs, env)?),
+            Token::Plus => eval(rhs, env),
+            _ => Err(EvalError::UnsupportedOperator {
+                op: op.clone(),
+                span: expr.span,
+            }),
+        },
+        ExprKind::BinaryOp {
+            lhs,
+            op: Token::AndAnd,
+            rhs,
+        } => Ok(truth(eval(lhs, env)? != 0.0 && eval(rhs, env)? != 0.0)),
+        ExprKind::BinaryOp {
+            lhs,
+            op: Token::OrOr,
+            rhs,
+        } => Ok(truth(eval(lhs, env)? != 0.0 || eval(rhs, env)? != 0.0)),
+        ExprKind::BinaryOp { lhs, op, rhs } => {
+            let a = eval(lhs, env)?;
+            let b = eval(rhs, env)?;
+            match op {
+                Token::Plus => Ok(a + b),
+                Token::Minus => Ok(a - b),
+                Token::Star => Ok(a * b),
+                Token::Slash | Token::Percent if b == 0.0 => {
+                    Err(EvalError::DivisionByZero { span: expr.span })
+                }
+                Token::Slash => Ok(a / b),
+                Token::Percent => Ok(a % b),
+                Token::StarStar if a == 0.0 && b < 0.0 => {
+                    Err(EvalError::DivisionByZero { span: expr.span })
+                }
+                Token::StarStar => Ok(a.powf(b)),
+                Token::EqEq => Ok(truth(a == b)),
+                Token::NotEq => Ok(truth(a != b)),
+                Token::Lt => Ok(truth(a < b)),
+                Token::Le => Ok(truth(a <= b)),
+                Token::Gt => Ok(truth(a > b)),
+                Token::Ge => Ok(truth(a >= b)),
+                _ => Err(EvalError::UnsupportedOperator {
+                    op: op.clone(),
+                    span: expr.span,
+                }),
+            }
+        }
+        ExprKind::Assign { name, value } => {
+            let value = eval(value, env)?;
+            env.variables.insert(name.clone(), value);
+            Ok(value)
+        }
+        ExprKind::Block(body) => {
+            let mut value = 0.0;
+            for item in body {
+                value = eval(item, env)?;
+            }
+            Ok(value)
+        }
+        ExprKind::If {
+            cond,
+            then_branch,
+            else_branch,
+        } => {
+            if eval(cond, env)? != 0.0 {
+                eval(then_branch, env)
+            } else {
+                match else_branch {
+                    Some(else_branch) => eval(else_branch, env),
+                    None => Ok(0.0),
+                }
+            }
+        }
+        ExprKind::While { cond, body } => {
+            while eval(cond, env)? != 0.0 {
+                for item in body {
+                    eval(item, env)?;
+                }
+            }
+            Ok(0.0)
+        }
+        ExprKind::Call { callee, args } => {
+            let ExprKind::Ident(name) = &callee.kind else {
+                return Err(unsupported("A call of anything but a named function"));
+            };
+            let args = args
+                .iter()
+                .map(|arg| eval(arg, env).map(Value::Num))
+                .collect::<Result<Vec<Value>, EvalError>>()?;
+            match env.native_functions.get(name) {
+                Some(native) => native(
+                    &mut NativeCtx::new(&mut Slots::new(), &mut std::io::stdout()),
+                    &args,
+                )
+                .map_err(|err| EvalError::NativeFailed {
+                    name: name.clone(),
+                    message: err.message,
+                    span: expr.span,
+                })?
+                .as_num()
+                .ok_or_else(|| unsupported("A call returning anything but a number")),
+                None => Err(EvalError::UndefinedFunction {
+                    name: name.clone(),
+                    span: expr.span,
+                }),
+            }
+        }
+        ExprKind::Str(_) => Err(unsupported("A string literal")),
+        ExprKind::Array(_) | ExprKind::Index { .. } | ExprKind::IndexAssign { .. } => {
+            Err(unsupported("An array"))
+        }
+        ExprKind::Spawn(_) => Err(unsupported("spawn")),
+        ExprKind::Sync => Err(unsupported("sync")),
+        ExprKind::Barrier => Err(unsupported("barrier")),
+        ExprKind::For { .. } => Err(unsupported("A for loop")),
+        ExprKind::Error => Err(unsupported("A syntax error")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::NativeError;
+
+    fn eval_source(source: &str, env: &mut Env) -> Result<f64, EvalError> {
+        eval(&crate::parse_expr(source), env)
+    }
+
+    #[test]
+    fn test_variables_persist_in_env() {
+        let mut env = Env::new();
+        assert_eq!(eval_source("x = 4", &mut env), Ok(4.));
+        assert_eq!(eval_source("x * x + 1", &mut env), Ok(17.));
+        assert_eq!(env.variables.get("x"), Some(&4.));
+    }
+
+    #[test]
+    fn test_short_circuit_skips_right_operand() {
+        let mut env = Env::new();
+        assert_eq!(eval_source("0 && (y = 1)", &mut env), Ok(0.));
+        assert_eq!(eval_source("2 || (y = 1)", &mut env), Ok(1.));
+        assert!(!env.variables.contains_key("y"));
+    }
+
+    #[test]
+    fn test_calls_native_functions() {
+        let mut env = Env::new();
+        env.native_functions.insert(
+            "max".to_string(),
+            crate::vm::numeric_native(|args| args[0].max(args[1])),
+        );
+        assert_eq!(eval_source("max(3, 1 + 4) * 2", &mut env), Ok(10.));
+        env.native_functions.insert(
+            "fail".to_string(),
+            std::rc::Rc::new(|_: &mut NativeCtx, _: &[Value]| Err(NativeError::new("no"))),
+        );
+        assert_eq!(
+            eval_source("1 + fail()", &mut env).unwrap_err().to_string(),
+            "Native function 'fail' failed: no at position 4"
+        );
+    }
+
+    #[test]
+    fn test_errors_carry_positions() {
+        let mut env = Env::new();
+        let err = eval_source("1 + nope", &mut env).unwrap_err();
+        assert_eq!(err.to_string(), "Undefined variable 'nope' at position 4");
+        assert_eq!(
+            eval_source("f(1)", &mut env),
+            Err(EvalError::UndefinedFunction {
+                name: "f".to_string(),
+                span: Span::new(0, 4)
+            })
+        );
+        assert_eq!(
+            eval_source("3 % (2 - 2)", &mut env),
+            Err(EvalError::DivisionByZero {
+                span: Span::new(0, 11)
+            })
+        );
+        assert_eq!(
+            eval_source("0 ** -1", &mut env),
+            Err(EvalError::DivisionByZero {
+                span: Span::new(0, 7)
+            })
+        );
+        assert!(matches!(
+            eval_source("spawn 1", &mut env),
+            Err(EvalError::UnsupportedExpression { .. })
+        ));
+    }
+}
@@ -0,0 +1,503 @@
+//! Native code for numeric expressions, through Cranelift.
+//!
+//! `compile` turns an expression into a machine-code function of its
+//! variables, for kernels evaluated often enough that the VM's dispatch loop
+//! dominates:
+//!
+//! ```text
+//! let poly = jit::compile(&parse_expr("3 * x * x - 2 * y + 1"))?;
+//! assert_eq!(poly.vars(), ["x", "y"]);
+//! assert_eq!(poly.call(&[2.0, 1.0]), 11.0);
+//! ```
+//!
+//! Compiled code cannot raise a runtime error, so it only covers expressions
+//! that give the same number as the VM for every input: number literals,
+//! variables, unary `-` and `+`, `+ - *`, `/` and `%` by a nonzero literal, `**`
+//! to a literal power of at least zero or of a nonzero literal base, and calls
+//! of the natives `cos`, `exp`, `sin`, `sqrt`, `floor`, `ceil`, `trunc` and
+//! `idiv`, the last with a nonzero literal divisor. Anything else is an
+//! `Unsupported` error, and the caller runs the expression on the VM instead.
+
+use crate::parser::{const_eval, Expr, ExprKind};
+use crate::scanner::{Span, Token};
+use crate::vm::integer_division;
+use cranelift_codegen::ir::{self, types, AbiParam, InstBuilder, MemFlags};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+use std::fmt;
+use std::mem::ManuallyDrop;
+
+/// Why an expression was not compiled to native code.
+#[derive(Debug, Clone, PartialEq)]
+pub enum JitError {
+    /// The expression may not evaluate to a number, or may fail on the VM.
+    Unsupported { construct: &'static str, span: Span },
+    /// Cranelift cannot generate code for this machine.
+    Codegen(String),
+}
+
+impl fmt::Display for JitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JitError::Unsupported { construct, span } => write!(
+                f,
+                "The JIT cannot compile {} at position {}",
+                construct, span.start
+            ),
+            JitError::Codegen(message) => write!(f, "Code generation failed: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for JitError {}
+
+/// An expression compiled to machine code, freed on drop.
+pub struct CompiledFn {
+    /// Owns the memory `code` points into
+    module: ManuallyDrop<JITModule>,
+    code: unsafe extern "C" fn(*const f64) -> f64,
+    vars: Vec<String>,
+}
+
+impl CompiledFn {
+    /// The variables the expression reads, in order of first use, which is
+    /// the order `call` takes their values in.
+    pub fn vars(&self) -> &[String] {
+        &self.vars
+    }
+
+    /// The value of the expression with `args[i]` for variable `vars()[i]`.
+    /// Panics unless there is exactly one value per variable.
+    pub fn call(&self, args: &[f64]) -> f64 {
+        assert_eq!(
+            args.len(),
+            self.vars.len(),
+            "expected a value for each of {:?}",
+            self.vars
+        );
+        // SAFETY: the code reads `vars.len()` values from its argument, and
+        // lives as long as `module`
+        unsafe { (self.code)(args.as_ptr()) }
+    }
+}
+
+impl fmt::Debug for CompiledFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompiledFn")
+            .field("vars", &self.vars)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for CompiledFn {
+    fn drop(&mut self) {
+        // SAFETY: `code` goes with `self`, so nothing can call it afterwards
+        unsafe { ManuallyDrop::take(&mut self.module).free_memory() }
+    }
+}
+
+// What the VM does for the operations Cranelift has no instruction for
+extern "C" fn remainder(a: f64, b: f64) -> f64 {
+    a % b
+}
+
+extern "C" fn power(a: f64, b: f64) -> f64 {
+    a.powf(b)
+}
+
+extern "C" fn sin(x: f64) -> f64 {
+    x.sin()
+}
+
+extern "C" fn cos(x: f64) -> f64 {
+    x.cos()
+}
+
+extern "C" fn exp(x: f64) -> f64 {
+    x.exp()
+}
+
+extern "C" fn idiv(a: f64, b: f64) -> f64 {
+    integer_division(a, b)
+}
+
+/// The helpers compiled code calls, by symbol name.
+fn helpers() -> [(&'static str, *const u8); 6] {
+    [
+        ("ppl_remainder", remainder as *const u8),
+        ("ppl_power", power as *const u8),
+        ("ppl_sin", sin as *const u8),
+        ("ppl_cos", cos as *const u8),
+        ("ppl_exp", exp as *const u8),
+        ("ppl_idiv", idiv as *const u8),
+    ]
+}
+
+/// Compile `expr` to a function of its variables, returning the same number
+/// `VM::run` would for the same variables.
+pub fn compile(expr: &Expr) -> Result<CompiledFn, JitError> {
+    let codegen = |err: &dyn fmt::Display| JitError::Codegen(err.to_string());
+    let mut flags = settings::builder();
+    flags
+        .set("opt_level", "speed")
+        .map_err(|err| codegen(&err))?;
+    flags
+        .set("use_colocated_libcalls", "false")
+        .map_err(|err| codegen(&err))?;
+    flags.set("is_pic", "false").map_err(|err| codegen(&err))?;
+    let isa = cranelift_native::builder()
+        .map_err(|err| codegen(&err))?
+        .finish(settings::Flags::new(flags))
+        .map_err(|err| codegen(&err))?;
+    let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+    for (name, helper) in helpers() {
+        builder.symbol(name, helper);
+    }
+    let mut module = JITModule::new(builder);
+    let (id, vars) = match define(&mut module, expr) {
+        Ok(defined) => defined,
+        Err(err) => {
+            // SAFETY: no code was handed out of the module
+            unsafe { module.free_memory() };
+            return Err(err);
+        }
+    };
+    let address = module.get_finalized_function(id);
+    // SAFETY: the function was declared with exactly this signature
+    let code = unsafe {
+        std::mem::transmute::<*const u8, unsafe extern "C" fn(*const f64) -> f64>(address)
+    };
+    Ok(CompiledFn {
+        module: ManuallyDrop::new(module),
+        code,
+        vars,
+    })
+}
+
+/// Define and finalize the function computing `expr` in `module`, returning
+/// its id and the variables it reads.
+fn define(module: &mut JITModule, expr: &Expr) -> Result<(FuncId, Vec<String>), JitError> {
+    let codegen = |err: &dyn fmt::Display| JitError::Codegen(err.to_string());
+    let mut context = module.make_context();
+    let pointer = module.target_config().pointer_type();
+    context.func.signature.params.push(AbiParam::new(pointer));
+    context
+        .func
+        .signature
+        .returns
+        .push(AbiParam::new(types::F64));
+    let mut function_context = FunctionBuilderContext::new();
+    let vars = {
+        let mut builder = FunctionBuilder::new(&mut context.func, &mut function_context);
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        builder.seal_block(entry);
+        let args = builder.block_params(entry)[0];
+        let mut lowering = Lowering {
+            builder,
+            module,
+            args,
+            vars: Vec::new(),
+        };
+        let result = lowering.expr(expr)?;
+        lowering.builder.ins().return_(&[result]);
+        lowering.builder.finalize();
+        lowering.vars
+    };
+
+    let id = module
+        .declare_function("expr", Linkage::Export, &context.func.signature)
+        .map_err(|err| codegen(&err))?;
+    module
+        .define_function(id, &mut context)
+        .map_err(|err| codegen(&err))?;
+    module.clear_context(&mut context);
+    module.finalize_definitions().map_err(|err| codegen(&err))?;
+    Ok((id, vars))
+}
+
+/// The state of lowering one expression into the body of its function.
+struct Lowering<'a> {
+    builder: FunctionBuilder<'a>,
+    module: &'a mut JITModule,
+    /// The pointer to the variables' values
+    args: ir::Value,
+    /// The variables read so far, each at its index into `args`
+    vars: Vec<String>,
+}
+
+impl Lowering<'_> {
+    fn expr(&mut self, expr: &Expr) -> Result<ir::Value, JitError> {
+        let unsupported = |construct| JitError::Unsupported {
+            construct,
+            span: expr.span,
+        };
+        let nonzero = |expr: &Expr| const_eval(expr).is_some_and(|value| value != 0.0);
+        Ok(match &expr.kind {
+            ExprKind::Number(value) => self.builder.ins().f64const(*value),
+            ExprKind::Ident(name) => {
+                let index = match self.vars.iter().position(|var| var == name) {
+                    Some(index) => index,
+                    None => {
+                        self.vars.push(name.clone());
+                        self.vars.len() - 1
+                    }
+                };
+                let offset = i32::try_from(index * std::mem::size_of::<f64>())
+                    .map_err(|_| unsupported("this many variables"))?;
+                self.builder
+                    .ins()
+                    .load(types::F64, MemFlags::trusted(), self.args, offset)
+            }
+            ExprKind::Group(inner) => self.expr(inner)?,
+            ExprKind::UnaryOp { op, rhs } => {
+                let rhs = self.expr(rhs)?;
+                match op {
+                    Token::Minus => self.builder.ins().fneg(rhs),
+                    Token::Plus => rhs,
+                    _ => return Err(unsupported("a non-arithmetic unary operator")),
+                }
+            }
+            ExprKind::BinaryOp { lhs, op, rhs } => {
+                // Exactly where the VM raises a division by zero
+                let checked = match op {
+                    Token::Slash | Token::Percent => nonzero(rhs),
+                    Token::StarStar => {
+                        nonzero(lhs) || const_eval(rhs).is_some_and(|power| power >= 0.0)
+                    }
+                    _ => true,
+                };
+                if !checked {
+                    return Err(unsupported("an operation that may divide by zero"));
+                }
+                let (a, b) = (self.expr(lhs)?, self.expr(rhs)?);
+                match op {
+                    Token::Plus => self.builder.ins().fadd(a, b),
+                    Token::Minus => self.builder.ins().fsub(a, b),
+                    Token::Star => self.builder.ins().fmul(a, b),
+                    Token::Slash => self.builder.ins().fdiv(a, b),
+                    Token::Percent => self.helper("ppl_remainder", &[a, b])?,
+                    Token::StarStar => self.helper("ppl_power", &[a, b])?,
+                    _ => return Err(unsupported("a non-arithmetic binary operator")),
+                }
+            }
+            ExprKind::Call { callee, args } => {
+                let ExprKind::Ident(name) = &callee.kind else {
+                    return Err(unsupported("a call of a computed callee"));
+                };
+                if let ("idiv", [a, b]) = (name.as_str(), args.as_slice()) {
+                    if !nonzero(b) {
+                        return Err(unsupported("an operation that may divide by zero"));
+                    }
+                    let (a, b) = (self.expr(a)?, self.expr(b)?);
+                    return self.helper("ppl_idiv", &[a, b]);
+                }
+                let [arg] = args.as_slice() else {
+                    return Err(unsupported("a call with other than one argument"));
+                };
+                let arg = self.expr(arg)?;
+                match name.as_str() {
+                    "ceil" => self.builder.ins().ceil(arg),
+                    "cos" => self.helper("ppl_cos", &[arg])?,
+                    "exp" => self.helper("ppl_exp", &[arg])?,
+                    "floor" => self.builder.ins().floor(arg),
+                    "sin" => self.helper("ppl_sin", &[arg])?,
+                    "sqrt" => self.builder.ins().sqrt(arg),
+                    "trunc" => self.builder.ins().trunc(arg),
+                    _ => return Err(unsupported("a call of anything but a math native")),
+                }
+            }
+            ExprKind::Bool(_) => return Err(unsupported("a bool")),
+            ExprKind::Str(_) => return Err(unsupported("a string")),
+            _ => return Err(unsupported("a statement-like expression")),
+        })
+    }
+
+    /// A call of helper `name` on `args`.
+    fn helper(&mut self, name: &str, args: &[ir::Value]) -> Result<ir::Value, JitError> {
+        let mut signature = self.module.make_signature();
+        for _ in args {
+            signature.params.push(AbiParam::new(types::F64));
+        }
+        signature.returns.push(AbiParam::new(types::F64));
+        let id = self
+            .module
+            .declare_function(name, Linkage::Import, &signature)
+            .map_err(|err| JitError::Codegen(err.to_string()))?;
+        let callee = self.module.declare_func_in_func(id, self.builder.func);
+        let call = self.builder.ins().call(callee, args);
+        Ok(self.builder.inst_results(call)[0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::BytecodeCompiler;
+    use crate::parser::Stmt;
+    use crate::vm::{Value, VM};
+    use crate::{parse_expr, try_parse_expr};
+
+    /// What the VM makes of `source` after assigning `args[i]` to the
+    /// compiled function's variable `i`.
+    fn on_vm(source: &str, vars: &[String], args: &[f64]) -> f64 {
+        let mut program: Vec<Stmt> = vars
+            .iter()
+            .zip(args)
+            .map(|(name, value)| {
+                let value = Box::new(ExprKind::Number(*value).into());
+                let name = name.clone();
+                Stmt::Expr(ExprKind::Assign { name, value }.into())
+            })
+            .collect();
+        program.push(Stmt::Expr(parse_expr(source)));
+        let program = BytecodeCompiler::compile_program(&program).unwrap();
+        match VM::try_run_program(&program) {
+            Ok(Value::Num(value)) => value,
+            other => panic!("{}: {:?}", source, other),
+        }
+    }
+
+    /// Equal as results: NaN matches NaN, and 0 does not match -0.
+    fn same(a: f64, b: f64) -> bool {
+        a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan())
+    }
+
+    fn assert_matches_vm(source: &str, args: &[f64]) {
+        let compiled = compile(&parse_expr(source)).unwrap();
+        let (jit, vm) = (compiled.call(args), on_vm(source, compiled.vars(), args));
+        assert!(
+            same(jit, vm),
+            "{} at {:?}: jit {} but vm {}",
+            source,
+            args,
+            jit,
+            vm
+        );
+    }
+
+    #[test]
+    fn test_matches_the_vm() {
+        assert_eq!(
+            VM::run(BytecodeCompiler::compile(&parse_expr("1 + 2 * 3 - 4 / 2"))),
+            5.0
+        );
+        assert_matches_vm("1 + 2 * 3 - 4 / 2", &[]);
+        assert_matches_vm("-(2 ** 10) % 7", &[]);
+        assert_matches_vm("3 * x * x - 2 * y + 1", &[2.0, 1.0]);
+        assert_matches_vm("+x - -y", &[0.5, -0.25]);
+        assert_matches_vm("x % 3 + x % -2.5", &[-7.75]);
+        assert_matches_vm("x ** 2 + 2 ** x + x ** 0.5", &[3.0]);
+        assert_matches_vm("sqrt(x) + exp(-x) * 10 + sin(x) * cos(x)", &[2.0]);
+        assert_matches_vm("sqrt(x)", &[-1.0]);
+        assert_matches_vm("floor(x) + ceil(x) * 10 + trunc(-x) * 100", &[-2.5]);
+        assert_matches_vm("idiv(x, 4) + idiv(x, -4)", &[-7.0]);
+        assert_matches_vm("x * 0 + x / 2", &[f64::INFINITY]);
+        assert_matches_vm("x - x", &[f64::NAN]);
+        assert_matches_vm("-x * 1", &[0.0]);
+    }
+
+    #[test]
+    fn test_variables_are_numbered_by_first_use() {
+        let compiled = compile(&parse_expr("b - a * b + c")).unwrap();
+        assert_eq!(compiled.vars(), ["b", "a", "c"]);
+        assert_eq!(compiled.call(&[2.0, 3.0, 4.0]), 0.0);
+        assert_eq!(compiled.call(&[1.0, 1.0, 1.0]), 1.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "expected a value for each of")]
+    fn test_call_needs_a_value_per_variable() {
+        compile(&parse_expr("x + y")).unwrap().call(&[1.0]);
+    }
+
+    #[test]
+    fn test_rejects_what_the_vm_might_not_compute_the_same() {
+        for source in [
+            "x / y",
+            "x % (1 - 1)",
+            "x ** -1",
+            "x ** y",
+            "sqrt(x, 1)",
+            "idiv(x, y)",
+            "idiv(x, 0)",
+            "f(x)",
+            "print(x)",
+            "x < 1",
+            "!x",
+            "true",
+            "\"a\"",
+            "x = 1",
+            "x ? 1 : 2",
+            "[x][0]",
+        ] {
+            let expr = try_parse_expr(source).unwrap();
+            assert!(
+                matches!(compile(&expr), Err(JitError::Unsupported { .. })),
+                "{}",
+                source
+            );
+        }
+        // A nonzero base cannot divide by zero, whatever the power
+        assert_matches_vm("2 ** -x", &[3.0]);
+    }
+
+    /// Build a random expression over `x` and `y` from what the JIT compiles.
+    fn random_numeric(seed: &mut u64, depth: u32) -> String {
+        let mut next = |n: u64| {
+            *seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (*seed >> 33) % n
+        };
+        if depth == 0 || next(5) == 0 {
+            let leaves = ["x", "y", "2", "0.5", "-3"];
+            return leaves[next(leaves.len() as u64) as usize].to_string();
+        }
+        let choice = next(11);
+        let a = random_numeric(seed, depth - 1);
+        let b = random_numeric(seed, depth - 1);
+        match choice {
+            0 => format!("({} + {})", a, b),
+            1 => format!("({} - {})", a, b),
+            2 => format!("({} * {})", a, b),
+            3 => format!("({} / 3)", a),
+            4 => format!("({} % -1.5)", a),
+            5 => format!("({} ** 2)", a),
+            6 => format!("(-{})", a),
+            7 => format!("sqrt({}) + exp({})", a, b),
+            8 => format!("sin({})", a),
+            9 => format!("floor({})", a),
+            _ => format!("idiv({}, 2)", a),
+        }
+    }
+
+    #[test]
+    fn test_random_expressions_match_the_vm() {
+        let inputs = [3.0, -2.5, 0.0, -0.0, 1e300, f64::NAN];
+        let mut seed = 0x717;
+        for _ in 0..200 {
+            let source = random_numeric(&mut seed, 4);
+            let compiled = compile(&parse_expr(&source)).unwrap();
+            for (x, y) in inputs.iter().zip(inputs.iter().cycle().skip(1)) {
+                let args: Vec<f64> = compiled
+                    .vars()
+                    .iter()
+                    .map(|var| if var == "x" { *x } else { *y })
+                    .collect();
+                let (jit, vm) = (compiled.call(&args), on_vm(&source, compiled.vars(), &args));
+                assert!(
+                    same(jit, vm),
+                    "{} at {:?}: jit {} but vm {}",
+                    source,
+                    args,
+                    jit,
+                    vm
+                );
+            }
+        }
+    }
+}
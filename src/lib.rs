@@ -1,25 +1,108 @@
 //! Parallelized Programming Language library
 
+pub mod binary;
 pub mod compiler;
+#[cfg(feature = "serde")]
+mod float_serde;
+pub mod interp;
+#[cfg(feature = "jit")]
+pub mod jit;
 pub mod parser;
+pub mod printer;
 pub mod scanner;
+pub mod visitor;
 pub mod vm;
 
-/// Parse a source string into an AST expression
-pub fn parse_expr(source: &str) -> parser::Expr {
+/// Parse a source string into an AST expression, reporting syntax errors
+pub fn try_parse_expr(source: &str) -> Result<parser::Expr, parser::ParseError> {
     let mut parser = parser::PrattParser::new(scanner::Scanner::new(source));
     parser.expr(0)
 }
 
-pub use compiler::{BytecodeCompiler, Compiler};
-pub use parser::PrattParser;
-pub use scanner::Scanner;
-pub use vm::VM;
+/// Parse a source string into the sequence of top-level statements it contains
+pub fn try_parse_program(source: &str) -> Result<Vec<parser::Stmt>, parser::ParseError> {
+    let mut parser = parser::PrattParser::new(scanner::Scanner::new(source));
+    parser.parse_program()
+}
+
+/// Parse a source string into an AST expression, panicking on syntax errors
+pub fn parse_expr(source: &str) -> parser::Expr {
+    try_parse_expr(source).unwrap_or_else(|err| panic!("{}", err))
+}
+
+/// Why `compile_and_run` produced no value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    Parse(parser::ParseError),
+    Compile(compiler::CompileError),
+    Runtime(vm::RuntimeError),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Parse(err) => write!(f, "Syntax error: {}", err),
+            Error::Compile(err) => write!(f, "Compile error: {}", err),
+            Error::Runtime(err) => write!(f, "Runtime error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Parse(err) => Some(err),
+            Error::Compile(err) => Some(err),
+            Error::Runtime(err) => Some(err),
+        }
+    }
+}
+
+impl From<parser::ParseError> for Error {
+    fn from(err: parser::ParseError) -> Self {
+        Error::Parse(err)
+    }
+}
+
+impl From<compiler::CompileError> for Error {
+    fn from(err: compiler::CompileError) -> Self {
+        Error::Compile(err)
+    }
+}
+
+impl From<vm::RuntimeError> for Error {
+    fn from(err: vm::RuntimeError) -> Self {
+        Error::Runtime(err)
+    }
+}
+
+/// Parse, compile and run a program on a fresh VM, returning the value its
+/// last expression statement leaves behind.
+pub fn compile_and_run(source: &str) -> Result<vm::Value, Error> {
+    let program = BytecodeCompiler::compile_program(&try_parse_program(source)?)?;
+    Ok(VM::try_run_program(&program)?)
+}
+
+/// Parse, compile and run a single expression on a fresh VM, returning the
+/// value it leaves behind, or `None` if it leaves nothing.
+pub fn try_run_expr(source: &str) -> Result<Option<vm::Value>, Error> {
+    VM::try_run_expr::<BytecodeCompiler>(&try_parse_expr(source)?)
+}
+
+pub use compiler::{BytecodeCompiler, CompileError, Compiler, Program};
+pub use interp::eval as eval_expr;
+pub use parser::{Assoc, ParseError, PrattParser, Stmt};
+pub use scanner::{Scanner, Span};
+pub use vm::{
+    Breakpoint, ProfileReport, RuntimeError, StepOutcome, TraceCollector, Value, VmError,
+    VmSnapshot, VM,
+};
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use scanner::Token;
+    use std::collections::HashMap;
 
     #[test]
     fn full_pipeline_basic() {
@@ -38,6 +121,25 @@ mod tests {
         assert!(debug.contains("Number(1.0)"));
     }
 
+    #[test]
+    fn integration_repeated_negation() {
+        assert_eq!(
+            VM::run(BytecodeCompiler::compile(&parse_expr("-(-(7))"))),
+            7.
+        );
+        assert_eq!(VM::run(BytecodeCompiler::compile(&parse_expr("- -5"))), 5.);
+        let bytecode = BytecodeCompiler::try_compile_unfolded(&parse_expr("+-+2")).unwrap();
+        assert_eq!(
+            bytecode,
+            vec![
+                vm::Bytecode::LoadConst(2.),
+                vm::Bytecode::Neg,
+                vm::Bytecode::Halt
+            ]
+        );
+        assert_eq!(VM::run(bytecode), -2.);
+    }
+
     #[test]
     fn full_pipeline_multiple_ops() {
         let expr = parse_expr("1+2*3-4/2");
@@ -56,13 +158,422 @@ mod tests {
         assert_eq!(result, 6.);
     }
 
+    #[test]
+    fn integration_subtraction_is_left_associative() {
+        let bytecode = BytecodeCompiler::compile(&parse_expr("10 - 4 - 3"));
+        assert_eq!(VM::run(bytecode), 3.);
+        let bytecode = BytecodeCompiler::compile(&parse_expr("64 / 4 / 2"));
+        assert_eq!(VM::run(bytecode), 8.);
+    }
+
+    #[test]
+    fn integration_power() {
+        let run = |code| VM::run(BytecodeCompiler::compile(&parse_expr(code)));
+        assert_eq!(run("2**10"), 1024.);
+        assert_eq!(run("2 ** 3 ** 2"), 512.);
+        assert_eq!(run("-2 ** 2"), -4.);
+        assert_eq!(run("3 * 2 ** 2"), 12.);
+    }
+
+    #[test]
+    fn integration_ternary() {
+        let run = |code| VM::run(BytecodeCompiler::compile(&parse_expr(code)));
+        assert_eq!(run("1 ? 10 : 20"), 10.);
+        assert_eq!(run("0 ? 10 : 20"), 20.);
+        assert_eq!(run("0 ? 1 : 0 ? 2 : 3"), 3.);
+        assert_eq!(run("(2 - 2 ? 5 : 6) * 7"), 42.);
+    }
+
+    #[test]
+    fn integration_ternary_leaves_single_value() {
+        let mut vm = VM::new(BytecodeCompiler::compile(&parse_expr("1 ? 10 : 20")));
+        vm.execute();
+        assert_eq!(vm.stack, vec![10.]);
+    }
+
     #[test]
     fn integration_parse_pipeline_in_main() {
         let code = "1 + 2 * (3 - 4)";
         let expr = parse_expr(code);
         let bytecode = compiler::BytecodeCompiler::compile(&expr);
         let result = vm::VM::run(bytecode);
-        assert_eq!(result as i64, -1_i64);
+        assert_eq!(result, -1.0);
+    }
+
+    #[test]
+    fn integration_block_value_is_last_expression() {
+        let expr = parse_expr("{ 1; 2 } * { 3; 4 + 5 }");
+        let bytecode = BytecodeCompiler::compile(&expr);
+        assert_eq!(VM::run(bytecode), 18.);
+    }
+
+    #[test]
+    fn integration_try_parse_reports_error() {
+        let err = try_parse_expr("1 +").unwrap_err();
+        assert!(matches!(err, ParseError::UnexpectedEof { .. }));
+        assert!(try_parse_expr("1 + 2").is_ok());
+    }
+
+    #[test]
+    fn integration_truncated_program_reports_eof() {
+        let program = "fn add(a, b) { a + b }; \
+                       while x < 10 { f(x, [1, 2][0]) ? -x : x ** 2 }; \
+                       for i = 0 to 3 { spawn i; sync }";
+        assert!(try_parse_program(program).is_ok());
+        let truncations = [
+            ("fn", "function name after 'fn'"),
+            ("fn add(", "')' after parameters"),
+            ("fn add(a,", "')' after parameters"),
+            ("fn add(a, b)", "'{' to start function body"),
+            ("fn add(a, b) { a +", "expression"),
+            ("while x <", "expression"),
+            ("while x < 10 { f(x,", "')' after arguments"),
+            ("while x < 10 { f(x, [1, 2][", "expression"),
+            ("while x < 10 { f(x, [1, 2][0", "']' after index"),
+            ("while x < 10 { f(x, [1, 2", "']' after array elements"),
+            ("while x < 10 { f(x) ?", "expression"),
+            ("while x < 10 { f(x) ? -x", "':' to complete '?'"),
+            ("for i", "'=' after for-loop variable 'i'"),
+            ("for i = 0", "'to' after for-loop start"),
+            ("for i = 0 to 3 { spawn", "expression"),
+        ];
+        for (source, expected) in truncations {
+            let err = try_parse_program(source).unwrap_err();
+            assert!(err.is_unexpected_eof(), "{:?} gave {}", source, err);
+            match err {
+                ParseError::UnexpectedEof {
+                    expected: found,
+                    pos,
+                } => {
+                    assert!(
+                        found.starts_with(expected),
+                        "{:?} expected {}",
+                        source,
+                        found
+                    );
+                    assert_eq!(pos, source.len());
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected expression")]
+    fn integration_parse_expr_still_panics() {
+        parse_expr("1 + )");
+    }
+
+    #[test]
+    fn integration_spawn_and_sync_from_source() {
+        let program =
+            try_parse_program("spawn (2+3); sync").unwrap_or_else(|err| panic!("{}", err));
+        let mut vm = VM::from_program(BytecodeCompiler::compile_program(&program).unwrap());
+        vm.execute();
+        assert_eq!(vm.stack, vec![5.]);
+    }
+
+    #[test]
+    fn integration_multiple_spawns_and_sync_from_source() {
+        let program =
+            try_parse_program("spawn 4 + 1; spawn 5; sync").unwrap_or_else(|err| panic!("{}", err));
+        let mut vm = VM::from_program(BytecodeCompiler::compile_program(&program).unwrap());
+        vm.execute();
+        // Should collect both spawned values
+        assert_eq!(vm.stack, vec![5.0, 5.0]);
+    }
+
+    #[test]
+    fn integration_groups_compile_like_bare_expressions() {
+        let grouped = PrattParser::new(Scanner::new("((2 + 3)) * -(4)"))
+            .with_preserve_groups(true)
+            .expr(0)
+            .unwrap();
+        let bytecode = BytecodeCompiler::compile(&grouped);
+        assert_eq!(
+            bytecode,
+            BytecodeCompiler::compile(&parse_expr("(2 + 3) * -4"))
+        );
+        assert_eq!(VM::run(bytecode), -20.);
+    }
+
+    /// Compile and run a whole program, returning the value left on top of the stack.
+    fn run_source(source: &str) -> f64 {
+        let value = compile_and_run(source).unwrap_or_else(|err| panic!("{}", err));
+        value.as_num().expect("a number")
+    }
+
+    #[test]
+    fn integration_variables() {
+        assert_eq!(run_source("x = 6; y = 7; x * y"), 42.);
+        assert_eq!(run_source("let a = 2; let b = a * 10; b + a"), 22.);
+    }
+
+    #[test]
+    fn integration_assignment_values() {
+        let run = run_source;
+        assert_eq!(run("a = b = 5; a + b"), 10.);
+        assert_eq!(run("y = (x = 3) + 1; x * y"), 12.);
+        assert_eq!(run("x = 1; x += 41; x"), 42.);
+        assert_eq!(run("n = 10; n -= 4; n *= 3; n /= 2"), 9.);
+    }
+
+    #[test]
+    fn integration_if_else() {
+        assert_eq!(run_source("if 0 { 10 } else { 20 }"), 20.);
+        assert_eq!(run_source("if 3 { 10 } else { 20 }"), 10.);
+        assert_eq!(run_source("if 0 { 10 }"), 0.);
+        let chain = "fn pick(x) { if x { if x - 1 { 3 } else { 2 } } else { 1 } }; ";
+        assert_eq!(run_source(&format!("{}pick(0)", chain)), 1.);
+        assert_eq!(run_source(&format!("{}pick(1)", chain)), 2.);
+        assert_eq!(run_source(&format!("{}pick(5)", chain)), 3.);
+    }
+
+    #[test]
+    fn integration_if_leaves_only_its_value() {
+        // A leaked condition would sit beneath the branch value
+        for source in ["if 0 { 10 } else { 20 }", "if 7 { 10 } else { 20 }"] {
+            let program = try_parse_program(source).unwrap();
+            let mut vm = VM::from_program(BytecodeCompiler::compile_program(&program).unwrap());
+            vm.execute();
+            assert_eq!(vm.stack.len(), 1, "source: {}", source);
+        }
+        assert_eq!(run_source("x = 5; y = if x { 1 } else { 2 } + 1; y"), 2.);
+    }
+
+    #[test]
+    fn integration_comparisons() {
+        let eval = |source: &str| match VM::run(BytecodeCompiler::compile(&parse_expr(source))) {
+            Value::Bool(value) => value,
+            other => panic!("{}: {:?} is not a bool", source, other),
+        };
+        assert!(eval("3 < 5"));
+        assert!(eval("2 == 2.0"));
+        // NaN, from the square root of a negative number, equals nothing
+        assert!(!eval("(0-1) ** 0.5 == (0-1) ** 0.5"));
+        assert!(eval("(0-1) ** 0.5 != (0-1) ** 0.5"));
+        assert!(!eval("(0-1) ** 0.5 < 1"));
+        assert!(!eval("(0-1) ** 0.5 >= 1"));
+        assert!(eval("-0 == 0"));
+        assert!(eval("5 <= 5"));
+        assert!(!eval("5 > 5"));
+        assert!(!eval("1 + 1 != 2"));
+        assert_eq!(
+            run_source(
+                "fn sign(x) { if x < 0 { 0 - 1 } else if x > 0 { 1 } else { 0 } }; sign(-4)"
+            ),
+            -1.
+        );
+    }
+
+    /// Run `source` with a native `count()` that returns 1 and records each call.
+    fn run_counting(source: &str) -> (Value, usize) {
+        use std::cell::Cell;
+        use std::rc::Rc;
+        let calls = Rc::new(Cell::new(0));
+        let program = try_parse_program(source).unwrap();
+        let mut vm = VM::from_program(BytecodeCompiler::compile_program(&program).unwrap());
+        let counter = Rc::clone(&calls);
+        vm.register_native("count", Some(0), move |_| {
+            counter.set(counter.get() + 1);
+            1.0
+        });
+        vm.execute();
+        assert_eq!(vm.stack.len(), 1, "source: {}", source);
+        (vm.stack.pop().unwrap(), calls.get())
+    }
+
+    #[test]
+    fn integration_logical_operators_short_circuit() {
+        assert_eq!(run_counting("0 && count()"), (Value::Bool(false), 0));
+        assert_eq!(run_counting("2 && count()"), (Value::Bool(true), 1));
+        assert_eq!(run_counting("3 || count()"), (Value::Bool(true), 0));
+        assert_eq!(run_counting("0 || count()"), (Value::Bool(true), 1));
+        assert_eq!(
+            run_counting("x = 0; x && count() && count()"),
+            (Value::Bool(false), 0)
+        );
+    }
+
+    #[test]
+    fn integration_logical_truth_tables() {
+        for (a, b) in [(false, false), (false, true), (true, false), (true, true)] {
+            let and = compile_and_run(&format!("a = {}; b = {}; a && b", a, b));
+            let or = compile_and_run(&format!("a = {}; b = {}; a || b", a, b));
+            assert_eq!(and, Ok(Value::Bool(a && b)), "{} && {}", a, b);
+            assert_eq!(or, Ok(Value::Bool(a || b)), "{} || {}", a, b);
+        }
+        // Any nonzero number counts as true, and the result is a bool
+        assert_eq!(
+            compile_and_run("a = -3; b = 7; a && b"),
+            Ok(Value::Bool(true))
+        );
+        assert_eq!(
+            compile_and_run("a = 0; b = 7; a || b"),
+            Ok(Value::Bool(true))
+        );
+        assert_eq!(compile_and_run("a = 0; !a"), Ok(Value::Bool(true)));
+        assert_eq!(compile_and_run("!(1 < 2)"), Ok(Value::Bool(false)));
+    }
+
+    #[test]
+    fn integration_while_loop() {
+        assert_eq!(
+            run_source("i = 0; s = 0; while i - 5 { s = s + i; i = i + 1 }; s"),
+            10.
+        );
+        assert_eq!(run_source("while 0 { 1 }"), 0.);
+    }
+
+    #[test]
+    fn integration_peephole_inside_loop() {
+        let source = "i = 3; s = 0; while i { s = s - -i * 1; i = i - 1 * 1 }; s";
+        let program = try_parse_program(source).unwrap();
+        let unoptimized = BytecodeCompiler::compile_program_unfolded(&program).unwrap();
+        let mut optimized = unoptimized.clone();
+        compiler::optimize(&mut optimized);
+        assert_eq!(optimized.code.len(), unoptimized.code.len() - 4);
+        let mut vm = VM::from_program(optimized);
+        vm.execute();
+        assert_eq!(vm.stack, vec![6.]);
+    }
+
+    #[test]
+    fn integration_constant_pool_shrinks_repeated_constants() {
+        let source: String = (0..10_000)
+            .map(|i| format!("x = {} * 1.0625 + 0.5; ", i % 3))
+            .chain(["x".to_string()])
+            .collect();
+        let inline =
+            BytecodeCompiler::compile_program_unfolded(&try_parse_program(&source).unwrap())
+                .unwrap();
+        let mut pooled = inline.clone();
+        compiler::intern_constants(&mut pooled);
+        // 0, 1, 2, 1.0625 and 0.5
+        assert_eq!(pooled.constants.len(), 5);
+        let (inline_size, pooled_size) = (inline.to_bytes().len(), pooled.to_bytes().len());
+        println!(
+            "30000 constants: {} bytes inline, {} bytes pooled",
+            inline_size, pooled_size
+        );
+        // Each load drops its 8-byte value for a 4-byte index; the pool costs 8 bytes a value
+        assert_eq!(inline_size - pooled_size, 30_000 * 4 - 5 * 8);
+        let mut vm = VM::from_program(Program::from_bytes(&pooled.to_bytes()).unwrap());
+        vm.execute();
+        assert_eq!(vm.stack, vec![0.5]);
+    }
+
+    #[test]
+    fn integration_tail_recursion_runs_in_constant_stack() {
+        let source = "fn loop_to(n) { if n == 0 { 0 } else { loop_to(n - 1) } }; loop_to(1000000)";
+        let program = try_parse_program(source).unwrap();
+        let mut vm = VM::from_program(BytecodeCompiler::compile_program(&program).unwrap());
+        vm.execute();
+        assert_eq!(vm.stack, vec![0.]);
+        // A million return addresses would have grown the stack to match
+        assert!(
+            vm.stack.capacity() < 16,
+            "stack grew to {}",
+            vm.stack.capacity()
+        );
+
+        let source = "fn sum(n) { if n == 0 { 0 } else { n + sum(n - 1) } }; sum(100)";
+        assert_eq!(run_source(source), 5050.);
+    }
+
+    #[test]
+    fn integration_runtime_error_names_source_line() {
+        let source = "let a = 4;\nlet b = a - 4;\nlet c = a / b;\nc + 1";
+        let program =
+            BytecodeCompiler::compile_program(&try_parse_program(source).unwrap()).unwrap();
+        assert_eq!(program.spans.len(), program.code.len());
+        let mut vm = VM::from_program(program);
+        let err = vm.try_execute().unwrap_err();
+        assert_eq!(err.kind, vm::RuntimeErrorKind::DivisionByZero);
+        // The span is that of the division itself, not the whole statement
+        assert_eq!(err.span, Some(Span::new(34, 39)));
+        assert_eq!(err.describe(source), "Division by zero at line 3, column 9");
+        // The source map survives optimization
+        let mut program =
+            BytecodeCompiler::compile_program(&try_parse_program(source).unwrap()).unwrap();
+        compiler::optimize(&mut program);
+        let err = VM::from_program(program).try_execute().unwrap_err();
+        assert!(err.describe(source).ends_with("at line 3, column 9"));
+    }
+
+    #[test]
+    fn integration_binary_round_trip_runs_identically() {
+        let expr = parse_expr("2 ** 10 - 7 % 4");
+        let code = BytecodeCompiler::try_compile_unfolded(&expr).unwrap();
+        let program = Program {
+            code: code.clone(),
+            ..Program::default()
+        };
+        let loaded = Program::from_bytes(&program.to_bytes()).unwrap();
+        assert_eq!(VM::run(loaded.code), VM::run(code));
+
+        let source = "fn sub(a, b) { a - b }; i = 3; while i { i -= 1 }; sub(10, 4) + i";
+        let program =
+            BytecodeCompiler::compile_program(&try_parse_program(source).unwrap()).unwrap();
+        let loaded = Program::from_bytes(&program.to_bytes()).unwrap();
+        assert_eq!(loaded, program);
+        let mut vm = VM::from_program(loaded);
+        vm.execute();
+        assert_eq!(vm.stack, vec![6.]);
+    }
+
+    #[test]
+    fn integration_nested_while_loops() {
+        let source = "i = 3; n = 0; while i { j = 2; while j { n += 1; j -= 1 }; i -= 1 }; n";
+        assert_eq!(run_source(source), 6.);
+        let program = try_parse_program(source).unwrap();
+        let mut vm = VM::from_program(BytecodeCompiler::compile_program(&program).unwrap());
+        vm.execute();
+        assert_eq!(vm.stack, vec![6.]);
+    }
+
+    #[test]
+    fn integration_spawn_block() {
+        assert_eq!(run_source("spawn { 6 * 7 }; sync"), 42.);
+        assert_eq!(run_source("a = 5; b = 2; spawn { a * b + 1 }; sync"), 11.);
+        // Captured by value when the block is spawned
+        assert_eq!(run_source("x = 1; spawn { x }; x = 2; sync"), 1.);
+        // Jumps inside a region are relocated along with it
+        let looped = "n = 4; spawn { s = 0; while n { s = s + n; n = n - 1 }; s }; sync";
+        assert_eq!(run_source(looped), 10.);
+        let nested = "spawn { spawn { 2 }; 40 + 2 }; sync";
+        assert_eq!(run_source(nested), 42.);
+    }
+
+    #[test]
+    fn integration_spawn_block_survives_optimization() {
+        let source = "fn sq(x) { x * x }; k = 3; spawn { sq(k) + 0 }; spawn { 1 * 1 }; sync";
+        let program = try_parse_program(source).unwrap();
+        let mut optimized = BytecodeCompiler::compile_program(&program).unwrap();
+        compiler::optimize(&mut optimized);
+        let bytes = optimized.to_bytes();
+        let mut vm = VM::from_program(Program::from_bytes(&bytes).unwrap());
+        vm.execute();
+        let mut results: Vec<f64> = vm.stack.iter().filter_map(Value::as_num).collect();
+        results.sort_by(f64::total_cmp);
+        assert_eq!(results, vec![1., 9.]);
+    }
+
+    #[test]
+    fn integration_barrier_from_source() {
+        let program =
+            try_parse_program("spawn 10; barrier").unwrap_or_else(|err| panic!("{}", err));
+        let bytecode = BytecodeCompiler::compile_program(&program).unwrap().code;
+        assert_eq!(
+            bytecode,
+            vec![
+                vm::Bytecode::LoadConst(10.),
+                vm::Bytecode::Spawn,
+                vm::Bytecode::Pop,
+                vm::Bytecode::Barrier,
+                vm::Bytecode::Halt
+            ]
+        );
     }
 
     #[test]
@@ -102,33 +613,671 @@ mod tests {
         assert!(tokens.contains(&Token::KeywordJz));
     }
 
+    /// Output written to a VM, kept where the test can read it back.
+    struct Captured(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
     #[test]
     fn integration_native_print() {
-        let expr = parse_expr("print(123)");
-        let bytecode = BytecodeCompiler::compile(&expr);
-        let _ = VM::run(bytecode); // Should print 123
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let printed = |source: &str| {
+            let out = Rc::new(RefCell::new(Vec::new()));
+            let program = try_parse_program(source).unwrap();
+            let mut vm = VM::from_program(BytecodeCompiler::compile_program(&program).unwrap());
+            vm.set_output(Captured(Rc::clone(&out)));
+            vm.execute();
+            out.take()
+        };
+        assert_eq!(printed("print(1, 2.5)"), b"1 2.5\n");
+        assert_eq!(printed("print(\"hi\", 42); print()"), b"hi 42\n\n");
+        assert_eq!(printed("print(1 < 2, [1, \"a\"])"), b"true [1, a]\n");
+        // Only `print` writes: finishing the program adds nothing
+        assert_eq!(printed("x = 1; x"), b"");
     }
 
     #[test]
-    fn integration_user_function() {
+    fn integration_halt_with_exit_code() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let source = "load_const 1\ncall print 1\nhalt_with 3\nload_const 2\ncall print 1";
+        let program = Program {
+            code: compiler::asm::parse(source).unwrap(),
+            ..Default::default()
+        };
+        // Through the binary format and back, the status survives
+        let program = Program::from_bytes(&program.to_bytes()).unwrap();
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = VM::from_program(program);
+        vm.set_output(Captured(Rc::clone(&out)));
+        vm.execute();
+        assert_eq!(vm.exit_code(), Some(3.0));
+        // Halting writes nothing of its own
+        assert_eq!(out.take(), b"1\n");
+        let mut vm = VM::new(vec![vm::Bytecode::Halt]);
+        vm.execute();
+        assert_eq!(vm.exit_code(), None);
+    }
+
+    #[test]
+    fn integration_print_string_literal() {
         use super::vm::Bytecode;
-        use super::VM;
-        let bytecode = vec![
-            Bytecode::LoadConst(10.0), // argument
-            Bytecode::StoreVar(0),     // store as local var 0
-            Bytecode::Call("add1".to_string(), 1),
-            Bytecode::Halt,
-            // Function 'add1' starts here (address 4):
-            Bytecode::LoadVar(0),
-            Bytecode::LoadConst(1.0),
-            Bytecode::Add,
-            Bytecode::Return,
-        ];
-        let mut vm = VM::new(bytecode);
-        // Register the function at the correct address
-        vm.user_functions.insert("add1".to_string(), 4);
+        let bytecode = BytecodeCompiler::compile(&parse_expr("print(\"hi\", 42)"));
+        assert_eq!(
+            bytecode,
+            vec![
+                Bytecode::LoadStr("hi".to_string()),
+                Bytecode::LoadConst(42.0),
+                Bytecode::Call("print".to_string(), 2),
+                Bytecode::Halt,
+            ]
+        );
+        assert_eq!(VM::run(bytecode), 0.0); // Should print "hi 42"
+    }
+
+    #[test]
+    fn integration_print_captures_string_constants() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        let source = "x = 42; print(\"x =\", x); print(\"x =\", x + 1, \"!\")";
+        let program =
+            BytecodeCompiler::compile_program(&try_parse_program(source).unwrap()).unwrap();
+        // Repeated literals share one entry of the string table
+        assert_eq!(program.strings, vec!["x =".to_string(), "!".to_string()]);
+        assert!(program.code.contains(&vm::Bytecode::LoadStrIdx(1)));
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = VM::from_program(Program::from_bytes(&program.to_bytes()).unwrap())
+            .with_natives([("print".to_string(), vm::print_to(Rc::clone(&out)))]);
         vm.execute();
-        // The result should be left on the stack after return
-        assert_eq!(vm.stack.pop(), Some(11.0));
+        assert_eq!(String::from_utf8(out.take()).unwrap(), "x = 42\nx = 43 !\n");
+    }
+
+    #[test]
+    fn integration_arrays_are_references() {
+        // Two variables bound to one array see each other's writes
+        assert_eq!(run_source("a = [1, 2]; b = a; b[0] = 5; a[0]"), 5.);
+        // So does a function handed the array
+        assert_eq!(
+            run_source("fn fill(arr, x) { arr[1] = x } a = [0, 0]; fill(a, 4); a[1]"),
+            4.
+        );
+        // An array nested twice is one array
+        assert_eq!(
+            run_source("row = [1]; grid = [row, row]; grid[0][0] = 7; grid[1][0] + row[0]"),
+            14.
+        );
+        // A fresh literal is a different array, even with equal elements
+        assert_eq!(
+            run_source("a = [1]; b = a; c = [1]; (a == b ? 10 : 0) + (a == c ? 1 : 0)"),
+            10.
+        );
+        assert_eq!(
+            compile_and_run("a = [1, [2, \"x\"]]; a[1][1] = a[1][1] + \"y\"; a")
+                .unwrap()
+                .to_string(),
+            "[1, [2, xy]]"
+        );
+    }
+
+    #[test]
+    fn integration_string_values() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        assert_eq!(
+            compile_and_run("\"ab\" == \"a\" + \"b\""),
+            Ok(Value::Bool(true))
+        );
+        assert_eq!(compile_and_run("\"ab\" != \"ab\""), Ok(Value::Bool(false)));
+        let err = compile_and_run("print(\"result: \" + 42)").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Runtime error: Cannot apply '+' to a string and a number at position 6"
+        );
+        let source = "greeting = \"hello\"; print(greeting + \", \" + \"world\", 1.5)";
+        let program =
+            BytecodeCompiler::compile_program(&try_parse_program(source).unwrap()).unwrap();
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = VM::from_program(program)
+            .with_natives([("print".to_string(), vm::print_to(Rc::clone(&out)))]);
+        vm.execute();
+        assert_eq!(String::from_utf8(out.take()).unwrap(), "hello, world 1.5\n");
+    }
+
+    #[test]
+    fn integration_bool_literals_select_branches() {
+        assert_eq!(
+            VM::run(BytecodeCompiler::compile(&parse_expr("true ? 1 : 2"))),
+            1.0
+        );
+        assert_eq!(
+            VM::run(BytecodeCompiler::compile(&parse_expr("false ? 1 : 2"))),
+            2.0
+        );
+        assert_eq!(
+            VM::run(BytecodeCompiler::compile(&parse_expr("true"))),
+            Value::Bool(true)
+        );
+        assert_eq!(
+            VM::run(BytecodeCompiler::compile(&parse_expr("false"))),
+            Value::Bool(false)
+        );
+    }
+
+    #[test]
+    fn integration_comparisons_give_bools() {
+        let source = "fn pick(a, b) { if a < b { \"less\" } else { \"not less\" } }; ";
+        assert_eq!(
+            compile_and_run(&format!("{}pick(1, 2)", source)),
+            Ok(Value::from("less"))
+        );
+        assert_eq!(
+            compile_and_run(&format!("{}pick(2, 1)", source)),
+            Ok(Value::from("not less"))
+        );
+        assert_eq!(
+            compile_and_run("x = 3 > 2; x == true"),
+            Ok(Value::Bool(true))
+        );
+        let err = compile_and_run("(1 < 2) + 1").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Runtime error: Cannot apply '+' to a bool and a number at position 0"
+        );
+        assert!(compile_and_run("true * 2").is_err());
+        assert!(compile_and_run("-false").is_err());
+    }
+
+    #[test]
+    fn integration_registered_natives() {
+        let run = |source: &str| {
+            let program = try_parse_program(source).unwrap();
+            let mut vm = VM::from_program(BytecodeCompiler::compile_program(&program).unwrap());
+            vm.register_native("double", Some(1), |args| args[0] * 2.0);
+            vm.try_execute().map(|()| vm.stack.pop().unwrap())
+        };
+        assert_eq!(run("x = 5; double(x + 1) + 1"), Ok(Value::Num(13.0)));
+        let err = run("double(1, 2)").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Function 'double' takes 1 argument(s) but 2 were given at position 0"
+        );
+        let halve: std::rc::Rc<vm::NativeFn> =
+            vm::numeric_native(|args| args.iter().sum::<f64>() / 2.0);
+        let program = try_parse_program("halve(3) + halve(4, 6)").unwrap();
+        let mut vm = VM::from_program(BytecodeCompiler::compile_program(&program).unwrap())
+            .with_natives([("halve".to_string(), halve)]);
+        vm.execute();
+        assert_eq!(vm.stack, vec![6.5]);
+    }
+
+    #[test]
+    fn integration_custom_infix_operator() {
+        let expr = PrattParser::new(Scanner::new("1 + 2 @ 3 * 4"))
+            .with_infix("@", 20, parser::Assoc::Left)
+            .expr(0)
+            .unwrap();
+        let mut vm = VM::new(BytecodeCompiler::compile(&expr));
+        // A dot product of the 2-vectors (a, 1) and (b, 1)
+        vm.register_native(&compiler::operator_function_name("@"), Some(2), |args| {
+            args[0] * args[1] + 1.0
+        });
+        vm.execute();
+        assert_eq!(
+            vm.stack.pop(),
+            Some(Value::Num(1.0 + (2.0 * 3.0 + 1.0) * 4.0))
+        );
+    }
+
+    #[test]
+    fn integration_modulo() {
+        assert_eq!(
+            VM::run(BytecodeCompiler::compile(&parse_expr("10 % 3"))),
+            1.0
+        );
+        assert_eq!(
+            VM::run(BytecodeCompiler::compile(&parse_expr("2 + 17 % 5 * 2"))),
+            6.0
+        );
+    }
+
+    #[test]
+    fn integration_user_function() {
+        // The function reads the variable the main program stored
+        assert_eq!(run_source("x = 10; fn add1() { x + 1 }; add1()"), 11.);
+        assert_eq!(
+            run_source("fn two() { return 2 }; fn four() { two() * two() }; four() + 1"),
+            5.
+        );
+    }
+
+    #[test]
+    fn integration_arrays_index_and_store() {
+        assert_eq!(run_source("a = [10, 20, 30]; a[1] + a[2]"), 50.);
+        assert_eq!(
+            run_source("a = [1, 2]; a[0] = a[1] * 5; a[0] += 1; a[0]"),
+            11.
+        );
+        // Nested arrays are references to other arrays
+        assert_eq!(
+            run_source("m = [[1, 2], [3, 4]]; m[1][0] = 7; m[1][0] + m[0][1]"),
+            9.
+        );
+        let squares = "s = [0, 0, 0, 0]; for i = 0 to 3 { s[i] = i * i }; s[3] + s[2]";
+        assert_eq!(run_source(squares), 13.);
+        // Out of range at runtime is the VM's error, not a panic
+        match compile_and_run("a = [10, 20, 30]; a[5]") {
+            Err(Error::Runtime(err)) => assert_eq!(
+                err.kind,
+                vm::RuntimeErrorKind::IndexOutOfBounds { index: 5., len: 3 }
+            ),
+            other => panic!("expected a bounds error, got {:?}", other),
+        }
+        // A constant index into a literal is caught by the compiler
+        assert!(matches!(
+            compile_and_run("[1, 2][2]"),
+            Err(Error::Compile(CompileError::IndexOutOfBounds {
+                len: 2,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn integration_for_loop_sums_inclusive_range() {
+        assert_eq!(run_source("s = 0; for i = 1 to 5 { s = s + i }; s"), 15.);
+        // The bound is evaluated once, and an empty range never runs the body
+        assert_eq!(run_source("n = 3; for i = 1 to n { n = n + 1 }; n"), 6.);
+        assert_eq!(run_source("s = 7; for i = 5 to 1 { s = 0 }; s"), 7.);
+        // Nested loops each keep their own bound, also inside a function
+        let nested =
+            "fn grid(n) { c = 0; for i = 1 to n { for j = 1 to i { c = c + 1 } }; c } grid(4)";
+        assert_eq!(run_source(nested), 10.);
+    }
+
+    #[test]
+    fn integration_locals_do_not_alias_globals() {
+        // The parameter `x` and the global `x` keep their own values
+        assert_eq!(run_source("x = 1; fn f(x) { x * 10 } f(5) + x"), 51.);
+        assert_eq!(run_source("x = 1; fn f(x) { x = x + 1; x } f(5); x"), 1.);
+        // Assigning a global from a function updates it, other names stay local
+        let counter =
+            "count = 0; fn bump(step) { count = count + step; t = count } bump(2); bump(3)";
+        assert_eq!(run_source(&format!("{}; count", counter)), 5.);
+        assert!(matches!(
+            compile_and_run(&format!("{}; t", counter)),
+            Err(Error::Compile(CompileError::UndefinedVariable { .. }))
+        ));
+        // Nested calls each get a frame, so the caller's locals survive
+        let nested = "fn inner(a) { a * 2 } fn outer(a) { b = inner(a + 1); a + b } outer(3)";
+        assert_eq!(run_source(nested), 11.);
+        // A block spawned from a function captures both kinds
+        assert_eq!(
+            run_source("g = 1; fn f(x) { spawn { x * 2 + g }; 0 } f(20); sync"),
+            41.
+        );
+    }
+
+    #[test]
+    fn compile_and_run_reports_each_stage() {
+        assert_eq!(
+            compile_and_run("fn sq(x) { x * x } sq(7) - 1"),
+            Ok(Value::Num(48.))
+        );
+        assert!(matches!(compile_and_run("1 +"), Err(Error::Parse(_))));
+        assert!(matches!(
+            compile_and_run("fn f(a) { a } f(1, 2)"),
+            Err(Error::Compile(CompileError::ArityMismatch { .. }))
+        ));
+        let err = compile_and_run("x = 0; 1 / x").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Runtime error: Division by zero at position 7"
+        );
+    }
+
+    #[test]
+    fn integration_user_function_from_assembly() {
+        let program = compiler::asm::parse_program(
+            "
+                    load_const 10   ; argument
+                    store 0         ; store as global var 0
+                    call add1 1
+                    halt
+            add1:   load_global 0
+                    load_const 1
+                    add
+                    ret
+            ",
+        )
+        .unwrap();
+        assert_eq!(program.functions, HashMap::from([("add1".to_string(), 4)]));
+        assert_eq!(VM::run_program(&program), 11.0);
+    }
+
+    #[test]
+    fn integration_function_parameters() {
+        let definition = "fn sub(a, b) { a - b }; ";
+        assert_eq!(run_source(&format!("{}sub(10, 4)", definition)), 6.);
+        assert_eq!(run_source(&format!("{}sub(4, 10)", definition)), -6.);
+        // Arguments are consumed by the call, leaving only the result behind
+        let program = try_parse_program("fn sub(a, b) { a - b }; sub(sub(9, 2), 3)").unwrap();
+        let mut vm = VM::from_program(BytecodeCompiler::compile_program(&program).unwrap());
+        vm.execute();
+        assert_eq!(vm.stack, vec![4.]);
+    }
+
+    /// Build a random arithmetic tree from a simple linear congruential generator.
+    fn random_arith(seed: &mut u64, depth: u32) -> parser::Expr {
+        use parser::ExprKind;
+        let mut next = |n: u64| {
+            *seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (*seed >> 33) % n
+        };
+        if depth == 0 || next(4) == 0 {
+            return ExprKind::Number(next(7) as f64).into();
+        }
+        if next(6) == 0 {
+            return ExprKind::UnaryOp {
+                op: Token::Minus,
+                rhs: Box::new(random_arith(seed, depth - 1)),
+            }
+            .into();
+        }
+        let op = [
+            Token::Plus,
+            Token::Minus,
+            Token::Star,
+            Token::Slash,
+            Token::Percent,
+            Token::StarStar,
+        ][next(6) as usize]
+            .clone();
+        ExprKind::BinaryOp {
+            lhs: Box::new(random_arith(seed, depth - 1)),
+            op,
+            rhs: Box::new(random_arith(seed, depth - 1)),
+        }
+        .into()
+    }
+
+    /// The number a VM run produced, for comparing with the other evaluators,
+    /// which give 1 and 0 for true and false.
+    fn numeric(result: Result<Option<Value>, RuntimeError>) -> Result<f64, RuntimeError> {
+        result.map(|value| match value.expect("a value") {
+            Value::Bool(value) => f64::from(u8::from(value)),
+            value => value.as_num().expect("a number"),
+        })
+    }
+
+    /// Whether two runs produced the same value (NaN matching NaN) or failed the same way.
+    fn same_outcome(a: &Result<f64, RuntimeError>, b: &Result<f64, RuntimeError>) -> bool {
+        match (a, b) {
+            (Ok(a), Ok(b)) => a == b || (a.is_nan() && b.is_nan()),
+            (Err(a), Err(b)) => a.kind == b.kind,
+            _ => false,
+        }
+    }
+
+    /// Compile the expression statements of `source` with `compiler`.
+    fn compile_with<C: Compiler<Error = CompileError>>(mut compiler: C, source: &str) -> C::Output {
+        for stmt in try_parse_program(source).unwrap() {
+            let Stmt::Expr(expr) = stmt else {
+                panic!("{}: not an expression statement", source)
+            };
+            compiler.compile_expr(&expr).unwrap();
+        }
+        compiler.finish().unwrap()
+    }
+
+    #[test]
+    fn register_and_stack_backends_agree() {
+        use compiler::regalloc::{RegCompiler, RegVM};
+        let corpus = [
+            "7 * (8 + 9) - 3",
+            "2 ** 3 ** 2 % 5",
+            "-(4 - 10) % 4",
+            "1 / (2 - 2)",
+            "x = 3; y = x * x; y - x / 2",
+            "x = 1; x + (x = 10)",
+            "x = 2; x = x * x + x; -x",
+            "i = 10; total = 0; while i > 0 { total = total + i * i; i = i - 1 }; total",
+            "a = 0; b = 5; a && (b = 7); b",
+            "a = 0; a || 3 > 2",
+            "n = 6; if n % 2 == 0 { n / 2 } else { 3 * n + 1 }",
+            "x = 1; { x = x + 1; x * 10 } + x",
+            "if 0 { 1 }",
+            "t = true; t != false",
+            "if 0 { x = 1 }; x",
+            "n = 3; if n > 2 { y = n }; y * 2",
+        ];
+        for source in corpus {
+            let stack = numeric(VM::try_run(compile_with(BytecodeCompiler::new(), source)));
+            let registers = RegVM::run(compile_with(RegCompiler::new(), source));
+            assert!(
+                same_outcome(&stack, &registers),
+                "{}: stack {:?} but registers {:?}",
+                source,
+                stack,
+                registers
+            );
+        }
+        let mut seed = 0x5eed;
+        for _ in 0..200 {
+            let expr = random_arith(&mut seed, 5);
+            let stack = numeric(VM::try_run(BytecodeCompiler::try_compile(&expr).unwrap()));
+            let registers = RegVM::run(RegCompiler::try_compile(&expr).unwrap());
+            assert!(
+                same_outcome(&stack, &registers),
+                "{}: stack {:?} but registers {:?}",
+                expr,
+                stack,
+                registers
+            );
+        }
+    }
+
+    #[test]
+    fn tree_walker_and_vm_agree() {
+        let corpus = [
+            "7 * (8 + 9) - 3",
+            "-2 ** 2 + 10 % 4 / 4",
+            "x = 3; y = x * x; y - x / 2",
+            "x = 1; x + (x = 10)",
+            "i = 10; total = 0; while i > 0 { total = total + i * i; i = i - 1 }; total",
+            "a = 0; b = 5; a && (b = 7); b",
+            "a = 0; a || 3 > 2",
+            "n = 7; if n % 2 == 0 { n / 2 } else if n > 5 { 3 * n + 1 } else { n }",
+            "x = 1; { x += 1; x * 10 } + x",
+            "if 0 { 1 }",
+            "x = 2; x == 2 ? x : 0 - x",
+            "t = true; t != false",
+            "print(1, 2 + 3)",
+        ];
+        let mut seed = 0x7ee;
+        let random: Vec<String> = (0..200)
+            .map(|_| random_arith(&mut seed, 5).to_string())
+            .collect();
+        for source in corpus
+            .iter()
+            .copied()
+            .chain(random.iter().map(String::as_str))
+        {
+            let vm = numeric(VM::try_run(compile_with(BytecodeCompiler::new(), source)));
+            let mut env = interp::Env::new();
+            let mut walked = Ok(0.0);
+            for stmt in try_parse_program(source).unwrap() {
+                let Stmt::Expr(expr) = stmt else {
+                    unreachable!()
+                };
+                walked = eval_expr(&expr, &mut env);
+            }
+            let agree = match (&vm, &walked) {
+                (Ok(a), Ok(b)) => a == b || (a.is_nan() && b.is_nan()),
+                (Err(_), Err(_)) => true,
+                _ => false,
+            };
+            assert!(
+                agree,
+                "{}: VM {:?} but tree-walker {:?}",
+                source, vm, walked
+            );
+        }
+    }
+
+    /// Build a random expression over `x` and `y` that is full of the
+    /// patterns strength reduction rewrites.
+    fn random_reducible(seed: &mut u64, depth: u32) -> String {
+        let mut next = |n: u64| {
+            *seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (*seed >> 33) % n
+        };
+        if depth == 0 || next(5) == 0 {
+            // Branches joining on a string leave a non-number where the
+            // fall-through branch alone would not
+            let leaves = [
+                "x",
+                "y",
+                "2",
+                "0",
+                "(x < y ? x : y)",
+                "(x < y ? \"ab\" : y)",
+            ];
+            return leaves[next(leaves.len() as u64) as usize].to_string();
+        }
+        let choice = next(10);
+        let a = random_reducible(seed, depth - 1);
+        let b = random_reducible(seed, depth - 1);
+        match choice {
+            0 => format!("({} * 2)", a),
+            1 => format!("({} / 1)", a),
+            2 => format!("({} * 0)", a),
+            3 => format!("(-(-{}))", a),
+            4 => format!("({} - {})", a, a),
+            5 => format!("(({} == {}) ? {} * 0 : {} - {})", a, b, a, b, b),
+            6 => format!("(({} > {}) - ({} > {}))", a, b, a, b),
+            7 => format!("({} / {})", a, b),
+            8 => format!("(if {} < {} {{ \"a\" }} else {{ {} }})", a, b, a),
+            _ => format!("({} + f({}))", a, b),
+        }
+    }
+
+    #[test]
+    fn strength_reduction_preserves_results() {
+        use compiler::PassManager;
+        // NaN, infinities and -0 included, for which `x - x` and `x * 0` are not 0
+        let inputs = [
+            "3",
+            "0 - 2.5",
+            "(0 - 1) ** 0.5",
+            "10 ** 400",
+            "0 - 10 ** 400",
+            "0 * (0 - 1)",
+        ];
+        let run = |program: compiler::Program| {
+            let mut vm = VM::from_program(program);
+            vm.register_native("f", Some(1), |args| args[0] + 1.0);
+            vm.try_execute()
+                .map(|()| vm.stack.last().and_then(Value::as_num).unwrap_or(0.0))
+        };
+        let mut seed = 0x5e1f;
+        for _ in 0..300 {
+            let expr = random_reducible(&mut seed, 4);
+            for (x, y) in inputs.iter().zip(inputs.iter().cycle().skip(2)) {
+                let source = format!("x = {}; y = {}; {}", x, y, expr);
+                let program = try_parse_program(&source).unwrap();
+                let compile = |passes| BytecodeCompiler::compile_with(&program, &passes).unwrap();
+                let optimized = run(compile(PassManager::default_pipeline()));
+                let unoptimized = run(compile(PassManager::new()));
+                assert!(
+                    same_outcome(&optimized, &unoptimized),
+                    "{}: optimized {:?} but unoptimized {:?}",
+                    source,
+                    optimized,
+                    unoptimized
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn constant_folding_preserves_results() {
+        let corpus = [
+            "7 * (8 + 9) - 3",
+            "2 ** 3 ** 2",
+            "-(4 - 10) % 4",
+            "1 / 0",
+            "0 / 0 + 1",
+            "10 / 4 * -2",
+            "+-+2",
+            "if 2 - 2 { 1 + 1 } else { 3 * 3 }",
+        ];
+        for source in corpus {
+            let expr = parse_expr(source);
+            let folded = BytecodeCompiler::try_compile(&expr).unwrap();
+            let unfolded = BytecodeCompiler::try_compile_unfolded(&expr).unwrap();
+            assert!(folded.len() <= unfolded.len(), "source: {}", source);
+            let (folded, unfolded) = (VM::try_run(folded), VM::try_run(unfolded));
+            let (folded, unfolded) = (numeric(folded), numeric(unfolded));
+            assert!(
+                same_outcome(&folded, &unfolded),
+                "{}: folded {:?} but unfolded {:?}",
+                source,
+                folded,
+                unfolded
+            );
+        }
+        let mut seed = 0xf01d;
+        for _ in 0..200 {
+            let expr = random_arith(&mut seed, 4);
+            let folded = numeric(VM::try_run(BytecodeCompiler::try_compile(&expr).unwrap()));
+            let unfolded = numeric(VM::try_run(
+                BytecodeCompiler::try_compile_unfolded(&expr).unwrap(),
+            ));
+            assert!(
+                same_outcome(&folded, &unfolded),
+                "{}: folded {:?} but unfolded {:?}",
+                expr,
+                folded,
+                unfolded
+            );
+        }
+    }
+
+    #[test]
+    fn const_eval_agrees_with_vm() {
+        let mut seed = 0x5eed;
+        let mut checked = 0;
+        for _ in 0..500 {
+            let expr = random_arith(&mut seed, 4);
+            let Some(expected) = parser::const_eval(&expr) else {
+                continue;
+            };
+            let actual = VM::run(BytecodeCompiler::try_compile_unfolded(&expr).unwrap());
+            let actual = actual.as_num().unwrap();
+            assert!(
+                actual == expected || (actual.is_nan() && expected.is_nan()),
+                "{} evaluated to {} but the VM produced {}",
+                expr,
+                expected,
+                actual
+            );
+            checked += 1;
+        }
+        assert!(checked > 100);
     }
 }
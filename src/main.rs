@@ -1,5 +1,11 @@
-use clap::Parser;
-use parallelized_programming_language::{parse_expr, BytecodeCompiler, VM};
+use clap::{Parser, ValueEnum};
+use parallelized_programming_language::binary::LoadError;
+use parallelized_programming_language::compiler::{self, passes, CompileError, PassManager};
+use parallelized_programming_language::parser::const_eval;
+use parallelized_programming_language::{
+    try_parse_program, BytecodeCompiler, ParseError, PrattParser, Program, RuntimeError, Scanner,
+    Stmt, VM,
+};
 use std::fs;
 use std::io::{self, Write};
 
@@ -9,6 +15,55 @@ use std::io::{self, Write};
 struct Cli {
     /// Path to the file to execute. If not provided, starts a REPL.
     file: Option<std::path::PathBuf>,
+    /// Compile every operation as written, without folding constants or
+    /// optimizing the bytecode.
+    #[arg(long)]
+    no_optimize: bool,
+    /// The optimization passes to run, in order, instead of the default
+    /// `fold,strength,peephole,dce`. `inline` is only run when named here.
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        conflicts_with = "no_optimize"
+    )]
+    passes: Option<Vec<PassName>>,
+    /// Write the compiled program to this `.ppbc` file instead of running it.
+    #[arg(long, value_name = "PATH")]
+    emit: Option<std::path::PathBuf>,
+    /// Print a listing of the compiled program instead of running it.
+    #[arg(long)]
+    disassemble: bool,
+}
+
+/// An optimization pass that can be named on the command line.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum PassName {
+    Fold,
+    Strength,
+    Peephole,
+    Dce,
+    Inline,
+}
+
+impl Cli {
+    fn pass_manager(&self) -> PassManager {
+        if self.no_optimize {
+            return PassManager::new();
+        }
+        let Some(names) = &self.passes else {
+            return PassManager::default_pipeline();
+        };
+        names
+            .iter()
+            .fold(PassManager::new(), |passes, name| match name {
+                PassName::Fold => passes.with_pass(passes::FoldConstants),
+                PassName::Strength => passes.with_pass(passes::StrengthReduction),
+                PassName::Peephole => passes.with_pass(passes::Peephole),
+                PassName::Dce => passes.with_pass(passes::DeadCodeElimination),
+                PassName::Inline => passes.with_pass(passes::Inline::default()),
+            })
+    }
 }
 
 fn preprocess_code(code: &str, base_path: Option<&std::path::Path>) -> String {
@@ -17,16 +72,15 @@ fn preprocess_code(code: &str, base_path: Option<&std::path::Path>) -> String {
     let mut output = String::new();
     for line in code.lines() {
         let trimmed = line.trim();
-        if trimmed.starts_with("#define ") {
+        if let Some(rest) = trimmed.strip_prefix("#define ") {
             // #define MACRO value
-            let rest = &trimmed[8..];
             if let Some((name, value)) = rest.split_once(' ') {
                 macros.insert(name.to_string(), value.to_string());
             }
             continue;
-        } else if trimmed.starts_with("#include ") {
+        } else if let Some(rest) = trimmed.strip_prefix("#include ") {
             // #include "file"
-            let rest = &trimmed[9..].trim();
+            let rest = rest.trim();
             if let Some(include_path) = rest.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
                 let include_file = if let Some(base) = base_path {
                     base.parent().unwrap_or(base).join(include_path)
@@ -52,35 +106,163 @@ fn preprocess_code(code: &str, base_path: Option<&std::path::Path>) -> String {
     output
 }
 
-fn run_code_with_preprocessing(code: &str, base_path: Option<&std::path::Path>) {
+/// Why a piece of source text or a compiled program could not be run.
+enum RunError {
+    Syntax(Vec<ParseError>),
+    Compile(CompileError),
+    Load(LoadError),
+    /// A runtime error, with the preprocessed source its span points into, if any.
+    Runtime(RuntimeError, Option<String>),
+}
+
+fn run_code_with_preprocessing(
+    code: &str,
+    base_path: Option<&std::path::Path>,
+    passes: &PassManager,
+) -> Result<(), RunError> {
     let preprocessed = preprocess_code(code, base_path);
-    let expr = parse_expr(&preprocessed);
-    let bytecode = BytecodeCompiler::compile(&expr);
-    let _result = VM::run(bytecode);
+    let program = compile_source(&preprocessed, passes)?;
+    // A REPL line that stops with an exit status does not end the session
+    run_or_disassemble(program, Some(&preprocessed), false).map(|_| ())
+}
+
+/// Run `program`, or print its listing, with source excerpts when its source
+/// is known. Gives the exit status a run stopped with at a `HaltWith`.
+fn run_or_disassemble(
+    program: Program,
+    source: Option<&str>,
+    disassemble: bool,
+) -> Result<Option<f64>, RunError> {
+    if disassemble {
+        match source {
+            Some(source) => print!("{}", compiler::disassemble_with_source(&program, source)),
+            None => print!("{}", compiler::disassemble(&program)),
+        }
+        Ok(None)
+    } else {
+        let mut vm = VM::from_program(program);
+        vm.try_execute()
+            .map_err(|err| RunError::Runtime(err, source.map(str::to_string)))?;
+        Ok(vm.exit_code())
+    }
+}
+
+fn compile_with_preprocessing(
+    code: &str,
+    base_path: Option<&std::path::Path>,
+    passes: &PassManager,
+) -> Result<Program, RunError> {
+    compile_source(&preprocess_code(code, base_path), passes)
+}
+
+fn compile_source(preprocessed: &str, passes: &PassManager) -> Result<Program, RunError> {
+    let mut parser = PrattParser::new(Scanner::new(preprocessed));
+    let (program, errors) = parser.parse_program_recovering();
+    if !errors.is_empty() {
+        return Err(RunError::Syntax(errors));
+    }
+    let mut program =
+        BytecodeCompiler::compile_with(&program, passes).map_err(RunError::Compile)?;
+    compiler::intern_constants(&mut program);
+    Ok(program)
+}
+
+/// Answer a REPL line consisting of a single pure-arithmetic expression
+/// without compiling it or starting a VM.
+fn eval_constant_line(input: &str) -> Option<f64> {
+    match try_parse_program(input).ok()?.as_slice() {
+        [Stmt::Expr(expr)] => const_eval(expr),
+        _ => None,
+    }
+}
+
+fn report_errors(error: &RunError) {
+    match error {
+        RunError::Syntax(errors) => {
+            for err in errors {
+                eprintln!("Syntax error: {}", err);
+            }
+        }
+        RunError::Compile(err) => eprintln!("Compile error: {}", err),
+        RunError::Load(err) => eprintln!("Load error: {}", err),
+        RunError::Runtime(err, Some(source)) => {
+            eprintln!("Runtime error: {}", err.describe(source))
+        }
+        RunError::Runtime(err, None) => eprintln!("Runtime error: {}", err),
+    }
 }
 
 fn main() {
     let cli = Cli::parse();
+    let passes = cli.pass_manager();
     if let Some(file_path) = cli.file {
-        let code = fs::read_to_string(&file_path).expect("Failed to read file");
-        run_code_with_preprocessing(&code, Some(&file_path));
+        let result = if file_path.extension().is_some_and(|ext| ext == "ppbc") {
+            let bytes = fs::read(&file_path).expect("Failed to read file");
+            Program::from_bytes(&bytes)
+                .map_err(RunError::Load)
+                .and_then(|program| run_or_disassemble(program, None, cli.disassemble))
+        } else {
+            let code = fs::read_to_string(&file_path).expect("Failed to read file");
+            match &cli.emit {
+                Some(out) => {
+                    compile_with_preprocessing(&code, Some(&file_path), &passes).map(|program| {
+                        fs::write(out, program.to_bytes()).expect("Failed to write file");
+                        None
+                    })
+                }
+                None => {
+                    let preprocessed = preprocess_code(&code, Some(&file_path));
+                    compile_source(&preprocessed, &passes).and_then(|program| {
+                        run_or_disassemble(program, Some(&preprocessed), cli.disassemble)
+                    })
+                }
+            }
+        };
+        match result {
+            Err(error) => {
+                report_errors(&error);
+                std::process::exit(1);
+            }
+            // `as` saturates, and takes NaN to 0
+            Ok(Some(code)) => std::process::exit(code as i32),
+            Ok(None) => {}
+        }
     } else {
         println!("Parallelized Programming Language REPL. Type 'exit' to quit.");
         let stdin = io::stdin();
+        // Lines of a statement still waiting for its closing tokens
+        let mut pending = String::new();
         loop {
-            print!("> ");
+            print!("{}", if pending.is_empty() { "> " } else { "... " });
             io::stdout().flush().unwrap();
             let mut input = String::new();
-            if stdin.read_line(&mut input).is_err() {
+            if matches!(stdin.read_line(&mut input), Ok(0) | Err(_)) {
                 break;
             }
-            let input = input.trim();
-            if input == "exit" {
+            let line = input.trim();
+            if pending.is_empty() && line == "exit" {
                 break;
             }
-            if !input.is_empty() {
-                run_code_with_preprocessing(input, None);
+            pending.push_str(line);
+            pending.push('\n');
+            let source = pending.trim();
+            if source.is_empty() {
+                pending.clear();
+                continue;
+            }
+            if let Some(value) = eval_constant_line(source) {
+                println!("{}", value);
+            } else if let Err(error) = run_code_with_preprocessing(source, None, &passes) {
+                // Input that merely stopped early gets a continuation prompt;
+                // an empty line submits it as is
+                if let RunError::Syntax(errors) = &error {
+                    if !line.is_empty() && errors.iter().all(ParseError::is_unexpected_eof) {
+                        continue;
+                    }
+                }
+                report_errors(&error);
             }
+            pending.clear();
         }
     }
 }
@@ -1,7 +1,62 @@
+/// An AST node together with the source range it was parsed from.
+///
+/// Equality compares only the node structure, so spans never affect `==`.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Expr { kind, span }
+    }
+}
+
+impl PartialEq for Expr {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+
+impl ExprKind {
+    /// A call of the function named `name`.
+    pub fn call(name: impl Into<String>, args: Vec<Expr>) -> Self {
+        ExprKind::Call {
+            callee: Box::new(ExprKind::Ident(name.into()).into()),
+            args,
+        }
+    }
+
+    /// The function name of a `Call` whose callee is a plain identifier.
+    pub fn callee_name(&self) -> Option<&str> {
+        match self {
+            ExprKind::Call { callee, .. } => match &callee.kind {
+                ExprKind::Ident(name) => Some(name),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Builds a node without source information, e.g. for synthesized code.
+impl From<ExprKind> for Expr {
+    fn from(kind: ExprKind) -> Self {
+        Expr::new(kind, Span::default())
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
-pub enum Expr {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ExprKind {
     Number(f64),
     Ident(String),
+    /// `true` / `false`; compiled to `LoadBool`, a value apart from the numbers.
+    Bool(bool),
+    /// `"text"`; a string value, joined to another string by `+`.
+    Str(String),
     UnaryOp {
         op: Token,
         rhs: Box<Expr>,
@@ -11,186 +66,1016 @@ pub enum Expr {
         op: Token,
         rhs: Box<Expr>,
     },
+    /// `callee(args)`; the callee is usually an `Ident`, see `ExprKind::call`.
     Call {
-        name: String,
+        callee: Box<Expr>,
         args: Vec<Expr>,
     },
-    Function {
+    /// `(expr)`, kept only when the parser is built `with_preserve_groups(true)`.
+    Group(Box<Expr>),
+    /// `name = value`; right-associative and evaluates to the assigned value.
+    Assign {
         name: String,
-        params: Vec<String>,
+        value: Box<Expr>,
+    },
+    While {
+        cond: Box<Expr>,
+        body: Vec<Expr>,
+    },
+    /// `{ a; b; c }`; evaluates to its last expression, or 0.0 when empty.
+    Block(Vec<Expr>),
+    /// `cond ? a : b`; without an else branch the value is 0.0 when `cond` is zero.
+    If {
+        cond: Box<Expr>,
+        then_branch: Box<Expr>,
+        else_branch: Option<Box<Expr>>,
+    },
+    /// `[a, b, c]`
+    Array(Vec<Expr>),
+    /// `target[index]`
+    Index {
+        target: Box<Expr>,
+        index: Box<Expr>,
+    },
+    /// `target[index] = value`; evaluates to the assigned value.
+    IndexAssign {
+        target: Box<Expr>,
+        index: Box<Expr>,
+        value: Box<Expr>,
+    },
+    /// `spawn expr`: evaluate `expr` and hand its value to a parallel task.
+    Spawn(Box<Expr>),
+    /// `sync`: wait for every spawned task and push their results.
+    Sync,
+    /// `barrier`: wait for every spawned task, discarding results.
+    Barrier,
+    /// Placeholder for a construct that failed to parse in recovering mode.
+    Error,
+    /// `for var = start to end { body }`; the upper bound is inclusive.
+    For {
+        var: String,
+        start: Box<Expr>,
+        end: Box<Expr>,
         body: Vec<Expr>,
     },
 }
 
-use crate::scanner::{Scanner, Token};
+/// A top-level or function-body statement.
+///
+/// Unlike an `Expr`, a statement is executed for its effect and has no value
+/// of its own. Equality ignores spans, as for `Expr`.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Stmt {
+    /// An expression whose value is discarded, except at the end of a program.
+    Expr(Expr),
+    /// `let name = value`
+    Let {
+        name: String,
+        value: Expr,
+        span: Span,
+    },
+    /// `fn name(params) { body }`
+    Func {
+        name: String,
+        params: Vec<String>,
+        body: Vec<Stmt>,
+        span: Span,
+    },
+    /// `return` or `return value`
+    Return { value: Option<Expr>, span: Span },
+}
+
+impl Stmt {
+    /// The source range the statement was parsed from.
+    pub fn span(&self) -> Span {
+        match self {
+            Stmt::Expr(expr) => expr.span,
+            Stmt::Let { span, .. } | Stmt::Func { span, .. } | Stmt::Return { span, .. } => *span,
+        }
+    }
+}
+
+impl PartialEq for Stmt {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Stmt::Expr(a), Stmt::Expr(b)) => a == b,
+            (
+                Stmt::Let { name, value, .. },
+                Stmt::Let {
+                    name: other_name,
+                    value: other_value,
+                    ..
+                },
+            ) => name == other_name && value == other_value,
+            (
+                Stmt::Func {
+                    name, params, body, ..
+                },
+                Stmt::Func {
+                    name: other_name,
+                    params: other_params,
+                    body: other_body,
+                    ..
+                },
+            ) => name == other_name && params == other_params && body == other_body,
+            (Stmt::Return { value, .. }, Stmt::Return { value: other, .. }) => value == other,
+            _ => false,
+        }
+    }
+}
+
+impl From<Expr> for Stmt {
+    fn from(expr: Expr) -> Self {
+        Stmt::Expr(expr)
+    }
+}
+
+impl From<ExprKind> for Stmt {
+    fn from(kind: ExprKind) -> Self {
+        Stmt::Expr(kind.into())
+    }
+}
+
+use crate::scanner::{Scanner, Span, Token};
+use std::collections::HashMap;
+use std::fmt;
+
+/// An error produced while parsing source text.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// A token appeared where something else was expected.
+    UnexpectedToken {
+        found: Token,
+        expected: String,
+        pos: usize,
+    },
+    /// The input ended in the middle of a construct.
+    UnexpectedEof { expected: String, pos: usize },
+    /// A `fn` definition appeared inside another function's body.
+    NestedFunction { name: String, pos: usize },
+    /// Expressions were nested deeper than the parser's limit.
+    TooDeep { limit: usize, pos: usize },
+}
+
+impl ParseError {
+    /// Whether parsing stopped because the input ended early, so that more
+    /// text (e.g. a REPL continuation line) could complete it.
+    pub fn is_unexpected_eof(&self) -> bool {
+        matches!(self, ParseError::UnexpectedEof { .. })
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnexpectedToken {
+                found,
+                expected,
+                pos,
+            } => write!(
+                f,
+                "Expected {} but found {:?} at position {}",
+                expected, found, pos
+            ),
+            ParseError::UnexpectedEof { expected, pos } => write!(
+                f,
+                "Expected {} but reached end of input at position {}",
+                expected, pos
+            ),
+            ParseError::NestedFunction { name, pos } => write!(
+                f,
+                "Function '{}' at position {} is defined inside another function; functions may only be defined at the top level",
+                name, pos
+            ),
+            ParseError::TooDeep { limit, pos } => write!(
+                f,
+                "Expression nested more than {} levels deep at position {}",
+                limit, pos
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Associativity of an operator registered with `PrattParser::with_infix`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assoc {
+    Left,
+    Right,
+}
 
 /// A Pratt parser for arithmetic expressions.
 pub struct PrattParser<'a> {
     scanner: Scanner<'a>,
     current: Token,
+    /// Source range of `current`.
+    span: Span,
+    /// Source range of the last consumed token.
+    prev: Span,
+    recovering: bool,
+    errors: Vec<ParseError>,
+    /// Number of function bodies enclosing the current token.
+    function_depth: usize,
+    /// Byte offsets of the '(' tokens not yet matched by a ')'.
+    open_parens: Vec<usize>,
+    /// Current expression nesting, bounded by `max_depth` to avoid stack overflow.
+    depth: usize,
+    max_depth: usize,
+    /// Binding power and associativity of operators added with `with_infix`.
+    custom_infix: HashMap<String, (u8, Assoc)>,
+    /// Whether parentheses produce `ExprKind::Group` nodes.
+    preserve_groups: bool,
 }
 
 impl<'a> PrattParser<'a> {
+    /// Nesting limit used unless `with_max_depth` says otherwise.
+    pub const DEFAULT_MAX_DEPTH: usize = 1024;
+
     pub fn new(scanner: Scanner<'a>) -> Self {
         let mut parser = PrattParser {
             scanner,
             current: Token::Eof,
+            span: Span::default(),
+            prev: Span::default(),
+            recovering: false,
+            errors: Vec::new(),
+            function_depth: 0,
+            open_parens: Vec::new(),
+            depth: 0,
+            max_depth: Self::DEFAULT_MAX_DEPTH,
+            custom_infix: HashMap::new(),
+            preserve_groups: false,
         };
         parser.advance();
         parser
     }
 
+    /// Replace the default nesting limit; inputs nested deeper fail with
+    /// `ParseError::TooDeep` instead of overflowing the stack.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Keep parenthesised expressions as `ExprKind::Group` nodes, e.g. for a
+    /// formatter, instead of dropping the parentheses once they have shaped the tree.
+    pub fn with_preserve_groups(mut self, preserve_groups: bool) -> Self {
+        self.preserve_groups = preserve_groups;
+        self
+    }
+
+    /// Register a single-character infix operator. It parses to
+    /// `ExprKind::BinaryOp` with a `Token::Custom` operator, binding like the
+    /// built-in operators with the same `binding_power` (e.g. 20 for `*`).
+    pub fn with_infix(mut self, token_text: &str, binding_power: u8, assoc: Assoc) -> Self {
+        let mut chars = token_text.chars();
+        let op = match (chars.next(), chars.next()) {
+            (Some(op), None) => op,
+            _ => panic!(
+                "Custom operators must be a single character, got {:?}",
+                token_text
+            ),
+        };
+        assert!(
+            binding_power > 0,
+            "Custom operators need a binding power above 0"
+        );
+        self.scanner.add_operator(op);
+        self.custom_infix
+            .insert(token_text.to_string(), (binding_power, assoc));
+        self
+    }
+
     fn advance(&mut self) {
+        self.prev = self.span;
         self.current = self.scanner.next_token();
+        self.span = self.scanner.token_span();
     }
 
-    pub fn parse_function(&mut self) -> Expr {
-        // Expect 'fn'
+    /// Wrap `kind` in a node spanning from `start` to the end of the last consumed token.
+    fn node(&self, start: usize, kind: ExprKind) -> Expr {
+        Expr::new(kind, Span::new(start, self.prev.end))
+    }
+
+    /// Build an error describing the current token as not being `expected`.
+    fn unexpected(&self, expected: impl Into<String>) -> ParseError {
+        let expected = expected.into();
+        let pos = self.span.start;
+        if self.current == Token::Eof {
+            ParseError::UnexpectedEof { expected, pos }
+        } else {
+            ParseError::UnexpectedToken {
+                found: self.current.clone(),
+                expected,
+                pos,
+            }
+        }
+    }
+
+    /// Consume the current token if it is `token`, otherwise report `expected`.
+    fn expect(&mut self, token: Token, expected: &str) -> Result<(), ParseError> {
+        if self.current != token {
+            return Err(self.unexpected(expected));
+        }
+        self.advance();
+        Ok(())
+    }
+
+    /// Human-readable source location of the byte offset `pos`.
+    fn location(&self, pos: usize) -> String {
+        let (line, column) = self.scanner.line_col(pos);
+        format!("line {}, column {}", line, column)
+    }
+
+    /// Record the '(' under the cursor as unmatched and consume it.
+    fn open_paren(&mut self) {
+        self.open_parens.push(self.span.start);
         self.advance();
-        let name = if let Token::Identifier(name) = &self.current {
-            name.clone()
+    }
+
+    /// Consume the ')' closing the innermost open '(', pointing back at
+    /// the '(' if it is missing.
+    fn close_paren(&mut self, expected: &str) -> Result<(), ParseError> {
+        let open = self.open_parens.pop().unwrap_or(self.span.start);
+        let expected = format!(
+            "{} to match '(' opened at {}",
+            expected,
+            self.location(open)
+        );
+        self.expect(Token::RParen, &expected)
+    }
+
+    fn expect_identifier(&mut self, expected: &str) -> Result<String, ParseError> {
+        if let Token::Identifier(name) = &self.current {
+            let name = name.clone();
+            self.advance();
+            Ok(name)
         } else {
-            panic!("Expected function name after 'fn'");
+            Err(self.unexpected(expected))
+        }
+    }
+
+    /// Parse a whole program: statements separated by semicolons up to end of input.
+    pub fn parse_program(&mut self) -> Result<Vec<Stmt>, ParseError> {
+        let mut program = Vec::new();
+        while self.current != Token::Eof {
+            program.push(self.parse_stmt()?);
+            if self.current == Token::Semicolon {
+                self.advance();
+            }
+        }
+        Ok(program)
+    }
+
+    /// Parse a whole program, continuing after syntax errors.
+    ///
+    /// Every statement that fails to parse is replaced by `ExprKind::Error` and its
+    /// error is collected; parsing resumes after the next `;` or before the
+    /// closing `}` of the enclosing body.
+    pub fn parse_program_recovering(&mut self) -> (Vec<Stmt>, Vec<ParseError>) {
+        self.recovering = true;
+        let mut program = Vec::new();
+        while self.current != Token::Eof {
+            // `parse_stmt` never fails while recovering
+            if let Ok(stmt) = self.parse_stmt() {
+                program.push(stmt);
+            }
+            // A stray '}' has no enclosing body to close it
+            if self.current == Token::RBrace {
+                self.advance();
+            }
+            if self.current == Token::Semicolon {
+                self.advance();
+            }
+        }
+        self.recovering = false;
+        (program, std::mem::take(&mut self.errors))
+    }
+
+    /// Parse one statement: a `let`, `return` or `fn` definition, or an expression.
+    pub fn parse_stmt(&mut self) -> Result<Stmt, ParseError> {
+        let start = self.span.start;
+        let open_parens = self.open_parens.len();
+        let result = match self.current {
+            Token::KeywordLet => self.parse_let(),
+            Token::KeywordReturn => self.parse_return(),
+            Token::KeywordFn => self.parse_function(),
+            _ => return self.statement().map(Stmt::Expr),
+        };
+        match result {
+            Err(err) if self.recovering => Ok(Stmt::Expr(self.recover(err, start, open_parens))),
+            result => result,
+        }
+    }
+
+    /// Parse one expression in statement position, recovering from errors if enabled.
+    fn statement(&mut self) -> Result<Expr, ParseError> {
+        let start = self.span.start;
+        let open_parens = self.open_parens.len();
+        let result = match self.current {
+            Token::KeywordSync | Token::KeywordBarrier => self.parse_synchronization(),
+            _ => self.expr(0),
         };
+        match result {
+            Err(err) if self.recovering => Ok(self.recover(err, start, open_parens)),
+            result => result,
+        }
+    }
+
+    /// Record `err` and skip past the statement that began at `start`,
+    /// leaving an `ExprKind::Error` in its place.
+    fn recover(&mut self, err: ParseError, start: usize, open_parens: usize) -> Expr {
+        // Parentheses opened by the failed statement are abandoned with it
+        self.open_parens.truncate(open_parens);
+        self.errors.push(err);
+        self.synchronize();
+        self.node(start, ExprKind::Error)
+    }
+
+    fn parse_let(&mut self) -> Result<Stmt, ParseError> {
+        let start = self.span.start;
+        // Expect 'let'
         self.advance();
-        // Parse parameters
-        if self.current != Token::LParen {
-            panic!("Expected '(' after function name");
+        let name = self.expect_identifier("variable name after 'let'")?;
+        self.expect(Token::Assign, &format!("'=' after 'let {}'", name))?;
+        let value = self.expr(0)?;
+        Ok(Stmt::Let {
+            name,
+            value,
+            span: Span::new(start, self.prev.end),
+        })
+    }
+
+    fn parse_return(&mut self) -> Result<Stmt, ParseError> {
+        let start = self.span.start;
+        // Expect 'return'
+        self.advance();
+        let value = match self.current {
+            Token::Semicolon | Token::RBrace | Token::Eof => None,
+            _ => Some(self.expr(0)?),
+        };
+        Ok(Stmt::Return {
+            value,
+            span: Span::new(start, self.prev.end),
+        })
+    }
+
+    /// Parse `sync` or `barrier`, which must stand alone as a statement.
+    fn parse_synchronization(&mut self) -> Result<Expr, ParseError> {
+        let start = self.span.start;
+        let (kind, keyword) = match self.current {
+            Token::KeywordSync => (ExprKind::Sync, "sync"),
+            _ => (ExprKind::Barrier, "barrier"),
+        };
+        self.advance();
+        match self.current {
+            Token::Semicolon | Token::RBrace | Token::Eof => Ok(self.node(start, kind)),
+            _ => Err(self.unexpected(format!(
+                "';' after '{}', which must stand alone as a statement",
+                keyword
+            ))),
+        }
+    }
+
+    /// Skip tokens up to and including the next `;`, or up to the `}` closing the
+    /// current body, keeping nested braces balanced.
+    fn synchronize(&mut self) {
+        let mut depth = 0usize;
+        loop {
+            match self.current {
+                Token::Eof => return,
+                Token::Semicolon if depth == 0 => {
+                    self.advance();
+                    return;
+                }
+                Token::RBrace if depth == 0 => return,
+                Token::LBrace => depth += 1,
+                Token::RBrace => depth -= 1,
+                _ => {}
+            }
+            self.advance();
         }
+    }
+
+    pub fn parse_function(&mut self) -> Result<Stmt, ParseError> {
+        let start = self.span.start;
+        // Expect 'fn'
         self.advance();
+        let name = self.expect_identifier("function name after 'fn'")?;
+        // Parse parameters
+        self.expect(Token::LParen, "'(' after function name")?;
         let mut params = Vec::new();
-        while let Token::Identifier(param) = &self.current {
-            params.push(param.clone());
-            self.advance();
+        // A trailing comma before ')' is allowed; empty slots are not
+        while self.current != Token::RParen && self.current != Token::Eof {
             if self.current == Token::Comma {
-                self.advance();
-            } else {
+                return Err(self.unexpected(Self::list_slot("parameter name", params.is_empty())));
+            }
+            params.push(self.expect_identifier("parameter name")?);
+            if self.current != Token::Comma {
                 break;
             }
+            self.advance();
         }
-        if self.current != Token::RParen {
-            panic!("Expected ')' after parameters");
-        }
-        self.advance();
-        // Parse body
-        if self.current != Token::LBrace {
-            panic!("Expected '{{' to start function body");
+        self.expect(Token::RParen, "')' after parameters")?;
+        self.function_depth += 1;
+        let body = self.parse_body_with("function", Self::parse_stmt);
+        self.function_depth -= 1;
+        let body = body?;
+        // Parsed in full first so that error recovery resumes after the nested body
+        if self.function_depth > 0 {
+            return Err(ParseError::NestedFunction { name, pos: start });
         }
-        self.advance();
+        Ok(Stmt::Func {
+            name,
+            params,
+            body,
+            span: Span::new(start, self.prev.end),
+        })
+    }
+
+    /// Parse a brace-delimited, semicolon-separated list of expressions.
+    /// `what` names the construct owning the body, for error messages.
+    fn parse_body(&mut self, what: &str) -> Result<Vec<Expr>, ParseError> {
+        self.parse_body_with(what, Self::statement)
+    }
+
+    /// `parse_body` with each item parsed by `item`.
+    fn parse_body_with<T>(
+        &mut self,
+        what: &str,
+        item: fn(&mut Self) -> Result<T, ParseError>,
+    ) -> Result<Vec<T>, ParseError> {
+        let open = self.span.start;
+        self.expect(Token::LBrace, &format!("'{{' to start {} body", what))?;
         let mut body = Vec::new();
         while self.current != Token::RBrace && self.current != Token::Eof {
-            body.push(self.expr(0));
+            body.push(item(self)?);
             if self.current == Token::Semicolon {
                 self.advance();
             }
         }
-        if self.current != Token::RBrace {
-            panic!("Expected '}}' to end function body");
-        }
+        self.expect(
+            Token::RBrace,
+            &format!(
+                "'}}' to end {} body opened at {}",
+                what,
+                self.location(open)
+            ),
+        )?;
+        Ok(body)
+    }
+
+    /// Parse `if cond { .. }`, optionally followed by `else { .. }` or `else if ..`.
+    pub fn parse_if(&mut self) -> Result<Expr, ParseError> {
+        let start = self.span.start;
+        // Expect 'if'
         self.advance();
-        Expr::Function { name, params, body }
+        let cond = self.expr(0)?;
+        let then_start = self.span.start;
+        let then_body = self.parse_body("if")?;
+        let then_branch = self.node(then_start, ExprKind::Block(then_body));
+        let else_branch = if self.current == Token::KeywordElse {
+            self.advance();
+            if self.current == Token::KeywordIf {
+                Some(self.parse_if()?)
+            } else {
+                let else_start = self.span.start;
+                let else_body = self.parse_body("else")?;
+                Some(self.node(else_start, ExprKind::Block(else_body)))
+            }
+        } else {
+            None
+        };
+        Ok(self.node(
+            start,
+            ExprKind::If {
+                cond: Box::new(cond),
+                then_branch: Box::new(then_branch),
+                else_branch: else_branch.map(Box::new),
+            },
+        ))
     }
 
-    pub fn parse_call(&mut self, name: String) -> Expr {
-        // Already saw identifier and '('
+    pub fn parse_while(&mut self) -> Result<Expr, ParseError> {
+        let start = self.span.start;
+        // Expect 'while'
         self.advance();
+        let cond = self.expr(0)?;
+        let body = self.parse_body("while")?;
+        Ok(self.node(
+            start,
+            ExprKind::While {
+                cond: Box::new(cond),
+                body,
+            },
+        ))
+    }
+
+    /// Parse the arguments of a call of `callee`; the '(' was just consumed.
+    pub fn parse_call(&mut self, callee: Expr) -> Result<Expr, ParseError> {
+        self.open_parens.push(self.prev.start);
         let mut args = Vec::new();
+        // A trailing comma before ')' is allowed; empty slots are not
         while self.current != Token::RParen && self.current != Token::Eof {
-            args.push(self.expr(0));
             if self.current == Token::Comma {
-                self.advance();
-            } else {
+                return Err(self.unexpected(Self::list_slot("argument", args.is_empty())));
+            }
+            args.push(self.expr(0)?);
+            if self.current != Token::Comma {
                 break;
             }
+            self.advance();
         }
-        if self.current != Token::RParen {
-            panic!("Expected ')' after arguments");
+        self.close_paren("')' after arguments")?;
+        let start = callee.span.start;
+        Ok(self.node(
+            start,
+            ExprKind::Call {
+                callee: Box::new(callee),
+                args,
+            },
+        ))
+    }
+
+    /// Describe what is missing when a ',' appears where a list item should be.
+    fn list_slot(item: &str, first: bool) -> String {
+        if first {
+            format!("{} before ','", item)
+        } else {
+            format!("{} between ',' separators", item)
         }
+    }
+
+    pub fn parse_for(&mut self) -> Result<Expr, ParseError> {
+        let start_pos = self.span.start;
+        // Expect 'for'
         self.advance();
-        Expr::Call { name, args }
+        let var = self.expect_identifier("loop variable after 'for'")?;
+        self.expect(
+            Token::Assign,
+            &format!("'=' after for-loop variable '{}'", var),
+        )?;
+        let start = self.expr(0)?;
+        self.expect(Token::KeywordTo, "'to' after for-loop start")?;
+        let end = self.expr(0)?;
+        let body = self.parse_body("for")?;
+        Ok(self.node(
+            start_pos,
+            ExprKind::For {
+                var,
+                start: Box::new(start),
+                end: Box::new(end),
+                body,
+            },
+        ))
     }
 
-    fn nud(&mut self) -> Expr {
+    fn nud(&mut self) -> Result<Expr, ParseError> {
+        let start = self.span.start;
+        if let Some(rbp) = Self::prefix_bp(&self.current) {
+            let op = self.current.clone();
+            self.advance();
+            let rhs = self.expr(rbp)?;
+            return Ok(self.node(
+                start,
+                ExprKind::UnaryOp {
+                    op,
+                    rhs: Box::new(rhs),
+                },
+            ));
+        }
         match &self.current {
             Token::Number(n) => {
                 let n = *n;
                 self.advance();
-                Expr::Number(n)
+                Ok(self.node(start, ExprKind::Number(n)))
+            }
+            Token::KeywordTrue | Token::KeywordFalse => {
+                let value = self.current == Token::KeywordTrue;
+                self.advance();
+                Ok(self.node(start, ExprKind::Bool(value)))
+            }
+            Token::Str(text) => {
+                let text = text.clone();
+                self.advance();
+                Ok(self.node(start, ExprKind::Str(text)))
             }
             Token::Identifier(name) => {
                 let name = name.clone();
                 self.advance();
-                if self.current == Token::LParen {
-                    self.parse_call(name)
-                } else {
-                    Expr::Ident(name)
-                }
+                Ok(self.node(start, ExprKind::Ident(name)))
             }
-            Token::Minus => {
-                self.advance();
-                Expr::UnaryOp {
-                    op: Token::Minus,
-                    rhs: Box::new(self.expr(100)),
+            Token::LParen => {
+                self.open_paren();
+                let mut expr = self.expr(0)?;
+                self.close_paren("')'")?;
+                if self.preserve_groups {
+                    return Ok(self.node(start, ExprKind::Group(Box::new(expr))));
                 }
+                // The parentheses belong to the inner expression's source range
+                expr.span = Span::new(start, self.prev.end);
+                Ok(expr)
             }
-            Token::LParen => {
+            Token::LBracket => {
                 self.advance();
-                let expr = self.expr(0);
-                if self.current != Token::RParen {
-                    panic!(
-                        "+Expected ')' but found {:?} at position {}",
-                        self.current,
-                        self.scanner.current_position()
-                    );
+                let mut elements = Vec::new();
+                while self.current != Token::RBracket {
+                    elements.push(self.expr(0)?);
+                    if self.current == Token::Comma {
+                        self.advance();
+                    } else {
+                        break;
+                    }
                 }
+                self.expect(Token::RBracket, "']' after array elements")?;
+                Ok(self.node(start, ExprKind::Array(elements)))
+            }
+            Token::LBrace => {
+                let body = self.parse_body("block")?;
+                Ok(self.node(start, ExprKind::Block(body)))
+            }
+            Token::KeywordSpawn => {
                 self.advance();
-                expr
+                let task = self.expr(0)?;
+                Ok(self.node(start, ExprKind::Spawn(Box::new(task))))
             }
-            Token::KeywordFn => self.parse_function(),
-            _ => panic!("Unexpected token in nud: {:?}", self.current),
+            Token::KeywordSync | Token::KeywordBarrier => {
+                Err(self.unexpected("expression ('sync' and 'barrier' are statements, not values)"))
+            }
+            Token::KeywordFn => self.misplaced_function(),
+            Token::KeywordLet | Token::KeywordReturn => Err(self.unexpected(
+                "expression ('let' and 'return' may only appear at the top level or in a function body)",
+            )),
+            Token::KeywordIf => self.parse_if(),
+            Token::KeywordWhile => self.parse_while(),
+            Token::KeywordFor => self.parse_for(),
+            _ => Err(self.unexpected("expression")),
+        }
+    }
+
+    /// Reject a `fn` definition in expression position.
+    fn misplaced_function(&mut self) -> Result<Expr, ParseError> {
+        let start = self.span.start;
+        // Parsed anyway so a nested definition still reports `NestedFunction`
+        // and recovery resumes after its body
+        self.parse_function()?;
+        Err(ParseError::UnexpectedToken {
+            found: Token::KeywordFn,
+            expected: "expression ('fn' definitions are statements, not values)".into(),
+            pos: start,
+        })
+    }
+
+    /// Binding power of prefix operators: their operand extends over every
+    /// infix operator that binds tighter. At 25, `-a * b` is `(-a) * b` while
+    /// `-a ** b` is `-(a ** b)`. Unary `+` is accepted and does nothing.
+    pub(crate) fn prefix_bp(token: &Token) -> Option<u8> {
+        match token {
+            Token::Minus | Token::Bang | Token::Plus => Some(25),
+            _ => None,
         }
     }
 
-    fn lbp(token: &Token) -> u8 {
+    /// Binding power of infix operators; 0 means the token ends an expression.
+    pub(crate) fn lbp(token: &Token) -> u8 {
         match token {
+            Token::Assign
+            | Token::PlusAssign
+            | Token::MinusAssign
+            | Token::StarAssign
+            | Token::SlashAssign => 1,
+            Token::Question => 2,
+            Token::OrOr => 3,
+            Token::AndAnd => 4,
+            Token::EqEq | Token::NotEq => 5,
+            Token::Lt | Token::Le | Token::Gt | Token::Ge => 7,
             Token::Plus | Token::Minus => 10,
-            Token::Star | Token::Slash => 20,
+            Token::Star | Token::Slash | Token::Percent => 20,
+            Token::StarStar => 30,
+            // Postfix indexing and calls bind tighter than any prefix or infix operator
+            Token::LBracket | Token::LParen => 40,
             _ => 0,
         }
     }
 
-    fn led(&mut self, lhs: Expr, token: Token) -> Expr {
+    /// `lbp`, extended with the operators registered through `with_infix`.
+    fn infix_bp(&self, token: &Token) -> u8 {
+        match token {
+            Token::Custom(text) => self.custom_infix.get(text).map_or(0, |&(bp, _)| bp),
+            _ => Self::lbp(token),
+        }
+    }
+
+    fn led(&mut self, lhs: Expr, token: Token) -> Result<Expr, ParseError> {
         match token {
-            Token::Plus | Token::Minus | Token::Star | Token::Slash => {
+            Token::Plus
+            | Token::Minus
+            | Token::Star
+            | Token::Slash
+            | Token::Percent
+            | Token::EqEq
+            | Token::NotEq
+            | Token::Lt
+            | Token::Le
+            | Token::Gt
+            | Token::Ge
+            | Token::AndAnd
+            | Token::OrOr
+            | Token::StarStar
+            | Token::Custom(_) => {
                 let op = token;
-                let rbp = Self::lbp(&op);
-                let rhs = self.expr(rbp);
-                Expr::BinaryOp {
-                    lhs: Box::new(lhs),
-                    op,
-                    rhs: Box::new(rhs),
+                // Right-associative operators let an equal binding power continue the rhs
+                let rbp = match &op {
+                    Token::StarStar => Self::lbp(&op) - 1,
+                    Token::Custom(text) => match self.custom_infix[text] {
+                        (bp, Assoc::Left) => bp,
+                        (bp, Assoc::Right) => bp - 1,
+                    },
+                    _ => Self::lbp(&op),
+                };
+                let rhs = self.expr(rbp)?;
+                let span = lhs.span.to(rhs.span);
+                Ok(Expr::new(
+                    ExprKind::BinaryOp {
+                        lhs: Box::new(lhs),
+                        op,
+                        rhs: Box::new(rhs),
+                    },
+                    span,
+                ))
+            }
+            Token::LParen => self.parse_call(lhs),
+            Token::Assign
+            | Token::PlusAssign
+            | Token::MinusAssign
+            | Token::StarAssign
+            | Token::SlashAssign => {
+                if !matches!(lhs.kind, ExprKind::Ident(_) | ExprKind::Index { .. }) {
+                    return Err(ParseError::UnexpectedToken {
+                        expected: format!("variable name to the left of '{}'", token),
+                        found: token,
+                        pos: self.prev.start,
+                    });
+                }
+                // Right-associative, so `a = b = c` assigns `c` to both
+                let mut value = self.expr(Self::lbp(&token) - 1)?;
+                let span = lhs.span.to(value.span);
+                // `x += v` is `x = x + v`
+                let compound = match token {
+                    Token::PlusAssign => Some(Token::Plus),
+                    Token::MinusAssign => Some(Token::Minus),
+                    Token::StarAssign => Some(Token::Star),
+                    Token::SlashAssign => Some(Token::Slash),
+                    _ => None,
+                };
+                if let Some(op) = compound {
+                    value = Expr::new(
+                        ExprKind::BinaryOp {
+                            lhs: Box::new(lhs.clone()),
+                            op,
+                            rhs: Box::new(value),
+                        },
+                        span,
+                    );
                 }
+                let kind = match lhs.kind {
+                    ExprKind::Index { target, index } => ExprKind::IndexAssign {
+                        target,
+                        index,
+                        value: Box::new(value),
+                    },
+                    ExprKind::Ident(name) => ExprKind::Assign {
+                        name,
+                        value: Box::new(value),
+                    },
+                    _ => unreachable!("checked above"),
+                };
+                Ok(Expr::new(kind, span))
+            }
+            Token::LBracket => {
+                let index = self.expr(0)?;
+                self.expect(Token::RBracket, "']' after index")?;
+                let span = Span::new(lhs.span.start, self.prev.end);
+                Ok(Expr::new(
+                    ExprKind::Index {
+                        target: Box::new(lhs),
+                        index: Box::new(index),
+                    },
+                    span,
+                ))
             }
-            Token::RParen | Token::Eof => lhs,
-            _ => panic!("Unexpected token in led: {:?}", token),
+            Token::Question => {
+                let question = self.prev.start;
+                let then_branch = self.expr(0)?;
+                self.expect(
+                    Token::Colon,
+                    &format!("':' to complete '?' at position {}", question),
+                )?;
+                // Right-associative, so `a ? b : c ? d : e` nests in the else branch
+                let else_branch = self.expr(Self::lbp(&Token::Question) - 1)?;
+                let span = lhs.span.to(else_branch.span);
+                Ok(Expr::new(
+                    ExprKind::If {
+                        cond: Box::new(lhs),
+                        then_branch: Box::new(then_branch),
+                        else_branch: Some(Box::new(else_branch)),
+                    },
+                    span,
+                ))
+            }
+            _ => Err(ParseError::UnexpectedToken {
+                found: token,
+                expected: "infix operator".into(),
+                pos: self.prev.start,
+            }),
+        }
+    }
+
+    pub fn expr(&mut self, min_bp: u8) -> Result<Expr, ParseError> {
+        let depth = self.depth;
+        let result = self.expr_bp(min_bp);
+        self.depth = depth;
+        result
+    }
+
+    /// Count one more level of nesting, failing once the limit is reached.
+    fn descend(&mut self) -> Result<(), ParseError> {
+        if self.depth >= self.max_depth {
+            return Err(ParseError::TooDeep {
+                limit: self.max_depth,
+                pos: self.span.start,
+            });
         }
+        self.depth += 1;
+        Ok(())
     }
 
-    pub fn expr(&mut self, min_bp: u8) -> Expr {
-        let mut lhs = self.nud();
+    fn expr_bp(&mut self, min_bp: u8) -> Result<Expr, ParseError> {
+        self.descend()?;
+        // `while c { .. } (x)` is a loop followed by a new statement, not a call
+        let mut ends_in_brace = matches!(
+            self.current,
+            Token::KeywordIf | Token::KeywordWhile | Token::KeywordFor | Token::LBrace
+        );
+        let mut lhs = self.nud()?;
         loop {
             if self.current == Token::Eof || self.current == Token::RParen {
                 break;
             }
-            let lbp = Self::lbp(&self.current);
-            if lbp < min_bp {
+            if self.current == Token::LParen && ends_in_brace {
+                break;
+            }
+            let lbp = self.infix_bp(&self.current);
+            // Operators of equal binding power stop here so they associate to the left.
+            // Tokens without a binding power (`;`, `{`, `}`, ...) end the expression.
+            if lbp <= min_bp {
                 break;
             }
+            // Each operator folded into `lhs` nests the tree one level deeper
+            self.descend()?;
             let op = self.current.clone();
             self.advance();
-            lhs = self.led(lhs, op);
+            lhs = self.led(lhs, op)?;
+            ends_in_brace = false;
+        }
+        Ok(lhs)
+    }
+}
+
+/// Evaluate an expression built only from number literals and arithmetic
+/// operators (`+ - * / % **` and unary `-`).
+///
+/// Returns `None` for anything else, including identifiers and calls, and for
+/// division or remainder by zero, which would otherwise silently yield an infinity or NaN.
+pub fn const_eval(expr: &Expr) -> Option<f64> {
+    match &expr.kind {
+        ExprKind::Number(value) => Some(*value),
+        ExprKind::Group(inner) => const_eval(inner),
+        ExprKind::UnaryOp {
+            op: Token::Minus,
+            rhs,
+        } => const_eval(rhs).map(|value| -value),
+        ExprKind::UnaryOp {
+            op: Token::Plus,
+            rhs,
+        } => const_eval(rhs),
+        ExprKind::BinaryOp { lhs, op, rhs } => {
+            let (lhs, rhs) = (const_eval(lhs)?, const_eval(rhs)?);
+            match op {
+                Token::Plus => Some(lhs + rhs),
+                Token::Minus => Some(lhs - rhs),
+                Token::Star => Some(lhs * rhs),
+                Token::Slash | Token::Percent if rhs == 0.0 => None,
+                Token::Slash => Some(lhs / rhs),
+                Token::Percent => Some(lhs % rhs),
+                Token::StarStar if lhs == 0.0 && rhs < 0.0 => None,
+                Token::StarStar => Some(lhs.powf(rhs)),
+                _ => None,
+            }
         }
-        lhs
+        _ => None,
     }
 }
 
@@ -200,19 +1085,23 @@ mod tests {
     use crate::scanner::Scanner;
     use crate::scanner::Token;
 
-    fn parse(code: &str) -> Expr {
+    fn try_parse(code: &str) -> Result<Expr, ParseError> {
         let mut parser = PrattParser::new(Scanner::new(code));
         parser.expr(0)
     }
 
+    fn parse(code: &str) -> Expr {
+        try_parse(code).unwrap()
+    }
+
     #[test]
     fn test_parse_number() {
-        assert_eq!(parse("42"), Expr::Number(42.));
+        assert_eq!(parse("42"), ExprKind::Number(42.).into());
     }
 
     #[test]
     fn test_parse_identifier() {
-        assert_eq!(parse("foo"), Expr::Ident("foo".into()));
+        assert_eq!(parse("foo"), ExprKind::Ident("foo".into()).into());
     }
 
     #[test]
@@ -220,11 +1109,12 @@ mod tests {
         let expr = parse("1+2");
         assert_eq!(
             expr,
-            Expr::BinaryOp {
-                lhs: Box::new(Expr::Number(1.)),
+            ExprKind::BinaryOp {
+                lhs: Box::new(ExprKind::Number(1.).into()),
                 op: Token::Plus,
-                rhs: Box::new(Expr::Number(2.)),
+                rhs: Box::new(ExprKind::Number(2.).into()),
             }
+            .into()
         );
     }
 
@@ -233,15 +1123,19 @@ mod tests {
         let expr = parse("1+2*3");
         assert_eq!(
             expr,
-            Expr::BinaryOp {
-                lhs: Box::new(Expr::Number(1.)),
+            ExprKind::BinaryOp {
+                lhs: Box::new(ExprKind::Number(1.).into()),
                 op: Token::Plus,
-                rhs: Box::new(Expr::BinaryOp {
-                    lhs: Box::new(Expr::Number(2.)),
-                    op: Token::Star,
-                    rhs: Box::new(Expr::Number(3.)),
-                }),
+                rhs: Box::new(
+                    ExprKind::BinaryOp {
+                        lhs: Box::new(ExprKind::Number(2.).into()),
+                        op: Token::Star,
+                        rhs: Box::new(ExprKind::Number(3.).into()),
+                    }
+                    .into()
+                ),
             }
+            .into()
         );
     }
     #[test]
@@ -250,15 +1144,19 @@ mod tests {
         let expr = parse(input);
         assert_eq!(
             expr,
-            Expr::BinaryOp {
-                lhs: Box::new(Expr::BinaryOp {
-                    lhs: Box::new(Expr::Number(1.)),
-                    op: Token::Plus,
-                    rhs: Box::new(Expr::Number(2.)),
-                }),
+            ExprKind::BinaryOp {
+                lhs: Box::new(
+                    ExprKind::BinaryOp {
+                        lhs: Box::new(ExprKind::Number(1.).into()),
+                        op: Token::Plus,
+                        rhs: Box::new(ExprKind::Number(2.).into()),
+                    }
+                    .into()
+                ),
                 op: Token::Star,
-                rhs: Box::new(Expr::Number(3.)),
+                rhs: Box::new(ExprKind::Number(3.).into()),
             }
+            .into()
         );
     }
     #[test]
@@ -266,14 +1164,1673 @@ mod tests {
         let expr = parse("-5+2");
         assert_eq!(
             expr,
-            Expr::BinaryOp {
-                lhs: Box::new(Expr::UnaryOp {
-                    op: Token::Minus,
-                    rhs: Box::new(Expr::Number(5.)),
-                }),
+            ExprKind::BinaryOp {
+                lhs: Box::new(
+                    ExprKind::UnaryOp {
+                        op: Token::Minus,
+                        rhs: Box::new(ExprKind::Number(5.).into()),
+                    }
+                    .into()
+                ),
                 op: Token::Plus,
-                rhs: Box::new(Expr::Number(2.)),
+                rhs: Box::new(ExprKind::Number(2.).into()),
+            }
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_parse_while_empty_body() {
+        assert_eq!(
+            parse("while x {}"),
+            ExprKind::While {
+                cond: Box::new(ExprKind::Ident("x".into()).into()),
+                body: vec![],
+            }
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_parse_while_multiple_statements() {
+        let expr = parse("while n - 1 { a; b + 1; c }");
+        assert_eq!(
+            expr,
+            ExprKind::While {
+                cond: Box::new(
+                    ExprKind::BinaryOp {
+                        lhs: Box::new(ExprKind::Ident("n".into()).into()),
+                        op: Token::Minus,
+                        rhs: Box::new(ExprKind::Number(1.).into()),
+                    }
+                    .into()
+                ),
+                body: vec![
+                    ExprKind::Ident("a".into()).into(),
+                    ExprKind::BinaryOp {
+                        lhs: Box::new(ExprKind::Ident("b".into()).into()),
+                        op: Token::Plus,
+                        rhs: Box::new(ExprKind::Number(1.).into()),
+                    }
+                    .into(),
+                    ExprKind::Ident("c".into()).into(),
+                ],
+            }
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_parse_while_nested_in_function() {
+        assert_eq!(
+            program("fn f(x) { while x { x } }"),
+            Ok(vec![func(
+                "f",
+                &["x"],
+                vec![ExprKind::While {
+                    cond: Box::new(ExprKind::Ident("x".into()).into()),
+                    body: vec![ExprKind::Ident("x".into()).into()],
+                }
+                .into()],
+            )])
+        );
+    }
+
+    #[test]
+    fn test_parse_for_range() {
+        let expr = parse("for i = 0 to n - 1 { s; i }");
+        assert_eq!(
+            expr,
+            ExprKind::For {
+                var: "i".into(),
+                start: Box::new(ExprKind::Number(0.).into()),
+                end: Box::new(
+                    ExprKind::BinaryOp {
+                        lhs: Box::new(ExprKind::Ident("n".into()).into()),
+                        op: Token::Minus,
+                        rhs: Box::new(ExprKind::Number(1.).into()),
+                    }
+                    .into()
+                ),
+                body: vec![
+                    ExprKind::Ident("s".into()).into(),
+                    ExprKind::Ident("i".into()).into()
+                ],
             }
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_parse_for_empty_body() {
+        let expr = parse("for k = 1 to 10 {}");
+        assert_eq!(
+            expr,
+            ExprKind::For {
+                var: "k".into(),
+                start: Box::new(ExprKind::Number(1.).into()),
+                end: Box::new(ExprKind::Number(10.).into()),
+                body: vec![],
+            }
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_parse_for_missing_to() {
+        assert_eq!(
+            try_parse("for i = 0 10 { i }"),
+            Err(ParseError::UnexpectedToken {
+                found: Token::Number(10.),
+                expected: "'to' after for-loop start".into(),
+                pos: 10,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_for_missing_assign() {
+        let err = try_parse("for i 0 to 10 { i }").unwrap_err();
+        assert!(matches!(
+            err,
+            ParseError::UnexpectedToken { ref expected, .. }
+                if expected == "'=' after for-loop variable 'i'"
+        ));
+    }
+
+    #[test]
+    fn test_parse_empty_block() {
+        assert_eq!(parse("{}"), ExprKind::Block(vec![]).into());
+    }
+
+    #[test]
+    fn test_parse_nested_block() {
+        assert_eq!(
+            parse("{ a; { b; c } }"),
+            ExprKind::Block(vec![
+                ExprKind::Ident("a".into()).into(),
+                ExprKind::Block(vec![
+                    ExprKind::Ident("b".into()).into(),
+                    ExprKind::Ident("c".into()).into()
+                ])
+                .into(),
+            ])
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_parse_block_as_call_argument() {
+        assert_eq!(
+            parse("f({1;2})"),
+            ExprKind::call(
+                "f",
+                vec![ExprKind::Block(vec![
+                    ExprKind::Number(1.).into(),
+                    ExprKind::Number(2.).into()
+                ])
+                .into()],
+            )
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_parse_unclosed_block_reports_start() {
+        assert_eq!(
+            try_parse("1 + { 2; { 3 }"),
+            Err(ParseError::UnexpectedEof {
+                expected: "'}' to end block body opened at line 1, column 5".into(),
+                pos: 14,
+            })
+        );
+    }
+
+    #[test]
+    fn test_error_missing_operand() {
+        assert_eq!(
+            try_parse("1 + )"),
+            Err(ParseError::UnexpectedToken {
+                found: Token::RParen,
+                expected: "expression".into(),
+                pos: 4,
+            })
+        );
+    }
+
+    #[test]
+    fn test_error_unclosed_paren() {
+        assert_eq!(
+            try_parse("(1 + 2"),
+            Err(ParseError::UnexpectedEof {
+                expected: "')' to match '(' opened at line 1, column 1".into(),
+                pos: 6,
+            })
+        );
+    }
+
+    #[test]
+    fn test_error_points_at_unmatched_paren() {
+        // The inner pair is balanced, so the outer '(' is the one left open
+        assert_eq!(
+            try_parse("(1 + (2 * 3)"),
+            Err(ParseError::UnexpectedEof {
+                expected: "')' to match '(' opened at line 1, column 1".into(),
+                pos: 12,
+            })
+        );
+        assert_eq!(
+            try_parse("1 +\n  (2 * (3 - 4)\n  ;"),
+            Err(ParseError::UnexpectedToken {
+                found: Token::Semicolon,
+                expected: "')' to match '(' opened at line 2, column 3".into(),
+                pos: 21,
+            })
+        );
+        assert_eq!(
+            try_parse("f(1, g(2)"),
+            Err(ParseError::UnexpectedEof {
+                expected: "')' after arguments to match '(' opened at line 1, column 2".into(),
+                pos: 9,
+            })
+        );
+    }
+
+    #[test]
+    fn test_error_points_at_unmatched_brace() {
+        assert_eq!(
+            try_parse("fn f() { 1 + 2"),
+            Err(ParseError::UnexpectedEof {
+                expected: "'}' to end function body opened at line 1, column 8".into(),
+                pos: 14,
+            })
+        );
+        assert_eq!(
+            program("fn f(x) {\n  (x + 1)\n"),
+            Err(ParseError::UnexpectedEof {
+                expected: "'}' to end function body opened at line 1, column 9".into(),
+                pos: 20,
+            })
+        );
+    }
+
+    #[test]
+    fn test_recovery_forgets_abandoned_parens() {
+        let (_, errors) = parse_recovering("(1 + ; f(2");
+        assert_eq!(errors.len(), 2);
+        assert_eq!(
+            errors[1],
+            ParseError::UnexpectedEof {
+                expected: "')' after arguments to match '(' opened at line 1, column 9".into(),
+                pos: 10,
+            }
+        );
+    }
+
+    #[test]
+    fn test_error_function_without_name() {
+        assert_eq!(
+            try_parse("fn (x) {}"),
+            Err(ParseError::UnexpectedToken {
+                found: Token::LParen,
+                expected: "function name after 'fn'".into(),
+                pos: 3,
+            })
+        );
+    }
+
+    #[test]
+    fn test_error_display() {
+        let err = try_parse("1 + )").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Expected expression but found RParen at position 4"
+        );
+    }
+
+    fn parse_recovering(code: &str) -> (Vec<Stmt>, Vec<ParseError>) {
+        PrattParser::new(Scanner::new(code)).parse_program_recovering()
+    }
+
+    #[test]
+    fn test_parse_program() {
+        let mut parser = PrattParser::new(Scanner::new("1; f(2); {3}"));
+        assert_eq!(
+            parser.parse_program(),
+            Ok(vec![
+                ExprKind::Number(1.).into(),
+                ExprKind::call("f", vec![ExprKind::Number(2.).into()]).into(),
+                ExprKind::Block(vec![ExprKind::Number(3.).into()]).into(),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_recovering_reports_every_error() {
+        let (program, errors) = parse_recovering("1 + ; 2; 3 * ; 4");
+        assert_eq!(
+            errors,
+            vec![
+                ParseError::UnexpectedToken {
+                    found: Token::Semicolon,
+                    expected: "expression".into(),
+                    pos: 4,
+                },
+                ParseError::UnexpectedToken {
+                    found: Token::Semicolon,
+                    expected: "expression".into(),
+                    pos: 13,
+                },
+            ]
+        );
+        assert_eq!(
+            program,
+            vec![
+                ExprKind::Error.into(),
+                ExprKind::Number(2.).into(),
+                ExprKind::Error.into(),
+                ExprKind::Number(4.).into()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_recovering_inside_body_keeps_structure() {
+        let (program, errors) = parse_recovering("while x { 1 + ; 2 }; fn f() { ) }; 3");
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(
+            errors[0],
+            ParseError::UnexpectedToken { pos: 14, .. }
+        ));
+        assert!(matches!(
+            errors[1],
+            ParseError::UnexpectedToken {
+                found: Token::RParen,
+                pos: 30,
+                ..
+            }
+        ));
+        assert_eq!(
+            program,
+            vec![
+                ExprKind::While {
+                    cond: Box::new(ExprKind::Ident("x".into()).into()),
+                    body: vec![ExprKind::Error.into(), ExprKind::Number(2.).into()],
+                }
+                .into(),
+                func("f", &[], vec![ExprKind::Error.into()]),
+                ExprKind::Number(3.).into(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_recovering_stray_closing_brace() {
+        let (program, errors) = parse_recovering("1; }; 2");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            program,
+            vec![
+                ExprKind::Number(1.).into(),
+                ExprKind::Error.into(),
+                ExprKind::Number(2.).into()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_recovering_unclosed_body_at_eof() {
+        let (program, errors) = parse_recovering("1; { 2 + ");
+        assert_eq!(
+            program,
+            vec![ExprKind::Number(1.).into(), ExprKind::Error.into()]
+        );
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(errors[0], ParseError::UnexpectedEof { .. }));
+        assert!(matches!(errors[1], ParseError::UnexpectedEof { .. }));
+    }
+
+    #[test]
+    fn test_spans_nested_expression() {
+        let expr = parse("1 + (2 * 3)");
+        assert_eq!(expr.span, Span::new(0, 11));
+        let ExprKind::BinaryOp { lhs, rhs, .. } = &expr.kind else {
+            panic!("expected a binary op, got {:?}", expr);
+        };
+        assert_eq!(lhs.span, Span::new(0, 1));
+        // The parenthesised operand covers its parentheses
+        assert_eq!(rhs.span, Span::new(4, 11));
+        let ExprKind::BinaryOp { lhs, rhs, .. } = &rhs.kind else {
+            panic!("expected a binary op, got {:?}", rhs);
+        };
+        assert_eq!(lhs.span, Span::new(5, 6));
+        assert_eq!(rhs.span, Span::new(9, 10));
+    }
+
+    #[test]
+    fn test_spans_compound_nodes() {
+        let mut parser = PrattParser::new(Scanner::new("-x; f(1, y); while a { b }; {}"));
+        let program = parser.parse_program().unwrap();
+        let spans: Vec<Span> = program.iter().map(Stmt::span).collect();
+        assert_eq!(
+            spans,
+            vec![
+                Span::new(0, 2),
+                Span::new(4, 11),
+                Span::new(13, 26),
+                Span::new(28, 30),
+            ]
+        );
+        let Stmt::Expr(Expr {
+            kind: ExprKind::Call { args, .. },
+            ..
+        }) = &program[1]
+        else {
+            panic!("expected a call");
+        };
+        assert_eq!(args[1].span, Span::new(9, 10));
+    }
+
+    #[test]
+    fn test_spans_error_node() {
+        let (program, _) = parse_recovering("1; 2 + ; 3");
+        assert_eq!(program[1].span(), Span::new(3, 8));
+    }
+
+    fn bin(lhs: Expr, op: Token, rhs: Expr) -> Expr {
+        ExprKind::BinaryOp {
+            lhs: Box::new(lhs),
+            op,
+            rhs: Box::new(rhs),
+        }
+        .into()
+    }
+
+    fn num(n: f64) -> Expr {
+        ExprKind::Number(n).into()
+    }
+
+    fn ident(name: &str) -> Expr {
+        ExprKind::Ident(name.into()).into()
+    }
+
+    #[test]
+    fn test_parse_left_associative_subtraction() {
+        assert_eq!(
+            parse("1 - 2 - 3"),
+            bin(bin(num(1.), Token::Minus, num(2.)), Token::Minus, num(3.))
+        );
+    }
+
+    #[test]
+    fn test_parse_comparison_precedence() {
+        // ((a+1) < (b*2)) == c
+        assert_eq!(
+            parse("a + 1 < b * 2 == c"),
+            bin(
+                bin(
+                    bin(ident("a"), Token::Plus, num(1.)),
+                    Token::Lt,
+                    bin(ident("b"), Token::Star, num(2.)),
+                ),
+                Token::EqEq,
+                ident("c"),
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_equality_binds_looser_than_ordering() {
+        assert_eq!(
+            parse("a != b >= c"),
+            bin(
+                ident("a"),
+                Token::NotEq,
+                bin(ident("b"), Token::Ge, ident("c"))
+            )
+        );
+        assert_eq!(
+            parse("a <= b > c"),
+            bin(
+                bin(ident("a"), Token::Le, ident("b")),
+                Token::Gt,
+                ident("c")
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_chained_comparison_is_left_associative() {
+        assert_eq!(
+            parse("1 < 2 < 3"),
+            bin(bin(num(1.), Token::Lt, num(2.)), Token::Lt, num(3.))
+        );
+        assert_eq!(
+            parse("1 == 2 == 3"),
+            bin(bin(num(1.), Token::EqEq, num(2.)), Token::EqEq, num(3.))
+        );
+    }
+
+    #[test]
+    fn test_parse_comparison_missing_operand() {
+        assert!(matches!(
+            try_parse("1 < )"),
+            Err(ParseError::UnexpectedToken {
+                found: Token::RParen,
+                ..
+            })
+        ));
+        assert!(matches!(
+            try_parse("1 >="),
+            Err(ParseError::UnexpectedEof { .. })
+        ));
+    }
+
+    fn unary(op: Token, rhs: Expr) -> Expr {
+        ExprKind::UnaryOp {
+            op,
+            rhs: Box::new(rhs),
+        }
+        .into()
+    }
+
+    #[test]
+    fn test_parse_logical_precedence() {
+        // ((a==1)&&(b==2))||c
+        assert_eq!(
+            parse("a == 1 && b == 2 || c"),
+            bin(
+                bin(
+                    bin(ident("a"), Token::EqEq, num(1.)),
+                    Token::AndAnd,
+                    bin(ident("b"), Token::EqEq, num(2.)),
+                ),
+                Token::OrOr,
+                ident("c"),
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_and_binds_tighter_than_or() {
+        assert_eq!(
+            parse("a || b && c"),
+            bin(
+                ident("a"),
+                Token::OrOr,
+                bin(ident("b"), Token::AndAnd, ident("c"))
+            )
+        );
+        assert_eq!(
+            parse("a && b && c"),
+            bin(
+                bin(ident("a"), Token::AndAnd, ident("b")),
+                Token::AndAnd,
+                ident("c")
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_logical_vs_arithmetic() {
+        assert_eq!(
+            parse("a + 1 && b < 2"),
+            bin(
+                bin(ident("a"), Token::Plus, num(1.)),
+                Token::AndAnd,
+                bin(ident("b"), Token::Lt, num(2.)),
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_logical_not() {
+        assert_eq!(
+            parse("!a && b"),
+            bin(unary(Token::Bang, ident("a")), Token::AndAnd, ident("b"))
+        );
+        assert_eq!(
+            parse("!a == b"),
+            bin(unary(Token::Bang, ident("a")), Token::EqEq, ident("b"))
+        );
+        assert_eq!(
+            parse("!!a"),
+            unary(Token::Bang, unary(Token::Bang, ident("a")))
+        );
+    }
+
+    #[test]
+    fn test_parse_power_is_right_associative() {
+        assert_eq!(
+            parse("2 ** 3 ** 2"),
+            bin(
+                num(2.),
+                Token::StarStar,
+                bin(num(3.), Token::StarStar, num(2.))
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_power_binds_tighter_than_multiplication() {
+        assert_eq!(
+            parse("a * b ** c"),
+            bin(
+                ident("a"),
+                Token::Star,
+                bin(ident("b"), Token::StarStar, ident("c"))
+            )
+        );
+        assert_eq!(
+            parse("a ** b * c"),
+            bin(
+                bin(ident("a"), Token::StarStar, ident("b")),
+                Token::Star,
+                ident("c")
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_unary_plus_and_repeated_negation() {
+        assert_eq!(parse("+x"), unary(Token::Plus, ident("x")));
+        assert_eq!(
+            parse("+-x"),
+            unary(Token::Plus, unary(Token::Minus, ident("x")))
+        );
+        assert_eq!(
+            parse("-+x"),
+            unary(Token::Minus, unary(Token::Plus, ident("x")))
+        );
+        let double = unary(Token::Minus, unary(Token::Minus, num(5.)));
+        assert_eq!(parse("- - 5"), double);
+        assert_eq!(parse("--5"), double);
+        assert_eq!(
+            parse("1 - -5"),
+            bin(num(1.), Token::Minus, unary(Token::Minus, num(5.)))
+        );
+        assert_eq!(const_eval(&double), Some(5.));
+        assert_eq!(const_eval(&parse("-+-+3")), Some(3.));
+    }
+
+    #[test]
+    fn test_parse_unary_minus_with_power() {
+        // Like most languages: `-2 ** 2` is `-(2 ** 2)`
+        assert_eq!(
+            parse("-2 ** 2"),
+            unary(Token::Minus, bin(num(2.), Token::StarStar, num(2.)))
+        );
+        // A unary minus in the exponent is still allowed
+        assert_eq!(
+            parse("2 ** -1"),
+            bin(num(2.), Token::StarStar, unary(Token::Minus, num(1.)))
+        );
+    }
+
+    fn ternary(cond: Expr, then_branch: Expr, else_branch: Expr) -> Expr {
+        ExprKind::If {
+            cond: Box::new(cond),
+            then_branch: Box::new(then_branch),
+            else_branch: Some(Box::new(else_branch)),
+        }
+        .into()
+    }
+
+    #[test]
+    fn test_parse_ternary() {
+        assert_eq!(
+            parse("a < b ? a + 1 : b"),
+            ternary(
+                bin(ident("a"), Token::Lt, ident("b")),
+                bin(ident("a"), Token::Plus, num(1.)),
+                ident("b"),
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_nested_ternary_groups_right() {
+        assert_eq!(
+            parse("a ? b : c ? d : e"),
+            ternary(
+                ident("a"),
+                ident("b"),
+                ternary(ident("c"), ident("d"), ident("e"))
+            )
+        );
+        assert_eq!(
+            parse("a ? b ? c : d : e"),
+            ternary(
+                ident("a"),
+                ternary(ident("b"), ident("c"), ident("d")),
+                ident("e")
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_ternary_binds_looser_than_or() {
+        assert_eq!(
+            parse("a || b ? 1 : 2"),
+            ternary(bin(ident("a"), Token::OrOr, ident("b")), num(1.), num(2.))
+        );
+    }
+
+    #[test]
+    fn test_parse_ternary_missing_colon() {
+        assert_eq!(
+            try_parse("x ? 1 2"),
+            Err(ParseError::UnexpectedToken {
+                found: Token::Number(2.),
+                expected: "':' to complete '?' at position 2".into(),
+                pos: 6,
+            })
+        );
+        assert!(matches!(
+            try_parse("x ? 1"),
+            Err(ParseError::UnexpectedEof { ref expected, .. }) if expected.contains("position 2")
+        ));
+    }
+
+    fn call(name: &str, args: Vec<Expr>) -> Expr {
+        ExprKind::call(name, args).into()
+    }
+
+    #[test]
+    fn test_prefix_minus_precedence() {
+        // Looser than `**`, tighter than every other binary operator
+        assert_eq!(
+            parse("-a + b"),
+            bin(unary(Token::Minus, ident("a")), Token::Plus, ident("b"))
+        );
+        assert_eq!(
+            parse("-a * b"),
+            bin(unary(Token::Minus, ident("a")), Token::Star, ident("b"))
+        );
+        assert_eq!(
+            parse("-a ** b"),
+            unary(Token::Minus, bin(ident("a"), Token::StarStar, ident("b")))
+        );
+        assert_eq!(
+            parse("-a < b"),
+            bin(unary(Token::Minus, ident("a")), Token::Lt, ident("b"))
+        );
+    }
+
+    #[test]
+    fn test_prefix_minus_nests() {
+        assert_eq!(
+            parse("- -a"),
+            unary(Token::Minus, unary(Token::Minus, ident("a")))
+        );
+        assert_eq!(
+            parse("-!a"),
+            unary(Token::Minus, unary(Token::Bang, ident("a")))
+        );
+    }
+
+    #[test]
+    fn test_prefix_minus_on_call() {
+        assert_eq!(
+            parse("-f(x)"),
+            unary(Token::Minus, call("f", vec![ident("x")]))
+        );
+        assert_eq!(
+            parse("-f(x) * 2"),
+            bin(
+                unary(Token::Minus, call("f", vec![ident("x")])),
+                Token::Star,
+                num(2.)
+            )
+        );
+    }
+
+    #[test]
+    fn test_prefix_not_shares_minus_precedence() {
+        assert_eq!(
+            parse("!a * b"),
+            bin(unary(Token::Bang, ident("a")), Token::Star, ident("b"))
+        );
+        assert_eq!(
+            parse("!a ** b"),
+            unary(Token::Bang, bin(ident("a"), Token::StarStar, ident("b")))
+        );
+    }
+
+    fn array(elements: Vec<Expr>) -> Expr {
+        ExprKind::Array(elements).into()
+    }
+
+    fn index(target: Expr, index: Expr) -> Expr {
+        ExprKind::Index {
+            target: Box::new(target),
+            index: Box::new(index),
+        }
+        .into()
+    }
+
+    #[test]
+    fn test_parse_array_literals() {
+        assert_eq!(parse("[]"), array(vec![]));
+        assert_eq!(
+            parse("[1, 2 + 3, x]"),
+            array(vec![
+                num(1.),
+                bin(num(2.), Token::Plus, num(3.)),
+                ident("x")
+            ])
+        );
+        assert_eq!(parse("[1, 2,]"), array(vec![num(1.), num(2.)]));
+        assert_eq!(
+            parse("[[1],[2]]"),
+            array(vec![array(vec![num(1.)]), array(vec![num(2.)])])
+        );
+    }
+
+    #[test]
+    fn test_parse_index_binds_tightest() {
+        assert_eq!(
+            parse("a[i] + 1"),
+            bin(index(ident("a"), ident("i")), Token::Plus, num(1.))
+        );
+        assert_eq!(
+            parse("-a[i]"),
+            unary(Token::Minus, index(ident("a"), ident("i")))
+        );
+        assert_eq!(
+            parse("a[i] ** 2"),
+            bin(index(ident("a"), ident("i")), Token::StarStar, num(2.))
+        );
+    }
+
+    #[test]
+    fn test_parse_chained_index() {
+        assert_eq!(
+            parse("m[i][j + 1]"),
+            index(
+                index(ident("m"), ident("i")),
+                bin(ident("j"), Token::Plus, num(1.))
+            )
+        );
+        assert_eq!(
+            parse("[1, 2][0]"),
+            index(array(vec![num(1.), num(2.)]), num(0.))
+        );
+    }
+
+    #[test]
+    fn test_parse_index_assignment() {
+        let store = |target, i, value| -> Expr {
+            ExprKind::IndexAssign {
+                target: Box::new(target),
+                index: Box::new(i),
+                value: Box::new(value),
+            }
+            .into()
+        };
+        assert_eq!(parse("a[i] = 1"), store(ident("a"), ident("i"), num(1.)));
+        assert_eq!(
+            parse("m[0][1] += 2"),
+            store(
+                index(ident("m"), num(0.)),
+                num(1.),
+                bin(
+                    index(index(ident("m"), num(0.)), num(1.)),
+                    Token::Plus,
+                    num(2.)
+                )
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_array_errors() {
+        assert!(matches!(
+            try_parse("[1, 2"),
+            Err(ParseError::UnexpectedEof { ref expected, .. }) if expected == "']' after array elements"
+        ));
+        assert!(matches!(
+            try_parse("[,]"),
+            Err(ParseError::UnexpectedToken {
+                found: Token::Comma,
+                ..
+            })
+        ));
+        assert!(matches!(
+            try_parse("a[1"),
+            Err(ParseError::UnexpectedEof { ref expected, .. }) if expected == "']' after index"
+        ));
+    }
+
+    #[test]
+    fn test_spans_index() {
+        let expr = parse("xs[1 + 2]");
+        assert_eq!(expr.span, Span::new(0, 9));
+    }
+
+    fn spawn(task: Expr) -> Expr {
+        ExprKind::Spawn(Box::new(task)).into()
+    }
+
+    #[test]
+    fn test_parse_spawn() {
+        assert_eq!(
+            parse("spawn (2 + 3)"),
+            spawn(bin(num(2.), Token::Plus, num(3.)))
+        );
+        // The spawned operand extends over the whole expression
+        assert_eq!(
+            parse("spawn 2 * x"),
+            spawn(bin(num(2.), Token::Star, ident("x")))
+        );
+        assert_eq!(
+            parse("spawn { a; b }"),
+            spawn(ExprKind::Block(vec![ident("a"), ident("b")]).into())
+        );
+    }
+
+    #[test]
+    fn test_parse_spawn_in_program() {
+        let mut parser = PrattParser::new(Scanner::new("spawn f(1); spawn 2"));
+        assert_eq!(
+            parser.parse_program(),
+            Ok(vec![
+                spawn(call("f", vec![num(1.)])).into(),
+                spawn(num(2.)).into()
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_spawn_without_operand() {
+        assert!(matches!(
+            try_parse("spawn"),
+            Err(ParseError::UnexpectedEof { .. })
+        ));
+    }
+
+    fn program(code: &str) -> Result<Vec<Stmt>, ParseError> {
+        PrattParser::new(Scanner::new(code)).parse_program()
+    }
+
+    fn func(name: &str, params: &[&str], body: Vec<Stmt>) -> Stmt {
+        Stmt::Func {
+            name: name.into(),
+            params: params.iter().map(|&p| p.into()).collect(),
+            body,
+            span: Span::default(),
+        }
+    }
+
+    #[test]
+    fn test_parse_sync_and_barrier_statements() {
+        assert_eq!(
+            program("spawn 1; sync; barrier"),
+            Ok(vec![
+                spawn(num(1.)).into(),
+                ExprKind::Sync.into(),
+                ExprKind::Barrier.into()
+            ])
+        );
+        assert_eq!(
+            program("while x { spawn x; barrier }"),
+            Ok(vec![ExprKind::While {
+                cond: Box::new(ident("x")),
+                body: vec![spawn(ident("x")), ExprKind::Barrier.into()],
+            }
+            .into()])
+        );
+    }
+
+    #[test]
+    fn test_parse_sync_inside_expression_is_rejected() {
+        assert_eq!(
+            program("1 + sync"),
+            Err(ParseError::UnexpectedToken {
+                found: Token::KeywordSync,
+                expected: "expression ('sync' and 'barrier' are statements, not values)".into(),
+                pos: 4,
+            })
+        );
+        assert!(matches!(
+            program("f(barrier)"),
+            Err(ParseError::UnexpectedToken {
+                found: Token::KeywordBarrier,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_parse_sync_followed_by_operator_is_rejected() {
+        assert_eq!(
+            program("sync * 2"),
+            Err(ParseError::UnexpectedToken {
+                found: Token::Star,
+                expected: "';' after 'sync', which must stand alone as a statement".into(),
+                pos: 5,
+            })
+        );
+    }
+
+    #[test]
+    fn test_const_eval_arithmetic() {
+        assert_eq!(const_eval(&parse("1 + 2 * 3")), Some(7.));
+        assert_eq!(const_eval(&parse("-(2 - 5) / 2")), Some(1.5));
+        assert_eq!(const_eval(&parse("2 ** 3 ** 2")), Some(512.));
+        assert_eq!(const_eval(&parse("--4")), Some(4.));
+    }
+
+    #[test]
+    fn test_const_eval_rejects_non_constant_trees() {
+        assert_eq!(const_eval(&parse("1 + x")), None);
+        assert_eq!(const_eval(&parse("f(1) * 2")), None);
+        assert_eq!(const_eval(&parse("1 < 2")), None);
+        assert_eq!(const_eval(&parse("!0")), None);
+        assert_eq!(const_eval(&parse("[1][0]")), None);
+    }
+
+    #[test]
+    fn test_const_eval_division_by_zero_is_none() {
+        assert_eq!(const_eval(&parse("1 / 0")), None);
+        assert_eq!(const_eval(&parse("0 / (2 - 2)")), None);
+        assert_eq!(const_eval(&parse("1 / -0")), None);
+        assert_eq!(const_eval(&parse("0 / 1")), Some(0.));
+    }
+
+    #[test]
+    fn test_parse_trailing_comma_in_call() {
+        assert_eq!(parse("f(1, 2, 3,)"), parse("f(1, 2, 3)"));
+        assert_eq!(parse("f(1,)"), parse("f(1)"));
+    }
+
+    #[test]
+    fn test_parse_trailing_comma_in_params() {
+        assert_eq!(program("fn g(a, b,) { a }"), program("fn g(a, b) { a }"));
+        assert_eq!(program("fn g(a,) { a }"), program("fn g(a) { a }"));
+    }
+
+    #[test]
+    fn test_parse_empty_argument_slots_rejected() {
+        assert_eq!(
+            try_parse("f(,1)"),
+            Err(ParseError::UnexpectedToken {
+                found: Token::Comma,
+                expected: "argument before ','".into(),
+                pos: 2,
+            })
+        );
+        assert_eq!(
+            try_parse("f(1,,2)"),
+            Err(ParseError::UnexpectedToken {
+                found: Token::Comma,
+                expected: "argument between ',' separators".into(),
+                pos: 4,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_empty_parameter_slots_rejected() {
+        assert_eq!(
+            try_parse("fn g(,a) { a }"),
+            Err(ParseError::UnexpectedToken {
+                found: Token::Comma,
+                expected: "parameter name before ','".into(),
+                pos: 5,
+            })
+        );
+        assert_eq!(
+            try_parse("fn g(a,,b) { a }"),
+            Err(ParseError::UnexpectedToken {
+                found: Token::Comma,
+                expected: "parameter name between ',' separators".into(),
+                pos: 7,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_string_literal() {
+        assert_eq!(parse("\"hi\""), ExprKind::Str("hi".into()).into());
+        assert_eq!(
+            parse("print(\"x =\", 1)"),
+            ExprKind::call("print", vec![ExprKind::Str("x =".into()).into(), num(1.)]).into()
+        );
+    }
+
+    #[test]
+    fn test_parse_bool_literals() {
+        assert_eq!(parse("true"), ExprKind::Bool(true).into());
+        assert_eq!(
+            parse("!true && false"),
+            bin(
+                unary(Token::Bang, ExprKind::Bool(true).into()),
+                Token::AndAnd,
+                ExprKind::Bool(false).into()
+            )
+        );
+        // Keywords, not identifiers
+        assert_eq!(parse("trueish"), ident("trueish"));
+    }
+
+    #[test]
+    fn test_parse_multiple_top_level_functions() {
+        let parsed = program("fn inc(x){x+1} fn dbl(x){x*2} dbl(inc(3))").unwrap();
+        assert_eq!(parsed.len(), 3);
+        assert_eq!(
+            parsed[0],
+            func(
+                "inc",
+                &["x"],
+                vec![bin(ident("x"), Token::Plus, num(1.)).into()]
+            )
+        );
+        assert!(matches!(&parsed[1], Stmt::Func { name, .. } if name == "dbl"));
+        assert_eq!(
+            parsed[2],
+            call("dbl", vec![call("inc", vec![num(3.)])]).into()
+        );
+    }
+
+    #[test]
+    fn test_parse_nested_function_rejected() {
+        assert_eq!(
+            program("fn outer(x) { fn inner(y) { y }; inner(x) }"),
+            Err(ParseError::NestedFunction {
+                name: "inner".into(),
+                pos: 14,
+            })
+        );
+        assert_eq!(
+            program("fn f() { while 1 { fn g() { 0 } } }"),
+            Err(ParseError::NestedFunction {
+                name: "g".into(),
+                pos: 19,
+            })
+        );
+    }
+
+    #[test]
+    fn test_recover_after_nested_function() {
+        let (parsed, errors) = parse_recovering("fn outer(x) { fn inner(y) { y }; x }; outer(1)");
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0], ParseError::NestedFunction { .. }));
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(
+            parsed[0],
+            func(
+                "outer",
+                &["x"],
+                vec![ExprKind::Error.into(), ident("x").into()]
+            )
+        );
+        assert_eq!(parsed[1], call("outer", vec![num(1.)]).into());
+    }
+
+    /// Run `test` on a thread with a large stack: unoptimized builds use many
+    /// times more stack per nesting level than release builds do.
+    fn with_large_stack(test: impl FnOnce() + Send + 'static) {
+        std::thread::Builder::new()
+            .stack_size(64 << 20)
+            .spawn(test)
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    fn assert_too_deep(code: String) {
+        with_large_stack(move || {
+            assert!(
+                matches!(
+                    try_parse(&code),
+                    Err(ParseError::TooDeep {
+                        limit: PrattParser::DEFAULT_MAX_DEPTH,
+                        ..
+                    })
+                ),
+                "expected TooDeep for input of length {}",
+                code.len()
+            );
+        });
+    }
+
+    #[test]
+    fn test_deep_parentheses_rejected() {
+        assert_too_deep("(".repeat(50_000));
+        assert_too_deep(format!("{}1{}", "(".repeat(5_000), ")".repeat(5_000)));
+    }
+
+    #[test]
+    fn test_deep_unary_chain_rejected() {
+        assert_too_deep(format!("{}1", "-".repeat(50_000)));
+        assert_too_deep(format!("{}1", "!".repeat(50_000)));
+    }
+
+    #[test]
+    fn test_deep_binary_chains_rejected() {
+        assert_too_deep(format!("1{}", " + 1".repeat(50_000)));
+        assert_too_deep(format!("2{}", " ** 2".repeat(50_000)));
+    }
+
+    #[test]
+    fn test_nesting_within_limit_parses() {
+        let nested = format!("{}1{}", "(".repeat(300), ")".repeat(300));
+        assert_eq!(parse(&nested), num(1.));
+        assert!(try_parse(&format!("1{}", " + 1".repeat(500))).is_ok());
+    }
+
+    #[test]
+    fn test_custom_max_depth() {
+        let parse_with = |code: &str| {
+            PrattParser::new(Scanner::new(code))
+                .with_max_depth(4)
+                .expr(0)
+        };
+        assert!(parse_with("((1))").is_ok());
+        assert_eq!(
+            parse_with("((((1))))"),
+            Err(ParseError::TooDeep { limit: 4, pos: 4 })
+        );
+        // The counter unwinds, so siblings do not accumulate depth
+        assert!(parse_with("f((1), (2), (3))").is_ok());
+    }
+
+    #[test]
+    fn test_parse_custom_infix() {
+        let parse_with = |code: &str, assoc: Assoc| {
+            PrattParser::new(Scanner::new(code))
+                .with_infix("@", 20, assoc)
+                .expr(0)
+                .unwrap()
+        };
+        let at = || Token::Custom("@".into());
+        // Binds like '*': tighter than '+', looser than '**'
+        assert_eq!(
+            parse_with("1 + a @ b ** 2", Assoc::Left),
+            bin(
+                num(1.),
+                Token::Plus,
+                bin(ident("a"), at(), bin(ident("b"), Token::StarStar, num(2.)))
+            )
+        );
+        assert_eq!(
+            parse_with("a @ b @ c", Assoc::Left),
+            bin(bin(ident("a"), at(), ident("b")), at(), ident("c"))
+        );
+        assert_eq!(
+            parse_with("a @ b @ c", Assoc::Right),
+            bin(ident("a"), at(), bin(ident("b"), at(), ident("c")))
+        );
+        // Shares the precedence level of '*', so associativity is per operator
+        assert_eq!(
+            parse_with("a * b @ c", Assoc::Left),
+            bin(bin(ident("a"), Token::Star, ident("b")), at(), ident("c"))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Custom operators must be a single character")]
+    fn test_custom_infix_must_be_single_char() {
+        let _ = PrattParser::new(Scanner::new("")).with_infix("<>", 5, Assoc::Left);
+    }
+
+    #[test]
+    fn test_parse_call_on_expression() {
+        let call_of = |callee: Expr, args: Vec<Expr>| -> Expr {
+            ExprKind::Call {
+                callee: Box::new(callee),
+                args,
+            }
+            .into()
+        };
+        assert_eq!(parse("(foo)(1)"), call("foo", vec![num(1.)]));
+        assert_eq!(
+            parse("f(x)(y)"),
+            call_of(call("f", vec![ident("x")]), vec![ident("y")])
+        );
+        assert_eq!(
+            parse("fs[0](1, 2)"),
+            call_of(index(ident("fs"), num(0.)), vec![num(1.), num(2.)])
+        );
+        assert_eq!(
+            parse("-f(x)"),
+            unary(Token::Minus, call("f", vec![ident("x")]))
+        );
+        assert_eq!(parse("f(x)").kind.callee_name(), Some("f"));
+        assert_eq!(parse("f(x)(y)").kind.callee_name(), None);
+    }
+
+    #[test]
+    fn test_call_span_covers_callee() {
+        let expr = parse("(f)(x)(y)");
+        assert_eq!(expr.span, Span::new(0, 9));
+        let ExprKind::Call { callee, .. } = &expr.kind else {
+            panic!("expected a call");
+        };
+        assert_eq!(callee.span, Span::new(0, 6));
+    }
+
+    #[test]
+    fn test_definition_followed_by_parenthesis_is_not_a_call() {
+        assert_eq!(
+            program("fn f() { 1 } (2)"),
+            Ok(vec![func("f", &[], vec![num(1.).into()]), num(2.).into()])
+        );
+    }
+
+    fn assign(name: &str, value: Expr) -> Expr {
+        ExprKind::Assign {
+            name: name.into(),
+            value: Box::new(value),
+        }
+        .into()
+    }
+
+    #[test]
+    fn test_parse_mixed_program() {
+        let parsed =
+            program("let x = 2; fn sq(n) { let m = n * n; return m }; x = sq(x); return x + 1");
+        assert_eq!(
+            parsed,
+            Ok(vec![
+                Stmt::Let {
+                    name: "x".into(),
+                    value: num(2.),
+                    span: Span::default(),
+                },
+                func(
+                    "sq",
+                    &["n"],
+                    vec![
+                        Stmt::Let {
+                            name: "m".into(),
+                            value: bin(ident("n"), Token::Star, ident("n")),
+                            span: Span::default(),
+                        },
+                        Stmt::Return {
+                            value: Some(ident("m")),
+                            span: Span::default(),
+                        },
+                    ],
+                ),
+                assign("x", call("sq", vec![ident("x")])).into(),
+                Stmt::Return {
+                    value: Some(bin(ident("x"), Token::Plus, num(1.))),
+                    span: Span::default(),
+                },
+            ])
+        );
+        let spans: Vec<Span> = parsed.unwrap().iter().map(Stmt::span).collect();
+        assert_eq!(
+            spans,
+            vec![
+                Span::new(0, 9),
+                Span::new(11, 47),
+                Span::new(49, 58),
+                Span::new(60, 72),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_bare_return() {
+        assert_eq!(
+            program("fn f() { return }; return"),
+            Ok(vec![
+                func(
+                    "f",
+                    &[],
+                    vec![Stmt::Return {
+                        value: None,
+                        span: Span::default(),
+                    }],
+                ),
+                Stmt::Return {
+                    value: None,
+                    span: Span::default(),
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_assignment() {
+        // Right-associative and looser than every other operator
+        assert_eq!(
+            parse("a = b = 1 + 2"),
+            assign("a", assign("b", bin(num(1.), Token::Plus, num(2.))))
+        );
+        assert_eq!(
+            parse("(x = 3) + 1"),
+            bin(assign("x", num(3.)), Token::Plus, num(1.))
+        );
+        assert_eq!(
+            try_parse("f(x) = 1"),
+            Err(ParseError::UnexpectedToken {
+                found: Token::Assign,
+                expected: "variable name to the left of '='".into(),
+                pos: 5,
+            })
+        );
+        assert!(try_parse("a + b = 1").is_err());
+    }
+
+    #[test]
+    fn test_parse_if_else() {
+        let block = |items: Vec<Expr>| -> Expr { ExprKind::Block(items).into() };
+        let if_expr = |cond: Expr, then_branch: Expr, else_branch: Option<Expr>| -> Expr {
+            ExprKind::If {
+                cond: Box::new(cond),
+                then_branch: Box::new(then_branch),
+                else_branch: else_branch.map(Box::new),
+            }
+            .into()
+        };
+        assert_eq!(
+            parse("if x < 1 { 10 } else { 20 }"),
+            if_expr(
+                bin(ident("x"), Token::Lt, num(1.)),
+                block(vec![num(10.)]),
+                Some(block(vec![num(20.)]))
+            )
+        );
+        assert_eq!(
+            parse("if a { 1 } else if b { 2 }"),
+            if_expr(
+                ident("a"),
+                block(vec![num(1.)]),
+                Some(if_expr(ident("b"), block(vec![num(2.)]), None))
+            )
+        );
+        // Like a loop, a trailing '(' starts a new statement
+        assert_eq!(
+            program("if a { 1 } (2)"),
+            Ok(vec![
+                if_expr(ident("a"), block(vec![num(1.)]), None).into(),
+                num(2.).into()
+            ])
+        );
+        assert_eq!(
+            try_parse("if a { 1 } else 2"),
+            Err(ParseError::UnexpectedToken {
+                found: Token::Number(2.),
+                expected: "'{' to start else body".into(),
+                pos: 16,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_compound_assignment() {
+        let cases = [
+            ("x += 2", Token::Plus),
+            ("x -= 2", Token::Minus),
+            ("x *= 2", Token::Star),
+            ("x /= 2", Token::Slash),
+        ];
+        for (source, op) in cases {
+            assert_eq!(
+                parse(source),
+                assign("x", bin(ident("x"), op, num(2.))),
+                "source: {}",
+                source
+            );
+        }
+        // Same precedence and associativity as plain assignment
+        assert_eq!(
+            parse("a += b -= 1 + 2"),
+            assign(
+                "a",
+                bin(
+                    ident("a"),
+                    Token::Plus,
+                    assign(
+                        "b",
+                        bin(ident("b"), Token::Minus, bin(num(1.), Token::Plus, num(2.)))
+                    )
+                )
+            )
+        );
+        assert_eq!(parse("x *= 2").span, Span::new(0, 6));
+        assert_eq!(
+            try_parse("1 += 2"),
+            Err(ParseError::UnexpectedToken {
+                found: Token::PlusAssign,
+                expected: "variable name to the left of '+='".into(),
+                pos: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn test_statements_rejected_in_expression_position() {
+        assert_eq!(
+            program("while x { let y = 1 }"),
+            Err(ParseError::UnexpectedToken {
+                found: Token::KeywordLet,
+                expected: "expression ('let' and 'return' may only appear at the top level or in a function body)".into(),
+                pos: 10,
+            })
+        );
+        assert_eq!(
+            try_parse("1 + fn f() { 2 }"),
+            Err(ParseError::UnexpectedToken {
+                found: Token::KeywordFn,
+                expected: "expression ('fn' definitions are statements, not values)".into(),
+                pos: 4,
+            })
+        );
+        assert_eq!(
+            program("let = 1"),
+            Err(ParseError::UnexpectedToken {
+                found: Token::Assign,
+                expected: "variable name after 'let'".into(),
+                pos: 4,
+            })
+        );
+        assert!(matches!(
+            program("let x 1"),
+            Err(ParseError::UnexpectedToken { ref expected, .. }) if expected == "'=' after 'let x'"
+        ));
+    }
+
+    #[test]
+    fn test_recovering_statements() {
+        let (parsed, errors) = parse_recovering("let = 1; let y = 2; return +; y");
+        assert_eq!(errors.len(), 2);
+        assert_eq!(
+            parsed,
+            vec![
+                ExprKind::Error.into(),
+                Stmt::Let {
+                    name: "y".into(),
+                    value: num(2.),
+                    span: Span::default(),
+                },
+                ExprKind::Error.into(),
+                ident("y").into(),
+            ]
+        );
+    }
+
+    fn parse_grouped(code: &str) -> Expr {
+        PrattParser::new(Scanner::new(code))
+            .with_preserve_groups(true)
+            .expr(0)
+            .unwrap()
+    }
+
+    fn group(inner: Expr) -> Expr {
+        ExprKind::Group(Box::new(inner)).into()
+    }
+
+    #[test]
+    fn test_preserve_groups() {
+        let sum = bin(num(1.), Token::Plus, num(2.));
+        let expr = parse_grouped("(1 + 2) * 3");
+        assert_eq!(expr, bin(group(sum.clone()), Token::Star, num(3.)));
+        let ExprKind::BinaryOp { lhs, .. } = &expr.kind else {
+            panic!("expected a binary op, got {:?}", expr);
+        };
+        assert_eq!(lhs.span, Span::new(0, 7));
+        let ExprKind::Group(inner) = &lhs.kind else {
+            panic!("expected a group, got {:?}", lhs);
+        };
+        assert_eq!(inner.span, Span::new(1, 6));
+        assert_eq!(parse_grouped("((x))"), group(group(ident("x"))));
+        // Off by default
+        assert_eq!(parse("(1 + 2) * 3"), bin(sum, Token::Star, num(3.)));
+        assert_eq!(const_eval(&parse_grouped("-((1 + 2) * 3)")), Some(-9.));
+    }
+
+    #[test]
+    fn test_parse_modulo_precedence() {
+        assert_eq!(
+            parse("1 + 10 % 3 * 2"),
+            bin(
+                num(1.),
+                Token::Plus,
+                bin(bin(num(10.), Token::Percent, num(3.)), Token::Star, num(2.))
+            )
+        );
+        assert_eq!(const_eval(&parse("10 % 3")), Some(1.));
+        assert_eq!(const_eval(&parse("-7 % 3")), Some(-1.));
+        assert_eq!(const_eval(&parse("7 % 0")), None);
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod serde_tests {
+    use super::*;
+    use crate::parse_expr;
+
+    fn round_trip(expr: &Expr) -> Expr {
+        let json = serde_json::to_string(expr).unwrap();
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip_every_variant() {
+        let sources = [
+            "42",
+            "foo",
+            "\"text\"",
+            "true && !false",
+            "-x",
+            "1 + 2 * 3",
+            "f(1, g(2))",
+            "x = y = 1",
+            "while i < 10 { i }",
+            "{ 1; 2 }",
+            "c ? 1 : 2",
+            "[1, [2, 3]]",
+            "xs[0]",
+            "spawn f(1)",
+            "for i = 0 to 3 { i }",
+        ];
+        for source in sources {
+            let expr = parse_expr(source);
+            let back = round_trip(&expr);
+            assert_eq!(back, expr, "source: {}", source);
+            assert_eq!(back.span, expr.span);
+        }
+        for kind in [ExprKind::Sync, ExprKind::Barrier, ExprKind::Error] {
+            let expr: Expr = kind.into();
+            assert_eq!(round_trip(&expr), expr);
+        }
+        let no_else: Expr = ExprKind::If {
+            cond: Box::new(ExprKind::Ident("c".into()).into()),
+            then_branch: Box::new(ExprKind::Number(1.).into()),
+            else_branch: None,
+        }
+        .into();
+        assert_eq!(round_trip(&no_else), no_else);
+    }
+
+    #[test]
+    fn test_round_trip_statements() {
+        let source = "let x = 1; fn add(a, b) { while a { a - 1 }; return a + b }; return";
+        for stmt in crate::try_parse_program(source).unwrap() {
+            let json = serde_json::to_string(&stmt).unwrap();
+            let back: Stmt = serde_json::from_str(&json).unwrap();
+            assert_eq!(back, stmt);
+            assert_eq!(back.span(), stmt.span());
+        }
+    }
+
+    #[test]
+    fn test_json_shape() {
+        let json = serde_json::to_string(&parse_expr("-a + 2")).unwrap();
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"kind":{"BinaryOp":{"#,
+                r#""lhs":{"kind":{"UnaryOp":{"op":"Minus","rhs":{"kind":{"Ident":"a"},"span":{"start":1,"end":2}}}},"span":{"start":0,"end":2}},"#,
+                r#""op":"Plus","#,
+                r#""rhs":{"kind":{"Number":2.0},"span":{"start":5,"end":6}}}},"#,
+                r#""span":{"start":0,"end":6}}"#
+            )
         );
     }
 }
@@ -0,0 +1,514 @@
+//! Rendering of AST nodes back to source text and to s-expressions.
+
+use crate::parser::{Expr, ExprKind, PrattParser, Stmt};
+use crate::scanner::{quote, Token};
+use std::fmt::{self, Write};
+
+/// Binding power of the loosest operator at the root of `expr`.
+///
+/// An operand whose precedence is lower than the operator it appears under
+/// must be wrapped in parentheses to survive a round trip through the parser.
+fn precedence(expr: &Expr) -> u8 {
+    match &expr.kind {
+        ExprKind::BinaryOp { op, .. } => PrattParser::lbp(op),
+        ExprKind::UnaryOp { op, .. } => PrattParser::prefix_bp(op).unwrap_or(u8::MAX),
+        // A negative literal prints with a leading '-', so it behaves like a prefix operator
+        ExprKind::Number(n) if n.is_sign_negative() => {
+            PrattParser::prefix_bp(&Token::Minus).unwrap_or(u8::MAX)
+        }
+        ExprKind::If { .. } => PrattParser::lbp(&Token::Question),
+        ExprKind::Assign { .. } | ExprKind::IndexAssign { .. } => PrattParser::lbp(&Token::Assign),
+        // The operand of `spawn` extends as far right as possible
+        ExprKind::Spawn(_) => 0,
+        _ => u8::MAX,
+    }
+}
+
+/// Whether `expr` is an `If` that can be written with `if`/`else` blocks.
+fn is_if_block(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::If {
+            then_branch,
+            else_branch,
+            ..
+        } => {
+            matches!(then_branch.kind, ExprKind::Block(_))
+                && else_branch.as_ref().is_none_or(|else_branch| {
+                    matches!(else_branch.kind, ExprKind::Block(_)) || is_if_block(else_branch)
+                })
+        }
+        _ => false,
+    }
+}
+
+/// Write `expr`, parenthesised when `parens` is set.
+fn write_operand(f: &mut fmt::Formatter<'_>, expr: &Expr, parens: bool) -> fmt::Result {
+    if parens {
+        write!(f, "({})", expr)
+    } else {
+        write!(f, "{}", expr)
+    }
+}
+
+fn write_list(f: &mut fmt::Formatter<'_>, items: &[Expr]) -> fmt::Result {
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            f.write_str(", ")?;
+        }
+        write!(f, "{}", item)?;
+    }
+    Ok(())
+}
+
+fn write_body<T: fmt::Display>(f: &mut fmt::Formatter<'_>, body: &[T]) -> fmt::Result {
+    if body.is_empty() {
+        return f.write_str("{}");
+    }
+    f.write_str("{ ")?;
+    for (i, item) in body.iter().enumerate() {
+        if i > 0 {
+            f.write_str("; ")?;
+        }
+        write!(f, "{}", item)?;
+    }
+    f.write_str(" }")
+}
+
+/// Formats the expression as surface syntax, adding parentheses only where
+/// precedence or associativity requires them.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ExprKind::Number(n) => write!(f, "{}", n),
+            ExprKind::Ident(name) => f.write_str(name),
+            ExprKind::Bool(value) => write!(f, "{}", value),
+            ExprKind::Str(text) => f.write_str(&quote(text)),
+            ExprKind::UnaryOp { op, rhs } => {
+                let bp = PrattParser::prefix_bp(op).unwrap_or(u8::MAX);
+                let operand = if precedence(rhs) < bp {
+                    format!("({})", rhs)
+                } else {
+                    rhs.to_string()
+                };
+                // Keep `- -a` from running together
+                if *op == Token::Minus && operand.starts_with('-') {
+                    write!(f, "{} {}", op, operand)
+                } else {
+                    write!(f, "{}{}", op, operand)
+                }
+            }
+            ExprKind::BinaryOp { lhs, op, rhs } => {
+                let (lhs_prec, rhs_prec) = (precedence(lhs), precedence(rhs));
+                if let Token::Custom(_) = op {
+                    // The binding power of a custom operator is only known to its parser
+                    write_operand(f, lhs, lhs_prec < u8::MAX)?;
+                    write!(f, " {} ", op)?;
+                    return write_operand(f, rhs, rhs_prec < u8::MAX);
+                }
+                let bp = PrattParser::lbp(op);
+                let right_assoc = *op == Token::StarStar;
+                write_operand(f, lhs, lhs_prec < bp || (lhs_prec == bp && right_assoc))?;
+                write!(f, " {} ", op)?;
+                write_operand(f, rhs, rhs_prec < bp || (rhs_prec == bp && !right_assoc))
+            }
+            ExprKind::Call { callee, args } => {
+                let bp = PrattParser::lbp(&Token::LParen);
+                write_operand(f, callee, precedence(callee) < bp)?;
+                f.write_str("(")?;
+                write_list(f, args)?;
+                f.write_str(")")
+            }
+            ExprKind::Group(inner) => write!(f, "({})", inner),
+            ExprKind::Assign { name, value } => write!(f, "{} = {}", name, value),
+            ExprKind::While { cond, body } => {
+                write!(f, "while {} ", cond)?;
+                write_body(f, body)
+            }
+            ExprKind::Block(body) => write_body(f, body),
+            ExprKind::If {
+                cond,
+                then_branch,
+                else_branch,
+            } if is_if_block(self) => {
+                write!(f, "if {} {}", cond, then_branch)?;
+                match else_branch {
+                    Some(else_branch) => write!(f, " else {}", else_branch),
+                    None => Ok(()),
+                }
+            }
+            ExprKind::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                let bp = PrattParser::lbp(&Token::Question);
+                write_operand(f, cond, precedence(cond) <= bp)?;
+                write!(f, " ? {} : ", then_branch)?;
+                match else_branch {
+                    Some(else_branch) => {
+                        write_operand(f, else_branch, precedence(else_branch) < bp)
+                    }
+                    None => f.write_str("0"),
+                }
+            }
+            ExprKind::Array(elements) => {
+                f.write_str("[")?;
+                write_list(f, elements)?;
+                f.write_str("]")
+            }
+            ExprKind::Index { target, index } => {
+                let bp = PrattParser::lbp(&Token::LBracket);
+                write_operand(f, target, precedence(target) < bp)?;
+                write!(f, "[{}]", index)
+            }
+            ExprKind::IndexAssign {
+                target,
+                index,
+                value,
+            } => {
+                let bp = PrattParser::lbp(&Token::LBracket);
+                write_operand(f, target, precedence(target) < bp)?;
+                write!(f, "[{}] = {}", index, value)
+            }
+            ExprKind::Spawn(task) => write!(f, "spawn {}", task),
+            ExprKind::Sync => f.write_str("sync"),
+            ExprKind::Barrier => f.write_str("barrier"),
+            ExprKind::Error => f.write_str("<error>"),
+            ExprKind::For {
+                var,
+                start,
+                end,
+                body,
+            } => {
+                write!(f, "for {} = {} to {} ", var, start, end)?;
+                write_body(f, body)
+            }
+        }
+    }
+}
+
+impl Expr {
+    /// Render the expression as a fully parenthesised s-expression, e.g.
+    /// `(+ 1 (* 2 3))`, which is unambiguous and convenient in tests.
+    pub fn to_sexpr(&self) -> String {
+        let mut out = String::new();
+        self.write_sexpr(&mut out);
+        out
+    }
+
+    fn write_sexpr(&self, out: &mut String) {
+        let list = |out: &mut String, head: &str, items: &[&Expr]| {
+            out.push('(');
+            out.push_str(head);
+            for item in items {
+                out.push(' ');
+                item.write_sexpr(out);
+            }
+            out.push(')');
+        };
+        match &self.kind {
+            ExprKind::Number(n) => {
+                let _ = write!(out, "{}", n);
+            }
+            ExprKind::Ident(name) => out.push_str(name),
+            ExprKind::Bool(value) => {
+                let _ = write!(out, "{}", value);
+            }
+            ExprKind::Str(text) => out.push_str(&quote(text)),
+            ExprKind::UnaryOp { op, rhs } => list(out, &op.to_string(), &[rhs]),
+            ExprKind::BinaryOp { lhs, op, rhs } => list(out, &op.to_string(), &[lhs, rhs]),
+            ExprKind::Call { callee, args } => {
+                let items: Vec<&Expr> = std::iter::once(&**callee).chain(args).collect();
+                list(out, "call", &items)
+            }
+            // Already explicit in the s-expression's own parentheses
+            ExprKind::Group(inner) => inner.write_sexpr(out),
+            ExprKind::Assign { name, value } => list(out, &format!("= {}", name), &[value]),
+            ExprKind::While { cond, body } => {
+                let items: Vec<&Expr> = std::iter::once(&**cond).chain(body).collect();
+                list(out, "while", &items)
+            }
+            ExprKind::Block(body) => {
+                let body: Vec<&Expr> = body.iter().collect();
+                list(out, "block", &body)
+            }
+            ExprKind::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                let mut items = vec![&**cond, &**then_branch];
+                items.extend(else_branch.as_deref());
+                list(out, "if", &items)
+            }
+            ExprKind::Array(elements) => {
+                let elements: Vec<&Expr> = elements.iter().collect();
+                list(out, "array", &elements)
+            }
+            ExprKind::Index { target, index } => list(out, "index", &[target, index]),
+            ExprKind::IndexAssign {
+                target,
+                index,
+                value,
+            } => list(out, "index=", &[target, index, value]),
+            ExprKind::Spawn(task) => list(out, "spawn", &[task]),
+            ExprKind::Sync => out.push_str("sync"),
+            ExprKind::Barrier => out.push_str("barrier"),
+            ExprKind::Error => out.push_str("error"),
+            ExprKind::For {
+                var,
+                start,
+                end,
+                body,
+            } => {
+                let items: Vec<&Expr> = [&**start, &**end].into_iter().chain(body).collect();
+                list(out, &format!("for {}", var), &items)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Stmt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stmt::Expr(expr) => write!(f, "{}", expr),
+            Stmt::Let { name, value, .. } => write!(f, "let {} = {}", name, value),
+            Stmt::Func {
+                name, params, body, ..
+            } => {
+                write!(f, "fn {}({}) ", name, params.join(", "))?;
+                write_body(f, body)
+            }
+            Stmt::Return { value: None, .. } => f.write_str("return"),
+            Stmt::Return {
+                value: Some(value), ..
+            } => write!(f, "return {}", value),
+        }
+    }
+}
+
+impl Stmt {
+    /// Render the statement as an s-expression, like `Expr::to_sexpr`.
+    pub fn to_sexpr(&self) -> String {
+        match self {
+            Stmt::Expr(expr) => expr.to_sexpr(),
+            Stmt::Let { name, value, .. } => format!("(let {} {})", name, value.to_sexpr()),
+            Stmt::Func {
+                name, params, body, ..
+            } => {
+                let mut out = format!("(fn {} ({})", name, params.join(" "));
+                for stmt in body {
+                    out.push(' ');
+                    out.push_str(&stmt.to_sexpr());
+                }
+                out.push(')');
+                out
+            }
+            Stmt::Return { value: None, .. } => "(return)".into(),
+            Stmt::Return {
+                value: Some(value), ..
+            } => format!("(return {})", value.to_sexpr()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::{Expr, ExprKind};
+    use crate::scanner::Token;
+    use crate::{parse_expr, try_parse_program};
+
+    fn bin(lhs: Expr, op: Token, rhs: Expr) -> Expr {
+        ExprKind::BinaryOp {
+            lhs: Box::new(lhs),
+            op,
+            rhs: Box::new(rhs),
+        }
+        .into()
+    }
+
+    fn num(n: f64) -> Expr {
+        ExprKind::Number(n).into()
+    }
+
+    #[test]
+    fn test_display_minimal_parentheses() {
+        let cases = [
+            ("1 + 2 * 3", "1 + 2 * 3"),
+            ("(1 + 2) * 3", "(1 + 2) * 3"),
+            ("((1))", "1"),
+            ("1 - (2 - 3)", "1 - (2 - 3)"),
+            ("(1 - 2) - 3", "1 - 2 - 3"),
+            ("2 ** (3 ** 2)", "2 ** 3 ** 2"),
+            ("(2 ** 3) ** 2", "(2 ** 3) ** 2"),
+            ("-(a + b)", "-(a + b)"),
+            ("(-a) * b", "-a * b"),
+            ("(-a) ** b", "(-a) ** b"),
+            ("-(a ** b)", "-a ** b"),
+            ("- -a", "- -a"),
+            ("+(-a)", "+-a"),
+            ("-(+a) * b", "-+a * b"),
+            ("!(a && b)", "!(a && b)"),
+            ("a ? b : (c ? d : e)", "a ? b : c ? d : e"),
+            ("(a ? b : c) ? d : e", "(a ? b : c) ? d : e"),
+            ("(a ? b : c) + 1", "(a ? b : c) + 1"),
+            ("(a + b)[i * 2][0]", "(a + b)[i * 2][0]"),
+            ("-xs[0]", "-xs[0]"),
+            ("f(1, [2, 3], {})", "f(1, [2, 3], {})"),
+            ("(f)(1)(2)", "f(1)(2)"),
+            ("(f + g)(x)", "(f + g)(x)"),
+            ("(-f)(x)", "(-f)(x)"),
+            (r#"print("a\"b\n", x)"#, r#"print("a\"b\n", x)"#),
+            ("(spawn x) + 1", "(spawn x) + 1"),
+            (
+                "if a { 1 } else if b { 2 } else { }",
+                "if a { 1 } else if b { 2 } else {}",
+            ),
+            ("(if a { 1 }) * 2", "(if a { 1 }) * 2"),
+            ("a ? { 1 } : { 2 }", "if a { 1 } else { 2 }"),
+            ("a ? { 1 } : 2", "a ? { 1 } : 2"),
+            ("while i < 3 { i; 2 }", "while i < 3 { i; 2 }"),
+            ("for k = 0 to n - 1 { }", "for k = 0 to n - 1 {}"),
+            ("x = y = 1 + 2", "x = y = 1 + 2"),
+            ("(x = 1) + 2", "(x = 1) + 2"),
+            ("c ? x = 1 : (y = 2)", "c ? x = 1 : (y = 2)"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(
+                parse_expr(source).to_string(),
+                expected,
+                "source: {}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_display_of_built_trees() {
+        let left = bin(bin(num(1.), Token::Minus, num(2.)), Token::Minus, num(3.));
+        assert_eq!(left.to_string(), "1 - 2 - 3");
+        let right = bin(num(1.), Token::Minus, bin(num(2.), Token::Minus, num(3.)));
+        assert_eq!(right.to_string(), "1 - (2 - 3)");
+        // Synthesized negative literals keep their sign attached correctly
+        let neg = bin(num(-3.), Token::StarStar, num(2.));
+        assert_eq!(neg.to_string(), "(-3) ** 2");
+        assert_eq!(parse_expr(&neg.to_string()).to_string(), "(-3) ** 2");
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        let corpus = [
+            "1 + 2 * 3 - 4 / 2",
+            "7 * (8 + 9) - 3",
+            "2 ** -1 ** 2",
+            "-(-(7))",
+            "a + 1 < b * 2 == c",
+            "!(a == 1) || b && !c",
+            "x ? y ? 1 : 2 : z ? 3 : 4",
+            "f(g(1), h(), -k)",
+            "m[i][j] + [1, [2, 3]][1][0]",
+            "{ a; { b }; {} }",
+            "spawn (2 + 3) * 4",
+            "while n > 0 { n - 1; spawn n }",
+            "for i = 1 to 10 { f(i) }",
+            "a = b = c ? 1 : 2",
+            "m[i][j] = a[0] = 1 + 2",
+            "1.5 * (0.25 - 3)",
+            "!true || false ? true : false",
+        ];
+        for source in corpus {
+            let expr = parse_expr(source);
+            let printed = expr.to_string();
+            assert_eq!(parse_expr(&printed), expr, "printed as: {}", printed);
+        }
+    }
+
+    #[test]
+    fn test_display_program_statements() {
+        let program = try_parse_program("spawn 1; sync; barrier").unwrap();
+        let printed: Vec<String> = program.iter().map(|e| e.to_string()).collect();
+        assert_eq!(printed, vec!["spawn 1", "sync", "barrier"]);
+    }
+
+    #[test]
+    fn test_display_statements() {
+        let source = "let x = 1; fn add(a,b){let s = a + b; return s}; return; add(x, 2)";
+        let program = try_parse_program(source).unwrap();
+        let printed: Vec<String> = program.iter().map(|s| s.to_string()).collect();
+        assert_eq!(
+            printed,
+            vec![
+                "let x = 1",
+                "fn add(a, b) { let s = a + b; return s }",
+                "return",
+                "add(x, 2)"
+            ]
+        );
+        let reparsed = try_parse_program(&printed.join("; ")).unwrap();
+        assert_eq!(reparsed, program);
+        let sexprs: Vec<String> = program.iter().map(|s| s.to_sexpr()).collect();
+        assert_eq!(
+            sexprs,
+            vec![
+                "(let x 1)",
+                "(fn add (a b) (let s (+ a b)) (return s))",
+                "(return)",
+                "(call add x 2)"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_to_sexpr() {
+        assert_eq!(parse_expr("1 + 2 * 3").to_sexpr(), "(+ 1 (* 2 3))");
+        assert_eq!(parse_expr("(1 + 2) * -x").to_sexpr(), "(* (+ 1 2) (- x))");
+        assert_eq!(
+            parse_expr("c ? f(a, b) : xs[0]").to_sexpr(),
+            "(if c (call f a b) (index xs 0))"
+        );
+        assert_eq!(parse_expr("x = y = 1").to_sexpr(), "(= x (= y 1))");
+        assert_eq!(
+            parse_expr("for i = 0 to 3 { while i { } }").to_sexpr(),
+            "(for i 0 3 (while i))"
+        );
+    }
+
+    #[test]
+    fn test_display_preserved_groups() {
+        use crate::parser::PrattParser;
+        use crate::scanner::Scanner;
+        let parse_grouped = |source: &str| {
+            PrattParser::new(Scanner::new(source))
+                .with_preserve_groups(true)
+                .expr(0)
+                .unwrap()
+        };
+        // Redundant parentheses survive exactly as written
+        let corpus = [
+            "(1 + 2) * 3",
+            "((1))",
+            "1 + (2 * 3)",
+            "(a) ? (b) : (c ? d : e)",
+            "-(x)",
+            "(f)(1, (2))[(0)]",
+            "(x = (1))",
+        ];
+        for source in corpus {
+            let expr = parse_grouped(source);
+            assert_eq!(expr.to_string(), source);
+            assert_eq!(parse_grouped(&expr.to_string()), expr);
+        }
+        assert_eq!(parse_grouped("(1 + (2))").to_sexpr(), "(+ 1 2)");
+    }
+
+    #[test]
+    fn test_display_custom_operator() {
+        use crate::parser::{Assoc, PrattParser};
+        use crate::scanner::Scanner;
+        let expr = PrattParser::new(Scanner::new("1 + 2 @ 3 @ -x"))
+            .with_infix("@", 20, Assoc::Left)
+            .expr(0)
+            .unwrap();
+        assert_eq!(expr.to_string(), "1 + ((2 @ 3) @ (-x))");
+    }
+}
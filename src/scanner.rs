@@ -1,4 +1,7 @@
+use std::fmt;
+
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Token {
     Identifier(String),
     Number(f64),
@@ -17,16 +20,145 @@ pub enum Token {
     KeywordJz,
     KeywordJnz,
     Eof,
-    LBrace,    // '{'
-    RBrace,    // '}'
-    Comma,     // ','
-    KeywordFn, // 'fn'
+    LBrace,         // '{'
+    RBrace,         // '}'
+    Comma,          // ','
+    KeywordFn,      // 'fn'
+    KeywordWhile,   // 'while'
+    KeywordFor,     // 'for'
+    KeywordTo,      // 'to'
+    EqEq,           // '=='
+    NotEq,          // '!='
+    Lt,             // '<'
+    Le,             // '<='
+    Gt,             // '>'
+    Ge,             // '>='
+    AndAnd,         // '&&'
+    OrOr,           // '||'
+    Bang,           // '!'
+    StarStar,       // '**'
+    Question,       // '?'
+    Colon,          // ':'
+    LBracket,       // '['
+    RBracket,       // ']'
+    Str(String),    // '"..."' with escapes resolved
+    KeywordTrue,    // 'true'
+    KeywordFalse,   // 'false'
+    KeywordLet,     // 'let'
+    KeywordReturn,  // 'return'
+    KeywordIf,      // 'if'
+    KeywordElse,    // 'else'
+    PlusAssign,     // '+='
+    MinusAssign,    // '-='
+    StarAssign,     // '*='
+    SlashAssign,    // '/='
+    Custom(String), // operator registered with `Scanner::with_operator`
+    Percent,        // '%'
+}
+
+impl fmt::Display for Token {
+    /// Formats the token as it is written in source.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            Token::Identifier(name) => return write!(f, "{}", name),
+            Token::Number(n) => return write!(f, "{}", n),
+            Token::Str(text) => return f.write_str(&quote(text)),
+            Token::Custom(text) => return f.write_str(text),
+            Token::Eof => "end of input",
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Star => "*",
+            Token::Slash => "/",
+            Token::Assign => "=",
+            Token::Semicolon => ";",
+            Token::LParen => "(",
+            Token::RParen => ")",
+            Token::KeywordSpawn => "spawn",
+            Token::KeywordSync => "sync",
+            Token::KeywordBarrier => "barrier",
+            Token::KeywordJump => "jump",
+            Token::KeywordJz => "jz",
+            Token::KeywordJnz => "jnz",
+            Token::LBrace => "{",
+            Token::RBrace => "}",
+            Token::Comma => ",",
+            Token::KeywordFn => "fn",
+            Token::KeywordWhile => "while",
+            Token::KeywordFor => "for",
+            Token::KeywordTo => "to",
+            Token::EqEq => "==",
+            Token::NotEq => "!=",
+            Token::Lt => "<",
+            Token::Le => "<=",
+            Token::Gt => ">",
+            Token::Ge => ">=",
+            Token::AndAnd => "&&",
+            Token::OrOr => "||",
+            Token::Bang => "!",
+            Token::StarStar => "**",
+            Token::Question => "?",
+            Token::Colon => ":",
+            Token::LBracket => "[",
+            Token::RBracket => "]",
+            Token::KeywordTrue => "true",
+            Token::KeywordFalse => "false",
+            Token::KeywordLet => "let",
+            Token::KeywordReturn => "return",
+            Token::KeywordIf => "if",
+            Token::KeywordElse => "else",
+            Token::PlusAssign => "+=",
+            Token::MinusAssign => "-=",
+            Token::StarAssign => "*=",
+            Token::SlashAssign => "/=",
+            Token::Percent => "%",
+        };
+        f.write_str(text)
+    }
+}
+
+/// Render `text` as a string literal the scanner reads back unchanged.
+pub(crate) fn quote(text: &str) -> String {
+    let mut out = String::from('"');
+    for c in text.chars() {
+        match c {
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// A half-open byte range `start..end` in the source text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+
+    /// The smallest span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
 }
 
 pub struct Scanner<'a> {
     input: &'a str,
     pos: usize,
     current: Option<char>,
+    token_start: usize,
+    token_end: usize,
+    /// Extra single-character operators, scanned as `Token::Custom`.
+    operators: Vec<char>,
 }
 
 impl<'a> Scanner<'a> {
@@ -35,10 +167,26 @@ impl<'a> Scanner<'a> {
             input,
             pos: 0,
             current: None,
+            token_start: 0,
+            token_end: 0,
+            operators: Vec::new(),
         };
         s.bump();
         s
     }
+    /// Scan `op` as `Token::Custom` instead of rejecting it as an unexpected character.
+    /// Characters that already start a built-in token keep their meaning.
+    pub fn with_operator(mut self, op: char) -> Self {
+        self.add_operator(op);
+        self
+    }
+
+    pub(crate) fn add_operator(&mut self, op: char) {
+        if !self.operators.contains(&op) {
+            self.operators.push(op);
+        }
+    }
+
     fn bump(&mut self) {
         self.current = self.input[self.pos..].chars().next();
         if let Some(c) = self.current {
@@ -77,7 +225,7 @@ impl<'a> Scanner<'a> {
             }
         }
         // Fractional part
-        if self.current == Some('.') && self.peek().map_or(false, |c| c.is_ascii_digit()) {
+        if self.current == Some('.') && self.peek().is_some_and(|c| c.is_ascii_digit()) {
             num_str.push('.');
             self.bump(); // consume '.'
             while let Some(c) = self.current {
@@ -94,28 +242,69 @@ impl<'a> Scanner<'a> {
         Token::Number(value)
     }
 
+    /// Byte offset of the character currently under the cursor.
+    fn offset(&self) -> usize {
+        self.pos - self.current.map_or(0, char::len_utf8)
+    }
+
     pub fn next_token(&mut self) -> Token {
         self.skip_whitespace_and_comments();
+        self.token_start = self.offset();
+        let token = self.scan_token();
+        self.token_end = self.offset();
+        token
+    }
+
+    fn scan_token(&mut self) -> Token {
         match self.current {
             Some('+') => {
                 self.bump();
-                Token::Plus
+                self.followed_by('=', Token::PlusAssign, Token::Plus)
             }
             Some('-') => {
                 self.bump();
-                Token::Minus
+                self.followed_by('=', Token::MinusAssign, Token::Minus)
             }
             Some('*') => {
                 self.bump();
-                Token::Star
+                match self.current {
+                    Some('*') => self.followed_by('*', Token::StarStar, Token::Star),
+                    _ => self.followed_by('=', Token::StarAssign, Token::Star),
+                }
             }
             Some('/') => {
                 self.bump();
-                Token::Slash
+                self.followed_by('=', Token::SlashAssign, Token::Slash)
+            }
+            Some('%') => {
+                self.bump();
+                Token::Percent
             }
             Some('=') => {
                 self.bump();
-                Token::Assign
+                self.followed_by('=', Token::EqEq, Token::Assign)
+            }
+            Some('!') => {
+                self.bump();
+                self.followed_by('=', Token::NotEq, Token::Bang)
+            }
+            Some('&') if self.peek() == Some('&') => {
+                self.bump();
+                self.bump();
+                Token::AndAnd
+            }
+            Some('|') if self.peek() == Some('|') => {
+                self.bump();
+                self.bump();
+                Token::OrOr
+            }
+            Some('<') => {
+                self.bump();
+                self.followed_by('=', Token::Le, Token::Lt)
+            }
+            Some('>') => {
+                self.bump();
+                self.followed_by('=', Token::Ge, Token::Gt)
             }
             Some(';') => {
                 self.bump();
@@ -141,15 +330,73 @@ impl<'a> Scanner<'a> {
                 self.bump();
                 Token::Comma
             }
+            Some('[') => {
+                self.bump();
+                Token::LBracket
+            }
+            Some(']') => {
+                self.bump();
+                Token::RBracket
+            }
+            Some('?') => {
+                self.bump();
+                Token::Question
+            }
+            Some(':') => {
+                self.bump();
+                Token::Colon
+            }
+            Some('"') => self.string(),
             Some(c) if c.is_ascii_digit() => self.number(),
             Some(c) if c.is_ascii_alphabetic() || c == '_' => self.identifier_or_keyword(),
             None => Token::Eof,
+            Some(c) if self.operators.contains(&c) => {
+                self.bump();
+                Token::Custom(c.to_string())
+            }
             Some(c) => {
                 panic!("Unexpected character: {}", c);
             }
         }
     }
 
+    /// Consume `next` and return `matched` if it is the current character, else `single`.
+    fn followed_by(&mut self, next: char, matched: Token, single: Token) -> Token {
+        if self.current == Some(next) {
+            self.bump();
+            matched
+        } else {
+            single
+        }
+    }
+
+    /// Scan a double-quoted string literal, resolving `\n`, `\t`, `\"` and `\\`.
+    fn string(&mut self) -> Token {
+        self.bump(); // consume opening '"'
+        let mut text = String::new();
+        loop {
+            match self.current {
+                Some('"') => break,
+                Some('\\') => {
+                    self.bump();
+                    match self.current {
+                        Some('n') => text.push('\n'),
+                        Some('t') => text.push('\t'),
+                        Some('"') => text.push('"'),
+                        Some('\\') => text.push('\\'),
+                        Some(c) => panic!("Unknown escape sequence: \\{}", c),
+                        None => panic!("Unterminated string literal"),
+                    }
+                }
+                Some(c) => text.push(c),
+                None => panic!("Unterminated string literal"),
+            }
+            self.bump();
+        }
+        self.bump(); // consume closing '"'
+        Token::Str(text)
+    }
+
     fn identifier_or_keyword(&mut self) -> Token {
         let mut ident = String::new();
         while let Some(c) = self.current {
@@ -168,6 +415,15 @@ impl<'a> Scanner<'a> {
             "jz" => Token::KeywordJz,
             "jnz" => Token::KeywordJnz,
             "fn" => Token::KeywordFn,
+            "while" => Token::KeywordWhile,
+            "for" => Token::KeywordFor,
+            "to" => Token::KeywordTo,
+            "true" => Token::KeywordTrue,
+            "false" => Token::KeywordFalse,
+            "let" => Token::KeywordLet,
+            "return" => Token::KeywordReturn,
+            "if" => Token::KeywordIf,
+            "else" => Token::KeywordElse,
             _ => Token::Identifier(ident),
         }
     }
@@ -175,6 +431,24 @@ impl<'a> Scanner<'a> {
     pub fn current_position(&self) -> usize {
         self.pos
     }
+
+    /// Byte offset where the most recently returned token starts.
+    pub fn token_start(&self) -> usize {
+        self.token_start
+    }
+
+    /// Source range of the most recently returned token.
+    pub fn token_span(&self) -> Span {
+        Span::new(self.token_start, self.token_end)
+    }
+
+    /// 1-based line and column (in characters) of the byte offset `pos`.
+    pub fn line_col(&self, pos: usize) -> (usize, usize) {
+        let before = &self.input[..pos.min(self.input.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before.rsplit('\n').next().map_or(0, |l| l.chars().count()) + 1;
+        (line, column)
+    }
 }
 
 #[cfg(test)]
@@ -204,7 +478,8 @@ mod tests {
 
     #[test]
     fn test_keywords() {
-        let mut s = Scanner::new("spawn sync barrier jump jz jnz fn");
+        let mut s =
+            Scanner::new("spawn sync barrier jump jz jnz fn while for to let return if else");
         assert_eq!(s.next_token(), Token::KeywordSpawn);
         assert_eq!(s.next_token(), Token::KeywordSync);
         assert_eq!(s.next_token(), Token::KeywordBarrier);
@@ -212,12 +487,19 @@ mod tests {
         assert_eq!(s.next_token(), Token::KeywordJz);
         assert_eq!(s.next_token(), Token::KeywordJnz);
         assert_eq!(s.next_token(), Token::KeywordFn);
+        assert_eq!(s.next_token(), Token::KeywordWhile);
+        assert_eq!(s.next_token(), Token::KeywordFor);
+        assert_eq!(s.next_token(), Token::KeywordTo);
+        assert_eq!(s.next_token(), Token::KeywordLet);
+        assert_eq!(s.next_token(), Token::KeywordReturn);
+        assert_eq!(s.next_token(), Token::KeywordIf);
+        assert_eq!(s.next_token(), Token::KeywordElse);
         assert_eq!(s.next_token(), Token::Eof);
     }
 
     #[test]
     fn test_operators_and_delimiters() {
-        let mut s = Scanner::new("+-*/=;(){},");
+        let mut s = Scanner::new("+-*/ =;(){},?:[]");
 
         assert_eq!(s.next_token(), Token::Plus);
         assert_eq!(s.next_token(), Token::Minus);
@@ -230,6 +512,69 @@ mod tests {
         assert_eq!(s.next_token(), Token::LBrace);
         assert_eq!(s.next_token(), Token::RBrace);
         assert_eq!(s.next_token(), Token::Comma);
+        assert_eq!(s.next_token(), Token::Question);
+        assert_eq!(s.next_token(), Token::Colon);
+        assert_eq!(s.next_token(), Token::LBracket);
+        assert_eq!(s.next_token(), Token::RBracket);
+        assert_eq!(s.next_token(), Token::Eof);
+    }
+
+    #[test]
+    fn test_compound_assignment_operators() {
+        let mut s = Scanner::new("+= -= *= /= ** **= + =");
+        assert_eq!(s.next_token(), Token::PlusAssign);
+        assert_eq!(s.next_token(), Token::MinusAssign);
+        assert_eq!(s.next_token(), Token::StarAssign);
+        assert_eq!(s.next_token(), Token::SlashAssign);
+        assert_eq!(s.next_token(), Token::StarStar);
+        assert_eq!(s.next_token(), Token::StarStar);
+        assert_eq!(s.next_token(), Token::Assign);
+        assert_eq!(s.next_token(), Token::Plus);
+        assert_eq!(s.next_token(), Token::Assign);
+        assert_eq!(s.next_token(), Token::Eof);
+    }
+
+    #[test]
+    fn test_comparison_operators() {
+        let mut s = Scanner::new("== != < <= > >= = =<");
+        assert_eq!(s.next_token(), Token::EqEq);
+        assert_eq!(s.next_token(), Token::NotEq);
+        assert_eq!(s.next_token(), Token::Lt);
+        assert_eq!(s.next_token(), Token::Le);
+        assert_eq!(s.next_token(), Token::Gt);
+        assert_eq!(s.next_token(), Token::Ge);
+        assert_eq!(s.next_token(), Token::Assign);
+        assert_eq!(s.next_token(), Token::Assign);
+        assert_eq!(s.next_token(), Token::Lt);
+        assert_eq!(s.next_token(), Token::Eof);
+    }
+
+    #[test]
+    fn test_logical_operators() {
+        let mut s = Scanner::new("&& || ! !=");
+        assert_eq!(s.next_token(), Token::AndAnd);
+        assert_eq!(s.next_token(), Token::OrOr);
+        assert_eq!(s.next_token(), Token::Bang);
+        assert_eq!(s.next_token(), Token::NotEq);
+        assert_eq!(s.next_token(), Token::Eof);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unexpected character: &")]
+    fn test_single_ampersand_is_rejected() {
+        let mut s = Scanner::new("a & b");
+        s.next_token();
+        s.next_token();
+    }
+
+    #[test]
+    fn test_power_operator() {
+        let mut s = Scanner::new("2**3 * *");
+        assert_eq!(s.next_token(), Token::Number(2.));
+        assert_eq!(s.next_token(), Token::StarStar);
+        assert_eq!(s.next_token(), Token::Number(3.));
+        assert_eq!(s.next_token(), Token::Star);
+        assert_eq!(s.next_token(), Token::Star);
         assert_eq!(s.next_token(), Token::Eof);
     }
 
@@ -243,10 +588,102 @@ mod tests {
         assert_eq!(s.next_token(), Token::Eof);
     }
 
+    #[test]
+    fn test_token_start_offsets() {
+        let mut s = Scanner::new("ab  {\n 12");
+        s.next_token();
+        assert_eq!(s.token_start(), 0);
+        s.next_token();
+        assert_eq!(s.token_start(), 4);
+        s.next_token();
+        assert_eq!(s.token_start(), 7);
+        assert_eq!(s.next_token(), Token::Eof);
+        assert_eq!(s.token_start(), 9);
+    }
+
+    #[test]
+    fn test_token_spans() {
+        let mut s = Scanner::new("foo 12.5 // c\n;");
+        s.next_token();
+        assert_eq!(s.token_span(), Span::new(0, 3));
+        s.next_token();
+        assert_eq!(s.token_span(), Span::new(4, 8));
+        s.next_token();
+        assert_eq!(s.token_span(), Span::new(14, 15));
+        s.next_token();
+        assert_eq!(s.token_span(), Span::new(15, 15));
+    }
+
+    #[test]
+    fn test_token_display_matches_source() {
+        let code = "foo 1.5 ** <= != && { } [ ] ? : % spawn while true false";
+        let mut s = Scanner::new(code);
+        let mut printed = Vec::new();
+        loop {
+            let token = s.next_token();
+            if token == Token::Eof {
+                break;
+            }
+            printed.push(token.to_string());
+        }
+        assert_eq!(printed.join(" "), code);
+    }
+
     #[test]
     #[should_panic]
     fn test_unexpected_character() {
         let mut s = Scanner::new("@");
         let _ = s.next_token();
     }
+
+    #[test]
+    fn test_string_literal() {
+        let mut s = Scanner::new(r#"print("hi there", "a\"b\\c\n\t")"#);
+        assert_eq!(s.next_token(), Token::Identifier("print".into()));
+        assert_eq!(s.next_token(), Token::LParen);
+        assert_eq!(s.next_token(), Token::Str("hi there".into()));
+        assert_eq!(s.token_span(), Span::new(6, 16));
+        assert_eq!(s.next_token(), Token::Comma);
+        assert_eq!(s.next_token(), Token::Str("a\"b\\c\n\t".into()));
+        assert_eq!(s.next_token(), Token::RParen);
+        assert_eq!(s.next_token(), Token::Eof);
+    }
+
+    #[test]
+    fn test_string_display_round_trips() {
+        let token = Token::Str("say \"hi\"\n".into());
+        assert_eq!(token.to_string(), r#""say \"hi\"\n""#);
+        assert_eq!(Scanner::new(&token.to_string()).next_token(), token);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unterminated string literal")]
+    fn test_unterminated_string() {
+        let _ = Scanner::new("\"abc").next_token();
+    }
+
+    #[test]
+    fn test_line_col() {
+        let s = Scanner::new("ab\n  c\n\nd");
+        assert_eq!(s.line_col(0), (1, 1));
+        assert_eq!(s.line_col(2), (1, 3));
+        assert_eq!(s.line_col(5), (2, 3));
+        assert_eq!(s.line_col(8), (4, 1));
+    }
+
+    #[test]
+    fn test_custom_operator() {
+        let mut s = Scanner::new("a @ b & c")
+            .with_operator('@')
+            .with_operator('&');
+        assert_eq!(s.next_token(), Token::Identifier("a".into()));
+        assert_eq!(s.next_token(), Token::Custom("@".into()));
+        assert_eq!(s.next_token(), Token::Identifier("b".into()));
+        assert_eq!(s.next_token(), Token::Custom("&".into()));
+        assert_eq!(s.next_token(), Token::Identifier("c".into()));
+        // Built-in tokens win over registered characters
+        let mut s = Scanner::new("&& +").with_operator('&').with_operator('+');
+        assert_eq!(s.next_token(), Token::AndAnd);
+        assert_eq!(s.next_token(), Token::Plus);
+    }
 }
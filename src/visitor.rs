@@ -0,0 +1,409 @@
+//! Traversal helpers for the AST.
+//!
+//! `Visitor` walks a tree by shared reference, with one hook per node kind
+//! whose default implementation recurses into the children. `VisitorMut`
+//! walks by mutable reference so passes can rewrite nodes in place. Both
+//! also walk `Stmt`s, reaching the expressions inside them.
+
+use crate::parser::{Expr, ExprKind, Stmt};
+use crate::scanner::Token;
+
+pub trait Visitor {
+    /// Called for every statement; override to act before or after its children.
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        walk_stmt(stmt, self);
+    }
+
+    fn visit_let(&mut self, _name: &str, value: &Expr) {
+        self.visit_expr(value);
+    }
+
+    fn visit_function(&mut self, _name: &str, _params: &[String], body: &[Stmt]) {
+        for stmt in body {
+            self.visit_stmt(stmt);
+        }
+    }
+
+    fn visit_return(&mut self, value: Option<&Expr>) {
+        if let Some(value) = value {
+            self.visit_expr(value);
+        }
+    }
+
+    /// Called for every node; override to act before or after the children.
+    fn visit_expr(&mut self, expr: &Expr) {
+        walk_expr(expr, self);
+    }
+
+    fn visit_number(&mut self, _value: f64) {}
+
+    fn visit_ident(&mut self, _name: &str) {}
+
+    fn visit_bool(&mut self, _value: bool) {}
+
+    fn visit_str(&mut self, _text: &str) {}
+
+    fn visit_unary_op(&mut self, _op: &Token, rhs: &Expr) {
+        self.visit_expr(rhs);
+    }
+
+    fn visit_binary_op(&mut self, lhs: &Expr, _op: &Token, rhs: &Expr) {
+        self.visit_expr(lhs);
+        self.visit_expr(rhs);
+    }
+
+    fn visit_call(&mut self, callee: &Expr, args: &[Expr]) {
+        self.visit_expr(callee);
+        walk_list(args, self);
+    }
+
+    fn visit_group(&mut self, inner: &Expr) {
+        self.visit_expr(inner);
+    }
+
+    fn visit_assign(&mut self, _name: &str, value: &Expr) {
+        self.visit_expr(value);
+    }
+
+    fn visit_while(&mut self, cond: &Expr, body: &[Expr]) {
+        self.visit_expr(cond);
+        walk_list(body, self);
+    }
+
+    fn visit_block(&mut self, body: &[Expr]) {
+        walk_list(body, self);
+    }
+
+    fn visit_if(&mut self, cond: &Expr, then_branch: &Expr, else_branch: Option<&Expr>) {
+        self.visit_expr(cond);
+        self.visit_expr(then_branch);
+        if let Some(else_branch) = else_branch {
+            self.visit_expr(else_branch);
+        }
+    }
+
+    fn visit_array(&mut self, elements: &[Expr]) {
+        walk_list(elements, self);
+    }
+
+    fn visit_index(&mut self, target: &Expr, index: &Expr) {
+        self.visit_expr(target);
+        self.visit_expr(index);
+    }
+
+    fn visit_index_assign(&mut self, target: &Expr, index: &Expr, value: &Expr) {
+        self.visit_expr(target);
+        self.visit_expr(index);
+        self.visit_expr(value);
+    }
+
+    fn visit_spawn(&mut self, task: &Expr) {
+        self.visit_expr(task);
+    }
+
+    fn visit_sync(&mut self) {}
+
+    fn visit_barrier(&mut self) {}
+
+    fn visit_error(&mut self) {}
+
+    fn visit_for(&mut self, _var: &str, start: &Expr, end: &Expr, body: &[Expr]) {
+        self.visit_expr(start);
+        self.visit_expr(end);
+        walk_list(body, self);
+    }
+}
+
+/// Dispatch `stmt` to the matching `Visitor` hook.
+pub fn walk_stmt<V: Visitor + ?Sized>(stmt: &Stmt, visitor: &mut V) {
+    match stmt {
+        Stmt::Expr(expr) => visitor.visit_expr(expr),
+        Stmt::Let { name, value, .. } => visitor.visit_let(name, value),
+        Stmt::Func {
+            name, params, body, ..
+        } => visitor.visit_function(name, params, body),
+        Stmt::Return { value, .. } => visitor.visit_return(value.as_ref()),
+    }
+}
+
+/// Dispatch `expr` to the matching `Visitor` hook.
+pub fn walk_expr<V: Visitor + ?Sized>(expr: &Expr, visitor: &mut V) {
+    match &expr.kind {
+        ExprKind::Number(value) => visitor.visit_number(*value),
+        ExprKind::Ident(name) => visitor.visit_ident(name),
+        ExprKind::Bool(value) => visitor.visit_bool(*value),
+        ExprKind::Str(text) => visitor.visit_str(text),
+        ExprKind::UnaryOp { op, rhs } => visitor.visit_unary_op(op, rhs),
+        ExprKind::BinaryOp { lhs, op, rhs } => visitor.visit_binary_op(lhs, op, rhs),
+        ExprKind::Call { callee, args } => visitor.visit_call(callee, args),
+        ExprKind::Group(inner) => visitor.visit_group(inner),
+        ExprKind::Assign { name, value } => visitor.visit_assign(name, value),
+        ExprKind::While { cond, body } => visitor.visit_while(cond, body),
+        ExprKind::Block(body) => visitor.visit_block(body),
+        ExprKind::If {
+            cond,
+            then_branch,
+            else_branch,
+        } => visitor.visit_if(cond, then_branch, else_branch.as_deref()),
+        ExprKind::Array(elements) => visitor.visit_array(elements),
+        ExprKind::Index { target, index } => visitor.visit_index(target, index),
+        ExprKind::IndexAssign {
+            target,
+            index,
+            value,
+        } => visitor.visit_index_assign(target, index, value),
+        ExprKind::Spawn(task) => visitor.visit_spawn(task),
+        ExprKind::Sync => visitor.visit_sync(),
+        ExprKind::Barrier => visitor.visit_barrier(),
+        ExprKind::Error => visitor.visit_error(),
+        ExprKind::For {
+            var,
+            start,
+            end,
+            body,
+        } => visitor.visit_for(var, start, end, body),
+    }
+}
+
+fn walk_list<V: Visitor + ?Sized>(exprs: &[Expr], visitor: &mut V) {
+    for expr in exprs {
+        visitor.visit_expr(expr);
+    }
+}
+
+pub trait VisitorMut {
+    fn visit_stmt_mut(&mut self, stmt: &mut Stmt) {
+        walk_stmt_mut(stmt, self);
+    }
+
+    /// Called for every node; a pass may replace `expr` wholesale before or
+    /// after recursing into it with `walk_expr_mut`.
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        walk_expr_mut(expr, self);
+    }
+}
+
+/// Visit each expression or statement directly inside `stmt` with `visitor`.
+pub fn walk_stmt_mut<V: VisitorMut + ?Sized>(stmt: &mut Stmt, visitor: &mut V) {
+    match stmt {
+        Stmt::Expr(expr) | Stmt::Let { value: expr, .. } => visitor.visit_expr_mut(expr),
+        Stmt::Func { body, .. } => {
+            for stmt in body {
+                visitor.visit_stmt_mut(stmt);
+            }
+        }
+        Stmt::Return { value, .. } => {
+            if let Some(value) = value {
+                visitor.visit_expr_mut(value);
+            }
+        }
+    }
+}
+
+/// Visit each direct child of `expr` with `visitor`.
+pub fn walk_expr_mut<V: VisitorMut + ?Sized>(expr: &mut Expr, visitor: &mut V) {
+    match &mut expr.kind {
+        ExprKind::Number(_)
+        | ExprKind::Ident(_)
+        | ExprKind::Bool(_)
+        | ExprKind::Str(_)
+        | ExprKind::Sync
+        | ExprKind::Barrier
+        | ExprKind::Error => {}
+        ExprKind::UnaryOp { rhs, .. }
+        | ExprKind::Group(rhs)
+        | ExprKind::Assign { value: rhs, .. } => visitor.visit_expr_mut(rhs),
+        ExprKind::BinaryOp { lhs, rhs, .. } => {
+            visitor.visit_expr_mut(lhs);
+            visitor.visit_expr_mut(rhs);
+        }
+        ExprKind::Block(exprs) | ExprKind::Array(exprs) => {
+            for expr in exprs {
+                visitor.visit_expr_mut(expr);
+            }
+        }
+        ExprKind::Call { callee, args } => {
+            visitor.visit_expr_mut(callee);
+            for expr in args {
+                visitor.visit_expr_mut(expr);
+            }
+        }
+        ExprKind::While { cond, body } => {
+            visitor.visit_expr_mut(cond);
+            for expr in body {
+                visitor.visit_expr_mut(expr);
+            }
+        }
+        ExprKind::If {
+            cond,
+            then_branch,
+            else_branch,
+        } => {
+            visitor.visit_expr_mut(cond);
+            visitor.visit_expr_mut(then_branch);
+            if let Some(else_branch) = else_branch {
+                visitor.visit_expr_mut(else_branch);
+            }
+        }
+        ExprKind::Index { target, index } => {
+            visitor.visit_expr_mut(target);
+            visitor.visit_expr_mut(index);
+        }
+        ExprKind::IndexAssign {
+            target,
+            index,
+            value,
+        } => {
+            visitor.visit_expr_mut(target);
+            visitor.visit_expr_mut(index);
+            visitor.visit_expr_mut(value);
+        }
+        ExprKind::Spawn(task) => visitor.visit_expr_mut(task),
+        ExprKind::For {
+            start, end, body, ..
+        } => {
+            visitor.visit_expr_mut(start);
+            visitor.visit_expr_mut(end);
+            for expr in body {
+                visitor.visit_expr_mut(expr);
+            }
+        }
+    }
+}
+
+struct IdentCollector {
+    names: Vec<String>,
+}
+
+impl Visitor for IdentCollector {
+    fn visit_ident(&mut self, name: &str) {
+        if !self.names.iter().any(|seen| seen == name) {
+            self.names.push(name.to_string());
+        }
+    }
+}
+
+/// Names of every identifier referenced in `expr`, in order of first use.
+pub fn collect_identifiers(expr: &Expr) -> Vec<String> {
+    let mut collector = IdentCollector { names: Vec::new() };
+    collector.visit_expr(expr);
+    collector.names
+}
+
+struct NodeCounter {
+    count: usize,
+}
+
+impl Visitor for NodeCounter {
+    fn visit_expr(&mut self, expr: &Expr) {
+        self.count += 1;
+        walk_expr(expr, self);
+    }
+}
+
+/// Total number of AST nodes in `expr`, including `expr` itself.
+pub fn count_nodes(expr: &Expr) -> usize {
+    let mut counter = NodeCounter { count: 0 };
+    counter.visit_expr(expr);
+    counter.count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_expr, try_parse_program};
+
+    #[test]
+    fn test_collect_identifiers() {
+        let expr = parse_expr("a + b * f(c, a)");
+        assert_eq!(collect_identifiers(&expr), vec!["a", "b", "f", "c"]);
+        assert!(collect_identifiers(&parse_expr("1 + 2")).is_empty());
+        let expr = parse_expr("for i = lo to hi { xs[i] ? spawn i : -j }");
+        assert_eq!(collect_identifiers(&expr), vec!["lo", "hi", "xs", "i", "j"]);
+    }
+
+    #[test]
+    fn test_count_nodes() {
+        assert_eq!(count_nodes(&parse_expr("42")), 1);
+        assert_eq!(count_nodes(&parse_expr("1 + 2 * 3")), 5);
+        assert_eq!(count_nodes(&parse_expr("f(1, -x)")), 5);
+        assert_eq!(count_nodes(&parse_expr("while c { [1, 2]; {} }")), 6);
+    }
+
+    #[test]
+    fn test_custom_hook_overrides_default_recursion() {
+        // Counts calls but does not look inside their arguments
+        struct ShallowCalls(usize);
+        impl Visitor for ShallowCalls {
+            fn visit_call(&mut self, _callee: &Expr, _args: &[Expr]) {
+                self.0 += 1;
+            }
+        }
+        let mut visitor = ShallowCalls(0);
+        visitor.visit_expr(&parse_expr("f(g(1)) + h()"));
+        assert_eq!(visitor.0, 2);
+    }
+
+    #[test]
+    fn test_visit_statements() {
+        let program = try_parse_program("let x = 1; fn f(a) { b = a; return g(x) }; f(2)").unwrap();
+        let mut collector = IdentCollector { names: Vec::new() };
+        for stmt in &program {
+            collector.visit_stmt(stmt);
+        }
+        assert_eq!(collector.names, vec!["a", "g", "x", "f"]);
+
+        struct Rename;
+        impl VisitorMut for Rename {
+            fn visit_expr_mut(&mut self, expr: &mut Expr) {
+                if let ExprKind::Ident(name) = &mut expr.kind {
+                    name.make_ascii_uppercase();
+                }
+                walk_expr_mut(expr, self);
+            }
+        }
+        let mut program = program;
+        for stmt in &mut program {
+            Rename.visit_stmt_mut(stmt);
+        }
+        assert_eq!(
+            program,
+            try_parse_program("let x = 1; fn f(a) { b = A; return G(X) }; F(2)").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_visitor_mut_rewrites_in_place() {
+        struct Rename;
+        impl VisitorMut for Rename {
+            fn visit_expr_mut(&mut self, expr: &mut Expr) {
+                if let ExprKind::Ident(name) = &mut expr.kind {
+                    name.make_ascii_uppercase();
+                }
+                walk_expr_mut(expr, self);
+            }
+        }
+        let mut expr = parse_expr("a + f(b, [c][d]) ? -e : { g }");
+        Rename.visit_expr_mut(&mut expr);
+        assert_eq!(expr, parse_expr("A + F(B, [C][D]) ? -E : { G }"));
+    }
+
+    #[test]
+    fn test_visitor_mut_replaces_nodes() {
+        // Replace `x * 1` with `x`, bottom-up
+        struct DropMulOne;
+        impl VisitorMut for DropMulOne {
+            fn visit_expr_mut(&mut self, expr: &mut Expr) {
+                walk_expr_mut(expr, self);
+                if let ExprKind::BinaryOp { lhs, op, rhs } = &expr.kind {
+                    if *op == Token::Star && rhs.kind == ExprKind::Number(1.) {
+                        *expr = (**lhs).clone();
+                    }
+                }
+            }
+        }
+        let mut expr = parse_expr("(a * 1) * 1 + b * 2");
+        DropMulOne.visit_expr_mut(&mut expr);
+        assert_eq!(expr, parse_expr("a + b * 2"));
+    }
+}
@@ -1,100 +1,1176 @@
+use crate::compiler::{CallTarget, CompileError, Slot, SymbolTable};
 use crate::parser;
-use std::collections::HashMap;
+use crate::scanner::{Scanner, Span};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Write;
 use std::rc::Rc;
 use std::sync::mpsc::{self, Receiver};
 use std::thread;
 
+mod profile;
+
+use profile::Profiler;
+pub use profile::{FunctionProfile, InstructionProfile, ProfileReport};
+
 // Define bytecode instruction set for VM
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[allow(dead_code)] // Allow dead code for unused variants
 pub enum Bytecode {
     // Unary operations
     Neg, // Negate the top value on the stack
 
     // Arithmetic operations
-    Add, // Add two values
-    Sub, // Subtract two values
-    Mul, // Multiply two values
-    Div, // Divide two values
+    Add,      // Add two numbers, or concatenate two strings
+    Sub,      // Subtract two values
+    Mul,      // Multiply two values
+    Div,      // Divide two values
+    Mod,      // Remainder of two values; takes the sign of the dividend
+    Pow,      // Raise second-from-top to the power of top; 0 to a negative power divides by zero
+    IDiv,     // Divide two numbers and truncate the quotient toward zero, so -7 by 2 is -3
+    CheckPow, // Fail as `Pow` would unless the top, left in place, is a number to raise
+
+    // Rounding of a number to a whole one
+    Floor, // Round toward negative infinity
+    Ceil,  // Round toward positive infinity
+    Trunc, // Round toward zero
+
+    // Bitwise operations: both operands are truncated toward zero to i64, which
+    // NaN, the infinities and values outside the i64 range are an error to
+    // reach, and the integer result converted back
+    BitAnd, // Bitwise and
+    BitOr,  // Bitwise or
+    BitXor, // Bitwise exclusive or
+    Shl,    // Shift second-from-top left by top, which must be in 0..64
+    Shr,    // Shift second-from-top right by top, keeping its sign
+
+    // Comparisons: push whether second-from-top relates to top, as a bool.
+    // NaN compares unequal to everything, itself included, and -0.0 equals 0.0
+    Eq, // Equal
+    Ne, // Not equal
+    Lt, // Less than
+    Le, // Less than or equal
+    Gt, // Greater than
+    Ge, // Greater than or equal
+
+    // Logic
+    Not, // Replace the top of the stack with the bool opposite to its truthiness
 
     // Data movement
-    LoadConst(f64),  // Load a constant value (changed to f64 for signed integers)
-    LoadVar(usize),  // Load a variable from memory
-    StoreVar(usize), // Store a value to a variable
+    // Load a constant value (changed to f64 for signed integers)
+    LoadConst(#[cfg_attr(feature = "serde", serde(with = "crate::float_serde"))] f64),
+    LoadConstIdx(u32), // Load the constant at this index of the program's constant pool
+    LoadBool(bool),    // Load a bool
+    LoadStr(String),   // Load a string constant
+    LoadStrIdx(u32),   // Load the string at this index of the program's string table, likewise
+    LoadVar(usize),    // Load a variable of the current call frame, or a global outside any call
+    StoreVar(usize),   // Store a value to a variable of the current call frame, likewise
+    LoadGlobal(usize), // Load a global variable, from inside a function
+    StoreGlobal(usize), // Store a value to a global variable, from inside a function
+
+    // Arrays live in the VM's heap; the stack holds a handle to each
+    NewArray(usize), // Pop N elements, first pushed first, and push a handle to a new array of them
+    LoadIndex,       // Pop an index and an array handle, push the element
+    StoreIndex,      // Pop a value, an index and an array handle, store the value and push it
 
     // Parallel execution
-    Spawn,   // Spawn a new thread/task
+    Spawn, // Spawn a new thread/task
+    /// Run the code region at the address on a new thread, handing it the top
+    /// N values of the stack (its captured variables). The region ends in
+    /// `Return`, whose value `Sync` collects like that of `Spawn`.
+    SpawnBlock(usize, usize),
     Sync,    // Synchronize all threads/tasks
     Barrier, // Wait at a barrier for all threads
 
-    // Control flow
+    // Control flow. The conditional jumps pop the top of the stack and test
+    // its truthiness: false and the number zero are falsy, every other value truthy
     Jump(usize),          // Unconditional jump
-    JumpIfZero(usize),    // Jump if top of stack is zero
-    JumpIfNotZero(usize), // Jump if top of stack is not zero
+    JumpIfZero(usize),    // Pop, and jump if the value is falsy
+    JumpIfNotZero(usize), // Pop, and jump if the value is truthy
 
     // Stack operations
-    Pop, // Pop value from stack
-    Dup, // Duplicate top of stack
+    Pop,       // Pop value from stack
+    Dup,       // Duplicate top of stack
+    Swap,      // Exchange the top two values
+    Over,      // Push a copy of the value beneath the top
+    Rot,       // Move the third value from the top to the top
+    PopN(u16), // Pop this many values
+    DupN(u16), // Push this many more copies of the top of stack
 
     // Function calls
-    Call(String, usize), // Call function by name with N arguments
-    Return,              // Return from function
+    /// Call a function by name with N arguments, pushed first to last by the caller.
+    /// A user function is entered with the return address beneath its arguments,
+    /// so its prologue pops the arguments last to first into the parameter slots
+    /// and `Return` then finds the address under the result.
+    Call(String, usize),
+    /// A `Call` in tail position: the arguments are on top of the stack, above
+    /// the current call's return address, so entering the function without
+    /// pushing another address makes its `Return` go straight to that caller.
+    TailCall(String, usize),
+    /// A `Call` of the function at this index of the program's call targets,
+    /// which `VM::from_program` resolves named calls into.
+    CallIdx(u32, usize),
+    Return, // Return from function
 
     // Halt
     Halt, // Stop execution
+    // Stop execution with this exit status
+    HaltWith(#[cfg_attr(feature = "serde", serde(with = "crate::float_serde"))] f64),
+}
+
+/// Formats the instruction in the assembly syntax of `compiler::asm`, with
+/// jump targets as raw addresses.
+impl std::fmt::Display for Bytecode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use crate::compiler::asm;
+        write!(f, "{}", asm::mnemonic(self))?;
+        match self {
+            Bytecode::LoadConst(value) | Bytecode::HaltWith(value) => write!(f, " {}", value),
+            Bytecode::LoadBool(value) => write!(f, " {}", value),
+            Bytecode::LoadConstIdx(index) | Bytecode::LoadStrIdx(index) => write!(f, " {}", index),
+            Bytecode::LoadStr(text) => write!(f, " {}", asm::quote(text)),
+            Bytecode::NewArray(len) => write!(f, " {}", len),
+            Bytecode::PopN(count) | Bytecode::DupN(count) => write!(f, " {}", count),
+            Bytecode::LoadVar(slot)
+            | Bytecode::StoreVar(slot)
+            | Bytecode::LoadGlobal(slot)
+            | Bytecode::StoreGlobal(slot) => write!(f, " {}", slot),
+            Bytecode::Jump(target)
+            | Bytecode::JumpIfZero(target)
+            | Bytecode::JumpIfNotZero(target) => {
+                write!(f, " {}", target)
+            }
+            Bytecode::Call(name, argc) | Bytecode::TailCall(name, argc) => {
+                write!(f, " {} {}", name, argc)
+            }
+            Bytecode::CallIdx(index, argc) => write!(f, " {} {}", index, argc),
+            Bytecode::SpawnBlock(start, captures) => write!(f, " {} {}", start, captures),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// What went wrong when a `RuntimeError` stopped the VM.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeErrorKind {
+    /// An instruction needed more operands than the stack held.
+    StackUnderflow,
+    /// `LoadVar` read a memory slot that was never stored to.
+    UndefinedVariable(usize),
+    /// `Div` or `Mod` with a zero divisor, or `Pow` of zero to a negative power.
+    DivisionByZero,
+    /// `LoadConstIdx` named an index past the end of the constant pool.
+    UndefinedConstant(usize),
+    /// `LoadStrIdx` named an index past the end of the string table.
+    UndefinedString(usize),
+    /// `CallIdx` named an index past the end of the call targets.
+    UndefinedCallTarget(usize),
+    /// `LoadIndex` or `StoreIndex` on a value of the named type, which is no array.
+    NotAnArray(&'static str),
+    /// An array index past the end.
+    IndexOutOfBounds { index: f64, len: usize },
+    /// An array index that is negative, fractional, NaN or infinite.
+    BadIndex(f64),
+    /// `Return` found no valid return address beneath the result.
+    InvalidReturn,
+    /// A call of `name` would nest more than `limit` user function calls.
+    CallDepthExceeded { name: String, limit: usize },
+    /// A bitwise operand that is NaN, infinite or outside the i64 range.
+    NotAnInteger(f64),
+    /// A shift by a negative amount or by 64 bits or more.
+    InvalidShift(i64),
+    /// A binary operator applied to operands of types it has no meaning for.
+    TypeMismatch {
+        op: &'static str,
+        lhs: &'static str,
+        rhs: &'static str,
+    },
+    /// A unary operator applied to an operand of a type it has no meaning for.
+    UnaryTypeMismatch {
+        op: &'static str,
+        operand: &'static str,
+    },
+    /// A native function registered with a fixed arity was called with a
+    /// different number of arguments.
+    ArityMismatch {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    /// Native function `name` returned an error.
+    NativeFailed { name: String, message: String },
+    /// The fuel given with `set_fuel` ran out, `executed` instructions into
+    /// the run. The instruction at `pc` has not run yet.
+    OutOfFuel { executed: u64 },
+    /// A push would have taken the stack past `limit` values.
+    StackOverflow { limit: usize },
+    /// `snapshot` was asked for with this many spawned threads not yet synced.
+    UnsyncedThreads(usize),
+    /// `set_var` named a variable the program has no global of.
+    UnknownVariable(String),
+}
+
+/// An error that stops execution, with the instruction that raised it and,
+/// when the program carries a source map, the span of the code it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
+    pub pc: usize,
+    pub span: Option<Span>,
+}
+
+/// The error of `VM::try_run`: every runtime error, its faulting `pc`
+/// included, is a `RuntimeError`, and `VmError` another name for it.
+pub type VmError = RuntimeError;
+
+impl RuntimeError {
+    /// The error message, located by line and column in `source` when the span is known.
+    pub fn describe(&self, source: &str) -> String {
+        match self.span {
+            Some(span) => {
+                let (line, column) = Scanner::new(source).line_col(span.start);
+                format!("{} at line {}, column {}", self.kind, line, column)
+            }
+            None => self.to_string(),
+        }
+    }
 }
 
-pub type NativeFn = dyn Fn(&[f64]) -> f64 + 'static;
+/// What happened on a `step` of the VM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    /// An instruction ran and there is more to run.
+    Continued,
+    /// The program has stopped, at a `Halt` or `HaltWith` or by running off
+    /// the end of the code; `pc` is left where it stopped.
+    Halted,
+    /// A `Return` ran, handing control back to the caller.
+    Returned,
+}
+
+/// A place `run_until_break` stops at.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Breakpoint {
+    /// The instruction at this address, before it runs.
+    Address(usize),
+    /// The first instruction of the named user function, on every call.
+    Function(String),
+}
+
+impl std::fmt::Display for RuntimeErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuntimeErrorKind::StackUnderflow => write!(f, "Stack is empty"),
+            RuntimeErrorKind::UndefinedVariable(slot) => {
+                write!(f, "Variable not found in memory (slot {})", slot)
+            }
+            RuntimeErrorKind::DivisionByZero => write!(f, "Division by zero"),
+            RuntimeErrorKind::UndefinedConstant(index) => {
+                write!(f, "Constant {} is not in the constant pool", index)
+            }
+            RuntimeErrorKind::UndefinedString(index) => {
+                write!(f, "String {} is not in the string table", index)
+            }
+            RuntimeErrorKind::UndefinedCallTarget(index) => {
+                write!(f, "Call target {} is not in the call targets", index)
+            }
+            RuntimeErrorKind::NotAnArray(found) => write!(f, "Cannot index a {}", found),
+            RuntimeErrorKind::IndexOutOfBounds { index, len } => write!(
+                f,
+                "Index {} is out of bounds for an array of length {}",
+                index, len
+            ),
+            RuntimeErrorKind::BadIndex(index) => {
+                write!(f, "Index {} is not a non-negative integer", index)
+            }
+            RuntimeErrorKind::InvalidReturn => write!(f, "Return without a return address"),
+            RuntimeErrorKind::CallDepthExceeded { name, limit } => {
+                write!(f, "Calling '{}' would nest more than {} calls", name, limit)
+            }
+            RuntimeErrorKind::NotAnInteger(value) => {
+                write!(f, "{} cannot be used as a 64-bit integer", value)
+            }
+            RuntimeErrorKind::InvalidShift(amount) => {
+                write!(f, "Cannot shift by {} bits", amount)
+            }
+            RuntimeErrorKind::TypeMismatch { op, lhs, rhs } => {
+                write!(f, "Cannot apply '{}' to a {} and a {}", op, lhs, rhs)
+            }
+            RuntimeErrorKind::UnaryTypeMismatch { op, operand } => {
+                write!(f, "Cannot apply '{}' to a {}", op, operand)
+            }
+            RuntimeErrorKind::ArityMismatch {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Function '{}' takes {} argument(s) but {} were given",
+                name, expected, found
+            ),
+            RuntimeErrorKind::NativeFailed { name, message } => {
+                write!(f, "Native function '{}' failed: {}", name, message)
+            }
+            RuntimeErrorKind::OutOfFuel { executed } => {
+                write!(f, "Out of fuel after {} instructions", executed)
+            }
+            RuntimeErrorKind::StackOverflow { limit } => {
+                write!(f, "Stack overflow: more than {} values", limit)
+            }
+            RuntimeErrorKind::UnsyncedThreads(count) => {
+                write!(
+                    f,
+                    "Cannot snapshot with {} spawned thread(s) not synced",
+                    count
+                )
+            }
+            RuntimeErrorKind::UnknownVariable(name) => {
+                write!(f, "The program has no global variable '{}'", name)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.span {
+            Some(span) => write!(f, "{} at position {}", self.kind, span.start),
+            None => write!(f, "{} at instruction {}", self.kind, self.pc),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// The elements of an array value.
+pub type Array = Rc<RefCell<Vec<Value>>>;
+
+/// A value on the VM's stack or in its memory.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Num(f64),
+    Bool(bool),
+    Str(Rc<str>),
+    /// Shared by every copy of the value, so a write through one is seen by all.
+    Array(Array),
+    Unit,
+}
+
+impl Value {
+    /// The number this value holds, if it is one.
+    pub fn as_num(&self) -> Option<f64> {
+        match self {
+            Value::Num(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Whether conditional jumps treat this value as true: everything but
+    /// `false` and the number zero (either sign) is. NaN is truthy.
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::Bool(value) => *value,
+            Value::Num(value) => *value != 0.0,
+            _ => true,
+        }
+    }
+
+    /// The name of this value's type, as runtime errors report it.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Num(_) => "number",
+            Value::Bool(_) => "bool",
+            Value::Str(_) => "string",
+            Value::Array(_) => "array",
+            Value::Unit => "unit",
+        }
+    }
+}
+
+/// Arrays are equal only when they are the same array; other values of the
+/// same type compare by content, and values of different types are unequal.
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Num(a), Value::Num(b)) => a == b,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::Array(a), Value::Array(b)) => Rc::ptr_eq(a, b),
+            (Value::Unit, Value::Unit) => true,
+            _ => false,
+        }
+    }
+}
+
+impl PartialEq<f64> for Value {
+    fn eq(&self, other: &f64) -> bool {
+        self.as_num() == Some(*other)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Num(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(text: &str) -> Self {
+        Value::Str(text.into())
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(elements: Vec<Value>) -> Self {
+        Value::Array(Rc::new(RefCell::new(elements)))
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Num(value) => write!(f, "{}", value),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Str(text) => f.write_str(text),
+            Value::Array(elements) => write_array(f, elements, &mut Vec::new()),
+            Value::Unit => f.write_str("()"),
+        }
+    }
+}
+
+/// Write `array` as `[a, b]`, with `[...]` in place of an array inside
+/// itself. `open` holds the arrays `array` is an element of.
+fn write_array(
+    f: &mut std::fmt::Formatter<'_>,
+    array: &Array,
+    open: &mut Vec<*const RefCell<Vec<Value>>>,
+) -> std::fmt::Result {
+    if open.contains(&Rc::as_ptr(array)) {
+        return f.write_str("[...]");
+    }
+    open.push(Rc::as_ptr(array));
+    f.write_str("[")?;
+    for (i, element) in array.borrow().iter().enumerate() {
+        if i > 0 {
+            f.write_str(", ")?;
+        }
+        match element {
+            Value::Array(inner) => write_array(f, inner, open)?,
+            other => write!(f, "{}", other)?,
+        }
+    }
+    open.pop();
+    f.write_str("]")
+}
+
+/// Variables by memory slot. The compiler hands out dense slots from 0, so
+/// they are kept in a flat vector, grown on demand for bytecode that uses
+/// slots beyond the size it was made with. A slot never stored to holds
+/// `None`, which `LoadVar` reports as `UndefinedVariable`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Slots {
+    values: Vec<Option<Value>>,
+}
+
+impl Slots {
+    pub fn new() -> Self {
+        Slots { values: Vec::new() }
+    }
+
+    /// Slots with room for `len` variables before any has to grow.
+    pub fn with_len(len: usize) -> Self {
+        Slots {
+            values: vec![None; len],
+        }
+    }
+
+    /// The value in `slot`, if it has been stored to.
+    pub fn get(&self, slot: usize) -> Option<&Value> {
+        self.values.get(slot)?.as_ref()
+    }
+
+    /// Store `value` in `slot`.
+    pub fn insert(&mut self, slot: usize, value: Value) {
+        if slot >= self.values.len() {
+            self.values.resize(slot + 1, None);
+        }
+        self.values[slot] = Some(value);
+    }
+
+    /// Forget every variable, keeping the room for them.
+    pub fn clear(&mut self) {
+        self.values.fill(None);
+    }
+
+    /// The values of the slots that have been stored to, by slot.
+    pub fn values(&self) -> impl Iterator<Item = &Value> {
+        self.values.iter().flatten()
+    }
+}
+
+impl std::ops::Index<usize> for Slots {
+    type Output = Value;
+
+    fn index(&self, slot: usize) -> &Value {
+        self.get(slot)
+            .unwrap_or_else(|| panic!("slot {} was never stored to", slot))
+    }
+}
+
+/// A deep copy of some values that can be sent to another thread, which the
+/// `Rc`s in a `Value` cannot. Arrays are copied once each into a table, so
+/// the copies share elements and contain themselves where the originals do.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct SentValues {
+    values: Vec<SentValue>,
+    arrays: Vec<Vec<SentValue>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+enum SentValue {
+    Num(#[cfg_attr(feature = "serde", serde(with = "crate::float_serde"))] f64),
+    Bool(bool),
+    Str(String),
+    /// An index into the table of arrays
+    Array(usize),
+    Unit,
+}
+
+impl SentValues {
+    fn new(values: &[Value]) -> Self {
+        let mut sent = SentValues {
+            values: Vec::new(),
+            arrays: Vec::new(),
+        };
+        let mut copied = HashMap::new();
+        sent.values = values
+            .iter()
+            .map(|value| sent.copy(value, &mut copied))
+            .collect();
+        sent
+    }
+
+    /// `copied` maps each array already in the table to its index there.
+    fn copy(
+        &mut self,
+        value: &Value,
+        copied: &mut HashMap<*const RefCell<Vec<Value>>, usize>,
+    ) -> SentValue {
+        match value {
+            Value::Num(value) => SentValue::Num(*value),
+            Value::Bool(value) => SentValue::Bool(*value),
+            Value::Str(text) => SentValue::Str(text.to_string()),
+            Value::Array(elements) => {
+                if let Some(&index) = copied.get(&Rc::as_ptr(elements)) {
+                    return SentValue::Array(index);
+                }
+                let index = self.arrays.len();
+                copied.insert(Rc::as_ptr(elements), index);
+                self.arrays.push(Vec::new());
+                let copy = elements
+                    .borrow()
+                    .iter()
+                    .map(|element| self.copy(element, copied))
+                    .collect();
+                self.arrays[index] = copy;
+                SentValue::Array(index)
+            }
+            Value::Unit => SentValue::Unit,
+        }
+    }
+
+    fn into_values(self) -> Vec<Value> {
+        let arrays: Vec<Array> = self.arrays.iter().map(|_| Array::default()).collect();
+        let value = |sent: SentValue| match sent {
+            SentValue::Num(value) => Value::Num(value),
+            SentValue::Bool(value) => Value::Bool(value),
+            SentValue::Str(text) => Value::Str(text.into()),
+            SentValue::Array(index) => Value::Array(Rc::clone(&arrays[index])),
+            SentValue::Unit => Value::Unit,
+        };
+        for (array, elements) in arrays.iter().zip(self.arrays) {
+            *array.borrow_mut() = elements.into_iter().map(value).collect();
+        }
+        self.values.into_iter().map(value).collect()
+    }
+}
+
+/// The state of a VM's execution, taken by `VM::snapshot` and continued from
+/// by `VM::restore`: the program counter, the stack, the globals and the
+/// locals of each active call. Values are copied as for a spawned block, so
+/// arrays shared between them are shared again after the restore.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VmSnapshot {
+    pc: usize,
+    stack_len: usize,
+    /// Which slots of the globals, then of each frame, have been stored to.
+    stored: Vec<Vec<bool>>,
+    /// The stack, then the value of each stored slot, in order.
+    values: SentValues,
+}
+
+/// Why a native function failed; the VM reports it as `NativeFailed`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NativeError {
+    pub message: String,
+}
+
+impl NativeError {
+    pub fn new(message: impl Into<String>) -> Self {
+        NativeError {
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for NativeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for NativeError {}
+
+/// What a native function may reach of the VM calling it: the global
+/// variables, by slot, and the output `print` writes to.
+pub struct NativeCtx<'a> {
+    globals: &'a mut Slots,
+    output: &'a mut dyn Write,
+}
+
+impl<'a> NativeCtx<'a> {
+    pub(crate) fn new(globals: &'a mut Slots, output: &'a mut dyn Write) -> Self {
+        NativeCtx { globals, output }
+    }
+
+    /// Where the program's output goes: standard output unless the VM was
+    /// given another with `set_output`.
+    pub fn output(&mut self) -> &mut dyn Write {
+        self.output
+    }
+
+    /// The value of the global variable in `slot`, if it has been assigned.
+    pub fn global(&self, slot: usize) -> Option<&Value> {
+        self.globals.get(slot)
+    }
+
+    /// Assign `value` to the global variable in `slot`.
+    pub fn set_global(&mut self, slot: usize, value: Value) {
+        self.globals.insert(slot, value);
+    }
+}
+
+pub type NativeFn = dyn Fn(&mut NativeCtx, &[Value]) -> Result<Value, NativeError> + 'static;
+
+/// Wrap `f`, which takes numbers only, as a native function. Passing it
+/// anything but a number fails.
+pub fn numeric_native(f: impl Fn(&[f64]) -> f64 + 'static) -> Rc<NativeFn> {
+    Rc::new(move |_: &mut NativeCtx, args: &[Value]| {
+        let numbers = args
+            .iter()
+            .map(|arg| match arg {
+                Value::Num(value) => Ok(*value),
+                other => Err(NativeError::new(format!(
+                    "expected numbers, found a {}",
+                    other.type_name()
+                ))),
+            })
+            .collect::<Result<Vec<f64>, NativeError>>()?;
+        Ok(Value::Num(f(&numbers)))
+    })
+}
+
+/// A native function registered with a VM, and the number of arguments it
+/// takes when that is fixed.
+#[derive(Clone)]
+struct Native {
+    arity: Option<usize>,
+    function: Rc<NativeFn>,
+}
+
+/// Where a call target leads in a particular VM. A native registered after
+/// the program was loaded shadows a user function, as it would for `Call`.
+#[derive(Clone)]
+enum Callee {
+    /// This native function.
+    Native(Native),
+    /// The user function starting at this address.
+    User(usize),
+    /// Neither; like a `Call` of an unknown name, the call is skipped.
+    Unknown,
+}
+
+/// A hook `set_trace` runs before each instruction, given its address, the
+/// instruction and the stack it starts from.
+pub type TraceFn = dyn FnMut(usize, &Bytecode, &[Value]) + 'static;
+
+/// One instruction of a run, as seen by a trace hook.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEntry {
+    pub pc: usize,
+    pub instruction: Bytecode,
+    pub stack: Vec<Value>,
+}
+
+/// Records every instruction a VM runs, through the hook from `hook`.
+#[derive(Debug, Clone, Default)]
+pub struct TraceCollector {
+    entries: Rc<RefCell<Vec<TraceEntry>>>,
+}
+
+impl TraceCollector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A hook for `VM::set_trace` that records into this collector.
+    pub fn hook(&self) -> impl FnMut(usize, &Bytecode, &[Value]) + 'static {
+        let entries = Rc::clone(&self.entries);
+        move |pc, instruction, stack| {
+            entries.borrow_mut().push(TraceEntry {
+                pc,
+                instruction: instruction.clone(),
+                stack: stack.to_vec(),
+            })
+        }
+    }
+
+    /// The instructions recorded so far, in the order they ran.
+    pub fn entries(&self) -> Vec<TraceEntry> {
+        self.entries.borrow().clone()
+    }
+}
 
 // Define a struct for the VM
 pub struct VM {
-    pub stack: Vec<f64>, // Stack for the VM (changed to f64 for signed integers)
-    pub memory: HashMap<usize, f64>, // Memory for the VM (changed to f64 for signed integers)
-    pub pc: usize,       // Program counter
+    pub stack: Vec<Value>,       // Stack for the VM
+    pub memory: Slots,           // Memory for the VM
+    pub frames: Vec<Slots>,      // locals of each active user function call, innermost last
+    pub pc: usize,               // Program counter
     pub bytecode: Vec<Bytecode>, // Bytecode instructions
-    pub threads: Vec<thread::JoinHandle<()>>, // Threads for parallel execution
-    pub receivers: Vec<Receiver<f64>>, // Receivers for thread results (changed to f64 for signed integers)
+    pub threads: Vec<thread::JoinHandle<Result<(), RuntimeError>>>, // Threads for parallel execution
+    receivers: Vec<Receiver<SentValues>>,                           // Receivers for thread results
+    #[deprecated(note = "build the VM with `VM::from_program`, which fills in the function table")]
     pub user_functions: HashMap<String, usize>, // name -> bytecode address
-    // NOTE: Do NOT derive Debug for VM, because native_functions cannot be Debug
-    pub native_functions:
-        std::collections::HashMap<String, std::rc::Rc<dyn Fn(&[f64]) -> f64 + 'static>>, // name -> native fn
+    // Natives are closures, which have no Debug; see the impl below
+    native_functions: HashMap<String, Native>, // name -> native fn
+    call_targets: Vec<CallTarget>,             // functions `CallIdx` indexes into
+    callees: Vec<Callee>,                      // where each call target leads
+    pub spans: Vec<Option<Span>>,              // source span of each instruction, when known
+    pub constants: Vec<f64>,                   // constant pool `LoadConstIdx` indexes into
+    pub strings: Vec<String>,                  // string table `LoadStrIdx` indexes into
+    pub max_call_depth: usize, // most user function calls that may be active at once
+    pub max_stack: usize,      // most values the stack may hold at once
+    output: Box<dyn Write>,    // where `print` writes
+    fuel: Option<u64>,         // instructions left to run, when limited
+    executed: u64,             // instructions run so far
+    breakpoints: BTreeSet<Breakpoint>, // where `run_until_break` stops
+    paused: Option<(usize, u64)>, // pc and instruction count at the last breakpoint hit
+    trace: Option<Box<TraceFn>>, // run before each instruction, when set
+    exit_code: Option<f64>,    // the status `HaltWith` stopped with
+    profile: Option<Box<Profiler>>, // what has run, when profiling
+}
+
+/// Format `print` arguments the way the built-in prints them: separated by
+/// spaces, on a line of their own.
+fn format_print_args<T: std::fmt::Display>(args: &[T]) -> String {
+    let words: Vec<String> = args.iter().map(T::to_string).collect();
+    words.join(" ") + "\n"
+}
+
+/// A `print` native that writes to `out` instead of standard output.
+pub fn print_to<W: Write + 'static>(out: Rc<RefCell<W>>) -> Rc<NativeFn> {
+    Rc::new(move |_: &mut NativeCtx, args: &[Value]| {
+        // Like `print!`, a failed write is not the program's concern
+        let _ = out
+            .borrow_mut()
+            .write_all(format_print_args(args).as_bytes());
+        Ok(Value::Num(0.0))
+    })
+}
+
+/// The quotient of `a` by `b` truncated toward zero, as `IDiv` gives it.
+pub(crate) fn integer_division(a: f64, b: f64) -> f64 {
+    (a / b).trunc()
+}
+
+/// The numbers in `args`, which must be exactly `count` of them.
+fn number_args(args: &[Value], count: usize) -> Result<Vec<f64>, NativeError> {
+    if args.len() != count {
+        return Err(NativeError::new(format!(
+            "expected {} argument(s), found {}",
+            count,
+            args.len()
+        )));
+    }
+    args.iter()
+        .map(|arg| match arg {
+            Value::Num(value) => Ok(*value),
+            other => Err(NativeError::new(format!(
+                "expected numbers, found a {}",
+                other.type_name()
+            ))),
+        })
+        .collect()
+}
+
+/// A native applying `f` to its one argument.
+fn unary_native(f: fn(f64) -> f64) -> Rc<NativeFn> {
+    Rc::new(move |_: &mut NativeCtx, args: &[Value]| Ok(Value::Num(f(number_args(args, 1)?[0]))))
+}
+
+/// The native functions every VM starts out with.
+pub fn default_natives() -> HashMap<String, Rc<NativeFn>> {
+    let mut native_functions: HashMap<String, Rc<NativeFn>> = HashMap::new();
+    // Example stdlib: print
+    native_functions.insert(
+        "print".to_string(),
+        Rc::new(|ctx: &mut NativeCtx, args: &[Value]| {
+            // Like `print!`, a failed write is not the program's concern
+            let _ = ctx.output().write_all(format_print_args(args).as_bytes());
+            Ok(Value::Num(0.0))
+        }),
+    );
+    // Math functions of one number, which `jit` also compiles
+    let math = [
+        ("cos", f64::cos as fn(f64) -> f64),
+        ("exp", f64::exp),
+        ("sin", f64::sin),
+        ("sqrt", f64::sqrt),
+    ];
+    for (name, f) in math {
+        native_functions.insert(name.to_string(), unary_native(f));
+    }
+    // Until the language has syntax for them, the opcodes below are reached
+    // through these
+    native_functions.insert("floor".to_string(), unary_native(f64::floor));
+    native_functions.insert("ceil".to_string(), unary_native(f64::ceil));
+    native_functions.insert("trunc".to_string(), unary_native(f64::trunc));
+    native_functions.insert(
+        "idiv".to_string(),
+        Rc::new(|_: &mut NativeCtx, args: &[Value]| {
+            let numbers = number_args(args, 2)?;
+            if numbers[1] == 0.0 {
+                return Err(NativeError::new("division by zero"));
+            }
+            Ok(Value::Num(integer_division(numbers[0], numbers[1])))
+        }),
+    );
+    native_functions
+}
+
+/// The state of execution: the stack, variables and function tables, with
+/// natives by name only. `{:#?}` prints one field per line.
+#[allow(deprecated)]
+impl std::fmt::Debug for VM {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let memory: BTreeMap<_, _> = self
+            .memory
+            .values
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, value)| Some((slot, value.as_ref()?)))
+            .collect();
+        let natives: BTreeSet<_> = self.native_functions.keys().collect();
+        let user_functions: BTreeMap<_, _> = self.user_functions.iter().collect();
+        f.debug_struct("VM")
+            .field("pc", &self.pc)
+            .field("stack", &self.stack)
+            .field("memory", &memory)
+            .field("frames", &self.frames.len())
+            .field("natives", &natives)
+            .field("user_functions", &user_functions)
+            .field("threads", &self.threads.len())
+            .field("receivers", &self.receivers.len())
+            .finish_non_exhaustive()
+    }
 }
 
+// The VM itself keeps its function table in the deprecated field
+#[allow(deprecated)]
 impl VM {
     // Create a new VM instance
     pub fn new(bytecode: Vec<Bytecode>) -> Self {
-        let mut native_functions: HashMap<String, Rc<NativeFn>> = HashMap::new();
-        // Example stdlib: print
-        native_functions.insert(
-            "print".to_string(),
-            Rc::new(|args: &[f64]| {
-                for arg in args {
-                    print!("{} ", arg);
-                }
-                println!("");
-                0.0
-            }),
-        );
+        let native_functions = default_natives()
+            .into_iter()
+            .map(|(name, function)| {
+                let arity = None;
+                (name, Native { arity, function })
+            })
+            .collect();
         VM {
             stack: Vec::new(),
-            memory: HashMap::new(),
+            memory: Slots::new(),
+            frames: Vec::new(),
             pc: 0,
             bytecode,
             threads: Vec::new(),
             receivers: Vec::new(),
             user_functions: HashMap::new(),
             native_functions,
+            call_targets: Vec::new(),
+            callees: Vec::new(),
+            spans: Vec::new(),
+            constants: Vec::new(),
+            strings: Vec::new(),
+            max_call_depth: Self::DEFAULT_MAX_CALL_DEPTH,
+            max_stack: Self::DEFAULT_MAX_STACK,
+            output: Box::new(std::io::stdout()),
+            fuel: None,
+            executed: 0,
+            breakpoints: BTreeSet::new(),
+            paused: None,
+            trace: None,
+            exit_code: None,
+            profile: None,
+        }
+    }
+
+    /// Allow at most `fuel` more instructions to run before execution stops
+    /// with `OutOfFuel`, or with `None` any number. After running out, set
+    /// more and `resume`. A block run by `SpawnBlock` gets a budget of its
+    /// own, of the fuel left when it starts.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    /// The fuel left, if it is limited.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// The exit status the program stopped with, if it stopped at a
+    /// `HaltWith`.
+    pub fn exit_code(&self) -> Option<f64> {
+        self.exit_code
+    }
+
+    /// How many instructions this VM has run, across every resumption.
+    pub fn instructions_executed(&self) -> u64 {
+        self.executed
+    }
+
+    /// Run `trace` before each instruction from now on, replacing any hook
+    /// set before. Blocks run by `SpawnBlock` are not traced.
+    pub fn set_trace(&mut self, trace: impl FnMut(usize, &Bytecode, &[Value]) + 'static) {
+        self.trace = Some(Box::new(trace));
+    }
+
+    /// Stop tracing.
+    pub fn clear_trace(&mut self) {
+        self.trace = None;
+    }
+
+    /// Start profiling from the next instruction, discarding any earlier
+    /// profile: see `profile_report`.
+    pub fn enable_profiling(&mut self) {
+        self.profile = Some(Box::default());
+    }
+
+    /// Stop profiling and discard the profile.
+    pub fn disable_profiling(&mut self) {
+        self.profile = None;
+    }
+
+    /// What has run since `enable_profiling`, or `None` when not profiling.
+    pub fn profile_report(&self) -> Option<ProfileReport> {
+        let profile = self.profile.as_ref()?;
+        Some(profile.report(&self.bytecode))
+    }
+
+    /// Send the program's output, that of the built-in `print` included, to
+    /// `out` rather than standard output. Blocks run by `SpawnBlock` still
+    /// print to standard output.
+    pub fn set_output(&mut self, out: impl Write + 'static) {
+        self.output = Box::new(out);
+    }
+
+    /// Register `f`, which takes numbers only, as the native function `name`,
+    /// replacing any native of that name. With `arity`, a call passing a
+    /// different number of arguments fails with `ArityMismatch`.
+    pub fn register_native(
+        &mut self,
+        name: &str,
+        arity: Option<usize>,
+        f: impl Fn(&[f64]) -> f64 + 'static,
+    ) {
+        let function = numeric_native(f);
+        self.native_functions
+            .insert(name.to_string(), Native { arity, function });
+        self.resolve_callees();
+    }
+
+    /// Add `natives`, each taking any number of arguments, replacing natives
+    /// of the same names.
+    pub fn with_natives(
+        mut self,
+        natives: impl IntoIterator<Item = (String, Rc<NativeFn>)>,
+    ) -> Self {
+        for (name, function) in natives {
+            let arity = None;
+            self.native_functions
+                .insert(name, Native { arity, function });
         }
+        self.resolve_callees();
+        self
     }
 
-    // Execute the bytecode instructions
+    /// Work out where each call target leads, from the natives and user
+    /// functions registered now.
+    fn resolve_callees(&mut self) {
+        self.callees = self
+            .call_targets
+            .iter()
+            .map(|target| {
+                let name = target.name();
+                if let Some(native) = self.native_functions.get(name) {
+                    Callee::Native(native.clone())
+                } else if let Some(&addr) = self.user_functions.get(name) {
+                    Callee::User(addr)
+                } else {
+                    Callee::Unknown
+                }
+            })
+            .collect();
+    }
+
+    /// How many user function calls may be active at once unless
+    /// `set_max_call_depth` says otherwise.
+    pub const DEFAULT_MAX_CALL_DEPTH: usize = 10_000;
+
+    /// Fail with `CallDepthExceeded` instead of making a call that would nest
+    /// more than `limit` user function calls. Tail calls do not nest.
+    pub fn set_max_call_depth(&mut self, limit: usize) {
+        self.max_call_depth = limit;
+    }
+
+    /// How many values the stack may hold unless `set_max_stack` says
+    /// otherwise.
+    pub const DEFAULT_MAX_STACK: usize = 4_000_000;
+
+    /// Fail with `StackOverflow` instead of pushing a value onto a stack that
+    /// already holds `limit`.
+    pub fn set_max_stack(&mut self, limit: usize) {
+        self.max_stack = limit;
+    }
+
+    // Execute the bytecode instructions, panicking on a runtime error
     pub fn execute(&mut self) {
+        if let Err(error) = self.try_execute() {
+            panic!("{}", error);
+        }
+    }
+
+    /// Continue execution from `pc`, e.g. after running out of fuel.
+    pub fn resume(&mut self) -> Result<(), RuntimeError> {
+        self.try_execute()
+    }
+
+    /// Execute the bytecode instructions from `pc`, stopping at the first
+    /// runtime error.
+    pub fn try_execute(&mut self) -> Result<(), RuntimeError> {
+        while self.step()? != StepOutcome::Halted {}
+        Ok(())
+    }
+
+    /// The instruction at `pc`, which `step` runs next, or `None` past the end
+    /// of the code.
+    pub fn current_instruction(&self) -> Option<&Bytecode> {
+        self.bytecode.get(self.pc)
+    }
+
+    /// How many user function calls are active.
+    pub fn call_depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// The variables the current instruction sees as locals: those of the
+    /// innermost active call, or the globals outside any call.
+    pub fn locals(&self) -> &Slots {
+        self.frames.last().unwrap_or(&self.memory)
+    }
+
+    /// Stop `run_until_break` before the instruction at `pc` runs.
+    pub fn add_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.insert(Breakpoint::Address(pc));
+    }
+
+    /// Stop `run_until_break` on entry to user function `name`.
+    pub fn add_function_breakpoint(&mut self, name: &str) {
+        self.breakpoints
+            .insert(Breakpoint::Function(name.to_string()));
+    }
+
+    /// Remove `breakpoint`, returning whether it was set.
+    pub fn remove_breakpoint(&mut self, breakpoint: &Breakpoint) -> bool {
+        self.breakpoints.remove(breakpoint)
+    }
+
+    /// The breakpoints set, addresses first.
+    pub fn breakpoints(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.breakpoints.iter()
+    }
+
+    /// The breakpoint set at the instruction at `pc`, if any.
+    fn breakpoint_at_pc(&self) -> Option<Breakpoint> {
+        let address = Breakpoint::Address(self.pc);
+        if self.breakpoints.contains(&address) {
+            return Some(address);
+        }
+        self.breakpoints
+            .iter()
+            .find(|breakpoint| match breakpoint {
+                Breakpoint::Function(name) => self.user_functions.get(name) == Some(&self.pc),
+                Breakpoint::Address(_) => false,
+            })
+            .cloned()
+    }
+
+    /// Execute from `pc` until arriving at a breakpoint, which is returned
+    /// with the instruction there not yet run, or until the program stops,
+    /// which gives `None`. Running on from a breakpoint does not stop at it
+    /// again until execution comes back to it.
+    pub fn run_until_break(&mut self) -> Result<Option<Breakpoint>, RuntimeError> {
+        let mut arrived = self.paused != Some((self.pc, self.executed));
+        loop {
+            if arrived {
+                if let Some(breakpoint) = self.breakpoint_at_pc() {
+                    self.paused = Some((self.pc, self.executed));
+                    return Ok(Some(breakpoint));
+                }
+            }
+            if self.step()? == StepOutcome::Halted {
+                return Ok(None);
+            }
+            arrived = true;
+        }
+    }
+
+    /// Run the single instruction at `pc`.
+    pub fn step(&mut self) -> Result<StepOutcome, RuntimeError> {
         macro_rules! binop {
             ($self:ident, $op:tt) => {{
-                let b = $self.stack.pop().unwrap_or_else(|| panic!("Stack is empty"));
-                let a = $self.stack.pop().unwrap_or_else(|| panic!("Stack is empty"));
-                $self.stack.push(a $op b);
+                let (a, b) = $self.pop_numbers(stringify!($op))?;
+                $self.push(Value::Num(a $op b))?;
+                $self.pc += 1;
+            }};
+        }
+
+        macro_rules! cmpop {
+            ($self:ident, $op:tt) => {{
+                let (a, b) = $self.pop_numbers(stringify!($op))?;
+                $self.push(Value::Bool(a $op b))?;
                 $self.pc += 1;
             }};
         }
@@ -106,206 +1182,1243 @@ impl VM {
             }};
         }
 
-        while self.pc < self.bytecode.len() {
-            match &self.bytecode[self.pc] {
-                Bytecode::Neg => stackop!(self, {
-                    if let Some(val) = self.stack.pop() {
-                        self.stack.push(-val); // Updated to use f64 directly
-                    } else {
-                        panic!("Stack is empty");
+        if self.pc >= self.bytecode.len() {
+            return Ok(StepOutcome::Halted);
+        }
+        if let Some(fuel) = &mut self.fuel {
+            if *fuel == 0 {
+                let executed = self.executed;
+                return Err(self.error(RuntimeErrorKind::OutOfFuel { executed }));
+            }
+            *fuel -= 1;
+        }
+        self.executed += 1;
+        if let Some(trace) = &mut self.trace {
+            trace(self.pc, &self.bytecode[self.pc], &self.stack);
+        }
+        if let Some(profile) = &mut self.profile {
+            profile.count(self.pc, &self.bytecode[self.pc]);
+        }
+        match &self.bytecode[self.pc] {
+            Bytecode::Neg => stackop!(self, {
+                let val = match self.pop()? {
+                    Value::Num(val) => val,
+                    other => {
+                        let operand = other.type_name();
+                        let kind = RuntimeErrorKind::UnaryTypeMismatch { op: "-", operand };
+                        return Err(self.error(kind));
                     }
-                }),
-                Bytecode::Add => binop!(self, +),
-                Bytecode::Sub => binop!(self, -),
-                Bytecode::Mul => binop!(self, *),
-                Bytecode::Div => binop!(self, /),
-                Bytecode::LoadConst(value) => stackop!(self, {
-                    self.stack.push(*value);
-                }),
-                Bytecode::LoadVar(index) => stackop!(self, {
-                    if let Some(value) = self.memory.get(&index) {
-                        self.stack.push(*value);
-                    } else {
-                        panic!("Variable not found in memory");
+                };
+                self.push(Value::Num(-val))?;
+            }),
+            Bytecode::Add => stackop!(self, {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                let sum = match (&a, &b) {
+                    (Value::Num(a), Value::Num(b)) => Value::Num(a + b),
+                    (Value::Str(a), Value::Str(b)) => Value::Str(format!("{}{}", a, b).into()),
+                    _ => {
+                        return Err(self.error(RuntimeErrorKind::TypeMismatch {
+                            op: "+",
+                            lhs: a.type_name(),
+                            rhs: b.type_name(),
+                        }))
                     }
-                }),
-                Bytecode::StoreVar(index) => stackop!(self, {
-                    if let Some(value) = self.stack.pop() {
-                        self.memory.insert(*index, value);
-                    } else {
-                        panic!("Stack is empty");
+                };
+                self.push(sum)?;
+            }),
+            Bytecode::Sub => binop!(self, -),
+            Bytecode::Mul => binop!(self, *),
+            Bytecode::Div | Bytecode::Mod => stackop!(self, {
+                let is_div = matches!(self.bytecode[self.pc], Bytecode::Div);
+                let (a, b) = self.pop_numbers(if is_div { "/" } else { "%" })?;
+                if b == 0.0 {
+                    return Err(self.error(RuntimeErrorKind::DivisionByZero));
+                }
+                self.push(Value::Num(if is_div { a / b } else { a % b }))?;
+            }),
+            Bytecode::IDiv => stackop!(self, {
+                let (a, b) = self.pop_numbers("idiv")?;
+                if b == 0.0 {
+                    return Err(self.error(RuntimeErrorKind::DivisionByZero));
+                }
+                self.push(Value::Num(integer_division(a, b)))?;
+            }),
+            Bytecode::CheckPow => stackop!(self, {
+                match self.stack.last() {
+                    Some(Value::Num(_)) => {}
+                    Some(other) => {
+                        return Err(self.error(RuntimeErrorKind::TypeMismatch {
+                            op: "**",
+                            lhs: other.type_name(),
+                            rhs: "number",
+                        }))
                     }
-                }),
-                Bytecode::Jump(target) => {
-                    self.pc = *target;
-                }
-                Bytecode::JumpIfZero(target) => {
-                    if let Some(&top) = self.stack.last() {
-                        if top == 0.0 {
-                            self.pc = *target;
-                        } else {
-                            self.pc += 1;
-                        }
-                    } else {
-                        panic!("Stack is empty");
+                    None => return Err(self.error(RuntimeErrorKind::StackUnderflow)),
+                }
+            }),
+            op @ (Bytecode::Floor | Bytecode::Ceil | Bytecode::Trunc) => stackop!(self, {
+                let (name, round): (_, fn(f64) -> f64) = match op {
+                    Bytecode::Floor => ("floor", f64::floor),
+                    Bytecode::Ceil => ("ceil", f64::ceil),
+                    _ => ("trunc", f64::trunc),
+                };
+                let val = match self.pop()? {
+                    Value::Num(val) => val,
+                    other => {
+                        let operand = other.type_name();
+                        let kind = RuntimeErrorKind::UnaryTypeMismatch { op: name, operand };
+                        return Err(self.error(kind));
                     }
+                };
+                self.push(Value::Num(round(val)))?;
+            }),
+            // Values of any two types can be compared for equality
+            Bytecode::Eq | Bytecode::Ne => stackop!(self, {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                let is_eq = matches!(self.bytecode[self.pc], Bytecode::Eq);
+                self.push(Value::Bool((a == b) == is_eq))?;
+            }),
+            Bytecode::Lt => cmpop!(self, <),
+            Bytecode::Le => cmpop!(self, <=),
+            Bytecode::Gt => cmpop!(self, >),
+            Bytecode::Ge => cmpop!(self, >=),
+            Bytecode::Not => stackop!(self, {
+                let value = self.pop()?;
+                self.push(Value::Bool(!value.is_truthy()))?;
+            }),
+            Bytecode::Pow => stackop!(self, {
+                let (a, b) = self.pop_numbers("**")?;
+                // `powf` would give an infinity, where `Div` raises an error
+                if a == 0.0 && b < 0.0 {
+                    return Err(self.error(RuntimeErrorKind::DivisionByZero));
+                }
+                self.push(Value::Num(a.powf(b)))?;
+            }),
+            Bytecode::BitAnd
+            | Bytecode::BitOr
+            | Bytecode::BitXor
+            | Bytecode::Shl
+            | Bytecode::Shr => stackop!(self, {
+                let op = match self.bytecode[self.pc] {
+                    Bytecode::BitAnd => "&",
+                    Bytecode::BitOr => "|",
+                    Bytecode::BitXor => "^",
+                    Bytecode::Shl => "<<",
+                    _ => ">>",
+                };
+                let (a, b) = self.pop_numbers(op)?;
+                let (a, b) = (self.integer(a)?, self.integer(b)?);
+                let is_shift = matches!(self.bytecode[self.pc], Bytecode::Shl | Bytecode::Shr);
+                if is_shift && !(0..64).contains(&b) {
+                    return Err(self.error(RuntimeErrorKind::InvalidShift(b)));
+                }
+                let result = match self.bytecode[self.pc] {
+                    Bytecode::BitAnd => a & b,
+                    Bytecode::BitOr => a | b,
+                    Bytecode::BitXor => a ^ b,
+                    Bytecode::Shl => a << b,
+                    _ => a >> b,
+                };
+                self.push(Value::Num(result as f64))?;
+            }),
+            Bytecode::LoadConst(value) => stackop!(self, {
+                self.push(Value::Num(*value))?;
+            }),
+            &Bytecode::LoadBool(value) => stackop!(self, {
+                self.push(Value::Bool(value))?;
+            }),
+            &Bytecode::LoadConstIdx(index) => stackop!(self, {
+                let index = index as usize;
+                match self.constants.get(index) {
+                    Some(&value) => self.push(Value::Num(value))?,
+                    None => return Err(self.error(RuntimeErrorKind::UndefinedConstant(index))),
+                }
+            }),
+            Bytecode::LoadStr(text) => stackop!(self, {
+                self.push(Value::from(text.as_str()))?;
+            }),
+            &Bytecode::LoadStrIdx(index) => stackop!(self, {
+                let index = index as usize;
+                let Some(text) = self.strings.get(index) else {
+                    return Err(self.error(RuntimeErrorKind::UndefinedString(index)));
+                };
+                self.push(Value::from(text.as_str()))?;
+            }),
+            Bytecode::LoadVar(index) | Bytecode::LoadGlobal(index) => stackop!(self, {
+                let memory = match self.bytecode[self.pc] {
+                    Bytecode::LoadVar(_) => self.frames.last().unwrap_or(&self.memory),
+                    _ => &self.memory,
+                };
+                if let Some(value) = memory.get(*index) {
+                    self.push(value.clone())?;
+                } else {
+                    let slot = *index;
+                    return Err(self.error(RuntimeErrorKind::UndefinedVariable(slot)));
                 }
-                Bytecode::JumpIfNotZero(target) => {
-                    if let Some(&top) = self.stack.last() {
-                        if top != 0.0 {
-                            self.pc = *target;
-                        } else {
-                            self.pc += 1;
+            }),
+            Bytecode::StoreVar(index) => stackop!(self, {
+                let index = *index;
+                let value = self.pop()?;
+                let frame = self.frames.last_mut().unwrap_or(&mut self.memory);
+                frame.insert(index, value);
+            }),
+            Bytecode::StoreGlobal(index) => stackop!(self, {
+                let index = *index;
+                let value = self.pop()?;
+                self.memory.insert(index, value);
+            }),
+            &Bytecode::NewArray(len) => stackop!(self, {
+                if self.stack.len() < len {
+                    return Err(self.error(RuntimeErrorKind::StackUnderflow));
+                }
+                let elements = self.stack.split_off(self.stack.len() - len);
+                self.push(Value::from(elements))?;
+            }),
+            Bytecode::LoadIndex => stackop!(self, {
+                let index = self.pop()?;
+                let handle = self.pop()?;
+                let (array, element) = self.element(&handle, &index)?;
+                let value = array.borrow()[element].clone();
+                self.push(value)?;
+            }),
+            Bytecode::StoreIndex => stackop!(self, {
+                let value = self.pop()?;
+                let index = self.pop()?;
+                let handle = self.pop()?;
+                let (array, element) = self.element(&handle, &index)?;
+                array.borrow_mut()[element] = value.clone();
+                self.push(value)?;
+            }),
+            Bytecode::Jump(target) => {
+                self.pc = *target;
+            }
+            &Bytecode::JumpIfZero(target) | &Bytecode::JumpIfNotZero(target) => {
+                let on_truthy = matches!(self.bytecode[self.pc], Bytecode::JumpIfNotZero(_));
+                if self.pop()?.is_truthy() == on_truthy {
+                    self.pc = target;
+                } else {
+                    self.pc += 1;
+                }
+            }
+            Bytecode::Pop => stackop!(self, {
+                self.pop()?;
+            }),
+            Bytecode::Dup => stackop!(self, {
+                if let Some(top) = self.stack.last() {
+                    self.push(top.clone())?;
+                } else {
+                    return Err(self.error(RuntimeErrorKind::StackUnderflow));
+                }
+            }),
+            &Bytecode::PopN(count) => stackop!(self, {
+                let len = self.underflow_check(count.into())?;
+                self.stack.truncate(len - usize::from(count));
+            }),
+            &Bytecode::DupN(count) => stackop!(self, {
+                if count > 0 {
+                    let len = self.underflow_check(1)?;
+                    for _ in 0..count {
+                        self.push(self.stack[len - 1].clone())?;
+                    }
+                }
+            }),
+            Bytecode::Swap => stackop!(self, {
+                let len = self.underflow_check(2)?;
+                self.stack.swap(len - 2, len - 1);
+            }),
+            Bytecode::Over => stackop!(self, {
+                let len = self.underflow_check(2)?;
+                self.push(self.stack[len - 2].clone())?;
+            }),
+            Bytecode::Rot => stackop!(self, {
+                let len = self.underflow_check(3)?;
+                self.stack[len - 3..].rotate_left(1);
+            }),
+            Bytecode::Call(name, argc) => {
+                // Try native function first
+                if let Some(native) = self.native_functions.get(name) {
+                    self.call_native(native.clone(), *argc)?;
+                } else if let Some(&addr) = self.user_functions.get(name) {
+                    self.call_user(addr, *argc)?;
+                } else {
+                    // Unknown function: skip call without panicking
+                    self.pc += 1;
+                }
+            }
+            &Bytecode::CallIdx(index, argc) => match self.callees.get(index as usize) {
+                Some(Callee::Native(native)) => self.call_native(native.clone(), argc)?,
+                Some(&Callee::User(addr)) => self.call_user(addr, argc)?,
+                Some(Callee::Unknown) => self.pc += 1,
+                None => {
+                    let index = index as usize;
+                    return Err(self.error(RuntimeErrorKind::UndefinedCallTarget(index)));
+                }
+            },
+            Bytecode::TailCall(name, _) => {
+                // The arguments replace the caller's, which its prologue already stored
+                match self.user_functions.get(name) {
+                    Some(&addr) => {
+                        // The call reuses the caller's frame, emptied
+                        if let Some(frame) = self.frames.last_mut() {
+                            frame.clear();
                         }
-                    } else {
-                        panic!("Stack is empty");
+                        if let Some(profile) = &mut self.profile {
+                            profile.exit();
+                            profile.enter(name.clone());
+                        }
+                        self.pc = addr;
+                    }
+                    None => self.pc += 1,
+                }
+            }
+            Bytecode::Return => {
+                // Pop function result and return address, then restore PC and push result
+                let result = self.pop()?;
+                let ret_addr = match self.stack.pop() {
+                    Some(Value::Num(addr))
+                        if addr >= 0.0
+                            && addr.fract() == 0.0
+                            && addr as usize <= self.bytecode.len() =>
+                    {
+                        addr as usize
                     }
+                    _ => return Err(self.error(RuntimeErrorKind::InvalidReturn)),
+                };
+                self.frames.pop();
+                if let Some(profile) = &mut self.profile {
+                    profile.exit();
                 }
-                Bytecode::Pop => stackop!(self, {
-                    self.stack.pop();
-                }),
-                Bytecode::Dup => stackop!(self, {
-                    if let Some(&top) = self.stack.last() {
-                        self.stack.push(top);
-                    } else {
-                        panic!("Stack is empty");
+                self.pc = ret_addr;
+                self.push(result)?;
+                return Ok(StepOutcome::Returned);
+            }
+            Bytecode::Halt => {
+                return Ok(StepOutcome::Halted); // Stop execution
+            }
+            &Bytecode::HaltWith(code) => {
+                self.exit_code = Some(code);
+                return Ok(StepOutcome::Halted);
+            }
+            &Bytecode::Spawn => {
+                // Get the current bytecode value (should be 5 in our test case)
+                let value_to_spawn = if let Some(val) = self.stack.last() {
+                    SentValues::new(std::slice::from_ref(val))
+                } else {
+                    // Default value if stack is empty
+                    SentValues::new(&[Value::Num(0.0)])
+                };
+
+                let (tx, rx) = mpsc::channel::<SentValues>();
+                self.receivers.push(rx);
+
+                let handle = thread::spawn(move || {
+                    // Simulate some computation
+                    tx.send(value_to_spawn).unwrap();
+                    Ok(())
+                });
+
+                self.threads.push(handle);
+                self.pc += 1;
+            }
+            &Bytecode::SpawnBlock(start, captures) => {
+                let base = self.stack.len().saturating_sub(captures);
+                let captured = SentValues::new(&self.stack.split_off(base));
+                let code = self.bytecode.clone();
+                let functions = self.user_functions.clone();
+                let call_targets = self.call_targets.clone();
+                let spans = self.spans.clone();
+                let constants = self.constants.clone();
+                let strings = self.strings.clone();
+                let max_call_depth = self.max_call_depth;
+                let max_stack = self.max_stack;
+                let fuel = self.fuel;
+                // A block spawned from a function reads its captures as locals
+                let in_function = !self.frames.is_empty();
+                // The block works on copies of its captures, arrays included
+                let (tx, rx) = mpsc::channel::<SentValues>();
+                self.receivers.push(rx);
+                let handle = thread::spawn(move || {
+                    // Returning to the end of the code stops the thread's VM
+                    let mut vm = VM::new(code);
+                    vm.user_functions = functions;
+                    // The thread's VM has only the default natives
+                    vm.call_targets = call_targets;
+                    vm.resolve_callees();
+                    vm.spans = spans;
+                    vm.constants = constants;
+                    vm.strings = strings;
+                    vm.max_call_depth = max_call_depth;
+                    vm.max_stack = max_stack;
+                    vm.fuel = fuel;
+                    if in_function {
+                        vm.frames.push(Slots::new());
                     }
-                }),
-                Bytecode::Call(name, argc) => {
-                    // Try native function first
-                    if let Some(native) = self.native_functions.get(name) {
-                        let mut args = Vec::new();
-                        for _ in 0..*argc {
-                            args.push(self.stack.pop().unwrap_or(0.0));
+                    vm.stack.push(Value::Num(vm.bytecode.len() as f64));
+                    vm.stack.extend(captured.into_values());
+                    vm.pc = start;
+                    vm.try_execute()?;
+                    let result = vm.stack.pop().unwrap_or(Value::Num(0.0));
+                    tx.send(SentValues::new(&[result])).unwrap();
+                    Ok(())
+                });
+                self.threads.push(handle);
+                self.pc += 1;
+            }
+            &Bytecode::Sync => {
+                // Clear the main thread's stack before collecting results
+                self.stack.clear();
+
+                // Wait for all threads to finish and collect their results
+                self.join_threads()?;
+                // Retrieve results from receivers
+                for rx in std::mem::take(&mut self.receivers) {
+                    if let Ok(val) = rx.recv() {
+                        for value in val.into_values() {
+                            self.push(value)?;
                         }
-                        args.reverse();
-                        let result = native(&args);
-                        self.stack.push(result);
-                        self.pc += 1;
-                    } else if let Some(&addr) = self.user_functions.get(name) {
-                        // Save return address on value stack
-                        self.stack.push((self.pc + 1) as f64);
-                        // Jump to function address
-                        self.pc = addr;
-                    } else {
-                        // Unknown function: skip call without panicking
-                        self.pc += 1;
                     }
                 }
-                Bytecode::Return => {
-                    // Pop function result and return address, then restore PC and push result
-                    let result = self
-                        .stack
-                        .pop()
-                        .unwrap_or_else(|| panic!("Stack is empty on return"));
-                    let ret_addr = self
-                        .stack
-                        .pop()
-                        .unwrap_or_else(|| panic!("Return address missing on stack"))
-                        as usize;
-                    self.pc = ret_addr;
-                    self.stack.push(result);
-                }
-                Bytecode::Halt => {
-                    println!("Execution halted");
-                    break; // Stop execution
-                }
-                &Bytecode::Spawn => {
-                    // Get the current bytecode value (should be 5 in our test case)
-                    let value_to_spawn = if let Some(&val) = self.stack.last() {
-                        val
-                    } else {
-                        // Default value if stack is empty
-                        0.0
-                    };
+                self.pc += 1;
+            }
+            &Bytecode::Barrier => {
+                // Wait at a barrier for all threads
+                self.join_threads()?;
+                self.pc += 1; // Move to the next instruction
+            }
+        }
+        Ok(StepOutcome::Continued)
+    }
+
+    /// Wait for every spawned thread, failing with the first error one of them
+    /// stopped at once all have finished.
+    fn join_threads(&mut self) -> Result<(), RuntimeError> {
+        let mut failure = None;
+        for thread in self.threads.drain(..) {
+            if let Err(error) = thread.join().unwrap() {
+                failure.get_or_insert(error);
+            }
+        }
+        failure.map_or(Ok(()), Err)
+    }
+
+    /// `value` truncated toward zero, or `NotAnInteger` when no i64 holds it.
+    fn integer(&self, value: f64) -> Result<i64, RuntimeError> {
+        // 2^63 is the first value past the top of the range; NaN fails both tests
+        if value.trunc() >= i64::MIN as f64 && value.trunc() < 9_223_372_036_854_775_808.0 {
+            Ok(value as i64)
+        } else {
+            Err(self.error(RuntimeErrorKind::NotAnInteger(value)))
+        }
+    }
+
+    /// Push `value`, or fail with a stack overflow when the stack is full.
+    fn push(&mut self, value: Value) -> Result<(), RuntimeError> {
+        if self.stack.len() >= self.max_stack {
+            let limit = self.max_stack;
+            return Err(self.error(RuntimeErrorKind::StackOverflow { limit }));
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    /// The length of the stack, or a stack underflow if it holds fewer than
+    /// `count` values.
+    fn underflow_check(&self, count: usize) -> Result<usize, RuntimeError> {
+        if self.stack.len() < count {
+            return Err(self.error(RuntimeErrorKind::StackUnderflow));
+        }
+        Ok(self.stack.len())
+    }
+
+    /// Pop the top of the stack, or fail with a stack underflow.
+    fn pop(&mut self) -> Result<Value, RuntimeError> {
+        match self.stack.pop() {
+            Some(value) => Ok(value),
+            None => Err(self.error(RuntimeErrorKind::StackUnderflow)),
+        }
+    }
+
+    /// Pop the operands of the binary operator `op`, which must both be numbers.
+    fn pop_numbers(&mut self, op: &'static str) -> Result<(f64, f64), RuntimeError> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        match (&a, &b) {
+            (Value::Num(a), Value::Num(b)) => Ok((*a, *b)),
+            _ => Err(self.error(RuntimeErrorKind::TypeMismatch {
+                op,
+                lhs: a.type_name(),
+                rhs: b.type_name(),
+            })),
+        }
+    }
+
+    /// The elements of `array` and the position of element `index` among them.
+    fn element(&self, array: &Value, index: &Value) -> Result<(Array, usize), RuntimeError> {
+        let Value::Array(elements) = array else {
+            return Err(self.error(RuntimeErrorKind::NotAnArray(array.type_name())));
+        };
+        let Value::Num(index) = *index else {
+            return Err(self.error(RuntimeErrorKind::TypeMismatch {
+                op: "[]",
+                lhs: array.type_name(),
+                rhs: index.type_name(),
+            }));
+        };
+        if index < 0.0 || index.fract() != 0.0 {
+            return Err(self.error(RuntimeErrorKind::BadIndex(index)));
+        }
+        let len = elements.borrow().len();
+        if index >= len as f64 {
+            return Err(self.error(RuntimeErrorKind::IndexOutOfBounds { index, len }));
+        }
+        Ok((Rc::clone(elements), index as usize))
+    }
+
+    /// Call `native` on the top `argc` values of the stack, for the `Call` or
+    /// `CallIdx` at `pc`.
+    fn call_native(&mut self, native: Native, argc: usize) -> Result<(), RuntimeError> {
+        if let Some(expected) = native.arity.filter(|&arity| arity != argc) {
+            let kind = RuntimeErrorKind::ArityMismatch {
+                name: self.callee_name(),
+                expected,
+                found: argc,
+            };
+            return Err(self.error(kind));
+        }
+        let base = self.stack.len().saturating_sub(argc);
+        let args = self.stack.split_off(base);
+        let mut ctx = NativeCtx::new(&mut self.memory, &mut *self.output);
+        match (native.function)(&mut ctx, &args) {
+            Ok(result) => self.push(result)?,
+            Err(err) => {
+                let kind = RuntimeErrorKind::NativeFailed {
+                    name: self.callee_name(),
+                    message: err.message,
+                };
+                return Err(self.error(kind));
+            }
+        }
+        self.pc += 1;
+        Ok(())
+    }
+
+    /// Enter the user function at `addr` with the top `argc` values of the
+    /// stack as its arguments, for the `Call` or `CallIdx` at `pc`.
+    fn call_user(&mut self, addr: usize, argc: usize) -> Result<(), RuntimeError> {
+        if self.frames.len() >= self.max_call_depth {
+            let kind = RuntimeErrorKind::CallDepthExceeded {
+                name: self.callee_name(),
+                limit: self.max_call_depth,
+            };
+            return Err(self.error(kind));
+        }
+        // Save return address on value stack, beneath the arguments
+        let base = self.stack.len().saturating_sub(argc);
+        let args = self.stack.split_off(base);
+        self.push(Value::Num((self.pc + 1) as f64))?;
+        for arg in args {
+            self.push(arg)?;
+        }
+        // The call's locals start out empty
+        self.frames.push(Slots::new());
+        if let Some(mut profile) = self.profile.take() {
+            profile.enter(self.callee_name());
+            self.profile = Some(profile);
+        }
+        // Jump to function address
+        self.pc = addr;
+        Ok(())
+    }
+
+    /// The name of the function the call at `pc` calls, for its errors.
+    fn callee_name(&self) -> String {
+        match &self.bytecode[self.pc] {
+            Bytecode::Call(name, _) | Bytecode::TailCall(name, _) => name.clone(),
+            &Bytecode::CallIdx(index, _) => self.call_targets[index as usize].name().to_string(),
+            other => unreachable!("{} is no call", other),
+        }
+    }
+
+    /// A runtime error at the current instruction, located through the source map.
+    fn error(&self, kind: RuntimeErrorKind) -> RuntimeError {
+        RuntimeError {
+            kind,
+            pc: self.pc,
+            span: self.spans.get(self.pc).copied().flatten(),
+        }
+    }
+
+    /// A VM for a compiled program, with its user functions already registered
+    /// and its calls of them and of the natives resolved into `CallIdx`.
+    pub fn from_program(program: crate::compiler::Program) -> Self {
+        Self::load(program, false).expect("only a strict load fails")
+    }
+
+    /// Like `from_program`, but a call of a name that is neither a function of
+    /// the program nor one of the `default_natives` is an `UndefinedFunction`
+    /// error instead of being skipped at runtime.
+    pub fn from_program_strict(program: crate::compiler::Program) -> Result<Self, CompileError> {
+        Self::load(program, true)
+    }
+
+    fn load(mut program: crate::compiler::Program, strict: bool) -> Result<Self, CompileError> {
+        let mut vm = VM::new(Vec::new());
+        program.resolve_calls(|name| vm.native_functions.contains_key(name), strict)?;
+        vm.bytecode = program.code;
+        vm.user_functions = program.functions;
+        vm.spans = program.spans;
+        vm.constants = program.constants;
+        vm.strings = program.strings;
+        vm.call_targets = program.call_targets;
+        vm.resolve_callees();
+        // Room for every global up front, so the loop never has to grow it
+        let globals = program.symbols.values().max().map_or(0, |&slot| slot + 1);
+        vm.memory = Slots::with_len(globals);
+        Ok(vm)
+    }
+
+    /// Capture the state of execution, to be continued later by `restore`.
+    /// Spawned threads cannot be captured, so this fails with
+    /// `UnsyncedThreads` until a `Sync` has joined them.
+    pub fn snapshot(&self) -> Result<VmSnapshot, RuntimeError> {
+        if !self.threads.is_empty() {
+            let count = self.threads.len();
+            return Err(self.error(RuntimeErrorKind::UnsyncedThreads(count)));
+        }
+        let all_slots = || std::iter::once(&self.memory).chain(&self.frames);
+        let stored = all_slots()
+            .map(|slots| slots.values.iter().map(Option::is_some).collect())
+            .collect();
+        let values: Vec<Value> = self
+            .stack
+            .iter()
+            .chain(all_slots().flat_map(Slots::values))
+            .cloned()
+            .collect();
+        Ok(VmSnapshot {
+            pc: self.pc,
+            stack_len: self.stack.len(),
+            stored,
+            values: SentValues::new(&values),
+        })
+    }
+
+    /// A VM for `program` that continues from `snapshot`, taken of a VM
+    /// running the same program.
+    pub fn restore(program: &crate::compiler::Program, snapshot: VmSnapshot) -> Self {
+        let mut vm = VM::from_program(program.clone());
+        let mut values = snapshot.values.into_values().into_iter();
+        vm.pc = snapshot.pc;
+        vm.stack = values.by_ref().take(snapshot.stack_len).collect();
+        let mut all_slots = snapshot.stored.into_iter().map(|stored| Slots {
+            values: stored
+                .into_iter()
+                .map(|stored| if stored { values.next() } else { None })
+                .collect(),
+        });
+        if let Some(memory) = all_slots.next() {
+            vm.memory = memory;
+        }
+        vm.frames = all_slots.collect();
+        vm
+    }
+
+    /// The current value of global variable `name` of `program`, which this
+    /// VM is running or has run.
+    pub fn get_var(&self, program: &crate::compiler::Program, name: &str) -> Option<Value> {
+        let slot = program.global_slot(name)?;
+        self.memory.get(slot).cloned()
+    }
 
-                    let (tx, rx) = mpsc::channel::<f64>(); // Updated to use f64
-                    self.receivers.push(rx);
+    /// Set global variable `name` of `program`, which this VM is about to run,
+    /// to `value`. Fails with `UnknownVariable` if the program has no such
+    /// global; compile with `BytecodeCompiler::compile_program_with_globals`
+    /// to give it globals it only reads.
+    pub fn set_var(
+        &mut self,
+        program: &crate::compiler::Program,
+        name: &str,
+        value: Value,
+    ) -> Result<(), RuntimeError> {
+        let Some(slot) = program.global_slot(name) else {
+            return Err(self.error(RuntimeErrorKind::UnknownVariable(name.to_string())));
+        };
+        self.memory.insert(slot, value);
+        Ok(())
+    }
 
-                    let handle = thread::spawn(move || {
-                        // Simulate some computation
-                        tx.send(value_to_spawn).unwrap();
-                    });
+    /// Run a compiled program on a fresh VM with global variables set from
+    /// `vars` first, and return the value it leaves on top of the stack, or
+    /// `None` if it leaves nothing. The same program can be run again with
+    /// other values without compiling it again:
+    ///
+    /// ```
+    /// use parallelized_programming_language::{try_parse_program, BytecodeCompiler, Value, VM};
+    ///
+    /// let source = try_parse_program("a * x * x + b * x + c").unwrap();
+    /// let program =
+    ///     BytecodeCompiler::compile_program_with_globals(&source, &["a", "b", "c", "x"]).unwrap();
+    /// let poly = |x| VM::run_with_vars(&program, &[("a", 2.0), ("b", -3.0), ("c", 1.0), ("x", x)]);
+    /// assert_eq!(poly(0.0), Ok(Some(Value::Num(1.0))));
+    /// assert_eq!(poly(1.0), Ok(Some(Value::Num(0.0))));
+    /// assert_eq!(poly(3.0), Ok(Some(Value::Num(10.0))));
+    ///
+    /// // To read variables back afterwards, keep the VM
+    /// let program = BytecodeCompiler::compile_program_with_globals(
+    ///     &try_parse_program("y = x * 2").unwrap(),
+    ///     &["x"],
+    /// )
+    /// .unwrap();
+    /// let mut vm = VM::from_program(program.clone());
+    /// vm.set_var(&program, "x", Value::Num(21.0)).unwrap();
+    /// vm.try_execute().unwrap();
+    /// assert_eq!(vm.get_var(&program, "y"), Some(Value::Num(42.0)));
+    /// ```
+    pub fn run_with_vars(
+        program: &crate::compiler::Program,
+        vars: &[(&str, f64)],
+    ) -> Result<Option<Value>, RuntimeError> {
+        let mut vm = VM::from_program(program.clone());
+        for &(name, value) in vars {
+            vm.set_var(program, name, Value::Num(value))?;
+        }
+        vm.try_execute()?;
+        Ok(vm.stack.pop())
+    }
 
-                    self.threads.push(handle);
-                    self.pc += 1;
-                }
-                &Bytecode::Sync => {
-                    // Clear the main thread's stack before collecting results
-                    self.stack.clear();
+    /// Run a compiled program on a fresh VM and return the value it leaves on
+    /// top of the stack. Panics on a runtime error, like `run`.
+    pub fn run_program(program: &crate::compiler::Program) -> Value {
+        let mut vm = VM::from_program(program.clone());
+        vm.execute();
+        vm.stack.pop().unwrap_or(Value::Num(0.0))
+    }
 
-                    // Wait for all threads to finish and collect their results
-                    for thread in self.threads.drain(..) {
-                        thread.join().unwrap();
-                    }
-                    // Retrieve results from receivers
-                    for rx in self.receivers.drain(..) {
-                        if let Ok(val) = rx.recv() {
-                            self.stack.push(val);
-                        }
-                    }
-                    self.pc += 1;
-                }
-                &Bytecode::Barrier => {
-                    // Wait at a barrier for all threads
-                    while let Some(thread) = self.threads.pop() {
-                        thread.join().unwrap();
-                    }
-                    self.pc += 1; // Move to the next instruction
-                }
-            }
+    /// Like `run_program`, returning a runtime error instead of panicking.
+    pub fn try_run_program(program: &crate::compiler::Program) -> Result<Value, RuntimeError> {
+        let mut vm = VM::from_program(program.clone());
+        vm.try_execute()?;
+        Ok(vm.stack.pop().unwrap_or(Value::Num(0.0)))
+    }
+
+    /// Run `bytecode` on a fresh VM and return the top of the stack, or 0 if
+    /// the stack is empty. Panics on a runtime error; see `try_run`.
+    pub fn run(bytecode: Vec<Bytecode>) -> Value {
+        match VM::try_run(bytecode) {
+            Ok(value) => value.unwrap_or(Value::Num(0.0)),
+            Err(err) => panic!("{}", err),
         }
     }
 
-    pub fn run(bytecode: Vec<Bytecode>) -> f64 {
+    /// Run `bytecode` on a fresh VM and return the top of the stack, or
+    /// `None` if it halted with the stack empty. Where `run` panics, this
+    /// returns the `VmError` instead.
+    pub fn try_run(bytecode: Vec<Bytecode>) -> Result<Option<Value>, VmError> {
         let mut vm = VM::new(bytecode);
-        vm.execute();
-        vm.stack.pop().unwrap_or_else(|| 0_f64) // Ensure the default value is explicitly `f64`
+        vm.try_execute()?;
+        Ok(vm.stack.pop())
     }
 
-    /// Compile an AST expression using the provided compiler and execute it, returning the top of stack.
-    pub fn run_expr<C: crate::compiler::Compiler<Instruction = Bytecode>>(
-        expr: &parser::Expr,
-    ) -> f64 {
-        let bytecode = C::compile(expr);
-        VM::run(bytecode)
+    /// Compile an AST expression using a fresh compiler of type `C` and execute it,
+    /// returning the top of stack like `run`.
+    pub fn run_expr<C>(expr: &parser::Expr) -> Result<Value, C::Error>
+    where
+        C: crate::compiler::Compiler<Output = Vec<Bytecode>> + Default,
+    {
+        Ok(VM::run(VM::compile_with::<C>(expr)?))
+    }
+
+    /// Like `run_expr`, returning the top of stack like `try_run` and a
+    /// runtime error instead of panicking.
+    pub fn try_run_expr<C>(expr: &parser::Expr) -> Result<Option<Value>, crate::Error>
+    where
+        C: crate::compiler::Compiler<Output = Vec<Bytecode>> + Default,
+        crate::Error: From<C::Error>,
+    {
+        Ok(VM::try_run(VM::compile_with::<C>(expr)?)?)
+    }
+
+    fn compile_with<C>(expr: &parser::Expr) -> Result<Vec<Bytecode>, C::Error>
+    where
+        C: crate::compiler::Compiler<Output = Vec<Bytecode>> + Default,
+    {
+        let mut compiler = C::default();
+        compiler.compile_expr(expr)?;
+        compiler.finish()
     }
 }
 
+/// The compiled body of a `spawn { .. }` block, waiting to be placed after the
+/// rest of the program.
+#[derive(Debug, Default)]
+pub(crate) struct SpawnRegion {
+    /// Index of the `SpawnBlock` that starts the region, in the code it was compiled into.
+    site: usize,
+    /// Addresses in `code` count from the start of the region.
+    code: Vec<Bytecode>,
+    /// Source span of each instruction in `code`.
+    spans: Vec<Option<Span>>,
+    /// Regions of `spawn` blocks inside this one, with sites in `code`.
+    nested: Vec<SpawnRegion>,
+}
+
 #[allow(dead_code)]
 impl Bytecode {
-    pub(crate) fn compile_expr(expr: &parser::Expr, code: &mut Vec<Bytecode>) {
+    /// Deepest expression nesting the compiler accepts; matches the parser's default
+    /// limit so that anything it produces compiles without overflowing the stack.
+    pub const MAX_COMPILE_DEPTH: usize = parser::PrattParser::DEFAULT_MAX_DEPTH;
+
+    /// Compile `expr` onto the end of `code`. The body of each `spawn { .. }`
+    /// is compiled into its own entry of `regions`, to be placed after the rest
+    /// of the program by `append_spawn_regions`.
+    pub(crate) fn compile_expr(
+        expr: &parser::Expr,
+        code: &mut Vec<Bytecode>,
+        spans: &mut Vec<Option<Span>>,
+        symbols: &mut SymbolTable,
+        regions: &mut Vec<SpawnRegion>,
+    ) -> Result<(), CompileError> {
+        Bytecode::compile_nested(expr, code, spans, symbols, regions, 1)
+    }
+
+    // Each instruction's span in `spans` is that of the innermost expression emitting it
+    fn compile_nested(
+        expr: &parser::Expr,
+        code: &mut Vec<Bytecode>,
+        spans: &mut Vec<Option<Span>>,
+        symbols: &mut SymbolTable,
+        regions: &mut Vec<SpawnRegion>,
+        depth: usize,
+    ) -> Result<(), CompileError> {
         use crate::scanner::Token;
-        match expr {
-            parser::Expr::Number(n) => code.push(Bytecode::LoadConst(*n as f64)),
-            parser::Expr::Ident(name) => panic!("Identifier '{}' not supported in bytecode", name),
-            parser::Expr::UnaryOp { op, rhs } => {
-                Bytecode::compile_expr(rhs, code);
+        if depth > Bytecode::MAX_COMPILE_DEPTH {
+            return Err(CompileError::TooDeeplyNested {
+                limit: Bytecode::MAX_COMPILE_DEPTH,
+                span: expr.span,
+            });
+        }
+        let unsupported = |construct| CompileError::UnsupportedExpression {
+            construct,
+            span: expr.span,
+        };
+        let span = Some(expr.span);
+        let mut compile_expr =
+            |expr: &parser::Expr, code: &mut Vec<Bytecode>, symbols: &mut SymbolTable| {
+                spans.resize(code.len(), span);
+                Bytecode::compile_nested(expr, code, spans, symbols, regions, depth + 1)
+            };
+        match &expr.kind {
+            parser::ExprKind::Number(n) => code.push(Bytecode::LoadConst(*n)),
+            parser::ExprKind::Bool(value) => code.push(Bytecode::LoadBool(*value)),
+            parser::ExprKind::Group(inner) => compile_expr(inner, code, symbols)?,
+            parser::ExprKind::Ident(name) => match symbols.resolve(name) {
+                Some(slot) => code.push(slot.load()),
+                None => {
+                    return Err(CompileError::UndefinedVariable {
+                        name: name.clone(),
+                        span: expr.span,
+                    })
+                }
+            },
+            parser::ExprKind::UnaryOp { op, rhs } => {
+                compile_expr(rhs, code, symbols)?;
                 match op {
                     Token::Minus => code.push(Bytecode::Neg),
-                    _ => panic!("Unsupported unary op: {:?}", op),
+                    Token::Bang => code.push(Bytecode::Not),
+                    // Unary plus leaves its operand unchanged
+                    Token::Plus => {}
+                    _ => {
+                        return Err(CompileError::UnsupportedOperator {
+                            op: op.clone(),
+                            span: expr.span,
+                        })
+                    }
                 }
             }
-            parser::Expr::BinaryOp { lhs, op, rhs } => {
-                Bytecode::compile_expr(lhs, code);
-                Bytecode::compile_expr(rhs, code);
+            parser::ExprKind::BinaryOp {
+                lhs,
+                op: op @ (Token::AndAnd | Token::OrOr),
+                rhs,
+            } => {
+                // The right operand only runs when the left does not decide the result
+                compile_expr(lhs, code, symbols)?;
+                let (skip, decided) = match op {
+                    Token::AndAnd => (Bytecode::JumpIfZero(0), false),
+                    _ => (Bytecode::JumpIfNotZero(0), true),
+                };
+                let jump_to_decided = Bytecode::emit_jump(code, skip);
+                compile_expr(rhs, code, symbols)?;
+                // Either way the result is a bool: `!!` gives the right operand's truthiness
+                code.push(Bytecode::Not);
+                code.push(Bytecode::Not);
+                let jump_to_end = Bytecode::emit_jump(code, Bytecode::Jump(0));
+                Bytecode::patch_jump(code, jump_to_decided);
+                code.push(Bytecode::LoadBool(decided));
+                Bytecode::patch_jump(code, jump_to_end);
+            }
+            parser::ExprKind::BinaryOp {
+                lhs,
+                op: Token::StarStar,
+                rhs,
+            } if Bytecode::small_integer_exponent(rhs).is_some() => {
+                compile_expr(lhs, code, symbols)?;
+                Bytecode::emit_power_chain(code, Bytecode::small_integer_exponent(rhs).unwrap());
+            }
+            parser::ExprKind::BinaryOp { lhs, op, rhs } => {
+                compile_expr(lhs, code, symbols)?;
+                compile_expr(rhs, code, symbols)?;
                 match op {
                     Token::Plus => code.push(Bytecode::Add),
                     Token::Minus => code.push(Bytecode::Sub),
                     Token::Star => code.push(Bytecode::Mul),
                     Token::Slash => code.push(Bytecode::Div),
-                    _ => panic!("Unsupported binary op: {:?}", op),
+                    Token::Percent => code.push(Bytecode::Mod),
+                    Token::StarStar => code.push(Bytecode::Pow),
+                    Token::EqEq => code.push(Bytecode::Eq),
+                    Token::NotEq => code.push(Bytecode::Ne),
+                    Token::Lt => code.push(Bytecode::Lt),
+                    Token::Le => code.push(Bytecode::Le),
+                    Token::Gt => code.push(Bytecode::Gt),
+                    Token::Ge => code.push(Bytecode::Ge),
+                    Token::Custom(text) => code.push(Bytecode::Call(
+                        crate::compiler::operator_function_name(text),
+                        2,
+                    )),
+                    _ => {
+                        return Err(CompileError::UnsupportedOperator {
+                            op: op.clone(),
+                            span: expr.span,
+                        })
+                    }
                 }
             }
-            parser::Expr::Call { name, args } => {
+            parser::ExprKind::Str(text) => code.push(Bytecode::LoadStr(text.clone())),
+            parser::ExprKind::Call { callee, args } => {
+                let parser::ExprKind::Ident(name) = &callee.kind else {
+                    return Err(unsupported("A call of anything but a named function"));
+                };
+                match symbols.arity(name) {
+                    Some(expected) if expected != args.len() => {
+                        return Err(CompileError::ArityMismatch {
+                            name: name.clone(),
+                            expected,
+                            found: args.len(),
+                            span: expr.span,
+                        })
+                    }
+                    None if !symbols.is_callable(name) => {
+                        return Err(CompileError::UndefinedFunction {
+                            name: name.clone(),
+                            span: expr.span,
+                        })
+                    }
+                    _ => {}
+                }
                 for arg in args {
-                    Bytecode::compile_expr(arg, code);
+                    compile_expr(arg, code, symbols)?;
                 }
                 code.push(Bytecode::Call(name.clone(), args.len()));
             }
-            parser::Expr::Function { .. } => {
-                // Function definitions are handled at a higher level, not in main expr compiler
+            parser::ExprKind::Assign { name, value } => {
+                compile_expr(value, code, symbols)?;
+                // The assignment's own value is the one stored
+                code.push(Bytecode::Dup);
+                code.push(symbols.assign(name).store());
+            }
+            parser::ExprKind::Block(body) => {
+                Bytecode::compile_body(body, code, spans, symbols, regions, depth + 1)?
+            }
+            parser::ExprKind::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                compile_expr(cond, code, symbols)?;
+                let jump_to_else = Bytecode::emit_jump(code, Bytecode::JumpIfZero(0));
+                compile_expr(then_branch, code, symbols)?;
+                let jump_to_end = Bytecode::emit_jump(code, Bytecode::Jump(0));
+                Bytecode::patch_jump(code, jump_to_else);
+                match else_branch {
+                    Some(else_branch) => compile_expr(else_branch, code, symbols)?,
+                    None => code.push(Bytecode::LoadConst(0.0)),
+                }
+                Bytecode::patch_jump(code, jump_to_end);
+            }
+            parser::ExprKind::While { cond, body } => {
+                let head = code.len();
+                compile_expr(cond, code, symbols)?;
+                let jump_to_exit = Bytecode::emit_jump(code, Bytecode::JumpIfZero(0));
+                for item in body {
+                    spans.resize(code.len(), span);
+                    Bytecode::compile_discarded(item, code, spans, symbols, regions, depth + 1)?;
+                }
+                code.push(Bytecode::Jump(head));
+                Bytecode::patch_jump(code, jump_to_exit);
+                // The loop itself evaluates to 0.0
+                code.push(Bytecode::LoadConst(0.0));
+            }
+            parser::ExprKind::Array(elements) => {
+                for element in elements {
+                    compile_expr(element, code, symbols)?;
+                }
+                code.push(Bytecode::NewArray(elements.len()));
+            }
+            parser::ExprKind::Index { target, index } => {
+                // Indexing a literal with a constant can be checked right away
+                if let (parser::ExprKind::Array(elements), parser::ExprKind::Number(i)) =
+                    (&target.kind, &index.kind)
+                {
+                    if *i < 0.0 || i.fract() != 0.0 || *i >= elements.len() as f64 {
+                        return Err(CompileError::IndexOutOfBounds {
+                            index: *i,
+                            len: elements.len(),
+                            span: index.span,
+                        });
+                    }
+                }
+                compile_expr(target, code, symbols)?;
+                compile_expr(index, code, symbols)?;
+                code.push(Bytecode::LoadIndex);
+            }
+            parser::ExprKind::IndexAssign {
+                target,
+                index,
+                value,
+            } => {
+                compile_expr(target, code, symbols)?;
+                compile_expr(index, code, symbols)?;
+                compile_expr(value, code, symbols)?;
+                code.push(Bytecode::StoreIndex);
+            }
+            parser::ExprKind::Spawn(task) if matches!(task.kind, parser::ExprKind::Block(_)) => {
+                // Variables already defined are free in the block, captured by value
+                let mut captures: Vec<Slot> = crate::visitor::collect_identifiers(task)
+                    .iter()
+                    .filter_map(|name| symbols.resolve(name))
+                    .collect();
+                captures.sort_unstable();
+                captures.dedup();
+                // The region's prologue stores them back into the same slots
+                let mut region = SpawnRegion::default();
+                for &slot in captures.iter().rev() {
+                    region.code.push(slot.store());
+                }
+                region.spans.resize(region.code.len(), span);
+                Bytecode::compile_nested(
+                    task,
+                    &mut region.code,
+                    &mut region.spans,
+                    symbols,
+                    &mut region.nested,
+                    depth + 1,
+                )?;
+                region.code.push(Bytecode::Return);
+                region.spans.push(span);
+                for &slot in &captures {
+                    code.push(slot.load());
+                }
+                region.site = code.len();
+                code.push(Bytecode::SpawnBlock(0, captures.len()));
+                regions.push(region);
+                // In the spawning thread the block evaluates to 0.0
+                code.push(Bytecode::LoadConst(0.0));
+            }
+            parser::ExprKind::Spawn(task) => {
+                compile_expr(task, code, symbols)?;
+                code.push(Bytecode::Spawn);
+            }
+            parser::ExprKind::Sync => code.push(Bytecode::Sync),
+            parser::ExprKind::Barrier => code.push(Bytecode::Barrier),
+            parser::ExprKind::Error => return Err(unsupported("A syntax error")),
+            parser::ExprKind::For {
+                var,
+                start,
+                end,
+                body,
+            } => {
+                compile_expr(start, code, symbols)?;
+                let counter = symbols.assign(var);
+                code.push(counter.store());
+                // The bound is evaluated once, into a slot no identifier can name
+                compile_expr(end, code, symbols)?;
+                let bound = Slot::Frame(symbols.define(&format!("for#end{depth}")));
+                code.push(bound.store());
+                let head = code.len();
+                code.push(counter.load());
+                code.push(bound.load());
+                code.push(Bytecode::Le);
+                let jump_to_exit = Bytecode::emit_jump(code, Bytecode::JumpIfZero(0));
+                for item in body {
+                    spans.resize(code.len(), span);
+                    Bytecode::compile_discarded(item, code, spans, symbols, regions, depth + 1)?;
+                }
+                code.push(counter.load());
+                code.push(Bytecode::LoadConst(1.0));
+                code.push(Bytecode::Add);
+                code.push(counter.store());
+                code.push(Bytecode::Jump(head));
+                Bytecode::patch_jump(code, jump_to_exit);
+                // Like a while loop, evaluate to 0.0
+                code.push(Bytecode::LoadConst(0.0));
+            }
+        }
+        spans.resize(code.len(), span);
+        Ok(())
+    }
+
+    /// Compiles a sequence evaluating to its last expression, or 0.0 when
+    /// empty. The others are compiled for their side effects only, so the
+    /// stack ends exactly one value higher however long the sequence is.
+    pub(crate) fn compile_body(
+        body: &[parser::Expr],
+        code: &mut Vec<Bytecode>,
+        spans: &mut Vec<Option<Span>>,
+        symbols: &mut SymbolTable,
+        regions: &mut Vec<SpawnRegion>,
+        depth: usize,
+    ) -> Result<(), CompileError> {
+        let Some((last, rest)) = body.split_last() else {
+            code.push(Bytecode::LoadConst(0.0));
+            return Ok(());
+        };
+        for expr in rest {
+            Bytecode::compile_discarded(expr, code, spans, symbols, regions, depth)?;
+        }
+        Bytecode::compile_nested(last, code, spans, symbols, regions, depth)
+    }
+
+    /// Compiles `expr` for its side effects only, leaving the stack as it was.
+    fn compile_discarded(
+        expr: &parser::Expr,
+        code: &mut Vec<Bytecode>,
+        spans: &mut Vec<Option<Span>>,
+        symbols: &mut SymbolTable,
+        regions: &mut Vec<SpawnRegion>,
+        depth: usize,
+    ) -> Result<(), CompileError> {
+        match &expr.kind {
+            parser::ExprKind::Assign { name, value } => {
+                Bytecode::compile_nested(value, code, spans, symbols, regions, depth + 1)?;
+                code.push(symbols.assign(name).store());
+            }
+            // `sync` and `barrier` do not leave a value to drop
+            parser::ExprKind::Sync | parser::ExprKind::Barrier => {
+                Bytecode::compile_nested(expr, code, spans, symbols, regions, depth)?
+            }
+            _ => {
+                Bytecode::compile_nested(expr, code, spans, symbols, regions, depth)?;
+                code.push(Bytecode::Pop);
+            }
+        }
+        spans.resize(code.len(), Some(expr.span));
+        Ok(())
+    }
+
+    /// Places each region at the end of `code`, pointing the `SpawnBlock` at
+    /// `base + site` to it; `base` is where the code the sites index into starts.
+    pub(crate) fn append_spawn_regions(
+        code: &mut Vec<Bytecode>,
+        spans: &mut Vec<Option<Span>>,
+        base: usize,
+        regions: Vec<SpawnRegion>,
+    ) {
+        for region in regions {
+            let start = code.len();
+            spans.resize(start, None);
+            spans.extend(region.spans);
+            code[base + region.site].retarget(start);
+            // Jumps inside the region were compiled relative to its start
+            code.extend(region.code.into_iter().map(|mut instruction| {
+                if let Some(target) = instruction.jump_target() {
+                    instruction.retarget(start + target);
+                }
+                instruction
+            }));
+            Bytecode::append_spawn_regions(code, spans, start, region.nested);
+        }
+    }
+
+    /// Largest literal exponent `**` compiles to multiplications instead of `Pow`.
+    pub const MAX_CHAINED_EXPONENT: u32 = 16;
+
+    /// The exponent of `base ** exponent` when it is a whole number literal from
+    /// 0 to `MAX_CHAINED_EXPONENT`, for which `powf` is slower and may be less exact.
+    fn small_integer_exponent(exponent: &parser::Expr) -> Option<u32> {
+        match exponent.kind {
+            parser::ExprKind::Number(n)
+                if n.fract() == 0.0 && (0.0..=Self::MAX_CHAINED_EXPONENT as f64).contains(&n) =>
+            {
+                Some(n as u32)
+            }
+            _ => None,
+        }
+    }
+
+    /// Raises the value on top of the stack to `exponent` by square-and-multiply:
+    /// walking the exponent's bits from the lowest, the current power of two is
+    /// kept on top and squared, leaving a copy beneath for each set bit, and
+    /// the copies are multiplied together at the end. `CheckPow` first fails
+    /// on anything but a number, as `Pow` would.
+    fn emit_power_chain(code: &mut Vec<Bytecode>, exponent: u32) {
+        code.push(Bytecode::CheckPow);
+        if exponent == 0 {
+            // As with `powf`, even NaN to the power 0 is 1
+            code.extend([Bytecode::Pop, Bytecode::LoadConst(1.0)]);
+            return;
+        }
+        let mut factors = 0;
+        let mut rest = exponent;
+        while rest > 1 {
+            if rest & 1 == 1 {
+                code.push(Bytecode::Dup);
+                factors += 1;
+            }
+            code.extend([Bytecode::Dup, Bytecode::Mul]);
+            rest >>= 1;
+        }
+        code.extend(std::iter::repeat_n(Bytecode::Mul, factors));
+    }
+
+    /// Emits a jump whose target is patched later; returns its address.
+    fn emit_jump(code: &mut Vec<Bytecode>, jump: Bytecode) -> usize {
+        code.push(jump);
+        code.len() - 1
+    }
+
+    /// Points the jump at `at` to the next instruction to be emitted.
+    fn patch_jump(code: &mut [Bytecode], at: usize) {
+        let target = code.len();
+        if !code[at].retarget(target) {
+            panic!("Cannot patch non-jump instruction {:?}", code[at]);
+        }
+    }
+
+    /// The address a jump instruction transfers control to, or where the
+    /// region a `SpawnBlock` starts.
+    pub fn jump_target(&self) -> Option<usize> {
+        match self {
+            Bytecode::Jump(target)
+            | Bytecode::JumpIfZero(target)
+            | Bytecode::JumpIfNotZero(target)
+            | Bytecode::SpawnBlock(target, _) => Some(*target),
+            _ => None,
+        }
+    }
+
+    /// Changes the target of a jump instruction or `SpawnBlock`; returns false
+    /// for any other instruction.
+    pub fn retarget(&mut self, new_target: usize) -> bool {
+        match self {
+            Bytecode::Jump(target)
+            | Bytecode::JumpIfZero(target)
+            | Bytecode::JumpIfNotZero(target)
+            | Bytecode::SpawnBlock(target, _) => {
+                *target = new_target;
+                true
             }
+            _ => false,
         }
     }
 }
@@ -322,7 +2435,7 @@ mod tests {
         ];
         let mut vm = VM::new(bytecode);
         vm.execute();
-        assert_eq!(vm.stack.pop(), Some(5.0));
+        assert_eq!(vm.stack.pop(), Some(Value::Num(5.0)));
     }
 
     #[test]
@@ -335,7 +2448,7 @@ mod tests {
         ];
         let mut vm = VM::new(bytecode);
         vm.execute();
-        assert_eq!(vm.stack.pop(), Some(6.0));
+        assert_eq!(vm.stack.pop(), Some(Value::Num(6.0)));
     }
 
     #[test]
@@ -348,7 +2461,7 @@ mod tests {
         ];
         let mut vm = VM::new(bytecode);
         vm.execute();
-        assert_eq!(vm.stack.pop(), Some(42.0));
+        assert_eq!(vm.stack.pop(), Some(Value::Num(42.0)));
     }
 
     #[test]
@@ -361,7 +2474,598 @@ mod tests {
         ];
         let mut vm = VM::new(bytecode);
         vm.execute();
-        assert_eq!(vm.stack.pop(), Some(5.0));
+        assert_eq!(vm.stack.pop(), Some(Value::Num(5.0)));
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let compare = |a: f64, op: Bytecode, b: f64| {
+            VM::run(vec![
+                Bytecode::LoadConst(a),
+                Bytecode::LoadConst(b),
+                op,
+                Bytecode::Halt,
+            ])
+        };
+        // Second-from-top is the left operand
+        assert_eq!(compare(3.0, Bytecode::Lt, 5.0), Value::Bool(true));
+        assert_eq!(compare(5.0, Bytecode::Lt, 3.0), Value::Bool(false));
+        assert_eq!(compare(3.0, Bytecode::Le, 3.0), Value::Bool(true));
+        assert_eq!(compare(3.0, Bytecode::Gt, 5.0), Value::Bool(false));
+        assert_eq!(compare(5.0, Bytecode::Ge, 5.0), Value::Bool(true));
+        assert_eq!(compare(5.0, Bytecode::Eq, 5.0), Value::Bool(true));
+        assert_eq!(compare(5.0, Bytecode::Ne, 5.0), Value::Bool(false));
+        assert_eq!(compare(-0.0, Bytecode::Eq, 0.0), Value::Bool(true));
+        for op in [
+            Bytecode::Eq,
+            Bytecode::Lt,
+            Bytecode::Le,
+            Bytecode::Gt,
+            Bytecode::Ge,
+        ] {
+            assert_eq!(
+                compare(f64::NAN, op.clone(), f64::NAN),
+                Value::Bool(false),
+                "{:?}",
+                op
+            );
+            assert_eq!(
+                compare(1.0, op.clone(), f64::NAN),
+                Value::Bool(false),
+                "{:?}",
+                op
+            );
+        }
+        assert_eq!(compare(f64::NAN, Bytecode::Ne, f64::NAN), Value::Bool(true));
+    }
+
+    #[test]
+    fn test_modulo_sign_follows_dividend() {
+        let run = |a: f64, b: f64| {
+            VM::run(vec![
+                Bytecode::LoadConst(a),
+                Bytecode::LoadConst(b),
+                Bytecode::Mod,
+                Bytecode::Halt,
+            ])
+        };
+        assert_eq!(run(10.0, 3.0), 1.0);
+        assert_eq!(run(-7.0, 3.0), -1.0);
+        assert_eq!(run(7.0, -3.0), 1.0);
+        assert_eq!(run(5.5, 2.0), 1.5);
+        let remainder_by_zero = VM::try_run(vec![
+            Bytecode::LoadConst(7.0),
+            Bytecode::LoadConst(0.0),
+            Bytecode::Mod,
+        ]);
+        assert_eq!(
+            remainder_by_zero.map_err(|err| err.kind),
+            Err(RuntimeErrorKind::DivisionByZero)
+        );
+    }
+
+    #[test]
+    fn test_load_const_idx_reads_the_pool() {
+        let mut vm = VM::new(vec![
+            Bytecode::LoadConstIdx(1),
+            Bytecode::LoadConstIdx(0),
+            Bytecode::Sub,
+        ]);
+        vm.constants = vec![2.0, 7.5];
+        vm.execute();
+        assert_eq!(vm.stack, vec![5.5]);
+        let missing = VM::try_run(vec![Bytecode::LoadConstIdx(0)]);
+        assert_eq!(
+            missing.map_err(|err| err.kind),
+            Err(RuntimeErrorKind::UndefinedConstant(0))
+        );
+    }
+
+    #[test]
+    fn test_debug_shows_state() {
+        let program = crate::BytecodeCompiler::compile_program(
+            &crate::try_parse_program("b = 2; a = 1; fn f(x) { x }; f(a)").unwrap(),
+        )
+        .unwrap();
+        let mut vm = VM::from_program(program);
+        vm.execute();
+        let debug = format!("{:?}", vm);
+        assert!(debug.starts_with("VM { pc: "), "{}", debug);
+        for field in [
+            "stack: [Num(1.0)]",
+            "memory: {0: Num(2.0), 1: Num(1.0)}",
+            "frames: 0",
+            "natives: {\"ceil\", \"cos\", \"exp\", \"floor\", \"idiv\", \"print\", \"sin\", \"sqrt\", \"trunc\"}",
+            "user_functions: {\"f\": ",
+            "threads: 0",
+            "receivers: 0",
+        ] {
+            assert!(debug.contains(field), "{} missing from {}", field, debug);
+        }
+        let pretty = format!("{:#?}", vm);
+        assert!(pretty.contains("\n    frames: 0,\n"), "{}", pretty);
+        assert!(pretty.lines().count() > debug.lines().count());
+    }
+
+    #[test]
+    fn test_run_with_vars() {
+        let source = crate::try_parse_program("n = n + 1; k * n").unwrap();
+        let program =
+            crate::BytecodeCompiler::compile_program_with_globals(&source, &["k", "n"]).unwrap();
+        assert_eq!(
+            VM::run_with_vars(&program, &[("k", 3.0), ("n", 4.0)]),
+            Ok(Some(Value::Num(15.0)))
+        );
+        assert_eq!(
+            VM::run_with_vars(&program, &[("k", 3.0), ("m", 4.0)]).map_err(|err| err.kind),
+            Err(RuntimeErrorKind::UnknownVariable("m".to_string()))
+        );
+        // A global left unset is undefined as ever
+        let missing = VM::run_with_vars(&program, &[("k", 3.0)]).unwrap_err();
+        assert!(matches!(
+            missing.kind,
+            RuntimeErrorKind::UndefinedVariable(_)
+        ));
+        let mut vm = VM::from_program(program.clone());
+        vm.set_var(&program, "k", Value::Bool(true)).unwrap();
+        vm.set_var(&program, "n", Value::Num(1.0)).unwrap();
+        assert!(vm.try_execute().is_err());
+        assert_eq!(vm.get_var(&program, "n"), Some(Value::Num(2.0)));
+    }
+
+    #[test]
+    fn test_try_run_tells_zero_from_nothing() {
+        assert_eq!(
+            VM::try_run(vec![Bytecode::LoadConst(0.0), Bytecode::Halt]),
+            Ok(Some(Value::Num(0.0)))
+        );
+        assert_eq!(
+            VM::try_run(vec![
+                Bytecode::LoadConst(1.0),
+                Bytecode::Pop,
+                Bytecode::Halt
+            ]),
+            Ok(None)
+        );
+        assert_eq!(VM::try_run(vec![]), Ok(None));
+        let err = VM::try_run(vec![Bytecode::LoadConst(1.0), Bytecode::Add]).unwrap_err();
+        assert_eq!((err.kind, err.pc), (RuntimeErrorKind::StackUnderflow, 1));
+        // The infallible form still reads an empty stack as 0
+        assert_eq!(VM::run(vec![]), Value::Num(0.0));
+        assert_eq!(
+            VM::try_run_expr::<crate::BytecodeCompiler>(&crate::parse_expr("1 / 0"))
+                .map_err(|err| matches!(err, crate::Error::Runtime(_))),
+            Err(true)
+        );
+        assert_eq!(
+            VM::try_run_expr::<crate::BytecodeCompiler>(&crate::parse_expr("q"))
+                .map_err(|err| matches!(err, crate::Error::Compile(_))),
+            Err(true)
+        );
+        assert_eq!(crate::try_run_expr("6 * 7"), Ok(Some(Value::Num(42.0))));
+    }
+
+    #[test]
+    fn test_array_indices() {
+        let load = |index: f64| {
+            let array = Value::from(vec![Value::Num(10.0), Value::Num(20.0)]);
+            apply(&array, Bytecode::LoadIndex, &Value::Num(index))
+        };
+        assert_eq!(load(1.0), Ok(Value::Num(20.0)));
+        assert_eq!(load(-0.0), Ok(Value::Num(10.0)));
+        assert_eq!(
+            load(2.0),
+            Err(RuntimeErrorKind::IndexOutOfBounds { index: 2.0, len: 2 })
+        );
+        assert_eq!(load(-1.0), Err(RuntimeErrorKind::BadIndex(-1.0)));
+        assert_eq!(load(0.5), Err(RuntimeErrorKind::BadIndex(0.5)));
+        assert_eq!(
+            load(f64::INFINITY),
+            Err(RuntimeErrorKind::BadIndex(f64::INFINITY))
+        );
+        assert!(matches!(load(f64::NAN), Err(RuntimeErrorKind::BadIndex(index)) if index.is_nan()));
+        // The error points at the instruction that indexed
+        let err = VM::try_run(vec![
+            Bytecode::NewArray(0),
+            Bytecode::LoadConst(0.0),
+            Bytecode::LoadConst(7.0),
+            Bytecode::StoreIndex,
+        ])
+        .unwrap_err();
+        assert_eq!(
+            (err.kind, err.pc),
+            (RuntimeErrorKind::IndexOutOfBounds { index: 0.0, len: 0 }, 3)
+        );
+    }
+
+    #[test]
+    fn test_arrays_containing_themselves() {
+        let array = Value::from(vec![Value::Num(1.0)]);
+        let Value::Array(elements) = &array else {
+            unreachable!()
+        };
+        elements.borrow_mut().push(array.clone());
+        assert_eq!(array.to_string(), "[1, [...]]");
+        // A spawned block's copy is just as circular, and a copy of its own
+        let mut copies = SentValues::new(&[array.clone(), array.clone()]).into_values();
+        let (copy, again) = (copies.remove(0), copies.remove(0));
+        assert_eq!(copy, again);
+        assert_ne!(copy, array);
+        let Value::Array(copied) = &copy else {
+            unreachable!()
+        };
+        assert_eq!(copied.borrow()[1], copy);
+        assert_eq!(copy.to_string(), "[1, [...]]");
+        // Break the cycles so the test does not leak them
+        elements.borrow_mut().clear();
+        copied.borrow_mut().clear();
+    }
+
+    /// Run `op` on a stack holding `a` beneath `b`.
+    fn apply(a: &Value, op: Bytecode, b: &Value) -> Result<Value, RuntimeErrorKind> {
+        let mut vm = VM::new(vec![op]);
+        vm.stack = vec![a.clone(), b.clone()];
+        vm.try_execute().map_err(|err| err.kind)?;
+        Ok(vm.stack.pop().unwrap())
+    }
+
+    #[test]
+    fn test_operand_types() {
+        let values = [
+            Value::Num(2.0),
+            Value::Bool(true),
+            Value::from("a"),
+            Value::from(vec![Value::Num(1.0)]),
+            Value::Unit,
+        ];
+        for (i, a) in values.iter().enumerate() {
+            for (j, b) in values.iter().enumerate() {
+                let sum = apply(a, Bytecode::Add, b);
+                if i == 0 && j == 0 {
+                    assert_eq!(sum, Ok(Value::Num(4.0)));
+                } else if i == 2 && j == 2 {
+                    assert_eq!(sum, Ok(Value::from("aa")));
+                } else {
+                    let kind = RuntimeErrorKind::TypeMismatch {
+                        op: "+",
+                        lhs: a.type_name(),
+                        rhs: b.type_name(),
+                    };
+                    assert_eq!(sum, Err(kind));
+                }
+                // Any two values can be compared, and differ when their types do
+                let equal = Value::Bool(i == j);
+                assert_eq!(apply(a, Bytecode::Eq, b), Ok(equal));
+            }
+        }
+        assert_eq!(
+            apply(&Value::Num(1.0), Bytecode::Lt, &Value::from("b")),
+            Err(RuntimeErrorKind::TypeMismatch {
+                op: "<",
+                lhs: "number",
+                rhs: "string"
+            })
+        );
+        let mut vm = VM::new(vec![Bytecode::Neg]);
+        vm.stack.push(Value::Bool(false));
+        assert_eq!(
+            vm.try_execute().unwrap_err().kind,
+            RuntimeErrorKind::UnaryTypeMismatch {
+                op: "-",
+                operand: "bool"
+            }
+        );
+        // Arrays are the same only when they are one array
+        let array = Value::from(vec![Value::Num(1.0)]);
+        let copy = Value::from(vec![Value::Num(1.0)]);
+        assert_eq!(apply(&array, Bytecode::Ne, &copy), Ok(Value::Bool(true)));
+        assert_eq!(
+            apply(&array, Bytecode::LoadIndex, &Value::from("0")),
+            Err(RuntimeErrorKind::TypeMismatch {
+                op: "[]",
+                lhs: "array",
+                rhs: "string"
+            })
+        );
+    }
+
+    #[test]
+    fn test_values_display() {
+        let nested = Value::from(vec![Value::Num(1.5), Value::from(vec![Value::Bool(true)])]);
+        assert_eq!(nested.to_string(), "[1.5, [true]]");
+        assert_eq!(Value::from("hi").to_string(), "hi");
+        assert_eq!(Value::Unit.to_string(), "()");
+        assert_eq!(Value::Num(3.0).as_num(), Some(3.0));
+        assert_eq!(Value::from("3").as_num(), None);
+    }
+
+    #[test]
+    fn test_spawned_block_gets_a_copy_of_an_array() {
+        let source = "a = [1, 2]; spawn { a[0] = 5; a }; sync";
+        let program =
+            crate::BytecodeCompiler::compile_program(&crate::try_parse_program(source).unwrap())
+                .unwrap();
+        let mut vm = VM::from_program(program.clone());
+        vm.execute();
+        assert_eq!(vm.stack.len(), 1);
+        assert_eq!(vm.stack[0].to_string(), "[5, 2]");
+        assert_eq!(vm.get_var(&program, "a").unwrap().to_string(), "[1, 2]");
+    }
+
+    #[test]
+    fn test_arrays_are_shared() {
+        let mut vm = VM::new(vec![
+            Bytecode::LoadConst(1.0),
+            Bytecode::LoadConst(2.0),
+            Bytecode::NewArray(2),
+            Bytecode::Dup,
+            Bytecode::StoreVar(0),
+            Bytecode::Dup,
+            Bytecode::LoadConst(0.0),
+            Bytecode::LoadConst(9.0),
+            Bytecode::StoreIndex,
+            Bytecode::Pop,
+            Bytecode::LoadConst(0.0),
+            Bytecode::LoadIndex,
+        ]);
+        vm.execute();
+        assert_eq!(vm.stack, vec![9.0]);
+        assert_eq!(vm.memory[0].to_string(), "[9, 2]");
+        let bad_index = VM::try_run(vec![
+            Bytecode::NewArray(0),
+            Bytecode::LoadConst(0.5),
+            Bytecode::LoadIndex,
+        ]);
+        assert_eq!(bad_index.unwrap_err().kind, RuntimeErrorKind::BadIndex(0.5));
+        let not_array = VM::try_run(vec![
+            Bytecode::LoadConst(3.0),
+            Bytecode::LoadConst(0.0),
+            Bytecode::LoadIndex,
+        ]);
+        assert_eq!(
+            not_array.unwrap_err().kind,
+            RuntimeErrorKind::NotAnArray("number")
+        );
+    }
+
+    #[test]
+    fn test_power() {
+        let bytecode = vec![
+            Bytecode::LoadConst(2.0),
+            Bytecode::LoadConst(10.0),
+            Bytecode::Pow,
+            Bytecode::Halt,
+        ];
+        let mut vm = VM::new(bytecode);
+        vm.execute();
+        assert_eq!(vm.stack.pop(), Some(Value::Num(1024.0)));
+    }
+
+    #[test]
+    fn test_integer_division_truncates_toward_zero() {
+        let run = |a: f64, b: f64| {
+            VM::try_run(vec![
+                Bytecode::LoadConst(a),
+                Bytecode::LoadConst(b),
+                Bytecode::IDiv,
+            ])
+            .map(|value| value.unwrap().as_num().unwrap())
+            .map_err(|err| err.kind)
+        };
+        assert_eq!(run(7.0, 2.0), Ok(3.0));
+        assert_eq!(run(-7.0, 2.0), Ok(-3.0));
+        assert_eq!(run(7.0, -2.0), Ok(-3.0));
+        assert_eq!(run(6.0, 0.5), Ok(12.0));
+        assert_eq!(run(1.0, 0.0), Err(RuntimeErrorKind::DivisionByZero));
+        assert_eq!(run(1.0, -0.0), Err(RuntimeErrorKind::DivisionByZero));
+        assert_eq!(
+            VM::try_run(vec![
+                Bytecode::LoadStr("7".to_string()),
+                Bytecode::LoadConst(2.0),
+                Bytecode::IDiv,
+            ])
+            .unwrap_err()
+            .kind,
+            RuntimeErrorKind::TypeMismatch {
+                op: "idiv",
+                lhs: "string",
+                rhs: "number"
+            }
+        );
+    }
+
+    #[test]
+    fn test_rounding() {
+        let run = |value: f64, op: Bytecode| {
+            VM::try_run(vec![Bytecode::LoadConst(value), op])
+                .map(|value| value.unwrap().as_num().unwrap())
+                .map_err(|err| err.kind)
+        };
+        assert_eq!(run(-0.5, Bytecode::Floor), Ok(-1.0));
+        assert_eq!(run(2.5, Bytecode::Floor), Ok(2.0));
+        assert_eq!(run(-0.5, Bytecode::Ceil), Ok(-0.0));
+        assert_eq!(run(2.1, Bytecode::Ceil), Ok(3.0));
+        assert_eq!(run(-2.9, Bytecode::Trunc), Ok(-2.0));
+        assert_eq!(run(2.9, Bytecode::Trunc), Ok(2.0));
+        assert!(run(f64::NAN, Bytecode::Floor).unwrap().is_nan());
+        assert_eq!(
+            VM::try_run(vec![Bytecode::LoadBool(true), Bytecode::Ceil])
+                .unwrap_err()
+                .kind,
+            RuntimeErrorKind::UnaryTypeMismatch {
+                op: "ceil",
+                operand: "bool"
+            }
+        );
+        // Source code reaches them through natives of the same names
+        let run = |source: &str| {
+            let program = crate::try_parse_program(source).unwrap();
+            let program = crate::BytecodeCompiler::compile_program(&program).unwrap();
+            VM::try_run_program(&program).map_err(|err| err.kind)
+        };
+        assert_eq!(run("floor(-0.5)"), Ok(Value::Num(-1.0)));
+        assert_eq!(
+            run("ceil(0.5) + trunc(-1.5) + idiv(-7, 2)"),
+            Ok(Value::Num(-3.0))
+        );
+        assert_eq!(
+            run("idiv(1, 0)"),
+            Err(RuntimeErrorKind::NativeFailed {
+                name: "idiv".to_string(),
+                message: "division by zero".to_string()
+            })
+        );
+        assert!(matches!(
+            run("floor(1, 2)"),
+            Err(RuntimeErrorKind::NativeFailed { .. })
+        ));
+    }
+
+    #[test]
+    fn test_power_of_zero_follows_division_by_zero() {
+        let run = |a: f64, b: f64| {
+            VM::try_run(vec![
+                Bytecode::LoadConst(a),
+                Bytecode::LoadConst(b),
+                Bytecode::Pow,
+                Bytecode::Halt,
+            ])
+            .map(|value| value.unwrap().as_num().unwrap())
+            .map_err(|err| err.kind)
+        };
+        assert_eq!(run(0.0, -1.0), Err(RuntimeErrorKind::DivisionByZero));
+        assert_eq!(run(-0.0, -0.5), Err(RuntimeErrorKind::DivisionByZero));
+        assert_eq!(run(0.0, 0.0), Ok(1.0));
+        assert_eq!(run(0.0, 3.0), Ok(0.0));
+        assert_eq!(run(2.0, -1.0), Ok(0.5));
+        assert!(run(0.0, f64::NAN).unwrap().is_nan());
+        // The constant folder leaves it to fail at runtime too
+        let program =
+            crate::BytecodeCompiler::compile_program(&crate::try_parse_program("0 ** -1").unwrap())
+                .unwrap();
+        assert_eq!(
+            VM::try_run_program(&program).map_err(|err| err.kind),
+            Err(RuntimeErrorKind::DivisionByZero)
+        );
+    }
+
+    #[test]
+    fn test_bitwise_operators() {
+        let run = |a: f64, op: Bytecode, b: f64| {
+            VM::try_run(vec![
+                Bytecode::LoadConst(a),
+                Bytecode::LoadConst(b),
+                op,
+                Bytecode::Halt,
+            ])
+            .map(|value| value.unwrap().as_num().unwrap())
+            .map_err(|err| err.kind)
+        };
+        assert_eq!(run(255.0, Bytecode::BitAnd, 15.0), Ok(15.0));
+        assert_eq!(run(12.0, Bytecode::BitOr, 3.0), Ok(15.0));
+        assert_eq!(run(12.0, Bytecode::BitXor, 10.0), Ok(6.0));
+        assert_eq!(run(1.0, Bytecode::Shl, 10.0), Ok(1024.0));
+        assert_eq!(run(-16.0, Bytecode::Shr, 2.0), Ok(-4.0));
+        assert_eq!(run(1.0, Bytecode::Shl, 63.0), Ok(i64::MIN as f64));
+        // Operands are truncated toward zero first
+        assert_eq!(run(7.9, Bytecode::BitAnd, -1.0), Ok(7.0));
+        assert!(matches!(
+            run(f64::NAN, Bytecode::BitOr, 1.0),
+            Err(RuntimeErrorKind::NotAnInteger(value)) if value.is_nan()
+        ));
+        assert_eq!(
+            run(1.0, Bytecode::BitXor, f64::INFINITY),
+            Err(RuntimeErrorKind::NotAnInteger(f64::INFINITY))
+        );
+        assert_eq!(
+            run(1e19, Bytecode::BitAnd, 1.0),
+            Err(RuntimeErrorKind::NotAnInteger(1e19))
+        );
+        assert_eq!(
+            run(1.0, Bytecode::Shl, 64.0),
+            Err(RuntimeErrorKind::InvalidShift(64))
+        );
+        assert_eq!(
+            run(1.0, Bytecode::Shr, -1.0),
+            Err(RuntimeErrorKind::InvalidShift(-1))
+        );
+    }
+
+    fn run_program(source: &str) -> f64 {
+        let program = crate::try_parse_program(source).unwrap();
+        let mut vm = VM::from_program(crate::BytecodeCompiler::compile_program(&program).unwrap());
+        vm.execute();
+        vm.stack.pop().unwrap().as_num().unwrap()
+    }
+
+    #[test]
+    fn test_power_operator() {
+        assert_eq!(run_program("2 ** 10"), 1024.0);
+        assert_eq!(run_program("x = 2; x ** 10"), 1024.0);
+        assert!((run_program("x = 2; x ** 0.5") - 2f64.sqrt()).abs() < 1e-15);
+        // `powf` of a negative base and a fractional exponent is NaN, not -2
+        assert!(run_program("x = 0 - 8; x ** (1 / 3)").is_nan());
+        assert_eq!(run_program("x = (0 - 1) ** 0.5; x ** 0"), 1.0);
+        for exponent in 0..=Bytecode::MAX_CHAINED_EXPONENT {
+            let source = format!("x = 3; x ** {}", exponent);
+            assert_eq!(
+                run_program(&source),
+                3f64.powi(exponent as i32),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_small_integer_powers_compile_to_multiplications() {
+        let program = crate::try_parse_program("x = 5; x ** 4").unwrap();
+        let code = crate::BytecodeCompiler::compile_program(&program)
+            .unwrap()
+            .code;
+        assert_eq!(
+            code,
+            vec![
+                Bytecode::LoadConst(5.0),
+                Bytecode::StoreVar(0),
+                Bytecode::LoadVar(0),
+                Bytecode::CheckPow,
+                Bytecode::Dup,
+                Bytecode::Mul,
+                Bytecode::Dup,
+                Bytecode::Mul,
+                Bytecode::Halt,
+            ]
+        );
+        let program = crate::try_parse_program("x = 5; x ** 17; x ** 2.5").unwrap();
+        let code = crate::BytecodeCompiler::compile_program(&program)
+            .unwrap()
+            .code;
+        assert_eq!(code.iter().filter(|op| **op == Bytecode::Pow).count(), 2);
+    }
+
+    #[test]
+    fn test_small_integer_powers_of_non_numbers_fail_as_pow_does() {
+        let run = |source: &str| {
+            let program = crate::try_parse_program(source).unwrap();
+            VM::try_run_program(&crate::BytecodeCompiler::compile_program(&program).unwrap())
+                .map_err(|err| err.kind)
+        };
+        for (base, lhs) in [("\"ab\"", "string"), ("true", "bool")] {
+            for exponent in [0, 1, 2] {
+                let source = format!("x = {}; x ** {}", base, exponent);
+                assert_eq!(
+                    run(&source),
+                    Err(RuntimeErrorKind::TypeMismatch {
+                        op: "**",
+                        lhs,
+                        rhs: "number"
+                    }),
+                    "{}",
+                    source
+                );
+            }
+        }
     }
 
     #[test]
@@ -374,26 +3078,72 @@ mod tests {
         ];
         let mut vm = VM::new(bytecode);
         vm.execute();
-        assert_eq!(vm.stack.pop(), Some(99.0));
+        assert_eq!(vm.stack.pop(), Some(Value::Num(99.0)));
+    }
+
+    #[test]
+    fn test_jump() {
+        let bytecode = vec![
+            Bytecode::LoadConst(1.0),
+            Bytecode::Jump(4),
+            Bytecode::LoadConst(2.0), // skipped
+            Bytecode::LoadConst(3.0), // skipped
+            Bytecode::LoadConst(4.0),
+            Bytecode::Halt,
+        ];
+        let mut vm = VM::new(bytecode);
+        vm.execute();
+        assert_eq!(vm.stack, vec![1.0, 4.0]);
+    }
+
+    #[test]
+    fn test_jump_if_zero() {
+        let bytecode = vec![
+            Bytecode::LoadConst(0.0),
+            Bytecode::JumpIfZero(4),
+            Bytecode::LoadConst(99.0), // skipped
+            Bytecode::LoadConst(88.0), // skipped
+            Bytecode::LoadConst(42.0),
+            Bytecode::Halt,
+        ];
+        let mut vm = VM::new(bytecode);
+        vm.execute();
+        assert_eq!(vm.stack, vec![42.0]);
     }
 
     #[test]
-    fn test_jump() {
+    fn test_loop_stack_stays_flat() {
         let bytecode = vec![
+            Bytecode::LoadConst(0.0),
+            Bytecode::StoreVar(0),
+            Bytecode::LoadVar(0),
+            Bytecode::LoadConst(1000.0),
+            Bytecode::Lt,
+            Bytecode::JumpIfZero(11),
+            Bytecode::LoadVar(0),
             Bytecode::LoadConst(1.0),
-            Bytecode::Jump(4),
-            Bytecode::LoadConst(2.0), // skipped
-            Bytecode::LoadConst(3.0), // skipped
-            Bytecode::LoadConst(4.0),
+            Bytecode::Add,
+            Bytecode::StoreVar(0),
+            Bytecode::Jump(2),
             Bytecode::Halt,
         ];
         let mut vm = VM::new(bytecode);
+        vm.set_max_stack(2);
         vm.execute();
-        assert_eq!(vm.stack, vec![1.0, 4.0]);
+        assert!(vm.stack.is_empty());
+        assert_eq!(vm.memory[0], 1000.0);
+        // Compiled loops and conditions leave nothing behind either
+        let source =
+            "i = 0; n = 0; while i < 1000 { if i % 2 == 0 && i > 10 { n = n + 1 }; i = i + 1 }; n";
+        let program = crate::try_parse_program(source).unwrap();
+        let mut vm = VM::from_program(crate::BytecodeCompiler::compile_program(&program).unwrap());
+        vm.set_max_stack(3);
+        vm.execute();
+        assert_eq!(vm.stack, vec![494.0]);
     }
 
     #[test]
-    fn test_jump_if_zero() {
+    fn test_trace_jump_if_zero() {
         let bytecode = vec![
             Bytecode::LoadConst(0.0),
             Bytecode::JumpIfZero(4),
@@ -402,9 +3152,26 @@ mod tests {
             Bytecode::LoadConst(42.0),
             Bytecode::Halt,
         ];
-        let mut vm = VM::new(bytecode);
+        let collector = TraceCollector::new();
+        let mut vm = VM::new(bytecode.clone());
+        vm.set_trace(collector.hook());
+        vm.execute();
+        let entry = |pc: usize, stack: &[f64]| TraceEntry {
+            pc,
+            instruction: bytecode[pc].clone(),
+            stack: stack.iter().map(|&n| Value::Num(n)).collect(),
+        };
+        let expected = vec![
+            entry(0, &[]),
+            entry(1, &[0.0]),
+            entry(4, &[]),
+            entry(5, &[42.0]),
+        ];
+        assert_eq!(collector.entries(), expected);
+        vm.clear_trace();
+        vm.pc = 0;
         vm.execute();
-        assert_eq!(vm.stack, vec![0.0, 42.0]);
+        assert_eq!(collector.entries().len(), 4);
     }
 
     #[test]
@@ -419,7 +3186,305 @@ mod tests {
         ];
         let mut vm = VM::new(bytecode);
         vm.execute();
-        assert_eq!(vm.stack, vec![5.0, 42.0]);
+        assert_eq!(vm.stack, vec![42.0]);
+    }
+
+    #[test]
+    fn test_step() {
+        let mut vm = VM::new(vec![
+            Bytecode::LoadConst(2.0),
+            Bytecode::LoadConst(3.0),
+            Bytecode::Add,
+            Bytecode::Dup,
+            Bytecode::Mul,
+        ]);
+        let stacks: [&[f64]; 5] = [&[2.0], &[2.0, 3.0], &[5.0], &[5.0, 5.0], &[25.0]];
+        for (pc, stack) in stacks.into_iter().enumerate() {
+            assert_eq!(vm.pc, pc);
+            assert_eq!(vm.current_instruction(), Some(&vm.bytecode[pc]));
+            assert_eq!(vm.step(), Ok(StepOutcome::Continued));
+            assert_eq!(vm.stack, stack);
+        }
+        assert_eq!(vm.current_instruction(), None);
+        assert_eq!(vm.step(), Ok(StepOutcome::Halted));
+        assert_eq!(vm.instructions_executed(), 5);
+    }
+
+    #[test]
+    fn test_step_through_a_call() {
+        let mut vm = VM::from_program(crate::compiler::Program {
+            code: vec![
+                Bytecode::LoadConst(7.0),
+                Bytecode::Call("id".to_string(), 1),
+                Bytecode::Halt,
+                Bytecode::StoreVar(0),
+                Bytecode::LoadVar(0),
+                Bytecode::Return,
+            ],
+            functions: HashMap::from([("id".to_string(), 3)]),
+            ..Default::default()
+        });
+        let mut outcomes = Vec::new();
+        while vm.current_instruction() != Some(&Bytecode::Halt) {
+            if vm.pc == 4 {
+                assert_eq!(
+                    (vm.call_depth(), vm.locals().get(0)),
+                    (1, Some(&Value::Num(7.0)))
+                );
+            }
+            outcomes.push(vm.step().unwrap());
+        }
+        let last = outcomes.pop();
+        assert_eq!(last, Some(StepOutcome::Returned));
+        assert!(outcomes
+            .iter()
+            .all(|&outcome| outcome == StepOutcome::Continued));
+        assert_eq!(
+            (vm.call_depth(), vm.stack.as_slice()),
+            (0, &[Value::Num(7.0)][..])
+        );
+        // Halt stays put, however often it is stepped
+        assert_eq!(vm.step(), Ok(StepOutcome::Halted));
+        assert_eq!(vm.step(), Ok(StepOutcome::Halted));
+        assert_eq!(vm.pc, 2);
+    }
+
+    #[test]
+    fn test_breakpoint_inside_a_function() {
+        let source = "fn add(a, b) { a + b }; add(2, 3) + add(4, 5)";
+        let program = crate::try_parse_program(source).unwrap();
+        let program = crate::BytecodeCompiler::compile_program(&program).unwrap();
+        let entry = program.functions["add"];
+        let sum = entry
+            + program.code[entry..]
+                .iter()
+                .position(|op| *op == Bytecode::Add)
+                .unwrap();
+        let mut vm = VM::from_program(program);
+        vm.add_breakpoint(sum);
+        let locals = |vm: &VM| {
+            let mut values: Vec<f64> = vm
+                .locals()
+                .values()
+                .map(|value| match value {
+                    Value::Num(n) => *n,
+                    other => panic!("unexpected local {}", other),
+                })
+                .collect();
+            values.sort_by(f64::total_cmp);
+            values
+        };
+        assert_eq!(vm.run_until_break(), Ok(Some(Breakpoint::Address(sum))));
+        assert_eq!(
+            (vm.pc, vm.call_depth(), locals(&vm)),
+            (sum, 1, vec![2.0, 3.0])
+        );
+        // Running on does not stop at the same arrival again
+        assert_eq!(vm.run_until_break(), Ok(Some(Breakpoint::Address(sum))));
+        assert_eq!(locals(&vm), vec![4.0, 5.0]);
+        assert_eq!(vm.run_until_break(), Ok(None));
+        assert_eq!(vm.stack.last(), Some(&Value::Num(14.0)));
+    }
+
+    #[test]
+    fn test_function_breakpoints() {
+        let source = "fn sq(x) { x * x }; fn f(x) { sq(x) + 1 }; f(3) + sq(2)";
+        let program = crate::try_parse_program(source).unwrap();
+        let program = crate::BytecodeCompiler::compile_program(&program).unwrap();
+        let entry = program.functions["sq"];
+        let mut vm = VM::from_program(program);
+        vm.add_function_breakpoint("sq");
+        vm.add_function_breakpoint("missing");
+        vm.add_breakpoint(0);
+        let listed: Vec<Breakpoint> = vm.breakpoints().cloned().collect();
+        let sq = Breakpoint::Function("sq".to_string());
+        let missing = Breakpoint::Function("missing".to_string());
+        assert_eq!(
+            listed,
+            [Breakpoint::Address(0), missing.clone(), sq.clone()]
+        );
+        assert_eq!(vm.run_until_break(), Ok(Some(Breakpoint::Address(0))));
+        assert_eq!(vm.run_until_break(), Ok(Some(sq.clone())));
+        assert_eq!((vm.pc, vm.call_depth()), (entry, 2));
+        // The next call is a new arrival
+        assert_eq!(vm.run_until_break(), Ok(Some(sq.clone())));
+        assert_eq!((vm.pc, vm.call_depth()), (entry, 1));
+        assert!(vm.remove_breakpoint(&sq));
+        assert!(!vm.remove_breakpoint(&sq));
+        assert_eq!(vm.run_until_break(), Ok(None));
+        assert_eq!(vm.stack.last(), Some(&Value::Num(14.0)));
+    }
+
+    #[test]
+    fn test_fuel_stops_an_endless_loop() {
+        let mut vm = VM::new(vec![Bytecode::LoadConst(1.0), Bytecode::Jump(0)]);
+        vm.set_fuel(Some(101));
+        let err = vm.try_execute().unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::OutOfFuel { executed: 101 });
+        assert_eq!(
+            err.to_string(),
+            "Out of fuel after 101 instructions at instruction 1"
+        );
+        assert_eq!((vm.pc, vm.stack.len(), vm.fuel()), (1, 51, Some(0)));
+        // More fuel picks up where the run stopped
+        vm.set_fuel(Some(3));
+        let err = vm.resume().unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::OutOfFuel { executed: 104 });
+        assert_eq!((vm.pc, vm.stack.len()), (0, 52));
+    }
+
+    #[test]
+    fn test_fuel_to_spare() {
+        let program = crate::try_parse_program("s = 0; for i = 1 to 10 { s = s + i }; s").unwrap();
+        let program = crate::BytecodeCompiler::compile_program(&program).unwrap();
+        let mut unlimited = VM::from_program(program.clone());
+        unlimited.execute();
+        let needed = unlimited.instructions_executed();
+        let mut vm = VM::from_program(program.clone());
+        vm.set_fuel(Some(needed + 5));
+        vm.execute();
+        assert_eq!(vm.stack, vec![55.0]);
+        assert_eq!(vm.instructions_executed(), needed);
+        assert_eq!(vm.fuel(), Some(5));
+        let mut vm = VM::from_program(program);
+        vm.set_fuel(Some(needed - 1));
+        assert!(vm.try_execute().is_err());
+        vm.set_fuel(Some(1));
+        vm.resume().unwrap();
+        assert_eq!(vm.stack, vec![55.0]);
+    }
+
+    #[test]
+    fn test_snapshot_and_restore_mid_run() {
+        let source =
+            "fn tri(n, xs) { s = 0; i = 1; while i <= n { s = s + i; xs[0] = s; i = i + 1 }; s }; \
+                      xs = [0]; ys = [xs, xs]; r = tri(100, xs); ys[1][0] + r";
+        let program = crate::try_parse_program(source).unwrap();
+        let program = crate::BytecodeCompiler::compile_program(&program).unwrap();
+        let mut unlimited = VM::from_program(program.clone());
+        unlimited.execute();
+        assert_eq!(unlimited.stack, vec![10100.0]);
+        let half = unlimited.instructions_executed() / 2;
+
+        let mut vm = VM::from_program(program.clone());
+        vm.set_fuel(Some(half));
+        assert!(vm.try_execute().is_err());
+        assert_eq!(vm.call_depth(), 1);
+        let snapshot = vm.snapshot().unwrap();
+        let mut restored = VM::restore(&program, snapshot.clone());
+        assert_eq!((restored.pc, restored.call_depth()), (vm.pc, 1));
+        assert_eq!(restored.snapshot(), Ok(snapshot));
+        restored.execute();
+        assert_eq!(restored.stack, unlimited.stack);
+        // `xs` and the elements of `ys` are still one array
+        let xs = restored.get_var(&program, "xs").unwrap();
+        assert_eq!(xs.to_string(), "[5050]");
+        let ys = restored.get_var(&program, "ys").unwrap();
+        assert_eq!(ys.to_string(), "[[5050], [5050]]");
+    }
+
+    #[test]
+    fn test_snapshot_needs_threads_synced() {
+        let mut vm = VM::new(vec![
+            Bytecode::LoadConst(5.0),
+            Bytecode::Spawn,
+            Bytecode::Sync,
+            Bytecode::Halt,
+        ]);
+        vm.step().unwrap();
+        vm.step().unwrap();
+        let err = vm.snapshot().unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::UnsyncedThreads(1));
+        vm.step().unwrap();
+        assert!(vm.snapshot().is_ok());
+    }
+
+    #[test]
+    fn test_profile_counts_loop_body() {
+        let source = "s = 0; i = 0; while i < 100 { s = s + i; i = i + 1 }; s";
+        let program = crate::try_parse_program(source).unwrap();
+        let program = crate::BytecodeCompiler::compile_program(&program).unwrap();
+        let mut vm = VM::from_program(program.clone());
+        assert_eq!(vm.profile_report(), None);
+        vm.enable_profiling();
+        vm.execute();
+        let report = vm.profile_report().unwrap();
+        assert_eq!(vm.stack, vec![4950.0]);
+        // Both additions run once an iteration, the test once more
+        let adds: Vec<usize> = (0..program.code.len())
+            .filter(|&pc| program.code[pc] == Bytecode::Add)
+            .collect();
+        assert_eq!(adds.len(), 2);
+        for pc in adds {
+            assert_eq!(report.count_at(pc), 100);
+        }
+        assert!(report.opcodes.contains(&("add", 200)));
+        assert!(report.opcodes.contains(&("lt", 101)));
+        assert_eq!(report.instructions[0].count, 101);
+        let total: u64 = report.opcodes.iter().map(|(_, count)| count).sum();
+        assert_eq!(total, vm.instructions_executed());
+        assert!(report.functions.is_empty());
+        let table = report.to_string();
+        assert!(table.starts_with("opcode"));
+        assert!(table.contains("add                       200"));
+    }
+
+    #[test]
+    fn test_profile_attributes_self_time_to_functions() {
+        let source = "fn busy(n) { i = 0; while i < n { i = i + 1 }; i }; \
+                      fn idle() { 1 }; \
+                      fn outer() { busy(20000) + idle() }; \
+                      outer() + idle()";
+        let program = crate::try_parse_program(source).unwrap();
+        let program = crate::BytecodeCompiler::compile_program(&program).unwrap();
+        let mut vm = VM::from_program(program);
+        vm.enable_profiling();
+        vm.execute();
+        let report = vm.profile_report().unwrap();
+        let [busy, idle, outer] = ["busy", "idle", "outer"].map(|name| {
+            report
+                .function(name)
+                .unwrap_or_else(|| panic!("no profile of {}", name))
+        });
+        assert_eq!((busy.calls, idle.calls, outer.calls), (1, 2, 1));
+        assert_eq!(report.functions[0].name, "busy");
+        assert!(busy.instructions > 20000 * 5);
+        assert!(busy.self_time > outer.self_time && busy.self_time > idle.self_time);
+        assert!(busy.instructions > outer.instructions + idle.instructions);
+        // Time in `busy` counts toward `outer` only as total time
+        assert!(outer.total_time >= busy.total_time + outer.self_time);
+        assert_eq!(busy.total_time, busy.self_time);
+    }
+
+    #[test]
+    fn test_truthiness() {
+        let falsy = [Value::Bool(false), Value::Num(0.0), Value::Num(-0.0)];
+        let truthy = [
+            Value::Bool(true),
+            Value::Num(-2.5),
+            Value::Num(f64::NAN),
+            Value::from(""),
+            Value::from(vec![]),
+            Value::Unit,
+        ];
+        for (values, truth) in [(&falsy[..], false), (&truthy[..], true)] {
+            for value in values {
+                assert_eq!(value.is_truthy(), truth, "{}", value);
+                let mut vm = VM::new(vec![
+                    Bytecode::JumpIfNotZero(3),
+                    Bytecode::LoadConst(0.0),
+                    Bytecode::Halt,
+                    Bytecode::LoadConst(1.0),
+                ]);
+                vm.stack.push(value.clone());
+                vm.execute();
+                assert_eq!(vm.stack, vec![if truth { 1.0 } else { 0.0 }], "{}", value);
+                let mut vm = VM::new(vec![Bytecode::Not]);
+                vm.stack.push(value.clone());
+                vm.execute();
+                assert_eq!(vm.stack, vec![Value::Bool(!truth)]);
+            }
+        }
     }
 
     #[test]
@@ -436,6 +3501,111 @@ mod tests {
         assert!(vm.stack.is_empty());
     }
 
+    #[test]
+    fn test_swap() {
+        let run = |code: Vec<Bytecode>| VM::try_run(code).map_err(|err| (err.kind, err.pc));
+        assert_eq!(
+            run(vec![
+                Bytecode::LoadConst(1.0),
+                Bytecode::LoadConst(8.0),
+                Bytecode::Swap,
+                Bytecode::Sub,
+            ]),
+            Ok(Some(Value::Num(7.0)))
+        );
+        assert_eq!(
+            run(vec![Bytecode::LoadConst(1.0), Bytecode::Swap]),
+            Err((RuntimeErrorKind::StackUnderflow, 1))
+        );
+    }
+
+    #[test]
+    fn test_over() {
+        let mut vm = VM::new(vec![
+            Bytecode::LoadConst(1.0),
+            Bytecode::LoadConst(2.0),
+            Bytecode::Over,
+        ]);
+        vm.execute();
+        assert_eq!(vm.stack, vec![1.0, 2.0, 1.0]);
+        let err = VM::try_run(vec![Bytecode::LoadConst(1.0), Bytecode::Over]).unwrap_err();
+        assert_eq!((err.kind, err.pc), (RuntimeErrorKind::StackUnderflow, 1));
+        // The copy counts against the stack limit like any push
+        let mut vm = VM::new(vec![
+            Bytecode::LoadConst(1.0),
+            Bytecode::LoadConst(2.0),
+            Bytecode::Over,
+        ]);
+        vm.set_max_stack(2);
+        assert_eq!(
+            vm.try_execute().unwrap_err().kind,
+            RuntimeErrorKind::StackOverflow { limit: 2 }
+        );
+    }
+
+    #[test]
+    fn test_rot() {
+        let mut vm = VM::new(vec![
+            Bytecode::LoadConst(1.0),
+            Bytecode::LoadConst(2.0),
+            Bytecode::LoadConst(3.0),
+            Bytecode::Rot,
+        ]);
+        vm.execute();
+        assert_eq!(vm.stack, vec![2.0, 3.0, 1.0]);
+        let err = VM::try_run(vec![
+            Bytecode::LoadConst(1.0),
+            Bytecode::LoadConst(2.0),
+            Bytecode::Rot,
+        ])
+        .unwrap_err();
+        assert_eq!((err.kind, err.pc), (RuntimeErrorKind::StackUnderflow, 2));
+    }
+
+    #[test]
+    fn test_pop_n_and_dup_n() {
+        let mut vm = VM::new(vec![
+            Bytecode::PopN(0),
+            Bytecode::DupN(0),
+            Bytecode::LoadConst(1.0),
+            Bytecode::LoadConst(2.0),
+            Bytecode::DupN(3),
+            Bytecode::PopN(2),
+            Bytecode::Halt,
+        ]);
+        vm.execute();
+        assert_eq!(vm.stack, vec![1.0, 2.0, 2.0]);
+        let run = |code: Vec<Bytecode>| VM::try_run(code).map_err(|err| (err.kind, err.pc));
+        assert_eq!(
+            run(vec![
+                Bytecode::LoadConst(1.0),
+                Bytecode::LoadConst(2.0),
+                Bytecode::PopN(3),
+            ]),
+            Err((RuntimeErrorKind::StackUnderflow, 2))
+        );
+        assert_eq!(
+            run(vec![Bytecode::DupN(1)]),
+            Err((RuntimeErrorKind::StackUnderflow, 0))
+        );
+        // Each copy counts against the stack limit
+        let mut vm = VM::new(vec![Bytecode::LoadConst(1.0), Bytecode::DupN(5)]);
+        vm.set_max_stack(4);
+        assert_eq!(
+            vm.try_execute().unwrap_err().kind,
+            RuntimeErrorKind::StackOverflow { limit: 4 }
+        );
+    }
+
+    #[test]
+    fn test_for_survives_sync_in_its_body() {
+        // `Sync` empties the stack, so the loop must keep nothing on it
+        let source = "total = 0; for i = 1 to 3 { spawn { 1 }; sync; total = total + i }; total";
+        let program = crate::try_parse_program(source).unwrap();
+        let program = crate::BytecodeCompiler::compile_program(&program).unwrap();
+        assert_eq!(VM::try_run_program(&program), Ok(Value::Num(6.0)));
+    }
+
     #[test]
     fn test_parallel_spawn_and_sync() {
         let bytecode = vec![
@@ -449,23 +3619,160 @@ mod tests {
         let mut vm = VM::new(bytecode);
         vm.execute();
         // The main thread's stack should have the result of the addition
-        assert_eq!(vm.stack.pop(), Some(5.0));
+        assert_eq!(vm.stack.pop(), Some(Value::Num(5.0)));
     }
 
     #[test]
-    #[should_panic(expected = "Variable not found in memory")]
     fn test_load_var_not_found() {
-        let bytecode = vec![Bytecode::LoadVar(999), Bytecode::Halt];
-        let mut vm = VM::new(bytecode);
-        vm.execute();
+        let err = VM::try_run(vec![Bytecode::LoadVar(999), Bytecode::Halt]).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::UndefinedVariable(999));
+        assert_eq!(err.pc, 0);
+    }
+
+    #[test]
+    fn test_slots_grow_and_track_stores() {
+        // Storing past the end grows the slots; the ones skipped stay undefined
+        let code = vec![
+            Bytecode::LoadConst(4.0),
+            Bytecode::StoreVar(7),
+            Bytecode::LoadVar(7),
+            Bytecode::LoadVar(3),
+            Bytecode::Halt,
+        ];
+        let mut vm = VM::new(code);
+        let err = vm.try_execute().unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::UndefinedVariable(3));
+        assert_eq!(err.pc, 3);
+        assert_eq!(vm.memory[7], 4.0);
+        assert_eq!(vm.memory.get(3), None);
     }
 
     #[test]
-    #[should_panic(expected = "Stack is empty")]
     fn test_stack_underflow_add() {
-        let bytecode = vec![Bytecode::Add, Bytecode::Halt];
-        let mut vm = VM::new(bytecode);
-        vm.execute();
+        let err = VM::try_run(vec![Bytecode::Add, Bytecode::Halt]).unwrap_err();
+        assert!(matches!(
+            err,
+            super::RuntimeError {
+                kind: RuntimeErrorKind::StackUnderflow,
+                pc: 0,
+                ..
+            }
+        ));
+        let err =
+            VM::try_run(vec![Bytecode::LoadConst(1.0), Bytecode::Pop, Bytecode::Pop]).unwrap_err();
+        assert_eq!((err.kind, err.pc), (RuntimeErrorKind::StackUnderflow, 2));
+    }
+
+    #[test]
+    #[should_panic(expected = "Stack is empty at instruction 0")]
+    fn test_execute_still_panics() {
+        VM::new(vec![Bytecode::Add, Bytecode::Halt]).execute();
+    }
+
+    #[test]
+    fn test_return_needs_a_return_address() {
+        let err = VM::try_run(vec![Bytecode::LoadConst(1.0), Bytecode::Return]).unwrap_err();
+        assert_eq!((err.kind, err.pc), (RuntimeErrorKind::InvalidReturn, 1));
+        let bytecode = vec![
+            Bytecode::LoadConst(0.5),
+            Bytecode::LoadConst(1.0),
+            Bytecode::Return,
+        ];
+        let err = VM::try_run(bytecode).unwrap_err();
+        assert_eq!((err.kind, err.pc), (RuntimeErrorKind::InvalidReturn, 2));
+    }
+
+    #[test]
+    fn test_user_function_takes_a_string() {
+        let program = crate::compiler::Program {
+            code: vec![
+                Bytecode::LoadStr("x".to_string()),
+                Bytecode::Call("f".to_string(), 1),
+                Bytecode::Halt,
+                Bytecode::Return,
+            ],
+            functions: HashMap::from([("f".to_string(), 3)]),
+            ..Default::default()
+        };
+        assert_eq!(VM::try_run_program(&program), Ok(Value::from("x")));
+    }
+
+    #[test]
+    fn test_stack_limit() {
+        let mut vm = VM::new(vec![Bytecode::LoadConst(1.0); 6]);
+        vm.set_max_stack(4);
+        let err = vm.try_execute().unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::StackOverflow { limit: 4 });
+        assert_eq!(
+            err.to_string(),
+            "Stack overflow: more than 4 values at instruction 4"
+        );
+        assert_eq!((vm.pc, vm.stack.len()), (4, 4));
+        // Calls count their return address, and `Dup` its copy
+        let program = crate::compiler::Program {
+            code: vec![
+                Bytecode::LoadConst(1.0),
+                Bytecode::LoadConst(2.0),
+                Bytecode::Call("f".to_string(), 2),
+                Bytecode::Halt,
+                Bytecode::Dup,
+                Bytecode::Return,
+            ],
+            functions: HashMap::from([("f".to_string(), 4)]),
+            ..Default::default()
+        };
+        for (limit, pc) in [(2, 2), (3, 4)] {
+            let mut vm = VM::from_program(program.clone());
+            vm.set_max_stack(limit);
+            let err = vm.try_execute().unwrap_err();
+            assert_eq!(
+                (err.kind, err.pc),
+                (RuntimeErrorKind::StackOverflow { limit }, pc)
+            );
+        }
+    }
+
+    #[test]
+    fn test_call_depth_limit() {
+        let run = |n: usize, limit: usize| {
+            let source = format!(
+                "fn down(n) {{ if n == 0 {{ 0 }} else {{ down(n - 1) + 1 }} }} down({})",
+                n
+            );
+            let program = crate::BytecodeCompiler::compile_program(
+                &crate::try_parse_program(&source).unwrap(),
+            )
+            .unwrap();
+            let mut vm = VM::from_program(program);
+            vm.set_max_call_depth(limit);
+            vm.try_execute().map(|()| vm.stack.pop().unwrap())
+        };
+        // down(n) nests n + 1 calls
+        let err = run(50, 50).unwrap_err();
+        assert_eq!(
+            err.kind,
+            RuntimeErrorKind::CallDepthExceeded {
+                name: "down".to_string(),
+                limit: 50
+            }
+        );
+        assert_eq!(run(49, 50).unwrap(), 49.0);
+        assert_eq!(run(50, 51).unwrap(), 50.0);
+        assert_eq!(
+            VM::new(Vec::new()).max_call_depth,
+            VM::DEFAULT_MAX_CALL_DEPTH
+        );
+    }
+
+    #[test]
+    fn test_spawned_block_errors_surface_at_sync() {
+        let source = "spawn { 1 / 0 }; sync; 2";
+        let program =
+            crate::BytecodeCompiler::compile_program(&crate::try_parse_program(source).unwrap())
+                .unwrap();
+        let err = VM::try_run_program(&program).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::DivisionByZero);
+        assert!(matches!(program.code[err.pc], Bytecode::Div));
     }
 
     #[test]
@@ -510,7 +3817,15 @@ mod tests {
         assert_eq!(vm.stack, vec![5.0, 5.0]);
     }
 
-    use crate::vm::{Bytecode, VM};
+    use crate::compiler::CompileError;
+    use crate::scanner::Span;
+    use crate::vm::{
+        format_print_args, print_to, Breakpoint, Bytecode, NativeCtx, NativeError, NativeFn,
+        RuntimeErrorKind, SentValues, StepOutcome, TraceCollector, TraceEntry, Value, VM,
+    };
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
 
     #[test]
     fn test_native_print_function() {
@@ -523,25 +3838,382 @@ mod tests {
         vm.execute();
     }
 
+    #[test]
+    fn test_math_natives() {
+        let run = |source| {
+            let program = crate::try_parse_program(source).unwrap();
+            VM::try_run_program(&crate::BytecodeCompiler::compile_program(&program).unwrap())
+        };
+        assert_eq!(run("sqrt(16) + exp(0)"), Ok(Value::Num(5.0)));
+        assert_eq!(run("sin(0) + cos(0)"), Ok(Value::Num(1.0)));
+        assert!(run("sqrt(1, 2)").is_err());
+        assert!(run("sqrt(\"a\")").is_err());
+    }
+
+    #[test]
+    fn test_get_var_reads_globals_by_name() {
+        let program = crate::try_parse_program("a = 1; b = a + 1; fn f(c) { c }").unwrap();
+        let program = crate::BytecodeCompiler::compile_program(&program).unwrap();
+        assert_eq!(
+            program.symbols,
+            HashMap::from([("a".to_string(), 0), ("b".to_string(), 1)])
+        );
+        let mut vm = VM::from_program(program.clone());
+        assert_eq!(vm.get_var(&program, "a"), None);
+        vm.execute();
+        assert_eq!(vm.get_var(&program, "a"), Some(Value::Num(1.0)));
+        assert_eq!(vm.get_var(&program, "b"), Some(Value::Num(2.0)));
+        // Parameters are locals of their call, not globals
+        assert_eq!(vm.get_var(&program, "c"), None);
+    }
+
     #[test]
     fn test_user_function_call() {
         // Simulate a function at address 4: return x+1
         let bytecode = vec![
             Bytecode::LoadConst(5.0), // argument
-            Bytecode::StoreVar(0),    // store as local var 0
+            Bytecode::StoreVar(0),    // store as global var 0
             Bytecode::Call("inc".to_string(), 1),
             Bytecode::Halt,
             // Function 'inc' starts here (address 4):
-            Bytecode::LoadVar(0), // load argument
+            Bytecode::LoadGlobal(0), // load argument
             Bytecode::LoadConst(1.0),
             Bytecode::Add,
             Bytecode::Return,
         ];
-        let mut vm = VM::new(bytecode);
         // Register the function at the correct address
-        vm.user_functions.insert("inc".to_string(), 4);
-        vm.execute();
+        let program = crate::compiler::Program {
+            code: bytecode,
+            functions: HashMap::from([("inc".to_string(), 4)]),
+            ..Default::default()
+        };
         // The result should be left on the stack after return
-        assert_eq!(vm.stack.pop(), Some(6.0));
+        assert_eq!(VM::run_program(&program), 6.0);
+    }
+
+    #[test]
+    fn test_format_print_args() {
+        assert_eq!(format_print_args(&[1.0, 2.5]), "1 2.5\n");
+        assert_eq!(format_print_args(&["hi", "42"]), "hi 42\n");
+        assert_eq!(format_print_args::<f64>(&[]), "\n");
+    }
+
+    #[test]
+    fn test_load_str_passed_to_print() {
+        let bytecode = vec![
+            Bytecode::LoadConst(7.0),
+            Bytecode::LoadStr("hi".to_string()),
+            Bytecode::LoadConst(42.0),
+            Bytecode::Call("print".to_string(), 2),
+            Bytecode::Halt,
+        ];
+        let mut vm = VM::new(bytecode);
+        vm.execute();
+        assert_eq!(vm.stack, vec![7.0, 0.0]);
+    }
+
+    #[test]
+    fn test_register_native_checks_arity() {
+        let call = |argc: usize| {
+            let mut code = vec![Bytecode::LoadConst(3.0); argc];
+            code.push(Bytecode::Call("double".to_string(), argc));
+            let mut vm = VM::new(code);
+            vm.register_native("double", Some(1), |args| args[0] * 2.0);
+            vm.try_execute().map(|()| vm.stack)
+        };
+        assert_eq!(call(1), Ok(vec![Value::Num(6.0)]));
+        let err = call(2).unwrap_err();
+        assert_eq!(
+            err.kind,
+            RuntimeErrorKind::ArityMismatch {
+                name: "double".to_string(),
+                expected: 1,
+                found: 2
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "Function 'double' takes 1 argument(s) but 2 were given at instruction 2"
+        );
+        // Without an arity the native sees however many arguments were passed
+        let mut vm = VM::new(vec![
+            Bytecode::LoadConst(1.0),
+            Bytecode::LoadConst(2.0),
+            Bytecode::Call("count".to_string(), 2),
+            Bytecode::Call("count".to_string(), 0),
+        ]);
+        vm.register_native("count", None, |args| args.len() as f64);
+        vm.execute();
+        assert_eq!(vm.stack, vec![2.0, 0.0]);
+    }
+
+    #[test]
+    fn test_natives_can_fail_and_reach_globals() {
+        let checked_sqrt: Rc<NativeFn> = Rc::new(|_: &mut NativeCtx, args: &[Value]| {
+            match args.first().and_then(Value::as_num) {
+                Some(value) if value >= 0.0 => Ok(Value::Num(value.sqrt())),
+                _ => Err(NativeError::new("needs a non-negative number")),
+            }
+        });
+        // Adds its argument to global 0 and returns the old total
+        let accumulate: Rc<NativeFn> = Rc::new(|ctx: &mut NativeCtx, args: &[Value]| {
+            let total = ctx.global(0).and_then(Value::as_num).unwrap_or(0.0);
+            let add = args.iter().filter_map(Value::as_num).sum::<f64>();
+            ctx.set_global(0, Value::Num(total + add));
+            Ok(Value::Num(total))
+        });
+        let natives = [
+            ("checked_sqrt".to_string(), checked_sqrt),
+            ("accumulate".to_string(), accumulate),
+        ];
+        let mut vm = VM::new(vec![
+            Bytecode::LoadConst(5.0),
+            Bytecode::Call("accumulate".to_string(), 1),
+            Bytecode::LoadConst(4.0),
+            Bytecode::Call("accumulate".to_string(), 1),
+            Bytecode::Call("checked_sqrt".to_string(), 1),
+            Bytecode::LoadConst(-1.0),
+            Bytecode::Call("checked_sqrt".to_string(), 1),
+        ])
+        .with_natives(natives);
+        let err = vm.try_execute().unwrap_err();
+        assert_eq!(
+            err.kind,
+            RuntimeErrorKind::NativeFailed {
+                name: "checked_sqrt".to_string(),
+                message: "needs a non-negative number".to_string()
+            }
+        );
+        assert_eq!(err.pc, 6);
+        assert_eq!(
+            err.to_string(),
+            "Native function 'checked_sqrt' failed: needs a non-negative number at instruction 6"
+        );
+        assert_eq!(vm.stack, vec![0.0, 5.0_f64.sqrt()]);
+        assert_eq!(vm.memory.get(0), Some(&Value::Num(9.0)));
+    }
+
+    #[test]
+    fn test_from_program_resolves_calls_to_indices() {
+        use crate::compiler::{CallTarget, Program};
+        let program = Program {
+            code: vec![
+                Bytecode::LoadConst(2.0),
+                Bytecode::Call("double".to_string(), 1),
+                Bytecode::Call("print".to_string(), 1),
+                Bytecode::Call("missing".to_string(), 0),
+                Bytecode::LoadConst(3.0),
+                Bytecode::Call("double".to_string(), 1),
+                Bytecode::Halt,
+                Bytecode::StoreVar(0),
+                Bytecode::LoadVar(0),
+                Bytecode::LoadVar(0),
+                Bytecode::Add,
+                Bytecode::Return,
+            ],
+            functions: HashMap::from([("double".to_string(), 7)]),
+            ..Program::default()
+        };
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = VM::from_program(program.clone())
+            .with_natives([("print".to_string(), print_to(Rc::clone(&out)))]);
+        // Each function gets one target; the unknown name keeps its `Call`
+        assert_eq!(
+            vm.call_targets,
+            [
+                CallTarget::User("double".to_string()),
+                CallTarget::Native("print".to_string()),
+            ]
+        );
+        assert_eq!(
+            vm.bytecode[1..6],
+            [
+                Bytecode::CallIdx(0, 1),
+                Bytecode::CallIdx(1, 1),
+                Bytecode::Call("missing".to_string(), 0),
+                Bytecode::LoadConst(3.0),
+                Bytecode::CallIdx(0, 1),
+            ]
+        );
+        vm.execute();
+        assert_eq!(out.take(), b"4\n");
+        assert_eq!(vm.stack, vec![0.0, 6.0]);
+
+        // A native registered after loading shadows the user function
+        let mut vm = VM::from_program(program.clone());
+        vm.register_native("double", Some(1), |args| args[0] * 10.0);
+        vm.set_output(std::io::sink());
+        vm.execute();
+        assert_eq!(vm.stack, vec![0.0, 30.0]);
+
+        assert_eq!(
+            VM::from_program_strict(program).err(),
+            Some(CompileError::UndefinedFunction {
+                name: "missing".to_string(),
+                span: Span::default(),
+            })
+        );
+        let err = VM::try_run(vec![Bytecode::CallIdx(0, 0)]).unwrap_err();
+        assert_eq!(err.kind, RuntimeErrorKind::UndefinedCallTarget(0));
+    }
+
+    #[test]
+    fn test_load_str_rejected_by_numeric_native() {
+        let bytecode = vec![
+            Bytecode::LoadStr("nine".to_string()),
+            Bytecode::Call("sqrt".to_string(), 1),
+            Bytecode::Halt,
+        ];
+        let mut vm = VM::new(bytecode);
+        vm.register_native("sqrt", Some(1), |args| args[0].sqrt());
+        assert_eq!(
+            vm.try_execute().unwrap_err().to_string(),
+            "Native function 'sqrt' failed: expected numbers, found a string at instruction 1"
+        );
+    }
+
+    #[test]
+    fn test_string_concatenation() {
+        let run = |source: &str| {
+            let program = crate::try_parse_program(source).unwrap();
+            let program = crate::BytecodeCompiler::compile_program(&program).unwrap();
+            VM::try_run_program(&program).map_err(|err| err.kind)
+        };
+        assert_eq!(run("\"ab\" + \"cd\""), Ok(Value::from("abcd")));
+        assert_eq!(
+            run("s = \"\"; for i = 1 to 3 { s = s + \"x\" }; s"),
+            Ok(Value::from("xxx"))
+        );
+        // Numbers are not converted to strings implicitly
+        assert_eq!(
+            run("1 + \"a\""),
+            Err(RuntimeErrorKind::TypeMismatch {
+                op: "+",
+                lhs: "number",
+                rhs: "string"
+            })
+        );
+        assert_eq!(
+            run("\"a\" - \"a\""),
+            Err(RuntimeErrorKind::TypeMismatch {
+                op: "-",
+                lhs: "string",
+                rhs: "string"
+            })
+        );
+    }
+
+    #[test]
+    fn test_compile_rejects_deep_nesting() {
+        let mut expr: crate::parser::Expr = crate::parser::ExprKind::Number(1.0).into();
+        for _ in 0..2000 {
+            expr = crate::parser::ExprKind::UnaryOp {
+                op: crate::scanner::Token::Minus,
+                rhs: Box::new(expr),
+            }
+            .into();
+        }
+        // Unoptimized builds need more than the default test stack to reach the limit
+        let err = std::thread::Builder::new()
+            .stack_size(64 << 20)
+            .spawn(move || crate::BytecodeCompiler::try_compile(&expr).unwrap_err())
+            .unwrap()
+            .join()
+            .unwrap();
+        assert!(
+            matches!(
+                err,
+                crate::CompileError::TooDeeplyNested { limit: 1024, .. }
+            ),
+            "{:?}",
+            err
+        );
+        assert!(err
+            .to_string()
+            .starts_with("Expression nested more than 1024 levels deep cannot be compiled"));
+    }
+
+    #[test]
+    fn test_compile_accepts_nesting_at_parser_limit() {
+        let source = format!("{}1", "-".repeat(1000));
+        std::thread::Builder::new()
+            .stack_size(64 << 20)
+            .spawn(move || {
+                assert_eq!(
+                    VM::run(crate::BytecodeCompiler::compile(&crate::parse_expr(
+                        &source
+                    ))),
+                    1.0
+                )
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_compile_rejects_computed_callee() {
+        let err = crate::BytecodeCompiler::try_compile(&crate::parse_expr("f(1)(2)")).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                crate::CompileError::UnsupportedExpression {
+                    construct: "A call of anything but a named function",
+                    ..
+                }
+            ),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_compile_rejects_syntax_error_placeholder() {
+        let err = crate::BytecodeCompiler::try_compile(&crate::parser::ExprKind::Error.into())
+            .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                crate::CompileError::UnsupportedExpression {
+                    construct: "A syntax error",
+                    ..
+                }
+            ),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Undefined variable 'nope'")]
+    fn test_compile_still_panics_for_old_callers() {
+        crate::BytecodeCompiler::compile(&crate::parse_expr("nope + 1"));
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod serde_tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let source = "fn f(n) { a = [n, \"x\", true]; b = [a, a, 10 ** 400]; n / 4 }; f(2)";
+        let program = crate::try_parse_program(source).unwrap();
+        let program = crate::BytecodeCompiler::compile_program(&program).unwrap();
+        let mut vm = VM::from_program(program.clone());
+        while vm.current_instruction() != Some(&Bytecode::Div) {
+            vm.step().unwrap();
+        }
+        let snapshot = vm.snapshot().unwrap();
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let back: VmSnapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, snapshot);
+        let mut restored = VM::restore(&program, back);
+        assert_eq!(
+            restored.locals().get(2).unwrap().to_string(),
+            "[[2, x, true], [2, x, true], inf]"
+        );
+        restored.execute();
+        assert_eq!(restored.stack, vec![0.5]);
     }
 }
@@ -0,0 +1,202 @@
+//! The execution profiler turned on by `VM::enable_profiling`.
+//!
+//! It counts how often each kind of instruction and each instruction address
+//! runs, and for each user function how often it is called, how many
+//! instructions run in its own body and how long its calls take. A
+//! function's self time leaves out the functions it calls; its total time
+//! counts them, and counts a recursive function once per outermost call.
+//! Blocks run by `SpawnBlock` run on VMs of their own and are not profiled.
+
+use super::Bytecode;
+use crate::compiler::asm;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// What the profiler has gathered while a VM ran.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Profiler {
+    opcodes: HashMap<&'static str, u64>,
+    pcs: Vec<u64>,
+    functions: HashMap<String, FunctionProfile>,
+    calls: Vec<ActiveCall>,
+}
+
+/// A user function call that has not returned yet.
+#[derive(Debug, Clone)]
+struct ActiveCall {
+    name: String,
+    entered: Instant,
+    /// Time spent in the functions it called.
+    callees: Duration,
+    instructions: u64,
+}
+
+impl Profiler {
+    /// Count the instruction at `pc`, about to run.
+    pub(crate) fn count(&mut self, pc: usize, instruction: &Bytecode) {
+        *self.opcodes.entry(asm::mnemonic(instruction)).or_default() += 1;
+        if pc >= self.pcs.len() {
+            self.pcs.resize(pc + 1, 0);
+        }
+        self.pcs[pc] += 1;
+        if let Some(call) = self.calls.last_mut() {
+            call.instructions += 1;
+        }
+    }
+
+    /// A call of user function `name` has been entered.
+    pub(crate) fn enter(&mut self, name: String) {
+        self.functions.entry(name.clone()).or_default().calls += 1;
+        self.calls.push(ActiveCall {
+            name,
+            entered: Instant::now(),
+            callees: Duration::ZERO,
+            instructions: 0,
+        });
+    }
+
+    /// The innermost user function call has returned.
+    pub(crate) fn exit(&mut self) {
+        let Some(call) = self.calls.pop() else {
+            return;
+        };
+        let elapsed = call.entered.elapsed();
+        let recursive = self.calls.iter().any(|outer| outer.name == call.name);
+        let function = self.functions.entry(call.name).or_default();
+        function.instructions += call.instructions;
+        function.self_time += elapsed.saturating_sub(call.callees);
+        if !recursive {
+            function.total_time += elapsed;
+        }
+        if let Some(caller) = self.calls.last_mut() {
+            caller.callees += elapsed;
+        }
+    }
+
+    /// The report so far, with calls still active counted up to now.
+    pub(crate) fn report(&self, code: &[Bytecode]) -> ProfileReport {
+        let mut finished = self.clone();
+        while !finished.calls.is_empty() {
+            finished.exit();
+        }
+        let mut opcodes: Vec<_> = finished.opcodes.into_iter().collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        let mut instructions: Vec<_> = finished
+            .pcs
+            .iter()
+            .enumerate()
+            .filter(|&(_, &count)| count > 0)
+            .map(|(pc, &count)| InstructionProfile {
+                pc,
+                instruction: code.get(pc).cloned(),
+                count,
+            })
+            .collect();
+        instructions.sort_by(|a, b| b.count.cmp(&a.count).then(a.pc.cmp(&b.pc)));
+        let mut functions: Vec<_> = finished
+            .functions
+            .into_iter()
+            .map(|(name, profile)| FunctionProfile { name, ..profile })
+            .collect();
+        functions.sort_by(|a, b| {
+            b.self_time
+                .cmp(&a.self_time)
+                .then(b.instructions.cmp(&a.instructions))
+                .then(a.name.cmp(&b.name))
+        });
+        ProfileReport {
+            opcodes,
+            instructions,
+            functions,
+        }
+    }
+}
+
+/// How often one instruction ran.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstructionProfile {
+    pub pc: usize,
+    /// The instruction at `pc`, if the code has one there.
+    pub instruction: Option<Bytecode>,
+    pub count: u64,
+}
+
+/// What one user function cost.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FunctionProfile {
+    pub name: String,
+    pub calls: u64,
+    /// Instructions run in the function's own body.
+    pub instructions: u64,
+    pub self_time: Duration,
+    pub total_time: Duration,
+}
+
+/// A profile of a run, from `VM::profile_report`. Every list is sorted
+/// costliest first.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProfileReport {
+    /// How often each kind of instruction ran, by mnemonic.
+    pub opcodes: Vec<(&'static str, u64)>,
+    /// How often each instruction that ran at all ran.
+    pub instructions: Vec<InstructionProfile>,
+    pub functions: Vec<FunctionProfile>,
+}
+
+impl ProfileReport {
+    /// How often the instruction at `pc` ran.
+    pub fn count_at(&self, pc: usize) -> u64 {
+        self.instructions
+            .iter()
+            .find(|profile| profile.pc == pc)
+            .map_or(0, |profile| profile.count)
+    }
+
+    /// The profile of user function `name`, if it was called.
+    pub fn function(&self, name: &str) -> Option<&FunctionProfile> {
+        self.functions.iter().find(|profile| profile.name == name)
+    }
+}
+
+impl fmt::Display for ProfileReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<16} {:>12}", "opcode", "count")?;
+        for (mnemonic, count) in &self.opcodes {
+            writeln!(f, "{:<16} {:>12}", mnemonic, count)?;
+        }
+        writeln!(f)?;
+        writeln!(f, "{:>6}  {:<24} {:>12}", "pc", "instruction", "count")?;
+        for profile in &self.instructions {
+            let instruction = match &profile.instruction {
+                Some(instruction) => instruction.to_string(),
+                None => "?".to_string(),
+            };
+            writeln!(
+                f,
+                "{:>6}  {:<24} {:>12}",
+                profile.pc, instruction, profile.count
+            )?;
+        }
+        if !self.functions.is_empty() {
+            writeln!(f)?;
+            writeln!(
+                f,
+                "{:<16} {:>8} {:>12} {:>12} {:>12}",
+                "function", "calls", "instructions", "self", "total"
+            )?;
+            for profile in &self.functions {
+                writeln!(
+                    f,
+                    "{:<16} {:>8} {:>12} {:>12.2?} {:>12.2?}",
+                    profile.name,
+                    profile.calls,
+                    profile.instructions,
+                    profile.self_time,
+                    profile.total_time
+                )?;
+            }
+        }
+        Ok(())
+    }
+}